@@ -0,0 +1,140 @@
+//! Write budget: freeze a backend after a byte count or time window
+//!
+//! `WriteBudgetBackend` passes reads and writes through to the inner backend
+//! until either the configured number of bytes has been written or the
+//! write window has elapsed. From then on every write fails with
+//! `ReadOnlyError`, which the frontends report as EROFS/EPERM, so captured
+//! data is guaranteed to stop changing.
+
+use super::{BlockBackend, ReadOnlyError};
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Backend wrapper that turns read-only once its write budget is used up
+pub struct WriteBudgetBackend<B> {
+    inner: B,
+    /// Maximum number of bytes accepted (`None` = unlimited)
+    budget: Option<u64>,
+    /// Writes are rejected from this point in time on (`None` = never)
+    deadline: Option<Instant>,
+    written: AtomicU64,
+    frozen: AtomicBool,
+}
+
+impl<B: BlockBackend> WriteBudgetBackend<B> {
+    /// Wrap `inner`; the write window starts now. A window too long to
+    /// represent never closes.
+    pub fn new(inner: B, budget: Option<u64>, window: Option<Duration>) -> Self {
+        Self {
+            inner,
+            budget,
+            deadline: window.and_then(|w| Instant::now().checked_add(w)),
+            written: AtomicU64::new(0),
+            frozen: AtomicBool::new(false),
+        }
+    }
+
+    /// Charge a write of `len` bytes against the budget, or refuse it
+    fn admit(&self, len: u64) -> Result<()> {
+        if self.frozen.load(Ordering::SeqCst) {
+            return Err(ReadOnlyError.into());
+        }
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            self.freeze("Write window elapsed");
+            return Err(ReadOnlyError.into());
+        }
+
+        let before = self.written.fetch_add(len, Ordering::SeqCst);
+        if self.budget.is_some_and(|budget| before + len > budget) {
+            // Rejected writes don't count towards the budget
+            self.written.fetch_sub(len, Ordering::SeqCst);
+            self.freeze("Write budget exhausted");
+            return Err(ReadOnlyError.into());
+        }
+        Ok(())
+    }
+
+    /// Switch to read-only, logging the transition once
+    fn freeze(&self, reason: &str) {
+        if !self.frozen.swap(true, Ordering::SeqCst) {
+            log::warn!(
+                "{}: backend is now read-only ({} bytes written)",
+                reason,
+                self.written.load(Ordering::SeqCst)
+            );
+        }
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for WriteBudgetBackend<B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.inner.read_at(offset, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.admit(src.len() as u64)?;
+        self.inner.write_at(offset, src)
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.admit(len)?;
+        self.inner.write_zeroes_at(offset, len)
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        // Refused once read-only, but transfers nothing so costs no budget
+        self.admit(0)?;
+        self.inner.discard_at(offset, len)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+
+    fn is_read_only(result: Result<()>) -> bool {
+        result.is_err_and(|e| e.is::<ReadOnlyError>())
+    }
+
+    #[test]
+    fn budget_freezes_the_backend() {
+        let backend = WriteBudgetBackend::new(RamBuffer::new(65536), Some(8192), None);
+        backend.write_at(0, &[1; 4096]).unwrap();
+        backend.write_at(4096, &[2; 4096]).unwrap();
+        assert!(is_read_only(backend.write_at(0, &[3; 1])));
+        // Still frozen, and the data is unchanged
+        assert!(is_read_only(backend.write_at(0, &[])));
+        let mut data = [0u8; 1];
+        backend.read_at(0, &mut data).unwrap();
+        assert_eq!(data, [1]);
+    }
+
+    #[test]
+    fn window_freezes_the_backend() {
+        let backend =
+            WriteBudgetBackend::new(RamBuffer::new(65536), None, Some(Duration::from_millis(20)));
+        backend.write_at(0, &[1; 4096]).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(is_read_only(backend.write_at(0, &[2; 4096])));
+    }
+
+    #[test]
+    fn huge_window_never_closes() {
+        let backend = WriteBudgetBackend::new(RamBuffer::new(65536), None, Some(Duration::MAX));
+        backend.write_at(0, &[1; 4096]).unwrap();
+    }
+}
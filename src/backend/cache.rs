@@ -0,0 +1,586 @@
+//! VRAM block cache in front of a slow backing store
+//!
+//! `CacheBackend` presents its inner (backing) backend, for example a file on
+//! NFS, and keeps recently used 64 KiB blocks of it in a cache backend such
+//! as a `VRamBuffer`. Reads are served from the cache when the block is
+//! there; a miss loads the whole block from the backing store first. When
+//! the cache is full, the least recently used block is evicted.
+//!
+//! In writethrough mode writes go to the backing store before completing,
+//! and cached copies are updated so the cache only ever holds clean data.
+//! In writeback mode writes complete in the cache and mark the block dirty;
+//! dirty blocks are written to the backing store when they are evicted and
+//! on `flush`. Until then, the only copy of the data is in the cache.
+//!
+//! The block map is guarded by one lock held for the whole request, so
+//! requests are served one at a time. Flushes are the exception: each one
+//! starts a new write epoch and writes back only the blocks dirtied in an
+//! earlier epoch, copying each out of the cache under the lock but writing
+//! it to the backing store without it, so reads and writes carry on during
+//! the write-back. A block on its way to the backing store stays pinned in
+//! its slot until it gets there.
+
+use super::BlockBackend;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+/// Unit of caching and write-back
+pub const CACHE_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// End of the LRU list
+const NIL: usize = usize::MAX;
+
+/// How writes reach the backing store
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CacheMode {
+    /// Write to the backing store before completing
+    Writethrough,
+    /// Complete writes in the cache; write dirty blocks back on eviction and flush
+    Writeback,
+}
+
+impl fmt::Display for CacheMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CacheMode::Writethrough => "writethrough",
+            CacheMode::Writeback => "writeback",
+        })
+    }
+}
+
+/// A cache slot holding one block, linked into the LRU list
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    block: u64,
+    /// Epoch of the oldest write not yet in the backing store
+    dirty: Option<u64>,
+    /// Being written back by a flush; not to be evicted
+    writing: bool,
+    /// More recently used neighbour
+    prev: usize,
+    /// Less recently used neighbour
+    next: usize,
+}
+
+/// Block map and LRU order of the cache slots
+struct Blocks {
+    slots: Vec<Slot>,
+    /// Cached block -> slot
+    map: HashMap<u64, usize>,
+    /// Slots not holding a block
+    free: Vec<usize>,
+    /// Most recently used slot
+    head: usize,
+    /// Least recently used slot
+    tail: usize,
+    /// Epoch of new writes, advanced by each flush
+    epoch: u64,
+}
+
+impl Blocks {
+    fn new(slots: usize) -> Self {
+        let empty = Slot {
+            block: 0,
+            dirty: None,
+            writing: false,
+            prev: NIL,
+            next: NIL,
+        };
+        Self {
+            slots: vec![empty; slots],
+            map: HashMap::new(),
+            free: (0..slots).rev().collect(),
+            head: NIL,
+            tail: NIL,
+            epoch: 0,
+        }
+    }
+
+    fn unlink(&mut self, slot: usize) {
+        let Slot { prev, next, .. } = self.slots[slot];
+        match prev {
+            NIL => self.head = next,
+            prev => self.slots[prev].next = next,
+        }
+        match next {
+            NIL => self.tail = prev,
+            next => self.slots[next].prev = prev,
+        }
+    }
+
+    fn push_front(&mut self, slot: usize) {
+        self.slots[slot].prev = NIL;
+        self.slots[slot].next = self.head;
+        match self.head {
+            NIL => self.tail = slot,
+            head => self.slots[head].prev = slot,
+        }
+        self.head = slot;
+    }
+
+    /// Mark `slot` as most recently used
+    fn touch(&mut self, slot: usize) {
+        if self.head != slot {
+            self.unlink(slot);
+            self.push_front(slot);
+        }
+    }
+
+    /// Put `block` into the unused `slot`, clean
+    fn insert(&mut self, slot: usize, block: u64) {
+        self.slots[slot].block = block;
+        self.slots[slot].dirty = None;
+        self.map.insert(block, slot);
+        self.push_front(slot);
+    }
+
+    /// Record a write to `slot` in the current epoch
+    fn mark_dirty(&mut self, slot: usize) {
+        let epoch = self.epoch;
+        self.slots[slot].dirty.get_or_insert(epoch);
+    }
+
+    /// Least recently used slot not being written back
+    fn evictable(&self) -> Option<usize> {
+        let mut slot = self.tail;
+        while slot != NIL && self.slots[slot].writing {
+            slot = self.slots[slot].prev;
+        }
+        (slot != NIL).then_some(slot)
+    }
+
+    /// Drop the block in `slot`, dirty or not
+    fn remove(&mut self, slot: usize) {
+        self.map.remove(&self.slots[slot].block);
+        self.unlink(slot);
+        self.free.push(slot);
+    }
+}
+
+/// Backend wrapper that caches blocks of `inner` in `cache`
+pub struct CacheBackend<C, B> {
+    cache: C,
+    inner: B,
+    mode: CacheMode,
+    blocks: Mutex<Blocks>,
+    /// Signalled when a flush finishes writing back a block
+    written: Condvar,
+    /// Serializes flushes
+    flushing: Mutex<()>,
+}
+
+impl<C: BlockBackend, B: BlockBackend> CacheBackend<C, B> {
+    /// Cache `inner` in as many blocks as fit in `cache`
+    pub fn new(cache: C, inner: B, mode: CacheMode) -> Result<Self> {
+        let slots = cache.size() / CACHE_BLOCK_SIZE;
+        if slots == 0 {
+            bail!(
+                "Cache of {} bytes is smaller than one {} KiB cache block",
+                cache.size(),
+                CACHE_BLOCK_SIZE / 1024
+            );
+        }
+        Ok(Self {
+            cache,
+            inner,
+            mode,
+            blocks: Mutex::new(Blocks::new(slots as usize)),
+            written: Condvar::new(),
+            flushing: Mutex::new(()),
+        })
+    }
+
+    /// Bytes of `block` within the device; the last block may be short
+    fn block_len(&self, block: u64) -> u64 {
+        (self.inner.size() - block * CACHE_BLOCK_SIZE).min(CACHE_BLOCK_SIZE)
+    }
+
+    /// Slot holding `block`, first loading it from the backing store unless
+    /// `load` is false because the caller overwrites the whole block
+    fn slot_for<'a>(
+        &'a self,
+        mut blocks: MutexGuard<'a, Blocks>,
+        block: u64,
+        load: bool,
+    ) -> Result<(MutexGuard<'a, Blocks>, usize)> {
+        let slot = loop {
+            // Checked again after waiting, as the block may have been loaded
+            if let Some(&slot) = blocks.map.get(&block) {
+                blocks.touch(slot);
+                return Ok((blocks, slot));
+            }
+            if let Some(slot) = blocks.free.pop() {
+                break slot;
+            }
+            match blocks.evictable() {
+                Some(slot) => break self.evict(&mut blocks, slot)?,
+                // Every slot is on its way to the backing store
+                None => blocks = self.written.wait(blocks).unwrap_or_else(|e| e.into_inner()),
+            }
+        };
+        if load {
+            let mut data = vec![0u8; self.block_len(block) as usize];
+            let loaded = self
+                .inner
+                .read_at(block * CACHE_BLOCK_SIZE, &mut data)
+                .and_then(|()| self.cache.write_at(slot as u64 * CACHE_BLOCK_SIZE, &data));
+            if let Err(e) = loaded {
+                blocks.free.push(slot);
+                return Err(e);
+            }
+        }
+        blocks.insert(slot, block);
+        Ok((blocks, slot))
+    }
+
+    /// Free `slot`, writing its block back if dirty
+    fn evict(&self, blocks: &mut Blocks, slot: usize) -> Result<usize> {
+        if blocks.slots[slot].dirty.is_some() {
+            let data = self.copy_out(blocks, slot)?;
+            self.inner
+                .write_at(blocks.slots[slot].block * CACHE_BLOCK_SIZE, &data)?;
+            blocks.slots[slot].dirty = None;
+        }
+        blocks.remove(slot);
+        blocks.free.pop();
+        Ok(slot)
+    }
+
+    /// Contents of the block in `slot`
+    fn copy_out(&self, blocks: &Blocks, slot: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; self.block_len(blocks.slots[slot].block) as usize];
+        self.cache
+            .read_at(slot as u64 * CACHE_BLOCK_SIZE, &mut data)?;
+        Ok(data)
+    }
+
+    /// Write `block` back if it still has writes from `epoch` or earlier,
+    /// without holding the block map during the write to the backing store
+    fn flush_block(&self, block: u64, epoch: u64) -> Result<()> {
+        let mut blocks = self.blocks.lock().unwrap_or_else(|e| e.into_inner());
+        // Evicted since, and written back on the way out
+        let Some(&slot) = blocks.map.get(&block) else {
+            return Ok(());
+        };
+        if blocks.slots[slot].dirty.is_none_or(|dirty| dirty > epoch) {
+            return Ok(());
+        }
+        let data = self.copy_out(&blocks, slot)?;
+        // Later writes dirty the slot again, in the new epoch
+        blocks.slots[slot].dirty = None;
+        blocks.slots[slot].writing = true;
+        drop(blocks);
+
+        let written = self.inner.write_at(block * CACHE_BLOCK_SIZE, &data);
+
+        let mut blocks = self.blocks.lock().unwrap_or_else(|e| e.into_inner());
+        blocks.slots[slot].writing = false;
+        if written.is_err() {
+            blocks.slots[slot].dirty = Some(epoch);
+        }
+        drop(blocks);
+        self.written.notify_all();
+        written
+    }
+
+    /// Split `len` bytes at `offset` into `(block, offset in block, length)`
+    fn pieces(offset: u64, len: u64) -> impl Iterator<Item = (u64, u64, u64)> {
+        let end = offset + len;
+        let mut at = offset;
+        std::iter::from_fn(move || {
+            if at >= end {
+                return None;
+            }
+            let block = at / CACHE_BLOCK_SIZE;
+            let within = at % CACHE_BLOCK_SIZE;
+            let n = (end - at).min(CACHE_BLOCK_SIZE - within);
+            at += n;
+            Some((block, within, n))
+        })
+    }
+}
+
+impl<C: BlockBackend, B: BlockBackend> BlockBackend for CacheBackend<C, B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.size() {
+            bail!("Attempted to read past end of cached device");
+        }
+        let mut blocks = self.blocks.lock().unwrap_or_else(|e| e.into_inner());
+        let mut done = 0;
+        for (block, within, n) in Self::pieces(offset, dst.len() as u64) {
+            let slot;
+            (blocks, slot) = self.slot_for(blocks, block, true)?;
+            let dst = &mut dst[done..done + n as usize];
+            self.cache
+                .read_at(slot as u64 * CACHE_BLOCK_SIZE + within, dst)?;
+            done += n as usize;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.size() {
+            bail!("Attempted to write past end of cached device");
+        }
+        let mut blocks = self.blocks.lock().unwrap_or_else(|e| e.into_inner());
+        if self.mode == CacheMode::Writethrough {
+            self.inner.write_at(offset, src)?;
+        }
+        let mut done = 0;
+        for (block, within, n) in Self::pieces(offset, src.len() as u64) {
+            let src = &src[done..done + n as usize];
+            done += n as usize;
+            let slot = match self.mode {
+                // Blocks not in the cache are not loaded just to be written
+                CacheMode::Writethrough => match blocks.map.get(&block) {
+                    Some(&slot) => slot,
+                    None => continue,
+                },
+                CacheMode::Writeback => {
+                    let whole = within == 0 && n == self.block_len(block);
+                    let slot;
+                    (blocks, slot) = self.slot_for(blocks, block, !whole)?;
+                    slot
+                }
+            };
+            self.cache
+                .write_at(slot as u64 * CACHE_BLOCK_SIZE + within, src)?;
+            if self.mode == CacheMode::Writeback {
+                blocks.mark_dirty(slot);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes back every block written before the flush began; writes that
+    /// arrive meanwhile go on in the next epoch
+    fn flush(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().unwrap_or_else(|e| e.into_inner());
+        let (epoch, mut dirty) = {
+            let mut blocks = self.blocks.lock().unwrap_or_else(|e| e.into_inner());
+            let epoch = blocks.epoch;
+            blocks.epoch += 1;
+            let dirty: Vec<u64> = blocks
+                .map
+                .iter()
+                .filter(|&(_, &slot)| blocks.slots[slot].dirty.is_some())
+                .map(|(&block, _)| block)
+                .collect();
+            (epoch, dirty)
+        };
+        dirty.sort_unstable();
+        if !dirty.is_empty() {
+            log::debug!("Writing back {} dirty cache block(s)", dirty.len());
+        }
+        for block in dirty {
+            self.flush_block(block, epoch)?;
+        }
+        self.inner.flush()
+    }
+
+    /// Cached blocks inside the range are dropped, dirty or not, and the
+    /// discard is passed on to the backing store
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        if offset + len > self.size() {
+            bail!("Attempted to discard past end of cached device");
+        }
+        let mut blocks = self.blocks.lock().unwrap_or_else(|e| e.into_inner());
+        for (block, within, n) in Self::pieces(offset, len) {
+            // A block being written back stays until it has been
+            if within == 0
+                && n == self.block_len(block)
+                && let Some(&slot) = blocks.map.get(&block)
+                && !blocks.slots[slot].writing
+            {
+                blocks.remove(slot);
+            }
+        }
+        self.inner.discard_at(offset, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::mpsc::{self, Receiver, Sender};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Each writer versions its own records of this size
+    const RECORD: u64 = 4096;
+    const WRITERS: u64 = 4;
+    const RECORDS_PER_WRITER: u64 = 32;
+
+    fn version_of(record: &[u8]) -> u64 {
+        let version = u64::from_le_bytes(record[..8].try_into().unwrap());
+        assert!(record.chunks(8).all(|v| v == &record[..8]), "torn record");
+        version
+    }
+
+    #[test]
+    fn flush_makes_acknowledged_writes_durable() {
+        let records = WRITERS * RECORDS_PER_WRITER;
+        let backing = Arc::new(RamBuffer::new(records * RECORD));
+        // Four cache blocks for eight blocks of data, so blocks are also
+        // written back on eviction while flushes run
+        let cache = Arc::new(RamBuffer::new(4 * CACHE_BLOCK_SIZE));
+        let backend =
+            Arc::new(CacheBackend::new(cache, backing.clone(), CacheMode::Writeback).unwrap());
+        // Latest version acknowledged per record
+        let acked: Arc<Vec<AtomicU64>> =
+            Arc::new((0..records).map(|_| AtomicU64::new(0)).collect());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|writer| {
+                let (backend, acked) = (backend.clone(), acked.clone());
+                thread::spawn(move || {
+                    for version in 1..=100u64 {
+                        // Interleave the writers' records across cache blocks
+                        let i = (version * 7 + writer) % RECORDS_PER_WRITER;
+                        let record = i * WRITERS + writer;
+                        let data: Vec<u8> =
+                            std::iter::repeat_n(version.to_le_bytes(), RECORD as usize / 8)
+                                .flatten()
+                                .collect();
+                        backend.write_at(record * RECORD, &data).unwrap();
+                        acked[record as usize].store(version, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        let flusher = {
+            let (backend, backing, acked, done) = (
+                backend.clone(),
+                backing.clone(),
+                acked.clone(),
+                done.clone(),
+            );
+            thread::spawn(move || {
+                let mut flushes = 0;
+                while !done.load(Ordering::SeqCst) || flushes == 0 {
+                    let before: Vec<u64> = acked.iter().map(|v| v.load(Ordering::SeqCst)).collect();
+                    backend.flush().unwrap();
+                    let mut stored = vec![0u8; (records * RECORD) as usize];
+                    backing.read_at(0, &mut stored).unwrap();
+                    for (record, data) in stored.chunks(RECORD as usize).enumerate() {
+                        assert!(
+                            version_of(data) >= before[record],
+                            "record {} lost version {} across a flush",
+                            record,
+                            before[record]
+                        );
+                    }
+                    flushes += 1;
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        flusher.join().unwrap();
+
+        backend.flush().unwrap();
+        let mut stored = vec![0u8; (records * RECORD) as usize];
+        backing.read_at(0, &mut stored).unwrap();
+        for (record, data) in stored.chunks(RECORD as usize).enumerate() {
+            assert_eq!(version_of(data), acked[record].load(Ordering::SeqCst));
+        }
+    }
+
+    #[test]
+    fn writethrough_writes_reach_backing_at_once() {
+        let backing = Arc::new(RamBuffer::new(4 * CACHE_BLOCK_SIZE));
+        let cache = RamBuffer::new(CACHE_BLOCK_SIZE);
+        let backend = CacheBackend::new(cache, backing.clone(), CacheMode::Writethrough).unwrap();
+        let mut data = vec![0u8; 100];
+        backend.read_at(1000, &mut data).unwrap();
+        backend.write_at(1000, &[7; 100]).unwrap();
+        backing.read_at(1000, &mut data).unwrap();
+        assert_eq!(data, [7; 100]);
+    }
+
+    /// Backing store whose writes each wait to be released
+    struct Gated {
+        inner: RamBuffer,
+        entered: Mutex<Sender<()>>,
+        release: Mutex<Receiver<()>>,
+    }
+
+    impl BlockBackend for Gated {
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+            self.inner.read_at(offset, dst)
+        }
+
+        fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+            self.entered.lock().unwrap().send(()).unwrap();
+            self.release.lock().unwrap().recv()?;
+            self.inner.write_at(offset, src)
+        }
+    }
+
+    #[test]
+    fn writes_during_a_flush_complete_first() {
+        let (entered_tx, entered) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let backing = Arc::new(Gated {
+            inner: RamBuffer::new(4 * CACHE_BLOCK_SIZE),
+            entered: Mutex::new(entered_tx),
+            release: Mutex::new(release_rx),
+        });
+        let cache = RamBuffer::new(4 * CACHE_BLOCK_SIZE);
+        let backend =
+            Arc::new(CacheBackend::new(cache, backing.clone(), CacheMode::Writeback).unwrap());
+        backend.write_at(0, &[1; 4096]).unwrap();
+
+        let flush = {
+            let backend = backend.clone();
+            thread::spawn(move || backend.flush())
+        };
+        entered.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The flush is stuck writing block 0 back; a write to the same
+        // block, one to another block and a read all go ahead
+        backend.write_at(100, &[2; 100]).unwrap();
+        backend.write_at(CACHE_BLOCK_SIZE, &[3; 4096]).unwrap();
+        let mut data = [0u8; 200];
+        backend.read_at(0, &mut data).unwrap();
+        assert_eq!(data[..100], [1; 100]);
+        assert_eq!(data[100..], [2; 100]);
+        assert!(!flush.is_finished());
+
+        release.send(()).unwrap();
+        flush.join().unwrap().unwrap();
+        // Only the write from before the flush reached the backing store
+        backing.inner.read_at(0, &mut data).unwrap();
+        assert_eq!(data, [1; 200]);
+        let mut other = [0u8; 4096];
+        backing.inner.read_at(CACHE_BLOCK_SIZE, &mut other).unwrap();
+        assert_eq!(other, [0; 4096]);
+
+        // The next flush picks up the writes made during the last one
+        for _ in 0..2 {
+            release.send(()).unwrap();
+        }
+        backend.flush().unwrap();
+        backing.inner.read_at(0, &mut data).unwrap();
+        assert_eq!(data[100..], [2; 100]);
+        backing.inner.read_at(CACHE_BLOCK_SIZE, &mut other).unwrap();
+        assert_eq!(other, [3; 4096]);
+    }
+}
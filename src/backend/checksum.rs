@@ -0,0 +1,360 @@
+//! Per-block checksums of the device contents
+//!
+//! `ChecksumBackend` keeps a 64-bit checksum of every 4 KiB block of the
+//! inner backend in host memory, computed by the `BlockChecksum` that
+//! `ChecksumAlgorithm` selects. Writes update the checksums of the blocks they
+//! cover; reads recompute them and fail the request if one differs, so a bit
+//! flip in GPU memory (consumer cards have no ECC) turns into an I/O error
+//! instead of silently corrupted data. Blocks written only partly are read,
+//! verified and patched through `PartialWrites`.
+//!
+//! The inner backend must read as zeros when the wrapper is created, as a
+//! freshly allocated buffer does: every checksum starts as that of a zero
+//! block.
+//!
+//! A read can race a write that covers other bytes of the same block and
+//! see the new data with the old checksum. A mismatch is therefore checked
+//! again under the block's lock before it is reported.
+
+use super::rmw::{aligned, PartialWrites};
+use super::BlockBackend;
+use crate::metrics::Metrics;
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Bytes covered by one checksum
+pub const CHECKSUM_BLOCK_SIZE: u64 = 4096;
+
+const BLOCK: usize = CHECKSUM_BLOCK_SIZE as usize;
+
+/// Checksum kept for each block
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumAlgorithm {
+    /// CRC32C, in hardware on x86-64 with SSE4.2
+    Crc32c,
+    /// XXH64, fast in software on any CPU (needs the `xxhash` feature)
+    Xxhash,
+    /// BLAKE3 cut to 64 bits; slowest, but hard to collide on purpose
+    Blake3,
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChecksumAlgorithm::Crc32c => "crc32c",
+            ChecksumAlgorithm::Xxhash => "xxhash",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        })
+    }
+}
+
+impl ChecksumAlgorithm {
+    /// The checksum function, if it was built in
+    fn checksum(self) -> Result<Box<dyn BlockChecksum>> {
+        Ok(match self {
+            ChecksumAlgorithm::Crc32c => Box::new(Crc32c),
+            #[cfg(feature = "xxhash")]
+            ChecksumAlgorithm::Xxhash => Box::new(Xxh64),
+            #[cfg(not(feature = "xxhash"))]
+            ChecksumAlgorithm::Xxhash => {
+                bail!("--checksum-algo xxhash requires building with the `xxhash` feature")
+            }
+            ChecksumAlgorithm::Blake3 => Box::new(Blake3),
+        })
+    }
+}
+
+/// A checksum function for `ChecksumBackend`
+pub trait BlockChecksum: Send + Sync {
+    fn sum(&self, data: &[u8]) -> u64;
+}
+
+struct Crc32c;
+
+impl BlockChecksum for Crc32c {
+    fn sum(&self, data: &[u8]) -> u64 {
+        crc32c(data) as u64
+    }
+}
+
+#[cfg(feature = "xxhash")]
+struct Xxh64;
+
+#[cfg(feature = "xxhash")]
+impl BlockChecksum for Xxh64 {
+    fn sum(&self, data: &[u8]) -> u64 {
+        super::xxhash::xxh64(data, 0)
+    }
+}
+
+struct Blake3;
+
+impl BlockChecksum for Blake3 {
+    fn sum(&self, data: &[u8]) -> u64 {
+        let hash = blake3::hash(data);
+        u64::from_le_bytes(hash.as_bytes()[..8].try_into().unwrap())
+    }
+}
+
+/// Backend wrapper that verifies a checksum per block on every read
+pub struct ChecksumBackend<B> {
+    inner: B,
+    checksum: Box<dyn BlockChecksum>,
+    sums: Vec<AtomicU64>,
+    /// Checksum of a block of zeros
+    zero: u64,
+    partial: PartialWrites,
+    metrics: Arc<Metrics>,
+}
+
+impl<B: BlockBackend> ChecksumBackend<B> {
+    /// Checksum `inner` with `algorithm`; `inner` must currently read as
+    /// zeros. Mismatches are counted in `metrics`.
+    pub fn new(inner: B, algorithm: ChecksumAlgorithm, metrics: Arc<Metrics>) -> Result<Self> {
+        if !inner.size().is_multiple_of(CHECKSUM_BLOCK_SIZE) {
+            bail!(
+                "Checksummed device size {} is not a multiple of {} bytes",
+                inner.size(),
+                CHECKSUM_BLOCK_SIZE
+            );
+        }
+        let checksum = algorithm.checksum()?;
+        let zero = checksum.sum(&[0u8; BLOCK]);
+        Ok(Self {
+            sums: (0..inner.size() / CHECKSUM_BLOCK_SIZE)
+                .map(|_| AtomicU64::new(zero))
+                .collect(),
+            inner,
+            checksum,
+            zero,
+            partial: PartialWrites::new(CHECKSUM_BLOCK_SIZE),
+            metrics,
+        })
+    }
+
+    /// Verify whole blocks in `buf`, read from `start`. With `locked`, the
+    /// caller holds the blocks' locks already.
+    fn verify(&self, start: u64, buf: &mut [u8], locked: bool) -> Result<()> {
+        for (i, data) in buf.chunks_exact_mut(BLOCK).enumerate() {
+            let block = start / CHECKSUM_BLOCK_SIZE + i as u64;
+            if self.checksum.sum(data) == self.sums[block as usize].load(Ordering::SeqCst) {
+                continue;
+            }
+            if locked {
+                self.report(block, data)?;
+            } else {
+                // Read it again while no partial write can change it
+                let _lock = self.partial.lock_block(block);
+                self.inner.read_at(block * CHECKSUM_BLOCK_SIZE, data)?;
+                self.report(block, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if `data`, the contents of `block`, doesn't match its checksum
+    fn report(&self, block: u64, data: &[u8]) -> Result<()> {
+        let expected = self.sums[block as usize].load(Ordering::SeqCst);
+        let actual = self.checksum.sum(data);
+        if actual != expected {
+            self.metrics.record_checksum_error();
+            log::error!(
+                "Checksum mismatch in block {} (offset {}): stored {:016x}, computed {:016x}",
+                block,
+                block * CHECKSUM_BLOCK_SIZE,
+                expected,
+                actual
+            );
+            bail!(
+                "Checksum mismatch in block {}; the device memory is corrupted",
+                block
+            );
+        }
+        Ok(())
+    }
+
+    /// Record the checksums of whole blocks in `buf`, written at `start`
+    fn update(&self, start: u64, buf: &[u8]) {
+        for (i, data) in buf.chunks_exact(BLOCK).enumerate() {
+            let block = start / CHECKSUM_BLOCK_SIZE + i as u64;
+            self.sums[block as usize].store(self.checksum.sum(data), Ordering::SeqCst);
+        }
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for ChecksumBackend<B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.size() {
+            bail!("Attempted to read past end of checksummed device");
+        }
+        let (start, end) = aligned(offset, dst.len() as u64, CHECKSUM_BLOCK_SIZE);
+        if start == offset && end == offset + dst.len() as u64 {
+            self.inner.read_at(offset, dst)?;
+            return self.verify(offset, dst, false);
+        }
+        let mut buf = vec![0u8; (end - start) as usize];
+        self.inner.read_at(start, &mut buf)?;
+        self.verify(start, &mut buf, false)?;
+        let skip = (offset - start) as usize;
+        dst.copy_from_slice(&buf[skip..skip + dst.len()]);
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.size() {
+            bail!("Attempted to write past end of checksummed device");
+        }
+        self.partial.write(
+            offset,
+            src,
+            |at, block| {
+                self.inner.read_at(at, block)?;
+                self.verify(at, block, true)
+            },
+            |start, buf| {
+                self.inner.write_at(start, buf)?;
+                self.update(start, buf);
+                Ok(())
+            },
+        )
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        let end = offset + len;
+        let first_full = offset.div_ceil(CHECKSUM_BLOCK_SIZE) * CHECKSUM_BLOCK_SIZE;
+        let end_full = end / CHECKSUM_BLOCK_SIZE * CHECKSUM_BLOCK_SIZE;
+        if first_full >= end_full {
+            return self.write_at(offset, &vec![0u8; len as usize]);
+        }
+        // Partial blocks at the ends need their other bytes for the checksum
+        if offset < first_full {
+            self.write_at(offset, &vec![0u8; (first_full - offset) as usize])?;
+        }
+        self.inner
+            .write_zeroes_at(first_full, end_full - first_full)?;
+        for block in first_full / CHECKSUM_BLOCK_SIZE..end_full / CHECKSUM_BLOCK_SIZE {
+            self.sums[block as usize].store(self.zero, Ordering::SeqCst);
+        }
+        if end_full < end {
+            self.write_at(end_full, &vec![0u8; (end - end_full) as usize])?;
+        }
+        Ok(())
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        // Discarded contents are unspecified, so they must not be left to
+        // fail verification; zero the whole blocks instead
+        let first = offset.div_ceil(CHECKSUM_BLOCK_SIZE) * CHECKSUM_BLOCK_SIZE;
+        let end = (offset + len) / CHECKSUM_BLOCK_SIZE * CHECKSUM_BLOCK_SIZE;
+        if first < end {
+            self.write_zeroes_at(first, end - first)?;
+        }
+        Ok(())
+    }
+}
+
+/// CRC32C (Castagnoli) of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if std::arch::is_x86_feature_detected!("sse4.2") {
+        return unsafe { crc32c_sse42(data) };
+    }
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(data: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    let mut crc = !0u64;
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &byte in words.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+    !crc
+}
+
+/// Lookup table for the reflected CRC32C polynomial
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+
+    #[test]
+    fn every_algorithm_catches_corruption() {
+        let mut algorithms = vec![ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::Blake3];
+        if cfg!(feature = "xxhash") {
+            algorithms.push(ChecksumAlgorithm::Xxhash);
+        }
+        for algorithm in algorithms {
+            let ram = Arc::new(RamBuffer::new(4 * CHECKSUM_BLOCK_SIZE));
+            let metrics = Arc::new(Metrics::default());
+            let backend = ChecksumBackend::new(ram.clone(), algorithm, metrics).unwrap();
+            backend.write_at(4096, &[7u8; 8192]).unwrap();
+            let mut buf = vec![0u8; 8192];
+            backend.read_at(4096, &mut buf).unwrap();
+            assert_eq!(buf, [7u8; 8192]);
+
+            ram.write_at(8192 + 100, &[8]).unwrap();
+            assert!(
+                backend.read_at(8192, &mut buf[..4096]).is_err(),
+                "{}",
+                algorithm
+            );
+            backend.read_at(4096, &mut buf[..4096]).unwrap();
+        }
+    }
+
+    #[test]
+    fn missing_algorithm_is_refused() {
+        let result = ChecksumBackend::new(
+            RamBuffer::new(CHECKSUM_BLOCK_SIZE),
+            ChecksumAlgorithm::Xxhash,
+            Arc::new(Metrics::default()),
+        );
+        assert_eq!(result.is_ok(), cfg!(feature = "xxhash"));
+    }
+}
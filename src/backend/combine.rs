@@ -0,0 +1,386 @@
+//! Write combining: merge small writes within a short delay
+//!
+//! Filesystems issue bursts of tiny, scattered metadata writes, and each one
+//! costs a full OpenCL transfer. `WriteCombineBackend` acknowledges small
+//! writes immediately and holds them in memory for at most `delay`, merging
+//! adjacent and overlapping ones, before a background thread commits the
+//! merged extents to the inner backend. Reads see pending data. A FLUSH,
+//! a full buffer or a large write commits everything right away.
+//!
+//! This is a latency-bounded merge window, not a write-back cache: nothing
+//! is held for longer than `delay`. The exception is a write that fails to
+//! commit: it stays pending, so reads still see the acknowledged data,
+//! every later commit retries it, and the next FLUSH reports the failure.
+//! While it is stuck, no more data is taken in once the buffer is full.
+
+use super::BlockBackend;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Writes larger than this bypass the combining buffer
+const MAX_COMBINED_WRITE: usize = 64 * 1024;
+
+/// Pending bytes that trigger an immediate commit
+const COMBINE_CAPACITY: usize = 4 * 1024 * 1024;
+
+#[derive(Default)]
+struct Pending {
+    /// Start offset -> data of non-overlapping, non-adjacent extents
+    extents: BTreeMap<u64, Vec<u8>>,
+    bytes: usize,
+    /// When the oldest pending write arrived
+    since: Option<Instant>,
+    shutdown: bool,
+}
+
+struct Shared<B> {
+    inner: B,
+    delay: Duration,
+    pending: Mutex<Pending>,
+    wake: Condvar,
+    /// A background commit failed since the last flush
+    failed: AtomicBool,
+    writes: AtomicU64,
+    transfers: AtomicU64,
+}
+
+/// Backend wrapper that coalesces small writes for up to `delay`
+pub struct WriteCombineBackend<B: BlockBackend + 'static> {
+    shared: Arc<Shared<B>>,
+    flusher: Option<JoinHandle<()>>,
+}
+
+impl<B: BlockBackend + 'static> WriteCombineBackend<B> {
+    pub fn new(inner: B, delay: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            inner,
+            delay,
+            pending: Mutex::new(Pending::default()),
+            wake: Condvar::new(),
+            failed: AtomicBool::new(false),
+            writes: AtomicU64::new(0),
+            transfers: AtomicU64::new(0),
+        });
+        let flusher = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("write-combine".into())
+                .spawn(move || shared.run_flusher())
+                .expect("failed to spawn write-combine thread")
+        };
+        Self {
+            shared,
+            flusher: Some(flusher),
+        }
+    }
+}
+
+impl<B: BlockBackend> Shared<B> {
+    fn lock(&self) -> Result<MutexGuard<'_, Pending>> {
+        self.pending
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Write-combine lock poisoned"))
+    }
+
+    /// Commit pending extents once they have waited `delay`
+    fn run_flusher(&self) {
+        let Ok(mut pending) = self.pending.lock() else {
+            return;
+        };
+        loop {
+            match pending.since {
+                None if pending.shutdown => return,
+                None => pending = self.wake.wait(pending).unwrap_or_else(|e| e.into_inner()),
+                Some(since) => {
+                    let due = since + self.delay;
+                    let now = Instant::now();
+                    if now < due && !pending.shutdown {
+                        pending = self
+                            .wake
+                            .wait_timeout(pending, due - now)
+                            .unwrap_or_else(|e| e.into_inner())
+                            .0;
+                        continue;
+                    }
+                    if let Err(e) = self.commit(&mut pending) {
+                        log::error!("Write-combine commit failed: {:#}", e);
+                        self.failed.store(true, Ordering::SeqCst);
+                        if pending.shutdown {
+                            log::error!(
+                                "Dropping {} bytes of combined writes that could not be committed",
+                                pending.bytes
+                            );
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Write all pending extents to the inner backend. Runs with the lock
+    /// held so readers never miss data that is between buffer and backend.
+    /// Extents that fail stay pending and are retried after `delay`.
+    fn commit(&self, pending: &mut Pending) -> Result<()> {
+        let extents = std::mem::take(&mut pending.extents);
+        pending.bytes = 0;
+        pending.since = None;
+        let mut result = Ok(());
+        for (offset, data) in extents {
+            self.transfers.fetch_add(1, Ordering::Relaxed);
+            if let Err(e) = self.inner.write_at(offset, &data) {
+                pending.bytes += data.len();
+                pending.extents.insert(offset, data);
+                pending.since = Some(Instant::now());
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Add a write to the buffer, merging it with any extents it overlaps
+    /// or touches.
+    fn insert(pending: &mut Pending, offset: u64, src: &[u8]) {
+        let end = offset + src.len() as u64;
+        let touching: Vec<u64> = pending
+            .extents
+            .range(..=end)
+            .rev()
+            .take_while(|(start, data)| **start + data.len() as u64 >= offset)
+            .map(|(start, _)| *start)
+            .collect();
+
+        let mut start = offset;
+        let mut merged_end = end;
+        let mut old = Vec::with_capacity(touching.len());
+        for key in touching {
+            let data = pending.extents.remove(&key).expect("extent present");
+            pending.bytes -= data.len();
+            start = start.min(key);
+            merged_end = merged_end.max(key + data.len() as u64);
+            old.push((key, data));
+        }
+
+        let mut merged = vec![0u8; (merged_end - start) as usize];
+        for (key, data) in old {
+            let at = (key - start) as usize;
+            merged[at..at + data.len()].copy_from_slice(&data);
+        }
+        let at = (offset - start) as usize;
+        merged[at..at + src.len()].copy_from_slice(src);
+
+        pending.bytes += merged.len();
+        pending.extents.insert(start, merged);
+    }
+}
+
+impl<B: BlockBackend + 'static> BlockBackend for WriteCombineBackend<B> {
+    fn size(&self) -> u64 {
+        self.shared.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        let end = offset + dst.len() as u64;
+        // Copy overlapping pending data first: anything not pending by now
+        // has already been committed to the inner backend.
+        let overlay: Vec<(u64, Vec<u8>)> = {
+            let pending = self.shared.lock()?;
+            pending
+                .extents
+                .range(..end)
+                .rev()
+                .take_while(|(start, data)| **start + data.len() as u64 > offset)
+                .map(|(start, data)| {
+                    let from = offset.max(*start);
+                    let to = end.min(*start + data.len() as u64);
+                    let piece = &data[(from - start) as usize..(to - start) as usize];
+                    (from, piece.to_vec())
+                })
+                .collect()
+        };
+
+        self.shared.inner.read_at(offset, dst)?;
+        for (from, piece) in overlay {
+            let at = (from - offset) as usize;
+            dst[at..at + piece.len()].copy_from_slice(&piece);
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.size() {
+            bail!("Attempted to write past end of device");
+        }
+        self.shared.writes.fetch_add(1, Ordering::Relaxed);
+
+        let mut pending = self.shared.lock()?;
+        if src.len() > MAX_COMBINED_WRITE {
+            // Commit first so older buffered data can't land on top later
+            self.shared.commit(&mut pending)?;
+            drop(pending);
+            self.shared.transfers.fetch_add(1, Ordering::Relaxed);
+            return self.shared.inner.write_at(offset, src);
+        }
+
+        if pending.bytes >= COMBINE_CAPACITY {
+            // Still full after the last commit failed
+            self.shared.commit(&mut pending)?;
+        }
+        Shared::<B>::insert(&mut pending, offset, src);
+        if pending.bytes >= COMBINE_CAPACITY {
+            return self.shared.commit(&mut pending);
+        }
+        if pending.since.is_none() {
+            pending.since = Some(Instant::now());
+            self.shared.wake.notify_one();
+        }
+        Ok(())
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        // Commit first so held writes can't land on the discarded range later
+        let mut pending = self.shared.lock()?;
+        self.shared.commit(&mut pending)?;
+        drop(pending);
+        self.shared.inner.discard_at(offset, len)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut pending = self.shared.lock()?;
+        self.shared.commit(&mut pending)?;
+        drop(pending);
+        if self.shared.failed.swap(false, Ordering::SeqCst) {
+            bail!("An earlier combined write failed to commit");
+        }
+        self.shared.inner.flush()
+    }
+}
+
+impl<B: BlockBackend + 'static> Drop for WriteCombineBackend<B> {
+    fn drop(&mut self) {
+        if let Ok(mut pending) = self.shared.pending.lock() {
+            pending.shutdown = true;
+            self.shared.wake.notify_one();
+        }
+        if let Some(flusher) = self.flusher.take() {
+            let _ = flusher.join();
+        }
+        log::info!(
+            "Write combining: {} writes committed in {} GPU transfers",
+            self.shared.writes.load(Ordering::Relaxed),
+            self.shared.transfers.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+    use std::sync::atomic::AtomicUsize;
+
+    const SIZE: u64 = 1024 * 1024;
+
+    /// RAM that counts writes and can be made to fail them
+    struct Backing {
+        ram: RamBuffer,
+        writes: AtomicUsize,
+        failing: AtomicBool,
+    }
+
+    impl Backing {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                ram: RamBuffer::new(SIZE),
+                writes: AtomicUsize::new(0),
+                failing: AtomicBool::new(false),
+            })
+        }
+
+        fn contents(&self, offset: u64, len: usize) -> Vec<u8> {
+            let mut data = vec![0u8; len];
+            self.ram.read_at(offset, &mut data).unwrap();
+            data
+        }
+    }
+
+    impl BlockBackend for Backing {
+        fn size(&self) -> u64 {
+            self.ram.size()
+        }
+
+        fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+            self.ram.read_at(offset, dst)
+        }
+
+        fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                bail!("Injected write failure");
+            }
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.ram.write_at(offset, src)
+        }
+    }
+
+    #[test]
+    fn adjacent_and_overlapping_writes_are_merged() {
+        let backing = Backing::new();
+        let combine = WriteCombineBackend::new(backing.clone(), Duration::from_secs(60));
+        combine.write_at(0, &[1; 512]).unwrap();
+        combine.write_at(512, &[2; 512]).unwrap();
+        combine.write_at(256, &[3; 512]).unwrap();
+        combine.write_at(8192, &[4; 512]).unwrap();
+        assert_eq!(backing.writes.load(Ordering::SeqCst), 0);
+
+        combine.flush().unwrap();
+        // One transfer per separate extent
+        assert_eq!(backing.writes.load(Ordering::SeqCst), 2);
+        let data = backing.contents(0, 1024);
+        assert_eq!(&data[..256], &[1; 256]);
+        assert_eq!(&data[256..768], &[3; 512]);
+        assert_eq!(&data[768..], &[2; 256]);
+        assert_eq!(backing.contents(8192, 512), vec![4; 512]);
+    }
+
+    #[test]
+    fn reads_see_pending_writes() {
+        let backing = Backing::new();
+        let combine = WriteCombineBackend::new(backing.clone(), Duration::from_secs(60));
+        backing.ram.write_at(0, &[9; 1024]).unwrap();
+        combine.write_at(100, &[5; 200]).unwrap();
+
+        let mut data = vec![0u8; 1024];
+        combine.read_at(0, &mut data).unwrap();
+        assert_eq!(&data[..100], &[9; 100]);
+        assert_eq!(&data[100..300], &[5; 200]);
+        assert_eq!(&data[300..], &[9; 724]);
+        assert_eq!(backing.contents(100, 200), vec![9; 200]);
+    }
+
+    #[test]
+    fn failed_commit_keeps_the_data_pending() {
+        let backing = Backing::new();
+        let combine = WriteCombineBackend::new(backing.clone(), Duration::from_millis(10));
+        backing.failing.store(true, Ordering::SeqCst);
+        combine.write_at(4096, &[7; 512]).unwrap();
+        // Past the delay, so the background commit has failed at least once
+        thread::sleep(Duration::from_millis(100));
+
+        let mut data = vec![0u8; 512];
+        combine.read_at(4096, &mut data).unwrap();
+        assert_eq!(data, vec![7; 512]);
+        assert!(combine.flush().is_err());
+        combine.read_at(4096, &mut data).unwrap();
+        assert_eq!(data, vec![7; 512]);
+
+        backing.failing.store(false, Ordering::SeqCst);
+        // The retry succeeds, but the earlier failure is still reported once
+        assert!(combine.flush().is_err());
+        assert_eq!(backing.contents(4096, 512), vec![7; 512]);
+        combine.flush().unwrap();
+    }
+}
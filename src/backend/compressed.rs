@@ -0,0 +1,502 @@
+//! Transparent compression of the device
+//!
+//! `CompressedBackend` presents a device that can be larger than its inner
+//! backend (the arena). The device is split into 64 KiB blocks; each block
+//! is stored compressed, by the `Codec` that `Compression` selects, at some offset in the arena, found through an
+//! index kept in host memory. Blocks that don't compress are stored as is,
+//! and all-zero blocks take no space at all.
+//!
+//! The arena is filled by a bump allocator. A rewritten block stays in place
+//! if its new data fits the space it has; otherwise it moves to the end and
+//! its old space becomes garbage. When the end of the arena is reached, all
+//! live blocks are compacted to the front. Only if the data still does not
+//! fit does the write fail with `NoSpaceError` (ENOSPC), so the advertised
+//! size is a promise only as good as the data's compressibility.
+//!
+//! The index exists only in memory, like the VRAM it describes.
+
+use super::{BlockBackend, NoSpaceError};
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use std::fmt;
+use std::sync::RwLock;
+
+/// Granularity of compression in bytes
+pub const COMPRESS_BLOCK_SIZE: u64 = 64 * 1024;
+
+const BLOCK: usize = COMPRESS_BLOCK_SIZE as usize;
+
+/// Compression applied to data stored in the GPU buffer
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// LZ4: several GB/s per core, modest ratio (needs the `lz4` feature)
+    Lz4,
+    /// Zstandard level 3: better ratio, several times slower than LZ4
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Lz4 => "LZ4",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+impl Compression {
+    /// The codec, if it was built in
+    pub fn codec(self) -> Result<Box<dyn Codec>> {
+        Ok(match self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Box::new(Lz4),
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => bail!("--compress lz4 requires building with the `lz4` feature"),
+            Compression::Zstd => Box::new(Zstd),
+        })
+    }
+}
+
+/// A block compressor for `CompressedBackend`
+pub trait Codec: Send + Sync {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Decompress `stored` into `block`, which it must fill exactly
+    fn decompress(&self, stored: &[u8], block: &mut [u8]) -> Result<()>;
+}
+
+#[cfg(feature = "lz4")]
+struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Codec for Lz4 {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(lz4_flex::block::compress(data))
+    }
+
+    fn decompress(&self, stored: &[u8], block: &mut [u8]) -> Result<()> {
+        match lz4_flex::block::decompress_into(stored, block) {
+            Ok(n) if n == block.len() => Ok(()),
+            Ok(n) => bail!("Compressed block decoded to {} bytes", n),
+            Err(e) => bail!("Corrupt compressed block: {}", e),
+        }
+    }
+}
+
+struct Zstd;
+
+/// Compression level for `Compression::Zstd`
+const ZSTD_LEVEL: i32 = 3;
+
+impl Codec for Zstd {
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?)
+    }
+
+    fn decompress(&self, stored: &[u8], block: &mut [u8]) -> Result<()> {
+        match zstd::bulk::decompress_to_buffer(stored, block) {
+            Ok(n) if n == block.len() => Ok(()),
+            Ok(n) => bail!("Compressed block decoded to {} bytes", n),
+            Err(e) => bail!("Corrupt compressed block: {}", e),
+        }
+    }
+}
+
+/// Where a block's data lives in the arena. `len == 0` means all zeros.
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot {
+    offset: u64,
+    /// Bytes of stored data
+    len: u32,
+    /// Bytes reserved at `offset`; a rewrite that fits stays in place
+    cap: u32,
+    /// Stored uncompressed because compression didn't help
+    raw: bool,
+}
+
+#[derive(Debug)]
+struct Arena {
+    slots: Vec<Slot>,
+    /// Start of never-used space
+    next_free: u64,
+    /// Bytes reserved by live slots
+    used: u64,
+}
+
+/// Backend wrapper that stores its blocks compressed in `inner`
+pub struct CompressedBackend<B> {
+    inner: B,
+    size: u64,
+    codec: Box<dyn Codec>,
+    arena: RwLock<Arena>,
+}
+
+impl<B: BlockBackend> CompressedBackend<B> {
+    /// Present a `size`-byte device stored in `inner`, compressed with `codec`
+    pub fn new(inner: B, size: u64, codec: Box<dyn Codec>) -> Result<Self> {
+        if size == 0 || !size.is_multiple_of(COMPRESS_BLOCK_SIZE) {
+            bail!(
+                "Compressed device size {} must be a non-zero multiple of {} KiB",
+                size,
+                COMPRESS_BLOCK_SIZE / 1024
+            );
+        }
+        let blocks = (size / COMPRESS_BLOCK_SIZE) as usize;
+        Ok(Self {
+            inner,
+            size,
+            codec,
+            arena: RwLock::new(Arena {
+                slots: vec![Slot::default(); blocks],
+                next_free: 0,
+                used: 0,
+            }),
+        })
+    }
+
+    fn read_arena(&self) -> Result<std::sync::RwLockReadGuard<'_, Arena>> {
+        self.arena
+            .read()
+            .map_err(|_| anyhow::anyhow!("Compression index lock poisoned"))
+    }
+
+    fn write_arena(&self) -> Result<std::sync::RwLockWriteGuard<'_, Arena>> {
+        self.arena
+            .write()
+            .map_err(|_| anyhow::anyhow!("Compression index lock poisoned"))
+    }
+
+    /// Read bytes `start..start + dst.len()` of the block stored in `slot`
+    fn read_slot(&self, slot: Slot, start: usize, dst: &mut [u8]) -> Result<()> {
+        if slot.len == 0 {
+            dst.fill(0);
+            return Ok(());
+        }
+        if slot.raw {
+            return self.inner.read_at(slot.offset + start as u64, dst);
+        }
+        let mut stored = vec![0u8; slot.len as usize];
+        self.inner.read_at(slot.offset, &mut stored)?;
+        if start == 0 && dst.len() == BLOCK {
+            return self.codec.decompress(&stored, dst);
+        }
+        let mut block = vec![0u8; BLOCK];
+        self.codec.decompress(&stored, &mut block)?;
+        dst.copy_from_slice(&block[start..start + dst.len()]);
+        Ok(())
+    }
+
+    /// Store the full contents of block `index`
+    fn store_block(&self, arena: &mut Arena, index: usize, data: &[u8]) -> Result<()> {
+        let old = arena.slots[index];
+        if data.iter().all(|&b| b == 0) {
+            arena.used -= old.cap as u64;
+            arena.slots[index] = Slot::default();
+            return Ok(());
+        }
+
+        let compressed = self.codec.compress(data)?;
+        let (stored, raw) = if compressed.len() < BLOCK {
+            (compressed.as_slice(), false)
+        } else {
+            (data, true)
+        };
+        let len = stored.len() as u32;
+
+        if old.cap >= len {
+            self.inner.write_at(old.offset, stored)?;
+            arena.slots[index] = Slot { len, raw, ..old };
+            return Ok(());
+        }
+
+        let offset = self.allocate(arena, len as u64)?;
+        self.inner.write_at(offset, stored)?;
+        arena.used = arena.used - old.cap as u64 + len as u64;
+        arena.slots[index] = Slot {
+            offset,
+            len,
+            cap: len,
+            raw,
+        };
+        Ok(())
+    }
+
+    /// Reserve `len` bytes at the end of the arena, compacting if needed
+    fn allocate(&self, arena: &mut Arena, len: u64) -> Result<u64> {
+        let capacity = self.inner.size();
+        if arena.next_free + len > capacity {
+            // Compaction only helps if the live data leaves room
+            if arena.used + len > capacity {
+                return Err(NoSpaceError.into());
+            }
+            self.compact(arena)?;
+        }
+        let offset = arena.next_free;
+        arena.next_free += len;
+        Ok(offset)
+    }
+
+    /// Move all live blocks to the front of the arena, in arena order, so
+    /// each one only ever moves down.
+    fn compact(&self, arena: &mut Arena) -> Result<()> {
+        let before = arena.next_free;
+        let mut live: Vec<usize> = (0..arena.slots.len())
+            .filter(|&i| arena.slots[i].len > 0)
+            .collect();
+        live.sort_by_key(|&i| arena.slots[i].offset);
+
+        let mut pos = 0;
+        let mut data = Vec::new();
+        for index in live {
+            let slot = arena.slots[index];
+            if slot.offset != pos {
+                data.resize(slot.len as usize, 0);
+                self.inner.read_at(slot.offset, &mut data)?;
+                self.inner.write_at(pos, &data)?;
+            }
+            // Slack behind the data is given up; the index stays valid if a
+            // later move fails
+            arena.used -= (slot.cap - slot.len) as u64;
+            arena.slots[index] = Slot {
+                offset: pos,
+                cap: slot.len,
+                ..slot
+            };
+            pos += slot.len as u64;
+        }
+        arena.next_free = pos;
+        arena.used = pos;
+        log::debug!(
+            "Compacted compression arena from {} to {} bytes",
+            before,
+            pos
+        );
+        Ok(())
+    }
+
+    /// Write `src` at `offset`, or zeros when `src` is `None`
+    fn write_blocks(&self, offset: u64, len: u64, src: Option<&[u8]>) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to write past end of compressed device");
+        }
+        let mut arena = self.write_arena()?;
+        let mut block = vec![0u8; BLOCK];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let index = (pos / COMPRESS_BLOCK_SIZE) as usize;
+            let start = (pos % COMPRESS_BLOCK_SIZE) as usize;
+            let n = (BLOCK - start).min((len - done) as usize);
+            if n < BLOCK {
+                self.read_slot(arena.slots[index], 0, &mut block)?;
+            }
+            let part = &mut block[start..start + n];
+            match src {
+                Some(src) => part.copy_from_slice(&src[done as usize..done as usize + n]),
+                None => part.fill(0),
+            }
+            self.store_block(&mut arena, index, &block)?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for CompressedBackend<B> {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.size {
+            bail!("Attempted to read past end of compressed device");
+        }
+        let arena = self.read_arena()?;
+        let mut done = 0;
+        while done < dst.len() {
+            let pos = offset + done as u64;
+            let index = (pos / COMPRESS_BLOCK_SIZE) as usize;
+            let start = (pos % COMPRESS_BLOCK_SIZE) as usize;
+            let n = (BLOCK - start).min(dst.len() - done);
+            self.read_slot(arena.slots[index], start, &mut dst[done..done + n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.write_blocks(offset, src.len() as u64, Some(src))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        // Whole zero blocks just drop their slot
+        self.write_blocks(offset, len, None)
+    }
+
+    fn fast_zero(&self) -> bool {
+        true
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.write_blocks(offset, len, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{is_no_space, RamBuffer};
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    const B: u64 = COMPRESS_BLOCK_SIZE;
+
+    /// Random, so incompressible, bytes from a xorshift generator
+    fn random_bytes(len: usize) -> Vec<u8> {
+        let mut state = RandomState::new().build_hasher().finish() | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// A block that compresses well
+    fn text_block(seed: u8) -> Vec<u8> {
+        (0..BLOCK)
+            .map(|i| seed.wrapping_add((i % 13) as u8))
+            .collect()
+    }
+
+    fn backend(arena_blocks: u64, size: u64) -> CompressedBackend<RamBuffer> {
+        let codec = Compression::Zstd.codec().unwrap();
+        CompressedBackend::new(RamBuffer::new(arena_blocks * B), size, codec).unwrap()
+    }
+
+    fn slot(backend: &CompressedBackend<RamBuffer>, index: usize) -> Slot {
+        backend.read_arena().unwrap().slots[index]
+    }
+
+    fn assert_reads(backend: &CompressedBackend<RamBuffer>, offset: u64, expected: &[u8]) {
+        let mut buf = vec![0u8; expected.len()];
+        backend.read_at(offset, &mut buf).unwrap();
+        assert!(buf == expected, "data at offset {} differs", offset);
+    }
+
+    #[test]
+    fn unaligned_writes_read_back_with_every_codec() {
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let Ok(codec) = compression.codec() else {
+                continue;
+            };
+            let backend = CompressedBackend::new(RamBuffer::new(4 * B), 8 * B, codec).unwrap();
+            let mut expected = vec![0u8; 8 * BLOCK];
+            let writes = [
+                (100, text_block(1)[..5000].to_vec()),
+                (B - 10, random_bytes(20)),
+                (3 * B + 7, text_block(2)),
+                (8 * B - 3, vec![9, 9, 9]),
+            ];
+            for (offset, data) in writes {
+                backend.write_at(offset, &data).unwrap();
+                expected[offset as usize..offset as usize + data.len()].copy_from_slice(&data);
+            }
+            assert_reads(&backend, 0, &expected);
+            assert_reads(&backend, B - 20, &expected[BLOCK - 20..BLOCK + 20]);
+        }
+    }
+
+    #[test]
+    fn growing_block_relocates_and_shrinking_one_stays() {
+        let backend = backend(4, 8 * B);
+        let first = text_block(1);
+        let second = text_block(2);
+        backend.write_at(0, &first).unwrap();
+        backend.write_at(B, &second).unwrap();
+        let before = slot(&backend, 0);
+        assert!(!before.raw && (before.len as u64) < B);
+
+        // No longer fits behind its neighbour, so it moves to the end
+        let grown = random_bytes(BLOCK);
+        backend.write_at(0, &grown).unwrap();
+        let after = slot(&backend, 0);
+        assert!(after.raw);
+        assert!(after.offset > slot(&backend, 1).offset);
+        assert_reads(&backend, 0, &grown);
+        assert_reads(&backend, B, &second);
+
+        // Data that fits the space it has is rewritten in place
+        backend.write_at(0, &first).unwrap();
+        let shrunk = slot(&backend, 0);
+        assert_eq!((shrunk.offset, shrunk.cap), (after.offset, after.cap));
+        assert!(!shrunk.raw);
+        assert_reads(&backend, 0, &first);
+
+        // Partial rewrites decompress, patch and recompress the block
+        backend.write_at(100, &[7u8; 50]).unwrap();
+        let mut expected = first.clone();
+        expected[100..150].fill(7);
+        assert_reads(&backend, 0, &expected);
+    }
+
+    #[test]
+    fn full_arena_is_compacted() {
+        let backend = backend(3, 8 * B);
+        let small = text_block(3);
+        let (a, b, c) = (
+            random_bytes(BLOCK),
+            random_bytes(BLOCK),
+            random_bytes(BLOCK),
+        );
+        backend.write_at(0, &small).unwrap();
+        backend.write_at(B, &a).unwrap();
+        backend.write_at(2 * B, &b).unwrap();
+        let moved_from = slot(&backend, 2).offset;
+
+        // Freeing block 1 leaves garbage at the front; the next block only
+        // fits once the live ones are moved down over it
+        backend.write_zeroes_at(B, B).unwrap();
+        backend.write_at(3 * B, &c).unwrap();
+        let small_len = slot(&backend, 0).len as u64;
+        assert_eq!(slot(&backend, 2).offset, small_len);
+        assert!(slot(&backend, 2).offset < moved_from);
+        assert_eq!(slot(&backend, 3).offset, small_len + B);
+        assert_eq!(backend.read_arena().unwrap().next_free, small_len + 2 * B);
+
+        assert_reads(&backend, 0, &small);
+        assert_reads(&backend, B, &vec![0u8; BLOCK]);
+        assert_reads(&backend, 2 * B, &b);
+        assert_reads(&backend, 3 * B, &c);
+    }
+
+    #[test]
+    fn incompressible_data_runs_out_of_space() {
+        let backend = backend(2, 8 * B);
+        let (a, b) = (random_bytes(BLOCK), random_bytes(BLOCK));
+        backend.write_at(0, &a).unwrap();
+        backend.write_at(B, &b).unwrap();
+
+        let err = backend.write_at(2 * B, &random_bytes(BLOCK)).unwrap_err();
+        assert!(is_no_space(&err), "{:#}", err);
+        // The failed write leaves the stored data alone
+        assert_reads(&backend, 0, &a);
+        assert_reads(&backend, B, &b);
+        assert_reads(&backend, 2 * B, &vec![0u8; BLOCK]);
+
+        // Zero blocks take no space, so they still fit
+        backend.write_zeroes_at(2 * B, 6 * B).unwrap();
+        // And freeing a block makes room again
+        backend.discard_at(0, B).unwrap();
+        let c = random_bytes(BLOCK);
+        backend.write_at(2 * B, &c).unwrap();
+        assert_reads(&backend, 2 * B, &c);
+        assert_reads(&backend, B, &b);
+    }
+}
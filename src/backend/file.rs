@@ -0,0 +1,179 @@
+//! Backend stored in a host file
+//!
+//! Positioned reads and writes on a regular file or block device. `flush`
+//! is `fdatasync`, and discards punch holes so the range reads as zeros,
+//! matching `VRamBuffer` and `RamBuffer`. `open_window` serves only part of
+//! the file, starting at an offset, such as one region of a disk.
+
+use super::BlockBackend;
+use anyhow::{bail, Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// A backend on a host file of fixed size
+pub struct FileBackend {
+    file: File,
+    /// Where the device starts in the file
+    offset: u64,
+    size: u64,
+}
+
+impl FileBackend {
+    /// Open `path` as a `size`-byte device, creating it if needed. With
+    /// `truncate` the old contents are dropped and the file reads as zeros;
+    /// otherwise an existing file must already be exactly `size` bytes.
+    pub fn open(path: &Path, size: u64, truncate: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(truncate)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let len = file.metadata()?.len();
+        if len == 0 {
+            file.set_len(size)
+                .with_context(|| format!("Failed to size {} to {} bytes", path.display(), size))?;
+        } else if len != size {
+            bail!(
+                "{} is {} bytes but the device is {} bytes",
+                path.display(),
+                len,
+                size
+            );
+        }
+        Ok(Self {
+            file,
+            offset: 0,
+            size,
+        })
+    }
+
+    /// Serve `size` bytes of the existing file or block device at `path`
+    /// starting at `offset`, or everything from `offset` to its end. The
+    /// range must lie within the file.
+    pub fn open_window(path: &Path, offset: u64, size: Option<u64>) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        // Block devices report a metadata length of 0
+        let len = file
+            .seek(SeekFrom::End(0))
+            .with_context(|| format!("Failed to size {}", path.display()))?;
+        if len == 0 {
+            bail!("{} is empty", path.display());
+        }
+        let size = match size {
+            Some(size) => size,
+            None => len.saturating_sub(offset),
+        };
+        if size == 0 || offset.checked_add(size).is_none_or(|end| end > len) {
+            bail!(
+                "{} bytes at offset {} don't fit in {} ({} bytes)",
+                size,
+                offset,
+                path.display(),
+                len
+            );
+        }
+        Ok(Self { file, offset, size })
+    }
+}
+
+impl BlockBackend for FileBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.size {
+            bail!("Attempted to read past end of file backend");
+        }
+        self.file.read_exact_at(dst, self.offset + offset)?;
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.size {
+            bail!("Attempted to write past end of file backend");
+        }
+        self.file.write_all_at(src, self.offset + offset)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.file.sync_data().context("fdatasync failed")
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to discard past end of file backend");
+        }
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                (self.offset + offset) as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret != 0 {
+            // Block devices and some filesystems can't punch holes
+            return self.write_zeroes_at(offset, len);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_file(name: &str, len: usize) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("vramblk-file-{}-{}", name, std::process::id()));
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn window_is_offset_into_the_file() {
+        let path = temp_file("window", 16384);
+        let backend = FileBackend::open_window(&path, 4096, Some(8192)).unwrap();
+        assert_eq!(backend.size(), 8192);
+
+        let mut head = [0u8; 4];
+        backend.read_at(0, &mut head).unwrap();
+        assert_eq!(head[0], (4096 % 251) as u8);
+        backend.write_at(8188, &[9; 4]).unwrap();
+        backend.discard_at(0, 4096).unwrap();
+        assert!(backend.write_at(8190, &[0; 4]).is_err());
+
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(file.len(), 16384);
+        assert_eq!(file[4095], (4095 % 251) as u8);
+        assert!(file[4096..8192].iter().all(|&b| b == 0));
+        assert_eq!(&file[12284..12288], &[9; 4]);
+        assert_eq!(file[12288], (12288 % 251) as u8);
+
+        let rest = FileBackend::open_window(&path, 4096, None).unwrap();
+        assert_eq!(rest.size(), 12288);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn window_must_fit_in_the_file() {
+        let path = temp_file("range", 8192);
+        assert!(FileBackend::open_window(&path, 4096, Some(4097)).is_err());
+        assert!(FileBackend::open_window(&path, 8192, None).is_err());
+        assert!(FileBackend::open_window(&path, u64::MAX, Some(2)).is_err());
+        assert!(FileBackend::open_window(&path, 4096, Some(4096)).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
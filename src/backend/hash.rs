@@ -0,0 +1,63 @@
+//! Whole-device content hashing
+//!
+//! Streams every byte of a backend through a hasher so two runs can be
+//! compared by a single digest.
+
+use super::BlockBackend;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::fmt;
+
+/// Bytes read from the backend per transfer
+const HASH_CHUNK: u64 = 4 * 1024 * 1024;
+
+/// Digest used for the device hash
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgorithm {
+    Blake3,
+    Sha256,
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        })
+    }
+}
+
+/// Hash the full contents of `backend`, returning the digest as hex
+pub fn hash_backend(backend: &dyn BlockBackend, algorithm: HashAlgorithm) -> Result<String> {
+    match algorithm {
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            stream(backend, |chunk| {
+                hasher.update(chunk);
+            })?;
+            Ok(hasher.finalize().to_hex().to_string())
+        }
+        HashAlgorithm::Sha256 => {
+            use sha2::Digest;
+            let mut hasher = sha2::Sha256::new();
+            stream(backend, |chunk| hasher.update(chunk))?;
+            Ok(format!("{:x}", hasher.finalize()))
+        }
+    }
+}
+
+/// Feed the backend to `sink` in order, one chunk at a time
+fn stream(backend: &dyn BlockBackend, mut sink: impl FnMut(&[u8])) -> Result<()> {
+    let size = backend.size();
+    let mut buf = vec![0u8; HASH_CHUNK.min(size) as usize];
+    let mut offset = 0;
+    while offset < size {
+        let len = HASH_CHUNK.min(size - offset) as usize;
+        backend
+            .read_at(offset, &mut buf[..len])
+            .with_context(|| format!("Failed to read device at offset {}", offset))?;
+        sink(&buf[..len]);
+        offset += len as u64;
+    }
+    Ok(())
+}
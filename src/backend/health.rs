@@ -0,0 +1,66 @@
+//! Feed backend failures into the health check
+//!
+//! `HealthBackend` wraps the allocated buffer and reports the outcome of
+//! every read and write to `Health`, which turns unhealthy once enough of
+//! them fail in a row (a wedged GPU), or at once and for good when one fails
+//! with `DeviceLostError` (a lost OpenCL context).
+
+use super::{is_device_lost, BlockBackend};
+use crate::health::Health;
+use anyhow::Result;
+use std::sync::Arc;
+
+/// Backend wrapper that records read and write outcomes in `Health`
+pub struct HealthBackend<B> {
+    inner: B,
+    health: Arc<Health>,
+}
+
+impl<B: BlockBackend> HealthBackend<B> {
+    pub fn new(inner: B, health: Arc<Health>) -> Self {
+        Self { inner, health }
+    }
+
+    fn record(&self, result: Result<()>) -> Result<()> {
+        match &result {
+            Ok(()) => self.health.record_success(),
+            Err(e) if is_device_lost(e) => self.health.set_device_lost(),
+            Err(_) => self.health.record_failure(),
+        }
+        result
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for HealthBackend<B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.record(self.inner.read_at(offset, dst))
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.record(self.inner.write_at(offset, src))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.inner.write_zeroes_at(offset, len)
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.inner.discard_at(offset, len)
+    }
+
+    fn is_known_zero(&self, offset: u64, len: u64) -> bool {
+        self.inner.is_known_zero(offset, len)
+    }
+}
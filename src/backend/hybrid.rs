@@ -0,0 +1,185 @@
+//! Striping across VRAM and host RAM
+//!
+//! `HybridStripeBackend` interleaves fixed-size stripe units between a VRAM
+//! backend and a RAM backend according to a `vram:ram` ratio, e.g. `3:1`
+//! puts three units in VRAM, then one in RAM, and repeats. Parallel
+//! workloads then use the PCIe path and the memory bus at the same time, so
+//! aggregate bandwidth approaches the sum of both at the cost of uneven
+//! per-request latency.
+
+use super::BlockBackend;
+use anyhow::{bail, Context, Result};
+
+/// Bytes per stripe unit
+pub const STRIPE_UNIT: u64 = 128 * 1024;
+
+/// Stripe units placed in VRAM and RAM per period
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StripeRatio {
+    pub vram: u64,
+    pub ram: u64,
+}
+
+impl StripeRatio {
+    fn period(&self) -> u64 {
+        self.vram + self.ram
+    }
+
+    /// Split a total size into the (VRAM, RAM) bytes needed to back it
+    pub fn split_size(&self, size: u64) -> (u64, u64) {
+        let units = size / STRIPE_UNIT;
+        let vram_units =
+            (units / self.period()) * self.vram + (units % self.period()).min(self.vram);
+        (vram_units * STRIPE_UNIT, (units - vram_units) * STRIPE_UNIT)
+    }
+}
+
+impl std::fmt::Display for StripeRatio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.vram, self.ram)
+    }
+}
+
+/// Parses a ratio string (e.g., "3:1") into a StripeRatio.
+pub fn parse_stripe_ratio(ratio_str: &str) -> Result<StripeRatio> {
+    let (vram, ram) = ratio_str
+        .trim()
+        .split_once(':')
+        .context("Ratio must be in VRAM:RAM form, e.g. 3:1")?;
+    let vram: u64 = vram.trim().parse().context("Invalid VRAM share")?;
+    let ram: u64 = ram.trim().parse().context("Invalid RAM share")?;
+    if vram == 0 || ram == 0 {
+        bail!("Both shares of the ratio must be at least 1");
+    }
+    Ok(StripeRatio { vram, ram })
+}
+
+/// A contiguous run on one side: (is VRAM, backend offset, length)
+type Segment = (bool, u64, usize);
+
+/// Backend that stripes units between VRAM and RAM
+pub struct HybridStripeBackend<V, R> {
+    vram: V,
+    ram: R,
+    ratio: StripeRatio,
+    size: u64,
+}
+
+impl<V: BlockBackend, R: BlockBackend> HybridStripeBackend<V, R> {
+    /// Combine `vram` and `ram`, sized as returned by `ratio.split_size()`.
+    pub fn new(vram: V, ram: R, ratio: StripeRatio) -> Result<Self> {
+        let size = vram.size() + ram.size();
+        if ratio.split_size(size) != (vram.size(), ram.size()) {
+            bail!(
+                "Backend sizes ({} + {} bytes) don't match a {} stripe of {} bytes",
+                vram.size(),
+                ram.size(),
+                ratio,
+                size
+            );
+        }
+        Ok(Self {
+            vram,
+            ram,
+            ratio,
+            size,
+        })
+    }
+
+    /// Map `[offset, offset + len)` onto the two backends, merging units
+    /// that are contiguous on the same side.
+    fn segments(&self, offset: u64, len: usize) -> Vec<Segment> {
+        let period = self.ratio.period();
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let unit = pos / STRIPE_UNIT;
+            let in_unit = pos % STRIPE_UNIT;
+            let chunk = ((STRIPE_UNIT - in_unit) as usize).min(len - done);
+            let (round, slot) = (unit / period, unit % period);
+            let (is_vram, target) = if slot < self.ratio.vram {
+                (true, round * self.ratio.vram + slot)
+            } else {
+                (false, round * self.ratio.ram + slot - self.ratio.vram)
+            };
+            let backend_offset = target * STRIPE_UNIT + in_unit;
+            match segments.last_mut() {
+                Some((last_vram, last_offset, last_len))
+                    if *last_vram == is_vram
+                        && *last_offset + *last_len as u64 == backend_offset =>
+                {
+                    *last_len += chunk;
+                }
+                _ => segments.push((is_vram, backend_offset, chunk)),
+            }
+            done += chunk;
+        }
+        segments
+    }
+
+    /// Run the VRAM and RAM parts of a request, on two threads if it has both
+    fn dispatch<T: Send>(
+        &self,
+        parts: Vec<(bool, u64, T)>,
+        io: impl Fn(&dyn BlockBackend, u64, T) -> Result<()> + Sync,
+    ) -> Result<()> {
+        let (vram, ram): (Vec<_>, Vec<_>) = parts.into_iter().partition(|part| part.0);
+        let run_side = |backend: &dyn BlockBackend, side: Vec<(bool, u64, T)>| {
+            side.into_iter()
+                .try_for_each(|(_, offset, buf)| io(backend, offset, buf))
+        };
+
+        if vram.is_empty() || ram.is_empty() {
+            run_side(&self.vram, vram)?;
+            return run_side(&self.ram, ram);
+        }
+        std::thread::scope(|scope| {
+            let vram_side = scope.spawn(|| run_side(&self.vram, vram));
+            let ram_result = run_side(&self.ram, ram);
+            vram_side
+                .join()
+                .map_err(|_| anyhow::anyhow!("VRAM stripe transfer panicked"))?
+                .and(ram_result)
+        })
+    }
+}
+
+impl<V: BlockBackend, R: BlockBackend> BlockBackend for HybridStripeBackend<V, R> {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.size {
+            bail!("Attempted to read past end of hybrid device");
+        }
+        let mut rest = dst;
+        let mut parts = Vec::new();
+        for (is_vram, backend_offset, len) in self.segments(offset, rest.len()) {
+            let (head, tail) = std::mem::take(&mut rest).split_at_mut(len);
+            parts.push((is_vram, backend_offset, head));
+            rest = tail;
+        }
+        self.dispatch(parts, |backend, offset, buf| backend.read_at(offset, buf))
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.size {
+            bail!("Attempted to write past end of hybrid device");
+        }
+        let mut rest = src;
+        let mut parts = Vec::new();
+        for (is_vram, backend_offset, len) in self.segments(offset, rest.len()) {
+            let (head, tail) = rest.split_at(len);
+            parts.push((is_vram, backend_offset, head));
+            rest = tail;
+        }
+        self.dispatch(parts, |backend, offset, buf| backend.write_at(offset, buf))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.vram.flush()?;
+        self.ram.flush()
+    }
+}
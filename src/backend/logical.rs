@@ -0,0 +1,86 @@
+//! Advertise a logical size that differs from the physical allocation
+//!
+//! Testing/emulation only: `LogicalSizeBackend` reports `logical_size` to
+//! the frontends regardless of how much the inner backend can hold. Reads
+//! past the physical end return zeros and writes past it fail with
+//! `NoSpaceError` (ENOSPC), which is how a thin-provisioned device behaves
+//! once its backing store is exhausted.
+
+use super::{BlockBackend, NoSpaceError};
+use anyhow::{bail, Result};
+
+/// Backend wrapper that advertises `logical_size` bytes
+pub struct LogicalSizeBackend<B> {
+    inner: B,
+    logical_size: u64,
+}
+
+impl<B: BlockBackend> LogicalSizeBackend<B> {
+    pub fn new(inner: B, logical_size: u64) -> Self {
+        Self {
+            inner,
+            logical_size,
+        }
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for LogicalSizeBackend<B> {
+    fn size(&self) -> u64 {
+        self.logical_size
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.logical_size {
+            bail!("Attempted to read past end of logical device");
+        }
+        let physical = self.inner.size();
+        // Bytes backed by the inner backend; the rest reads as zeros
+        let backed = physical.saturating_sub(offset).min(dst.len() as u64) as usize;
+        let (head, tail) = dst.split_at_mut(backed);
+        if !head.is_empty() {
+            self.inner.read_at(offset, head)?;
+        }
+        tail.fill(0);
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.logical_size {
+            bail!("Attempted to write past end of logical device");
+        }
+        if offset + src.len() as u64 > self.inner.size() {
+            return Err(NoSpaceError.into());
+        }
+        self.inner.write_at(offset, src)
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        if offset + len > self.logical_size {
+            bail!("Attempted to write past end of logical device");
+        }
+        if offset + len > self.inner.size() {
+            return Err(NoSpaceError.into());
+        }
+        self.inner.write_zeroes_at(offset, len)
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        if offset + len > self.logical_size {
+            bail!("Attempted to discard past end of logical device");
+        }
+        // Only the physically backed part has anything to discard
+        let backed = self.inner.size().saturating_sub(offset).min(len);
+        if backed == 0 {
+            return Ok(());
+        }
+        self.inner.discard_at(offset, backed)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
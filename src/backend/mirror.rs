@@ -0,0 +1,104 @@
+//! Write-through mirror of the device on disk
+//!
+//! `MirrorBackend` keeps a second copy of every write on a slower, durable
+//! backend (normally a `FileBackend`). Reads are served from the primary
+//! only, so VRAM stays the fast path while the disk copy is the source of
+//! truth: a write is acknowledged once both copies have it, and `flush`
+//! syncs the disk. At startup the mirror can prime the primary from the
+//! disk copy, otherwise both start out zeroed.
+
+use super::BlockBackend;
+use anyhow::{bail, Context, Result};
+use std::time::Instant;
+
+/// Bytes copied from the mirror to the primary per transfer when restoring
+const RESTORE_CHUNK: u64 = 4 * 1024 * 1024;
+
+/// Backend wrapper that writes through to a secondary copy
+pub struct MirrorBackend<P, S> {
+    primary: P,
+    secondary: S,
+}
+
+impl<P: BlockBackend, S: BlockBackend> MirrorBackend<P, S> {
+    /// Mirror `primary` onto `secondary`, which must be the same size. With
+    /// `restore` the primary is loaded from the secondary; otherwise the
+    /// primary is zeroed to match a freshly created secondary.
+    pub fn new(primary: P, secondary: S, restore: bool) -> Result<Self> {
+        let size = primary.size();
+        if secondary.size() != size {
+            bail!(
+                "Mirror is {} bytes but the device is {} bytes",
+                secondary.size(),
+                size
+            );
+        }
+        let start = Instant::now();
+        if restore {
+            let mut buf = vec![0u8; RESTORE_CHUNK.min(size) as usize];
+            let mut offset = 0;
+            while offset < size {
+                let len = RESTORE_CHUNK.min(size - offset) as usize;
+                if secondary.is_known_zero(offset, len as u64) {
+                    primary.write_zeroes_at(offset, len as u64)?;
+                } else {
+                    secondary
+                        .read_at(offset, &mut buf[..len])
+                        .with_context(|| format!("Failed to read mirror at offset {}", offset))?;
+                    primary.write_at(offset, &buf[..len])?;
+                }
+                offset += len as u64;
+            }
+            log::info!(
+                "Restored {} bytes from the mirror in {:?}",
+                size,
+                start.elapsed()
+            );
+        } else {
+            primary.write_zeroes_at(0, size)?;
+        }
+        primary.flush()?;
+        Ok(Self { primary, secondary })
+    }
+}
+
+impl<P: BlockBackend, S: BlockBackend> BlockBackend for MirrorBackend<P, S> {
+    fn size(&self) -> u64 {
+        self.primary.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.primary.read_at(offset, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        // Disk first: a write that fails there never reaches the primary
+        self.secondary
+            .write_at(offset, src)
+            .context("Mirror write failed")?;
+        self.primary.write_at(offset, src)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.primary.flush()?;
+        self.secondary.flush().context("Mirror flush failed")
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.secondary
+            .write_zeroes_at(offset, len)
+            .context("Mirror write failed")?;
+        self.primary.write_zeroes_at(offset, len)
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.primary.fast_zero() && self.secondary.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.secondary
+            .discard_at(offset, len)
+            .context("Mirror discard failed")?;
+        self.primary.discard_at(offset, len)
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::Result;
+use std::sync::Arc;
+use crate::opencl::VRamBuffer;
+#[cfg(feature = "cuda")]
+use crate::cuda::CudaBuffer;
+#[cfg(feature = "vulkan")]
+use crate::vulkan::VulkanBuffer;
+
+mod budget;
+mod cache;
+mod checksum;
+mod combine;
+mod compressed;
+mod encrypted;
+mod file;
+mod hash;
+mod health;
+mod hybrid;
+mod logical;
+mod mirror;
+mod persist;
+mod qcow2;
+mod ram;
+mod rangelock;
+mod readahead;
+mod remap;
+mod resize;
+mod rmw;
+mod sparse;
+mod striped;
+#[cfg(feature = "xxhash")]
+mod xxhash;
+mod zeromap;
+
+pub use budget::WriteBudgetBackend;
+pub use cache::{CacheBackend, CacheMode};
+pub use checksum::{ChecksumAlgorithm, ChecksumBackend};
+#[cfg(not(feature = "sync-nbd"))]
+pub use checksum::crc32c;
+pub use combine::WriteCombineBackend;
+pub use compressed::{CompressedBackend, Compression};
+pub use encrypted::EncryptedBackend;
+pub use file::FileBackend;
+pub use hash::{hash_backend, HashAlgorithm};
+pub use health::HealthBackend;
+pub use hybrid::{parse_stripe_ratio, HybridStripeBackend, StripeRatio, STRIPE_UNIT};
+pub use logical::LogicalSizeBackend;
+pub use mirror::MirrorBackend;
+pub use persist::PersistentBackend;
+pub use qcow2::Qcow2Backend;
+pub use ram::RamBuffer;
+pub use rangelock::RangeLockBackend;
+pub use readahead::ReadaheadBackend;
+pub use remap::BadBlockRemapBackend;
+pub use resize::{Allocator, ResizableBackend};
+pub use sparse::{SparseFileBackend, SPARSE_BLOCK_SIZE};
+pub use striped::StripedBackend;
+pub use zeromap::ZeroMapBackend;
+
+/// Error returned by a backend that no longer accepts writes
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOnlyError;
+
+impl std::fmt::Display for ReadOnlyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("backend is read-only")
+    }
+}
+
+impl std::error::Error for ReadOnlyError {}
+
+/// Whether a backend error means the write was refused as read-only
+pub fn is_read_only(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<ReadOnlyError>())
+}
+
+/// Error returned for writes beyond the space a backend can store
+#[derive(Debug, Clone, Copy)]
+pub struct NoSpaceError;
+
+impl std::fmt::Display for NoSpaceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("no space left on backend")
+    }
+}
+
+impl std::error::Error for NoSpaceError {}
+
+/// Whether a backend error means the write ran out of space
+pub fn is_no_space(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<NoSpaceError>())
+}
+
+/// Error returned once the GPU context is gone (driver reset, device hang);
+/// the device memory and everything in it are lost for good
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceLostError;
+
+impl std::fmt::Display for DeviceLostError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("GPU device lost")
+    }
+}
+
+impl std::error::Error for DeviceLostError {}
+
+/// Whether a backend error means the GPU device was lost
+pub fn is_device_lost(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| cause.is::<DeviceLostError>())
+}
+
+/// Largest host buffer used by the default `write_zeroes_at`
+const ZERO_CHUNK: u64 = 1024 * 1024;
+
+/// Minimal block backend abstraction shared by different frontends (NBD, ublk)
+pub trait BlockBackend: Send + Sync {
+    fn size(&self) -> u64;
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()>;
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()>;
+
+    /// Make all acknowledged writes durable in the backend, acting as a
+    /// barrier for everything completed before it.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Zero a range. The default writes zeros from the host in chunks.
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        let zeroes = vec![0u8; len.min(ZERO_CHUNK) as usize];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(ZERO_CHUNK);
+            self.write_at(offset + done, &zeroes[..chunk as usize])?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Whether `write_zeroes_at` avoids transferring zeros from the host
+    fn fast_zero(&self) -> bool {
+        false
+    }
+
+    /// Tell the backend a range is no longer in use. Discarded contents are
+    /// unspecified afterwards; the default ignores the hint.
+    fn discard_at(&self, _offset: u64, _len: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether the range is known to read as zeros without reading it. The
+    /// default knows nothing and says no.
+    fn is_known_zero(&self, _offset: u64, _len: u64) -> bool {
+        false
+    }
+}
+
+impl BlockBackend for VRamBuffer {
+    fn size(&self) -> u64 {
+        self.size() as u64
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.read(offset as usize, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.write(offset as usize, src)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.finish()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.fill_zeroes(offset as usize, len as usize)
+    }
+
+    fn fast_zero(&self) -> bool {
+        true
+    }
+
+    /// Discarded ranges read back as zeros, like a freshly allocated buffer
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.fill_zeroes(offset as usize, len as usize)
+    }
+}
+
+#[cfg(feature = "cuda")]
+impl BlockBackend for CudaBuffer {
+    fn size(&self) -> u64 {
+        self.size() as u64
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.read(offset as usize, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.write(offset as usize, src)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.finish()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.fill_zeroes(offset as usize, len as usize)
+    }
+
+    fn fast_zero(&self) -> bool {
+        true
+    }
+
+    /// Discarded ranges read back as zeros, like a freshly allocated buffer
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.fill_zeroes(offset as usize, len as usize)
+    }
+}
+
+#[cfg(feature = "vulkan")]
+impl BlockBackend for VulkanBuffer {
+    fn size(&self) -> u64 {
+        self.size() as u64
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.read(offset as usize, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.write(offset as usize, src)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.finish()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.fill_zeroes(offset as usize, len as usize)
+    }
+
+    fn fast_zero(&self) -> bool {
+        true
+    }
+
+    /// Discarded ranges read back as zeros, like a freshly allocated buffer
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.fill_zeroes(offset as usize, len as usize)
+    }
+}
+
+impl<T> BlockBackend for Arc<T>
+where
+    T: BlockBackend + ?Sized,
+{
+    fn size(&self) -> u64 {
+        (**self).size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        (**self).read_at(offset, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        (**self).write_at(offset, src)
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        (**self).write_zeroes_at(offset, len)
+    }
+
+    fn fast_zero(&self) -> bool {
+        (**self).fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        (**self).discard_at(offset, len)
+    }
+
+    fn is_known_zero(&self, offset: u64, len: u64) -> bool {
+        (**self).is_known_zero(offset, len)
+    }
+}
@@ -0,0 +1,189 @@
+//! Keep device contents across restarts in a host file
+//!
+//! `PersistentBackend` passes I/O straight through to its inner backend and
+//! only touches the file at the edges: the image is loaded into the device
+//! when the wrapper is opened, and `save` writes the device back out on a
+//! clean shutdown. The file is a plain raw image of exactly the device size.
+//! Nothing is saved if the process dies, so this is for scratch data that
+//! should usually survive a restart, not for data that must.
+//!
+//! A zstd-compressed snapshot (`--snapshot-compress zstd`) copied into place
+//! is recognised by its magic number and decompressed on load. It must
+//! decompress to exactly the device size and pass its checksum, so a torn
+//! snapshot is refused. A file of exactly the device size is always taken
+//! as a raw image.
+
+use super::BlockBackend;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Bytes moved between the file and the device per transfer
+const PERSIST_CHUNK: u64 = 4 * 1024 * 1024;
+
+/// First bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Backend wrapper that loads its contents from a file and saves them back
+pub struct PersistentBackend<B> {
+    inner: B,
+    path: PathBuf,
+}
+
+impl<B: BlockBackend> PersistentBackend<B> {
+    /// Wrap `inner`, first loading `path` into it if the file exists. A file
+    /// whose size differs from the device is an error.
+    pub fn open(inner: B, path: &Path) -> Result<Self> {
+        let backend = Self {
+            inner,
+            path: path.to_path_buf(),
+        };
+        match File::open(path) {
+            Ok(file) => backend.load(file)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                log::info!(
+                    "Persist file {} does not exist yet; starting with an empty device",
+                    path.display()
+                );
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to open persist file {}", path.display()));
+            }
+        }
+        Ok(backend)
+    }
+
+    fn load(&self, mut file: File) -> Result<()> {
+        let size = self.inner.size();
+        let file_size = file.metadata()?.len();
+        let mut magic = [0u8; 4];
+        let compressed =
+            file_size != size && file.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+        if !compressed && file_size != size {
+            bail!(
+                "Persist file {} is {} bytes but the device is {} bytes",
+                self.path.display(),
+                file_size,
+                size
+            );
+        }
+        file.rewind()?;
+
+        let start = Instant::now();
+        if compressed {
+            log::info!("{} is zstd-compressed", self.path.display());
+            let mut decoder = zstd::Decoder::new(file)?.single_frame();
+            self.copy_in(&mut decoder, size)?;
+            // Anything left over means the image was made for a larger device
+            if decoder.read(&mut [0u8; 1])? != 0 {
+                bail!(
+                    "Persist file {} holds more than the device's {} bytes",
+                    self.path.display(),
+                    size
+                );
+            }
+        } else {
+            self.copy_in(&mut file, size)?;
+        }
+        self.inner.flush()?;
+        log::info!(
+            "Loaded {} bytes from {} in {:?}",
+            size,
+            self.path.display(),
+            start.elapsed()
+        );
+        Ok(())
+    }
+
+    /// Write `size` bytes from `image` to the device
+    fn copy_in(&self, image: &mut impl Read, size: u64) -> Result<()> {
+        let mut buf = vec![0u8; PERSIST_CHUNK.min(size) as usize];
+        let mut offset = 0;
+        while offset < size {
+            let len = PERSIST_CHUNK.min(size - offset) as usize;
+            image
+                .read_exact(&mut buf[..len])
+                .with_context(|| format!("Failed to read {}", self.path.display()))?;
+            self.inner
+                .write_at(offset, &buf[..len])
+                .with_context(|| format!("Failed to load device at offset {}", offset))?;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    /// Write the device contents to the file. The image is written next to
+    /// it and renamed into place, so an interrupted save keeps the old one.
+    pub fn save(&self) -> Result<()> {
+        self.inner.flush()?;
+
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file =
+            File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+
+        let start = Instant::now();
+        let size = self.inner.size();
+        let mut buf = vec![0u8; PERSIST_CHUNK.min(size) as usize];
+        let mut offset = 0;
+        while offset < size {
+            let len = PERSIST_CHUNK.min(size - offset) as usize;
+            self.inner
+                .read_at(offset, &mut buf[..len])
+                .with_context(|| format!("Failed to read device at offset {}", offset))?;
+            file.write_all(&buf[..len])
+                .with_context(|| format!("Failed to write {}", tmp.display()))?;
+            offset += len as u64;
+        }
+        file.sync_all()
+            .with_context(|| format!("Failed to sync {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path).with_context(|| {
+            format!(
+                "Failed to move {} to {}",
+                tmp.display(),
+                self.path.display()
+            )
+        })?;
+        log::info!(
+            "Saved {} bytes to {} in {:?}",
+            size,
+            self.path.display(),
+            start.elapsed()
+        );
+        Ok(())
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for PersistentBackend<B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.inner.read_at(offset, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.inner.write_at(offset, src)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.inner.write_zeroes_at(offset, len)
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.inner.discard_at(offset, len)
+    }
+}
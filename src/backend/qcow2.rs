@@ -0,0 +1,736 @@
+//! qcow2 image layout interpreted on top of a raw backend
+//!
+//! `Qcow2Backend` treats the inner backend (normally the VRAM buffer) as a
+//! qcow2 image file and exposes the guest-visible virtual disk instead of the
+//! raw bytes. Supported: version 2 and 3 images without encryption, backing
+//! files or external data files; plain, zero and deflate-compressed clusters
+//! (compressed clusters are rewritten uncompressed when written to); and
+//! clusters shared with internal snapshots, which are copied on write.
+
+use super::BlockBackend;
+use anyhow::{bail, ensure, Context, Result};
+use flate2::{Decompress, FlushDecompress, Status};
+use std::sync::Mutex;
+
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+
+const L1E_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2E_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFT_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+const QCOW_OFLAG_ZERO: u64 = 1;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_COMPRESSION_TYPE: u64 = 1 << 3;
+
+/// Cluster size used when formatting a fresh image (64 KiB, qemu's default)
+const FORMAT_CLUSTER_BITS: u32 = 16;
+/// Refcount width used when formatting a fresh image (16 bit, qemu's default)
+const FORMAT_REFCOUNT_ORDER: u32 = 4;
+/// Length of a version 3 header without optional fields
+const V3_HEADER_LENGTH: u32 = 104;
+
+/// Parsed fields of the qcow2 header that the backend needs
+#[derive(Debug, Clone)]
+struct Header {
+    version: u32,
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u32,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_clusters: u32,
+}
+
+/// Where the data of one guest cluster lives
+#[derive(Debug, Clone, Copy)]
+enum Mapping {
+    /// Never written: reads as zeros
+    Unallocated,
+    /// Zero cluster, possibly with a preallocated host cluster (offset != 0)
+    Zero { offset: u64 },
+    /// Plain host cluster; `copied` means refcount == 1 so it may be written in place
+    Data { offset: u64, copied: bool },
+    /// Deflate-compressed data starting at host `offset`
+    Compressed { offset: u64, size: u64 },
+}
+
+/// Mutable metadata cached in host memory
+struct Meta {
+    l1: Vec<u64>,
+    refcount_table: Vec<u64>,
+    /// First cluster index that may be free
+    free_hint: u64,
+}
+
+/// Exposes the virtual disk of a qcow2 image stored in `inner`
+pub struct Qcow2Backend<B> {
+    inner: B,
+    header: Header,
+    cluster_size: u64,
+    l2_entries: u64,
+    refcount_bits: u64,
+    refblock_entries: u64,
+    physical_clusters: u64,
+    // Serializes all IO: metadata lookups and allocations must not interleave
+    meta: Mutex<Meta>,
+}
+
+impl<B: BlockBackend> Qcow2Backend<B> {
+    /// Open the qcow2 image in `inner`, or format a fresh empty image with the
+    /// given virtual size if `inner` does not start with a qcow2 header.
+    pub fn open_or_format(inner: B, virtual_size: u64) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        inner.read_at(0, &mut magic)?;
+        if u32::from_be_bytes(magic) == QCOW2_MAGIC {
+            let backend = Self::open(inner)?;
+            if backend.header.size != virtual_size {
+                log::warn!(
+                    "qcow2: existing image has virtual size {} bytes, ignoring requested {}",
+                    backend.header.size,
+                    virtual_size
+                );
+            }
+            Ok(backend)
+        } else {
+            Self::format(inner, virtual_size)
+        }
+    }
+
+    /// Open an existing qcow2 image stored in `inner`.
+    pub fn open(inner: B) -> Result<Self> {
+        let mut raw = [0u8; V3_HEADER_LENGTH as usize + 1];
+        inner.read_at(0, &mut raw)?;
+        let be32 = |off: usize| u32::from_be_bytes(raw[off..off + 4].try_into().unwrap());
+        let be64 = |off: usize| u64::from_be_bytes(raw[off..off + 8].try_into().unwrap());
+
+        ensure!(be32(0) == QCOW2_MAGIC, "Not a qcow2 image (bad magic)");
+        let version = be32(4);
+        ensure!(
+            version == 2 || version == 3,
+            "Unsupported qcow2 version {}",
+            version
+        );
+        if be64(8) != 0 {
+            bail!("qcow2 images with a backing file are not supported");
+        }
+        let cluster_bits = be32(20);
+        ensure!(
+            (9..=21).contains(&cluster_bits),
+            "Invalid qcow2 cluster_bits {}",
+            cluster_bits
+        );
+        if be32(32) != 0 {
+            bail!("Encrypted qcow2 images are not supported");
+        }
+
+        let refcount_order = if version >= 3 {
+            let incompatible = be64(72);
+            if incompatible & INCOMPAT_DIRTY != 0 {
+                bail!("qcow2 image is marked dirty; repair it with `qemu-img check -r all` first");
+            }
+            if incompatible & INCOMPAT_CORRUPT != 0 {
+                bail!("qcow2 image is marked corrupt");
+            }
+            if incompatible & INCOMPAT_COMPRESSION_TYPE != 0 && be32(100) > 104 && raw[104] != 0 {
+                bail!(
+                    "qcow2 compression type {} is not supported (only zlib)",
+                    raw[104]
+                );
+            }
+            let unknown = incompatible & !INCOMPAT_COMPRESSION_TYPE;
+            ensure!(
+                unknown == 0,
+                "qcow2 image uses unsupported incompatible features 0x{:x}",
+                unknown
+            );
+            be32(96)
+        } else {
+            4
+        };
+        ensure!(
+            refcount_order <= 6,
+            "Invalid qcow2 refcount_order {}",
+            refcount_order
+        );
+
+        let header = Header {
+            version,
+            cluster_bits,
+            size: be64(24),
+            l1_size: be32(36),
+            l1_table_offset: be64(40),
+            refcount_table_offset: be64(48),
+            refcount_table_clusters: be32(56),
+        };
+
+        let cluster_size = 1u64 << cluster_bits;
+        let l2_entries = cluster_size / 8;
+        let refcount_bits = 1u64 << refcount_order;
+        let physical_clusters = inner.size() / cluster_size;
+
+        // Header fields are untrusted, so none of this may overflow
+        let covered = (header.l1_size as u64)
+            .checked_mul(l2_entries)
+            .and_then(|entries| entries.checked_mul(cluster_size))
+            .context("qcow2 L1 table size overflows")?;
+        ensure!(
+            covered >= header.size,
+            "qcow2 L1 table ({} entries) does not cover the virtual size",
+            header.l1_size
+        );
+        let l1_bytes = header.l1_size as u64 * 8;
+        let reft_bytes = header.refcount_table_clusters as u64 * cluster_size;
+        let l1_end = header.l1_table_offset.checked_add(l1_bytes);
+        let reft_end = header.refcount_table_offset.checked_add(reft_bytes);
+        ensure!(
+            l1_end.is_some_and(|end| end <= inner.size())
+                && reft_end.is_some_and(|end| end <= inner.size()),
+            "qcow2 metadata extends past the end of the {} byte buffer",
+            inner.size()
+        );
+
+        let mut l1_raw = vec![0u8; l1_bytes as usize];
+        inner
+            .read_at(header.l1_table_offset, &mut l1_raw)
+            .context("Failed to read qcow2 L1 table")?;
+        let mut reft_raw = vec![0u8; reft_bytes as usize];
+        inner
+            .read_at(header.refcount_table_offset, &mut reft_raw)
+            .context("Failed to read qcow2 refcount table")?;
+
+        log::info!(
+            "qcow2: v{} image, virtual size {} bytes, cluster size {}, {}-bit refcounts",
+            header.version,
+            header.size,
+            cluster_size,
+            refcount_bits
+        );
+
+        Ok(Self {
+            inner,
+            cluster_size,
+            l2_entries,
+            refcount_bits,
+            refblock_entries: cluster_size * 8 / refcount_bits,
+            physical_clusters,
+            meta: Mutex::new(Meta {
+                l1: be_u64s(&l1_raw),
+                refcount_table: be_u64s(&reft_raw),
+                free_hint: 0,
+            }),
+            header,
+        })
+    }
+
+    /// Write a fresh, empty version 3 image into `inner` and open it.
+    ///
+    /// Refcount blocks covering the whole buffer are preallocated, since the
+    /// image can never grow beyond the buffer it lives in.
+    pub fn format(inner: B, virtual_size: u64) -> Result<Self> {
+        let virtual_size = virtual_size.div_ceil(512) * 512;
+        ensure!(virtual_size > 0, "qcow2 virtual size must be non-zero");
+
+        let cluster_size = 1u64 << FORMAT_CLUSTER_BITS;
+        let physical_clusters = inner.size() / cluster_size;
+        let refblock_entries = cluster_size * 8 / (1 << FORMAT_REFCOUNT_ORDER);
+
+        let refblocks = physical_clusters.div_ceil(refblock_entries);
+        let reft_clusters = (refblocks * 8).div_ceil(cluster_size).max(1);
+        let l1_size = virtual_size.div_ceil(cluster_size * (cluster_size / 8));
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+
+        let reft_offset = cluster_size;
+        let refblocks_offset = reft_offset + reft_clusters * cluster_size;
+        let l1_offset = refblocks_offset + refblocks * cluster_size;
+        let meta_clusters = 1 + reft_clusters + refblocks + l1_clusters;
+        ensure!(
+            meta_clusters < physical_clusters,
+            "Buffer of {} bytes is too small for a qcow2 image",
+            inner.size()
+        );
+
+        log::info!(
+            "qcow2: formatting new image (virtual size {} bytes) in {} byte buffer",
+            virtual_size,
+            inner.size()
+        );
+
+        // VRAM is not zeroed on allocation, so clear every metadata cluster
+        let zeros = vec![0u8; cluster_size as usize];
+        for cluster in 0..meta_clusters {
+            inner.write_at(cluster * cluster_size, &zeros)?;
+        }
+
+        let mut header = Vec::with_capacity(V3_HEADER_LENGTH as usize);
+        header.extend_from_slice(&QCOW2_MAGIC.to_be_bytes());
+        header.extend_from_slice(&3u32.to_be_bytes()); // version
+        header.extend_from_slice(&0u64.to_be_bytes()); // backing_file_offset
+        header.extend_from_slice(&0u32.to_be_bytes()); // backing_file_size
+        header.extend_from_slice(&FORMAT_CLUSTER_BITS.to_be_bytes());
+        header.extend_from_slice(&virtual_size.to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes()); // crypt_method
+        header.extend_from_slice(&(l1_size as u32).to_be_bytes());
+        header.extend_from_slice(&l1_offset.to_be_bytes());
+        header.extend_from_slice(&reft_offset.to_be_bytes());
+        header.extend_from_slice(&(reft_clusters as u32).to_be_bytes());
+        header.extend_from_slice(&0u32.to_be_bytes()); // nb_snapshots
+        header.extend_from_slice(&0u64.to_be_bytes()); // snapshots_offset
+        header.extend_from_slice(&0u64.to_be_bytes()); // incompatible_features
+        header.extend_from_slice(&0u64.to_be_bytes()); // compatible_features
+        header.extend_from_slice(&0u64.to_be_bytes()); // autoclear_features
+        header.extend_from_slice(&FORMAT_REFCOUNT_ORDER.to_be_bytes());
+        header.extend_from_slice(&V3_HEADER_LENGTH.to_be_bytes());
+        inner.write_at(0, &header)?;
+
+        let reft: Vec<u8> = (0..refblocks)
+            .flat_map(|i| (refblocks_offset + i * cluster_size).to_be_bytes())
+            .collect();
+        inner.write_at(reft_offset, &reft)?;
+
+        // 16-bit refcounts of 1 for the metadata clusters, which all sit in
+        // the first refcount blocks
+        let refcounts: Vec<u8> = (0..meta_clusters)
+            .flat_map(|_| 1u16.to_be_bytes())
+            .collect();
+        inner.write_at(refblocks_offset, &refcounts)?;
+
+        Self::open(inner)
+    }
+
+    fn read_u64(&self, offset: u64) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.inner.read_at(offset, &mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn write_u64(&self, offset: u64, value: u64) -> Result<()> {
+        self.inner.write_at(offset, &value.to_be_bytes())
+    }
+
+    /// Look up the mapping of guest cluster `vcluster`
+    fn mapping(&self, meta: &Meta, vcluster: u64) -> Result<Mapping> {
+        let l1_entry = meta.l1[(vcluster / self.l2_entries) as usize];
+        let l2_offset = l1_entry & L1E_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Mapping::Unallocated);
+        }
+        let entry = self.read_u64(l2_offset + (vcluster % self.l2_entries) * 8)?;
+        Ok(self.decode_l2_entry(entry))
+    }
+
+    fn decode_l2_entry(&self, entry: u64) -> Mapping {
+        if entry & QCOW_OFLAG_COMPRESSED != 0 {
+            let csize_shift = 62 - (self.header.cluster_bits - 8);
+            let csize_mask = (1u64 << (self.header.cluster_bits - 8)) - 1;
+            let offset = entry & ((1u64 << csize_shift) - 1);
+            let sectors = ((entry >> csize_shift) & csize_mask) + 1;
+            return Mapping::Compressed {
+                offset,
+                size: sectors * 512 - (offset & 511),
+            };
+        }
+        let offset = entry & L2E_OFFSET_MASK;
+        if self.header.version >= 3 && entry & QCOW_OFLAG_ZERO != 0 {
+            Mapping::Zero { offset }
+        } else if offset == 0 {
+            Mapping::Unallocated
+        } else {
+            Mapping::Data {
+                offset,
+                copied: entry & QCOW_OFLAG_COPIED != 0,
+            }
+        }
+    }
+
+    /// Host clusters referenced by a mapping (for refcount bookkeeping)
+    fn referenced_clusters(&self, mapping: Mapping) -> std::ops::Range<u64> {
+        let bits = self.header.cluster_bits;
+        match mapping {
+            Mapping::Data { offset, .. } | Mapping::Zero { offset } if offset != 0 => {
+                (offset >> bits)..(offset >> bits) + 1
+            }
+            Mapping::Compressed { offset, size } => {
+                let last = (offset & !511) + (size + (offset & 511)).div_ceil(512) * 512 - 1;
+                (offset >> bits)..(last >> bits) + 1
+            }
+            _ => 0..0,
+        }
+    }
+
+    fn decompress(&self, offset: u64, size: u64, out: &mut [u8]) -> Result<()> {
+        let size = size.min(self.inner.size().saturating_sub(offset));
+        let mut compressed = vec![0u8; size as usize];
+        self.inner.read_at(offset, &mut compressed)?;
+        let mut inflater = Decompress::new(false);
+        let status = inflater
+            .decompress(&compressed, out, FlushDecompress::Finish)
+            .context("Corrupt compressed qcow2 cluster")?;
+        ensure!(
+            matches!(status, Status::StreamEnd) || inflater.total_out() == out.len() as u64,
+            "Truncated compressed qcow2 cluster at offset {}",
+            offset
+        );
+        Ok(())
+    }
+
+    fn refcount_get(&self, meta: &Meta, cluster: u64) -> Result<u64> {
+        let block_idx = cluster / self.refblock_entries;
+        let block = match meta.refcount_table.get(block_idx as usize) {
+            Some(entry) if entry & REFT_OFFSET_MASK != 0 => entry & REFT_OFFSET_MASK,
+            _ => return Ok(0),
+        };
+        let index = cluster % self.refblock_entries;
+        if self.refcount_bits >= 8 {
+            let width = (self.refcount_bits / 8) as usize;
+            let mut buf = [0u8; 8];
+            self.inner
+                .read_at(block + index * width as u64, &mut buf[8 - width..])?;
+            Ok(u64::from_be_bytes(buf))
+        } else {
+            let bit = index * self.refcount_bits;
+            let mut byte = [0u8; 1];
+            self.inner.read_at(block + bit / 8, &mut byte)?;
+            Ok((byte[0] as u64 >> (bit % 8)) & ((1 << self.refcount_bits) - 1))
+        }
+    }
+
+    fn refcount_set(&self, meta: &mut Meta, cluster: u64, value: u64) -> Result<()> {
+        ensure!(
+            self.refcount_bits == 64 || value < (1u64 << self.refcount_bits),
+            "qcow2 refcount overflow for cluster {}",
+            cluster
+        );
+        let block_idx = cluster / self.refblock_entries;
+        let block = match meta.refcount_table.get(block_idx as usize) {
+            Some(entry) if entry & REFT_OFFSET_MASK != 0 => entry & REFT_OFFSET_MASK,
+            Some(_) => self.alloc_refblock(meta, block_idx, cluster)?,
+            None => bail!(
+                "qcow2 refcount table is too small to cover cluster {}",
+                cluster
+            ),
+        };
+        let index = cluster % self.refblock_entries;
+        if self.refcount_bits >= 8 {
+            let width = (self.refcount_bits / 8) as usize;
+            self.inner.write_at(
+                block + index * width as u64,
+                &value.to_be_bytes()[8 - width..],
+            )?;
+        } else {
+            let bit = index * self.refcount_bits;
+            let mask = ((1u8 << self.refcount_bits) - 1) << (bit % 8);
+            let mut byte = [0u8; 1];
+            self.inner.read_at(block + bit / 8, &mut byte)?;
+            byte[0] = (byte[0] & !mask) | (((value as u8) << (bit % 8)) & mask);
+            self.inner.write_at(block + bit / 8, &byte)?;
+        }
+        if value == 0 {
+            meta.free_hint = meta.free_hint.min(cluster);
+        }
+        Ok(())
+    }
+
+    fn refcount_adjust(
+        &self,
+        meta: &mut Meta,
+        clusters: std::ops::Range<u64>,
+        delta: i64,
+    ) -> Result<()> {
+        for cluster in clusters {
+            let current = self.refcount_get(meta, cluster)?;
+            let updated = current
+                .checked_add_signed(delta)
+                .with_context(|| format!("qcow2 refcount underflow for cluster {}", cluster))?;
+            self.refcount_set(meta, cluster, updated)?;
+        }
+        Ok(())
+    }
+
+    /// Allocate a refcount block for `block_idx`, never reusing `reserved`
+    fn alloc_refblock(&self, meta: &mut Meta, block_idx: u64, reserved: u64) -> Result<u64> {
+        let cluster = self.find_free_cluster(meta, Some(reserved))?;
+        let offset = cluster << self.header.cluster_bits;
+        self.inner
+            .write_at(offset, &vec![0u8; self.cluster_size as usize])?;
+        meta.refcount_table[block_idx as usize] = offset;
+        self.write_u64(self.header.refcount_table_offset + block_idx * 8, offset)?;
+        // The new block may have to account for itself
+        self.refcount_set(meta, cluster, 1)?;
+        Ok(offset)
+    }
+
+    /// Find a cluster with refcount 0, scanning whole refcount blocks at a time
+    fn find_free_cluster(&self, meta: &mut Meta, reserved: Option<u64>) -> Result<u64> {
+        let mut cluster = meta.free_hint;
+        let mut block = vec![0u8; self.cluster_size as usize];
+        while cluster < self.physical_clusters {
+            let block_idx = cluster / self.refblock_entries;
+            let block_end = ((block_idx + 1) * self.refblock_entries).min(self.physical_clusters);
+            let entry = meta
+                .refcount_table
+                .get(block_idx as usize)
+                .map_or(0, |e| e & REFT_OFFSET_MASK);
+            if entry != 0 {
+                self.inner.read_at(entry, &mut block)?;
+            }
+            while cluster < block_end {
+                let index = cluster % self.refblock_entries;
+                let free = entry == 0 || refcount_in_block(&block, index, self.refcount_bits) == 0;
+                if free && Some(cluster) != reserved {
+                    if reserved.is_none() {
+                        meta.free_hint = cluster + 1;
+                    }
+                    return Ok(cluster);
+                }
+                cluster += 1;
+            }
+        }
+        bail!(
+            "qcow2 image is full: no free clusters left in the {} byte buffer",
+            self.inner.size()
+        )
+    }
+
+    /// Allocate a cluster with refcount 1 and return its host offset
+    fn alloc_cluster(&self, meta: &mut Meta) -> Result<u64> {
+        let cluster = self.find_free_cluster(meta, None)?;
+        self.refcount_set(meta, cluster, 1)?;
+        Ok(cluster << self.header.cluster_bits)
+    }
+
+    /// Return an L2 table for `l1_index` that may be modified in place,
+    /// allocating it or copying a snapshot-shared table as needed.
+    fn writable_l2_table(&self, meta: &mut Meta, l1_index: usize) -> Result<u64> {
+        let entry = meta.l1[l1_index];
+        let old = entry & L1E_OFFSET_MASK;
+        if old != 0 && entry & QCOW_OFLAG_COPIED != 0 {
+            return Ok(old);
+        }
+
+        let new = self.alloc_cluster(meta)?;
+        let mut table = vec![0u8; self.cluster_size as usize];
+        if old != 0 {
+            self.inner.read_at(old, &mut table)?;
+            // Data refcounts already count every L1 table (snapshots included)
+            // referencing them, so only the COPIED flags need clearing
+            for raw in table.chunks_exact_mut(8) {
+                let l2_entry = u64::from_be_bytes(raw.try_into().unwrap());
+                raw.copy_from_slice(&(l2_entry & !QCOW_OFLAG_COPIED).to_be_bytes());
+            }
+        }
+        self.inner.write_at(new, &table)?;
+        if old != 0 {
+            let bits = self.header.cluster_bits;
+            self.refcount_adjust(meta, (old >> bits)..(old >> bits) + 1, -1)?;
+        }
+
+        meta.l1[l1_index] = new | QCOW_OFLAG_COPIED;
+        self.write_u64(
+            self.header.l1_table_offset + l1_index as u64 * 8,
+            meta.l1[l1_index],
+        )?;
+        Ok(new)
+    }
+
+    fn read_cluster(&self, meta: &Meta, vcluster: u64, in_off: u64, dst: &mut [u8]) -> Result<()> {
+        match self.mapping(meta, vcluster)? {
+            Mapping::Unallocated | Mapping::Zero { .. } => dst.fill(0),
+            Mapping::Data { offset, .. } => self.inner.read_at(offset + in_off, dst)?,
+            Mapping::Compressed { offset, size } => {
+                let mut cluster = vec![0u8; self.cluster_size as usize];
+                self.decompress(offset, size, &mut cluster)?;
+                dst.copy_from_slice(&cluster[in_off as usize..in_off as usize + dst.len()]);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_cluster(&self, meta: &mut Meta, vcluster: u64, in_off: u64, src: &[u8]) -> Result<()> {
+        let mapping = self.mapping(meta, vcluster)?;
+        if let Mapping::Data {
+            offset,
+            copied: true,
+        } = mapping
+        {
+            return self.inner.write_at(offset + in_off, src);
+        }
+
+        // Build the full new cluster contents, then point the L2 entry at a
+        // freshly allocated, exclusively owned cluster
+        let mut data = vec![0u8; self.cluster_size as usize];
+        if (src.len() as u64) < self.cluster_size {
+            match mapping {
+                Mapping::Data { offset, .. } => self.inner.read_at(offset, &mut data)?,
+                Mapping::Compressed { offset, size } => self.decompress(offset, size, &mut data)?,
+                Mapping::Unallocated | Mapping::Zero { .. } => {}
+            }
+        }
+        data[in_off as usize..in_off as usize + src.len()].copy_from_slice(src);
+
+        let l2_table = self.writable_l2_table(meta, (vcluster / self.l2_entries) as usize)?;
+        let host = self.alloc_cluster(meta)?;
+        self.inner.write_at(host, &data)?;
+        self.write_u64(
+            l2_table + (vcluster % self.l2_entries) * 8,
+            host | QCOW_OFLAG_COPIED,
+        )?;
+        self.refcount_adjust(meta, self.referenced_clusters(mapping), -1)
+    }
+
+    fn lock_meta(&self) -> Result<std::sync::MutexGuard<'_, Meta>> {
+        self.meta
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock qcow2 metadata mutex"))
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for Qcow2Backend<B> {
+    fn size(&self) -> u64 {
+        self.header.size
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.header.size {
+            bail!("Attempted to read past end of qcow2 virtual disk");
+        }
+        let meta = self.lock_meta()?;
+        let mut done = 0usize;
+        while done < dst.len() {
+            let pos = offset + done as u64;
+            let in_off = pos % self.cluster_size;
+            let len = ((self.cluster_size - in_off) as usize).min(dst.len() - done);
+            self.read_cluster(
+                &meta,
+                pos >> self.header.cluster_bits,
+                in_off,
+                &mut dst[done..done + len],
+            )?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.header.size {
+            bail!("Attempted to write past end of qcow2 virtual disk");
+        }
+        let mut meta = self.lock_meta()?;
+        let mut done = 0usize;
+        while done < src.len() {
+            let pos = offset + done as u64;
+            let in_off = pos % self.cluster_size;
+            let len = ((self.cluster_size - in_off) as usize).min(src.len() - done);
+            self.write_cluster(
+                &mut meta,
+                pos >> self.header.cluster_bits,
+                in_off,
+                &src[done..done + len],
+            )?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decode big-endian u64 table entries
+fn be_u64s(raw: &[u8]) -> Vec<u64> {
+    raw.chunks_exact(8)
+        .map(|c| u64::from_be_bytes(c.try_into().unwrap()))
+        .collect()
+}
+
+/// Read refcount entry `index` from an in-memory refcount block
+fn refcount_in_block(block: &[u8], index: u64, refcount_bits: u64) -> u64 {
+    if refcount_bits >= 8 {
+        let width = (refcount_bits / 8) as usize;
+        let start = index as usize * width;
+        block[start..start + width]
+            .iter()
+            .fold(0u64, |acc, b| (acc << 8) | *b as u64)
+    } else {
+        let bit = index * refcount_bits;
+        (block[(bit / 8) as usize] as u64 >> (bit % 8)) & ((1 << refcount_bits) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+    use std::sync::Arc;
+
+    const BUFFER_SIZE: u64 = 4 * 1024 * 1024;
+    const VIRTUAL_SIZE: u64 = 64 * 1024 * 1024;
+
+    fn pattern(seed: u8, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    #[test]
+    fn format_write_reopen_read() {
+        let inner = Arc::new(RamBuffer::new(BUFFER_SIZE));
+        let cluster = 1u64 << FORMAT_CLUSTER_BITS;
+        // Inside a cluster, across a cluster boundary, a whole cluster,
+        // and the end of the virtual disk
+        let writes = [
+            (7, pattern(1, 100)),
+            (cluster - 10, pattern(2, 30)),
+            (5 * cluster, pattern(3, cluster as usize)),
+            (VIRTUAL_SIZE - 512, pattern(4, 512)),
+        ];
+        {
+            let qcow2 = Qcow2Backend::format(inner.clone(), VIRTUAL_SIZE).unwrap();
+            assert_eq!(qcow2.size(), VIRTUAL_SIZE);
+            for (offset, data) in &writes {
+                qcow2.write_at(*offset, data).unwrap();
+            }
+            qcow2.flush().unwrap();
+        }
+
+        let qcow2 = Qcow2Backend::open(inner).unwrap();
+        assert_eq!(qcow2.size(), VIRTUAL_SIZE);
+        for (offset, data) in &writes {
+            let mut back = vec![0u8; data.len()];
+            qcow2.read_at(*offset, &mut back).unwrap();
+            assert_eq!(&back, data, "data at {}", offset);
+        }
+        // Unallocated clusters read as zeros
+        let mut back = vec![1u8; cluster as usize];
+        qcow2.read_at(20 * cluster, &mut back).unwrap();
+        assert!(back.iter().all(|&b| b == 0));
+    }
+
+    /// A freshly formatted image with the header field at `at` replaced
+    fn with_header_field(at: u64, value: &[u8]) -> Arc<RamBuffer> {
+        let inner = Arc::new(RamBuffer::new(BUFFER_SIZE));
+        Qcow2Backend::format(inner.clone(), VIRTUAL_SIZE).unwrap();
+        inner.write_at(at, value).unwrap();
+        inner
+    }
+
+    #[test]
+    fn overflowing_header_is_rejected() {
+        // l1_size * l2_entries * cluster_size past u64::MAX
+        let mut inner = with_header_field(20, &21u32.to_be_bytes());
+        inner.write_at(36, &u32::MAX.to_be_bytes()).unwrap();
+        assert!(Qcow2Backend::open(inner).is_err());
+
+        // l1_table_offset + l1_bytes past u64::MAX
+        inner = with_header_field(40, &(u64::MAX - 7).to_be_bytes());
+        assert!(Qcow2Backend::open(inner).is_err());
+
+        // refcount_table_offset + refcount table bytes past u64::MAX
+        inner = with_header_field(48, &(u64::MAX - 7).to_be_bytes());
+        assert!(Qcow2Backend::open(inner).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! Host RAM backend
+//!
+//! Plain memory for setups that combine VRAM with system RAM, and for
+//! running without a GPU (`--backend mem`). Bounds checks and discard
+//! behave like `VRamBuffer`, so either can stand in for the other. The
+//! process runs under `mlockall(MCL_FUTURE)`, so the allocation is locked
+//! like the rest of the address space. Data is split into shards with their
+//! own lock so concurrent transfers to different regions don't serialize.
+
+use super::BlockBackend;
+use anyhow::{bail, Result};
+use std::sync::RwLock;
+
+/// Size of one independently locked shard
+const SHARD_SIZE: u64 = 1024 * 1024;
+
+/// A buffer in host memory
+pub struct RamBuffer {
+    shards: Vec<RwLock<Box<[u8]>>>,
+    size: u64,
+}
+
+impl RamBuffer {
+    pub fn new(size: u64) -> Self {
+        let shards = (0..size.div_ceil(SHARD_SIZE))
+            .map(|i| {
+                let len = SHARD_SIZE.min(size - i * SHARD_SIZE) as usize;
+                RwLock::new(vec![0u8; len].into_boxed_slice())
+            })
+            .collect();
+        Self { shards, size }
+    }
+
+    /// Call `f` with (shard index, offset in shard, range in the caller's buffer)
+    fn for_each_shard(
+        &self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(usize, usize, std::ops::Range<usize>) -> Result<()>,
+    ) -> Result<()> {
+        if offset + len as u64 > self.size {
+            bail!("Attempted to access past end of RAM buffer");
+        }
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let in_shard = (pos % SHARD_SIZE) as usize;
+            let chunk = (SHARD_SIZE as usize - in_shard).min(len - done);
+            f((pos / SHARD_SIZE) as usize, in_shard, done..done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl BlockBackend for RamBuffer {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.for_each_shard(offset, dst.len(), |shard, start, range| {
+            let data = self.shards[shard]
+                .read()
+                .map_err(|_| anyhow::anyhow!("RAM shard lock poisoned"))?;
+            dst[range.clone()].copy_from_slice(&data[start..start + range.len()]);
+            Ok(())
+        })
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.for_each_shard(offset, src.len(), |shard, start, range| {
+            let mut data = self.shards[shard]
+                .write()
+                .map_err(|_| anyhow::anyhow!("RAM shard lock poisoned"))?;
+            data[start..start + range.len()].copy_from_slice(&src[range]);
+            Ok(())
+        })
+    }
+
+    /// Discarded ranges read back as zeros, as on VRAM
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.write_zeroes_at(offset, len)
+    }
+}
@@ -0,0 +1,244 @@
+//! Serialization of overlapping requests
+//!
+//! Frontends call the backend from many threads at once, one request per
+//! thread, and nothing below orders two requests that touch the same bytes:
+//! a write split across GPUs or striped between VRAM and RAM can interleave
+//! with another write to the same range and leave a mix of both.
+//!
+//! `RangeLockBackend` locks the byte range of each request for its whole
+//! duration. Reads take the range shared, writes, write-zeroes and discards
+//! take it exclusively. The resulting consistency model: every request is
+//! atomic with respect to the requests it overlaps, so a read returns the
+//! data of whole writes only, and overlapping writes land in some order as
+//! a whole. Requests on disjoint ranges, and overlapping reads, still run
+//! concurrently. Flush is not locked; it covers the requests completed
+//! before it, as before.
+
+use super::BlockBackend;
+use anyhow::Result;
+use std::sync::{Condvar, Mutex};
+
+/// A held lock on `start..end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Held {
+    id: u64,
+    start: u64,
+    end: u64,
+    exclusive: bool,
+}
+
+#[derive(Debug, Default)]
+struct Ranges {
+    held: Vec<Held>,
+    next_id: u64,
+}
+
+/// Reader/writer locks on byte ranges
+#[derive(Debug, Default)]
+struct RangeLock {
+    ranges: Mutex<Ranges>,
+    released: Condvar,
+}
+
+impl RangeLock {
+    /// Block until `start..end` can be held, shared or exclusively
+    fn lock(&self, start: u64, end: u64, exclusive: bool) -> RangeGuard<'_> {
+        let conflicts = |ranges: &Ranges| {
+            ranges
+                .held
+                .iter()
+                .any(|held| held.start < end && start < held.end && (exclusive || held.exclusive))
+        };
+        let mut ranges = self.ranges.lock().unwrap_or_else(|e| e.into_inner());
+        while conflicts(&ranges) {
+            ranges = self
+                .released
+                .wait(ranges)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        let id = ranges.next_id;
+        ranges.next_id += 1;
+        ranges.held.push(Held {
+            id,
+            start,
+            end,
+            exclusive,
+        });
+        RangeGuard { lock: self, id }
+    }
+}
+
+/// Releases its range when dropped
+struct RangeGuard<'a> {
+    lock: &'a RangeLock,
+    id: u64,
+}
+
+impl Drop for RangeGuard<'_> {
+    fn drop(&mut self) {
+        let mut ranges = self.lock.ranges.lock().unwrap_or_else(|e| e.into_inner());
+        ranges.held.retain(|held| held.id != self.id);
+        drop(ranges);
+        self.lock.released.notify_all();
+    }
+}
+
+/// Backend wrapper that serializes overlapping requests
+pub struct RangeLockBackend<B> {
+    inner: B,
+    locks: RangeLock,
+}
+
+impl<B: BlockBackend> RangeLockBackend<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            locks: RangeLock::default(),
+        }
+    }
+
+    fn exclusive(&self, offset: u64, len: u64) -> RangeGuard<'_> {
+        self.locks.lock(offset, offset.saturating_add(len), true)
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for RangeLockBackend<B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        let end = offset.saturating_add(dst.len() as u64);
+        let _range = self.locks.lock(offset, end, false);
+        self.inner.read_at(offset, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        let _range = self.exclusive(offset, src.len() as u64);
+        self.inner.write_at(offset, src)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        let _range = self.exclusive(offset, len);
+        self.inner.write_zeroes_at(offset, len)
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        let _range = self.exclusive(offset, len);
+        self.inner.discard_at(offset, len)
+    }
+
+    fn is_known_zero(&self, offset: u64, len: u64) -> bool {
+        self.inner.is_known_zero(offset, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    const BLOCK: usize = 4096;
+    const BLOCKS: usize = 16;
+
+    /// Writes in small pieces and pauses between them, as a transfer split
+    /// across GPUs can interleave with another
+    struct Piecewise(RamBuffer);
+
+    impl BlockBackend for Piecewise {
+        fn size(&self) -> u64 {
+            self.0.size()
+        }
+
+        fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+            for (i, piece) in dst.chunks_mut(512).enumerate() {
+                self.0.read_at(offset + (i * 512) as u64, piece)?;
+                thread::sleep(Duration::from_micros(20));
+            }
+            Ok(())
+        }
+
+        fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+            for (i, piece) in src.chunks(512).enumerate() {
+                self.0.write_at(offset + (i * 512) as u64, piece)?;
+                thread::sleep(Duration::from_micros(20));
+            }
+            Ok(())
+        }
+    }
+
+    /// The id filling each block, or `None` for a torn block
+    fn block_ids(data: &[u8]) -> Vec<Option<u8>> {
+        data.chunks(BLOCK)
+            .map(|block| block.iter().all(|&b| b == block[0]).then_some(block[0]))
+            .collect()
+    }
+
+    #[test]
+    fn overlapping_writes_do_not_tear_blocks() {
+        let backend = Arc::new(RangeLockBackend::new(Piecewise(RamBuffer::new(
+            (BLOCKS * BLOCK) as u64,
+        ))));
+        let writers: Vec<_> = (1..=8u8)
+            .map(|id| {
+                let backend = backend.clone();
+                thread::spawn(move || {
+                    // Runs of 1 to 4 blocks at offsets that overlap the
+                    // other writers' runs
+                    for i in 0..50 {
+                        let first = (i * id as usize + id as usize) % BLOCKS;
+                        let blocks = (1 + (i + id as usize) % 4).min(BLOCKS - first);
+                        let data = vec![id; blocks * BLOCK];
+                        backend.write_at((first * BLOCK) as u64, &data).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let backend = backend.clone();
+                thread::spawn(move || {
+                    let mut data = vec![0u8; BLOCKS * BLOCK];
+                    for _ in 0..20 {
+                        backend.read_at(0, &mut data).unwrap();
+                        assert!(block_ids(&data).iter().all(Option::is_some));
+                    }
+                })
+            })
+            .collect();
+        for thread in writers.into_iter().chain(readers) {
+            thread.join().unwrap();
+        }
+
+        let mut data = vec![0u8; BLOCKS * BLOCK];
+        backend.read_at(0, &mut data).unwrap();
+        for (block, id) in block_ids(&data).into_iter().enumerate() {
+            assert!(
+                matches!(id, Some(1..=8)),
+                "block {} is torn or unwritten",
+                block
+            );
+        }
+    }
+
+    #[test]
+    fn disjoint_ranges_are_held_at_once() {
+        let locks = RangeLock::default();
+        let _a = locks.lock(0, 100, true);
+        let _b = locks.lock(100, 200, true);
+        let _c = locks.lock(300, 400, false);
+        let _d = locks.lock(300, 400, false);
+        assert_eq!(locks.ranges.lock().unwrap().held.len(), 4);
+    }
+}
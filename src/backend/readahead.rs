@@ -0,0 +1,470 @@
+//! Readahead for sequential reads
+//!
+//! A client streaming through the device (`dd`, a backup, copying an image
+//! off it) asks for the next chunk only once the previous one has crossed
+//! PCIe and the network. `ReadaheadBackend` spots reads that continue where
+//! earlier ones ended and has a background thread read the following data
+//! from the inner backend in halves of `window`, so the next half comes off
+//! the GPU while the current one is sent, up to `window` bytes ahead of the
+//! reader. Reads that land in prefetched data are copied from it, and reads
+//! that land in the half still being fetched wait for it.
+//!
+//! A read counts as sequential when it starts where one of the last few
+//! reads ended, so requests a client has in flight at once may arrive out of
+//! order. Prefetching starts after two sequential reads in a row and stops at
+//! the first read that isn't, so random access costs no extra transfers. A
+//! failed prefetch ends the stream too, so waiting reads go to the inner
+//! backend themselves and get its error. A
+//! sequential read far from the stream starts a new one and drops the
+//! prefetched data. Writes, write-zeroes and discards drop any
+//! prefetched data they overlap once they have completed. One stream is
+//! followed per backend; clients reading different parts at once restart
+//! each other's readahead.
+
+use super::BlockBackend;
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+/// Read ends remembered to recognize a read continuing one of them
+const RECENT_READS: usize = 16;
+
+/// Sequential reads in a row before prefetching starts
+const SEQUENTIAL_READS: u32 = 2;
+
+struct Chunk {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl Chunk {
+    fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+/// The one prefetch queued or in progress
+struct Fetch {
+    offset: u64,
+    len: usize,
+    started: bool,
+    /// Data it overlaps changed while it was being read
+    stale: bool,
+}
+
+impl Fetch {
+    fn end(&self) -> u64 {
+        self.offset + self.len as u64
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// Ends of the most recent reads, oldest first
+    recent: VecDeque<u64>,
+    /// Sequential reads since the last one that wasn't
+    streak: u32,
+    /// End of the furthest read of the current stream
+    pos: Option<u64>,
+    /// Prefetched data: contiguous chunks, oldest first
+    chunks: VecDeque<Chunk>,
+    fetch: Option<Fetch>,
+    shutdown: bool,
+}
+
+impl State {
+    /// The range the chunks hold
+    fn cached(&self) -> Option<(u64, u64)> {
+        let first = self.chunks.front()?;
+        let last = self.chunks.back()?;
+        Some((first.offset, last.end()))
+    }
+
+    /// The range the chunks will hold once the fetch completes
+    fn expected(&self) -> Option<(u64, u64)> {
+        let fetch = self.fetch.as_ref().filter(|fetch| !fetch.stale)?;
+        match self.cached() {
+            Some((start, end)) if end == fetch.offset => Some((start, fetch.end())),
+            _ => Some((fetch.offset, fetch.end())),
+        }
+    }
+
+    /// Forget the stream's data, discarding whatever the fetch reads
+    fn reset(&mut self) {
+        self.chunks.clear();
+        match &mut self.fetch {
+            Some(fetch) if fetch.started => fetch.stale = true,
+            _ => self.fetch = None,
+        }
+    }
+}
+
+struct Shared<B> {
+    inner: B,
+    window: u64,
+    state: Mutex<State>,
+    wake: Condvar,
+    reads: AtomicU64,
+    hits: AtomicU64,
+    prefetched: AtomicU64,
+}
+
+/// Backend wrapper that prefetches up to `window` bytes ahead of
+/// sequential reads
+pub struct ReadaheadBackend<B: BlockBackend + 'static> {
+    shared: Arc<Shared<B>>,
+    prefetcher: Option<JoinHandle<()>>,
+}
+
+impl<B: BlockBackend + 'static> ReadaheadBackend<B> {
+    pub fn new(inner: B, window: u64) -> Self {
+        let shared = Arc::new(Shared {
+            inner,
+            window,
+            state: Mutex::new(State::default()),
+            wake: Condvar::new(),
+            reads: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            prefetched: AtomicU64::new(0),
+        });
+        let prefetcher = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("readahead".into())
+                .spawn(move || shared.run_prefetcher())
+                .expect("failed to spawn readahead thread")
+        };
+        Self {
+            shared,
+            prefetcher: Some(prefetcher),
+        }
+    }
+}
+
+impl<B: BlockBackend> Shared<B> {
+    fn lock(&self) -> Result<MutexGuard<'_, State>> {
+        self.state
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Readahead lock poisoned"))
+    }
+
+    /// Read queued fetches into chunks until shutdown
+    fn run_prefetcher(&self) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        loop {
+            if state.shutdown {
+                return;
+            }
+            let Some(fetch) = state.fetch.as_mut().filter(|fetch| !fetch.started) else {
+                state = self.wake.wait(state).unwrap_or_else(|e| e.into_inner());
+                continue;
+            };
+            fetch.started = true;
+            let (offset, len) = (fetch.offset, fetch.len);
+            drop(state);
+
+            let mut data = vec![0u8; len];
+            let result = self.inner.read_at(offset, &mut data);
+
+            state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let stale = state.fetch.take().is_none_or(|fetch| fetch.stale);
+            match result {
+                Ok(()) if !stale => {
+                    if state.cached().is_some_and(|(_, end)| end != offset) {
+                        state.chunks.clear();
+                    }
+                    state.chunks.push_back(Chunk { offset, data });
+                    self.prefetched.fetch_add(len as u64, Ordering::Relaxed);
+                }
+                Ok(()) => {}
+                Err(e) => {
+                    log::debug!("Readahead of {} bytes at {} failed: {:#}", len, offset, e);
+                    // Retrying at once would fail the same way and keep the
+                    // readers waiting
+                    state.streak = 0;
+                    state.pos = None;
+                }
+            }
+            self.schedule(&mut state);
+            self.wake.notify_all();
+        }
+    }
+
+    /// Queue the next half window if the stream is less than a window
+    /// behind the prefetched data
+    fn schedule(&self, state: &mut State) {
+        let Some(pos) = state.pos.filter(|_| state.streak >= SEQUENTIAL_READS) else {
+            return;
+        };
+        while state
+            .chunks
+            .front()
+            .is_some_and(|chunk| chunk.end() + self.window <= pos)
+        {
+            state.chunks.pop_front();
+        }
+        if state.fetch.is_some() {
+            return;
+        }
+        let frontier = match state.cached() {
+            Some((start, end)) if start <= pos && pos <= end => end,
+            _ => {
+                state.chunks.clear();
+                pos
+            }
+        };
+        let half = self.window / 2;
+        let size = self.inner.size();
+        if frontier - pos + half > self.window || frontier >= size {
+            return;
+        }
+        state.fetch = Some(Fetch {
+            offset: frontier,
+            len: half.min(size - frontier) as usize,
+            started: false,
+            stale: false,
+        });
+        self.wake.notify_all();
+    }
+
+    /// Drop prefetched data overlapping a range that was just modified
+    fn invalidate(&self, offset: u64, len: u64) {
+        let end = offset + len;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state
+            .cached()
+            .is_some_and(|(start, stop)| start < end && offset < stop)
+        {
+            state.chunks.clear();
+        }
+        // A fetch that hasn't started yet reads the new data
+        if let Some(fetch) = &mut state.fetch
+            && fetch.started
+            && fetch.offset < end
+            && offset < fetch.end()
+        {
+            fetch.stale = true;
+        }
+    }
+}
+
+impl<B: BlockBackend + 'static> BlockBackend for ReadaheadBackend<B> {
+    fn size(&self) -> u64 {
+        self.shared.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        let end = offset + dst.len() as u64;
+        let window = self.shared.window;
+        self.shared.reads.fetch_add(1, Ordering::Relaxed);
+
+        let mut state = self.shared.lock()?;
+        let sequential = state.recent.contains(&offset);
+        if state.recent.len() == RECENT_READS {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(end);
+        if sequential {
+            state.streak = state.streak.saturating_add(1);
+            match state.pos {
+                Some(pos) if pos.abs_diff(end) <= window => state.pos = Some(pos.max(end)),
+                _ => {
+                    state.reset();
+                    state.pos = Some(end);
+                }
+            }
+            self.shared.schedule(&mut state);
+        } else {
+            state.streak = 0;
+        }
+
+        let contains = |range: Option<(u64, u64)>| {
+            range.is_some_and(|(start, stop)| start <= offset && end <= stop)
+        };
+        while !contains(state.cached()) && contains(state.expected()) {
+            state = self
+                .shared
+                .wake
+                .wait(state)
+                .map_err(|_| anyhow::anyhow!("Readahead lock poisoned"))?;
+        }
+        if !contains(state.cached()) {
+            drop(state);
+            return self.shared.inner.read_at(offset, dst);
+        }
+        for chunk in &state.chunks {
+            let from = offset.max(chunk.offset);
+            let to = end.min(chunk.end());
+            if from < to {
+                let piece =
+                    &chunk.data[(from - chunk.offset) as usize..(to - chunk.offset) as usize];
+                let at = (from - offset) as usize;
+                dst[at..at + piece.len()].copy_from_slice(piece);
+            }
+        }
+        self.shared.hits.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        let result = self.shared.inner.write_at(offset, src);
+        self.shared.invalidate(offset, src.len() as u64);
+        result
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.shared.inner.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        let result = self.shared.inner.write_zeroes_at(offset, len);
+        self.shared.invalidate(offset, len);
+        result
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.shared.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        let result = self.shared.inner.discard_at(offset, len);
+        self.shared.invalidate(offset, len);
+        result
+    }
+
+    fn is_known_zero(&self, offset: u64, len: u64) -> bool {
+        self.shared.inner.is_known_zero(offset, len)
+    }
+}
+
+impl<B: BlockBackend + 'static> Drop for ReadaheadBackend<B> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.shared.state.lock() {
+            state.shutdown = true;
+            self.shared.wake.notify_all();
+        }
+        if let Some(prefetcher) = self.prefetcher.take() {
+            let _ = prefetcher.join();
+        }
+        log::info!(
+            "Readahead: {} of {} reads served from {} bytes prefetched",
+            self.shared.hits.load(Ordering::Relaxed),
+            self.shared.reads.load(Ordering::Relaxed),
+            self.shared.prefetched.load(Ordering::Relaxed)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const BLOCK: usize = 4096;
+
+    /// Fails every read that touches `bad`, counting the reads it gets
+    struct Failing {
+        ram: RamBuffer,
+        bad: (u64, u64),
+        reads: AtomicU64,
+    }
+
+    impl BlockBackend for Failing {
+        fn size(&self) -> u64 {
+            self.ram.size()
+        }
+
+        fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            if offset < self.bad.1 && self.bad.0 < offset + dst.len() as u64 {
+                anyhow::bail!("bad block");
+            }
+            self.ram.read_at(offset, dst)
+        }
+
+        fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+            self.ram.write_at(offset, src)
+        }
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i / BLOCK) as u8).collect()
+    }
+
+    #[test]
+    fn sequential_reads_are_served_from_prefetched_data() {
+        let inner = Arc::new(RamBuffer::new(256 * 1024));
+        let data = pattern(256 * 1024);
+        inner.write_at(0, &data).unwrap();
+        let backend = ReadaheadBackend::new(inner, 64 * 1024);
+
+        let mut buf = vec![0u8; BLOCK];
+        for offset in (0..data.len()).step_by(BLOCK) {
+            backend.read_at(offset as u64, &mut buf).unwrap();
+            assert_eq!(buf, data[offset..offset + BLOCK]);
+        }
+        assert!(backend.shared.hits.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn failed_prefetch_is_dropped_and_the_read_fails() {
+        let size = 256 * 1024;
+        let ram = RamBuffer::new(size);
+        let data = pattern(size as usize);
+        ram.write_at(0, &data).unwrap();
+        let inner = Arc::new(Failing {
+            ram,
+            bad: (40 * 1024, 44 * 1024),
+            reads: AtomicU64::new(0),
+        });
+        let backend = Arc::new(ReadaheadBackend::new(inner.clone(), 64 * 1024));
+
+        let (tx, rx) = mpsc::channel();
+        let (go_on, wait) = mpsc::channel::<()>();
+        {
+            let backend = backend.clone();
+            thread::spawn(move || {
+                let mut buf = vec![0u8; BLOCK];
+                for offset in (0..80 * 1024).step_by(BLOCK) {
+                    // Pause with the bad range just ahead of the stream
+                    if offset == 32 * 1024 {
+                        wait.recv().unwrap();
+                    }
+                    let result = backend.read_at(offset, &mut buf).map(|()| buf.clone());
+                    tx.send((offset, result)).unwrap();
+                }
+            });
+        }
+        let check = |reads: usize| {
+            for _ in 0..reads {
+                let (offset, result) = rx
+                    .recv_timeout(Duration::from_secs(5))
+                    .expect("read hung behind a failed prefetch");
+                let in_bad = (40 * 1024..44 * 1024).contains(&offset);
+                match result {
+                    Ok(buf) => {
+                        assert!(!in_bad, "read at {} should fail", offset);
+                        assert_eq!(buf, data[offset as usize..offset as usize + BLOCK]);
+                    }
+                    Err(_) => assert!(in_bad, "read at {} failed", offset),
+                }
+            }
+        };
+
+        check(8);
+        // The prefetch into the bad range failed; it isn't retried forever
+        thread::sleep(Duration::from_millis(50));
+        let reads = inner.reads.load(Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(inner.reads.load(Ordering::SeqCst), reads);
+
+        go_on.send(()).unwrap();
+        check(12);
+    }
+}
@@ -0,0 +1,641 @@
+//! Bad-block remapping onto a spare pool
+//!
+//! `BadBlockRemapBackend` reserves the last `spare_blocks` blocks of the inner
+//! backend as spares and exposes the remaining space. When a transfer to a
+//! block fails after the inner backend's own retries, it is tried once more;
+//! only if that fails too is the block marked bad and its logical address
+//! redirected to the next free spare, so a handful of faulty VRAM cells don't
+//! take down the whole device. A lost device is never remapped around.
+//!
+//! Write failures are absorbed: the data is written to the spare instead.
+//! Read failures lose the block's contents; the spare is zeroed and the read
+//! still fails so the client sees the error once. If the spare can't be
+//! written either, the block keeps its old mapping and the spare is not
+//! reused.
+//!
+//! With a table file the remap table is loaded at startup and saved on every
+//! change, so a persistent store below finds its remapped blocks again.
+
+use super::{is_device_lost, BlockBackend};
+use crate::metrics::Metrics;
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Granularity of bad-block tracking in bytes
+pub const REMAP_BLOCK_SIZE: u64 = 4096;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct RemapTable {
+    /// Logical block -> spare block
+    remapped: HashMap<u64, u64>,
+    /// Next spare block not handed out yet
+    next_spare: u64,
+}
+
+impl RemapTable {
+    /// Read a table written by `save` and check it against the device
+    fn load(path: &Path, data_blocks: u64, spare_blocks: u64) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let mut table = Self::default();
+        let mut spares = BTreeSet::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || format!("{} line {}: '{}'", path.display(), n + 1, line);
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["next", next] => table.next_spare = next.parse().with_context(bad_line)?,
+                [block, spare] => {
+                    let block: u64 = block.parse().with_context(bad_line)?;
+                    let spare: u64 = spare.parse().with_context(bad_line)?;
+                    if block >= data_blocks || !spares.insert(spare) {
+                        bail!("Invalid remap entry at {}", bad_line());
+                    }
+                    table.remapped.insert(block, spare);
+                }
+                _ => bail!("Malformed remap table at {}", bad_line()),
+            }
+        }
+        if table.next_spare > spare_blocks || spares.last().is_some_and(|&s| s >= table.next_spare)
+        {
+            bail!(
+                "Remap table {} does not fit a pool of {} spare blocks",
+                path.display(),
+                spare_blocks
+            );
+        }
+        Ok(table)
+    }
+
+    /// Write the table to `path` through a temporary file, so an interrupted
+    /// save keeps the old one
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut text = String::from("# vramblk remap table: logical block, spare block\n");
+        text.push_str(&format!("next {}\n", self.next_spare));
+        let mut entries: Vec<_> = self.remapped.iter().collect();
+        entries.sort_unstable();
+        for (block, spare) in entries {
+            text.push_str(&format!("{} {}\n", block, spare));
+        }
+
+        let mut tmp = path.to_path_buf().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file =
+            File::create(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+        file.write_all(text.as_bytes())
+            .and_then(|()| file.sync_all())
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("Failed to rename {} to {}", tmp.display(), path.display()))
+    }
+}
+
+/// Backend wrapper that remaps failing blocks to a spare pool
+pub struct BadBlockRemapBackend<B> {
+    inner: B,
+    /// Number of blocks exposed to clients; spares start right after them
+    data_blocks: u64,
+    spare_blocks: u64,
+    table: RwLock<RemapTable>,
+    /// Where the table is saved (`None` = host memory only)
+    table_path: Option<PathBuf>,
+    /// Serializes remapping, so a block is only moved once
+    remapping: Mutex<()>,
+    metrics: Arc<Metrics>,
+}
+
+impl<B: BlockBackend> BadBlockRemapBackend<B> {
+    /// Reserve `spare_blocks` blocks at the end of `inner`, load the remap
+    /// table from `table_path` if it exists, and remap the logical blocks in
+    /// `known_bad` up front.
+    pub fn new(
+        inner: B,
+        spare_blocks: u64,
+        known_bad: &BTreeSet<u64>,
+        table_path: Option<PathBuf>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        let total_blocks = inner.size() / REMAP_BLOCK_SIZE;
+        if spare_blocks == 0 || spare_blocks >= total_blocks {
+            bail!(
+                "Spare pool of {} blocks does not fit a backend of {} blocks",
+                spare_blocks,
+                total_blocks
+            );
+        }
+        let data_blocks = total_blocks - spare_blocks;
+
+        let table = match &table_path {
+            Some(path) if path.exists() => {
+                let table = RemapTable::load(path, data_blocks, spare_blocks)?;
+                log::info!(
+                    "Loaded {} remapped block(s) from {}",
+                    table.remapped.len(),
+                    path.display()
+                );
+                table
+            }
+            _ => RemapTable::default(),
+        };
+        let backend = Self {
+            inner,
+            data_blocks,
+            spare_blocks,
+            table: RwLock::new(table),
+            table_path,
+            remapping: Mutex::new(()),
+            metrics,
+        };
+
+        let mut seeded = 0;
+        for &block in known_bad {
+            if block >= backend.data_blocks {
+                bail!(
+                    "Known bad block {} is outside the device ({} blocks)",
+                    block,
+                    backend.data_blocks
+                );
+            }
+            if backend.physical_block(block) == block * REMAP_BLOCK_SIZE {
+                let spare = backend.take_spare(block)?;
+                backend.commit(block, spare)?;
+                seeded += 1;
+            }
+        }
+        if seeded > 0 {
+            log::info!(
+                "Remapped {} known bad block(s) to spares ({} spare blocks left)",
+                seeded,
+                backend.spares_left()
+            );
+        }
+        backend.update_metrics();
+
+        Ok(backend)
+    }
+
+    fn spares_left(&self) -> u64 {
+        self.table
+            .read()
+            .map(|t| self.spare_blocks - t.next_spare)
+            .unwrap_or(0)
+    }
+
+    fn update_metrics(&self) {
+        if let Ok(table) = self.table.read() {
+            self.metrics.set_bad_blocks(table.remapped.len() as u64);
+        }
+    }
+
+    /// Physical byte offset of a logical block
+    fn physical_block(&self, block: u64) -> u64 {
+        let spare = self
+            .table
+            .read()
+            .ok()
+            .and_then(|t| t.remapped.get(&block).copied());
+        match spare {
+            Some(spare) => (self.data_blocks + spare) * REMAP_BLOCK_SIZE,
+            None => block * REMAP_BLOCK_SIZE,
+        }
+    }
+
+    /// Hand out the next spare for `block` and return its physical byte
+    /// offset. It stays used up even if `block` is never pointed at it.
+    fn take_spare(&self, block: u64) -> Result<u64> {
+        let mut table = self
+            .table
+            .write()
+            .map_err(|_| anyhow::anyhow!("Remap table lock poisoned"))?;
+        if table.next_spare >= self.spare_blocks {
+            bail!("No spare blocks left to remap bad block {}", block);
+        }
+        let spare = table.next_spare;
+        table.next_spare += 1;
+        Ok((self.data_blocks + spare) * REMAP_BLOCK_SIZE)
+    }
+
+    /// Point `block` at the spare at physical byte offset `spare` (replacing
+    /// a failed spare if it already had one) and save the table
+    fn commit(&self, block: u64, spare: u64) -> Result<()> {
+        let spare = spare / REMAP_BLOCK_SIZE - self.data_blocks;
+        let mut table = self
+            .table
+            .write()
+            .map_err(|_| anyhow::anyhow!("Remap table lock poisoned"))?;
+        table.remapped.insert(block, spare);
+        log::warn!(
+            "Remapped bad block {} (offset {}) to spare {}; {} bad block(s), {} spare(s) left",
+            block,
+            block * REMAP_BLOCK_SIZE,
+            spare,
+            table.remapped.len(),
+            self.spare_blocks - table.next_spare
+        );
+        self.metrics.set_bad_blocks(table.remapped.len() as u64);
+        match &self.table_path {
+            Some(path) => table
+                .save(path)
+                .context("Failed to save the remap table; the remap is lost on restart"),
+            None => Ok(()),
+        }
+    }
+
+    /// Split `[offset, offset + len)` into runs that are physically
+    /// contiguous: (logical offset, physical offset, length).
+    fn segments(&self, offset: u64, len: u64) -> Vec<(u64, u64, u64)> {
+        let mut segments: Vec<(u64, u64, u64)> = Vec::new();
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let block = pos / REMAP_BLOCK_SIZE;
+            let in_block = pos % REMAP_BLOCK_SIZE;
+            let chunk = (REMAP_BLOCK_SIZE - in_block).min(end - pos);
+            let phys = self.physical_block(block) + in_block;
+            match segments.last_mut() {
+                Some((_, last_phys, last_len)) if *last_phys + *last_len == phys => {
+                    *last_len += chunk;
+                }
+                _ => segments.push((pos, phys, chunk)),
+            }
+            pos += chunk;
+        }
+        segments
+    }
+
+    /// Write one block-contained chunk, remapping the block if it fails twice
+    fn write_chunk(&self, offset: u64, src: &[u8]) -> Result<()> {
+        let block = offset / REMAP_BLOCK_SIZE;
+        let within = offset % REMAP_BLOCK_SIZE;
+        let phys = self.physical_block(block);
+        let Err(e) = self.inner.write_at(phys + within, src) else {
+            return Ok(());
+        };
+        if is_device_lost(&e) {
+            return Err(e);
+        }
+        log::warn!("Write failed at offset {}, retrying: {:#}", offset, e);
+        let Err(e) = self.inner.write_at(phys + within, src) else {
+            return Ok(());
+        };
+        if is_device_lost(&e) {
+            return Err(e);
+        }
+        log::error!("Write failed again at offset {}: {:#}", offset, e);
+
+        let _remapping = self.remapping.lock().unwrap_or_else(|e| e.into_inner());
+        let moved = self.physical_block(block);
+        if moved != phys {
+            // Another request remapped the block meanwhile
+            return self.inner.write_at(moved + within, src);
+        }
+        let mut data = vec![0u8; REMAP_BLOCK_SIZE as usize];
+        if src.len() as u64 != REMAP_BLOCK_SIZE {
+            // Partial write: carry over what can still be read of the old block
+            if let Err(e) = self.inner.read_at(phys, &mut data) {
+                log::warn!(
+                    "Could not salvage block {} before remapping: {:#}",
+                    block,
+                    e
+                );
+                data.fill(0);
+            }
+        }
+        data[within as usize..within as usize + src.len()].copy_from_slice(src);
+
+        let spare = match self.take_spare(block) {
+            Ok(spare) => spare,
+            Err(remap_err) => return Err(e.context(remap_err.to_string())),
+        };
+        self.inner
+            .write_at(spare, &data)
+            .with_context(|| format!("Failed to move bad block {} to a spare", block))?;
+        self.commit(block, spare)
+    }
+
+    /// Read one block-contained chunk, remapping the block if it fails twice
+    fn read_chunk(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        let block = offset / REMAP_BLOCK_SIZE;
+        let within = offset % REMAP_BLOCK_SIZE;
+        let phys = self.physical_block(block);
+        let Err(e) = self.inner.read_at(phys + within, dst) else {
+            return Ok(());
+        };
+        if is_device_lost(&e) {
+            return Err(e);
+        }
+        log::warn!("Read failed at offset {}, retrying: {:#}", offset, e);
+        let Err(e) = self.inner.read_at(phys + within, dst) else {
+            return Ok(());
+        };
+        if is_device_lost(&e) {
+            return Err(e);
+        }
+        log::error!("Read failed again at offset {}: {:#}", offset, e);
+
+        let _remapping = self.remapping.lock().unwrap_or_else(|e| e.into_inner());
+        let moved = self.physical_block(block);
+        if moved != phys {
+            // Another request remapped the block meanwhile
+            return self.inner.read_at(moved + within, dst);
+        }
+        // The contents are lost; give the block a zeroed spare for future use
+        let spare = match self.take_spare(block) {
+            Ok(spare) => spare,
+            Err(remap_err) => return Err(e.context(remap_err.to_string())),
+        };
+        if let Err(spare_err) = self
+            .inner
+            .write_at(spare, &vec![0u8; REMAP_BLOCK_SIZE as usize])
+        {
+            log::error!(
+                "Failed to move bad block {} to a spare: {:#}",
+                block,
+                spare_err
+            );
+            return Err(e);
+        }
+        self.commit(block, spare)?;
+        Err(e.context(format!("Block {} was bad and has been remapped", block)))
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for BadBlockRemapBackend<B> {
+    fn size(&self) -> u64 {
+        self.data_blocks * REMAP_BLOCK_SIZE
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.size() {
+            bail!("Attempted to read past end of remapped device");
+        }
+        let mut result = Ok(());
+        for (logical, phys, len) in self.segments(offset, dst.len() as u64) {
+            let start = (logical - offset) as usize;
+            let buf = &mut dst[start..start + len as usize];
+            match self.inner.read_at(phys, buf) {
+                Ok(()) => continue,
+                Err(e) if is_device_lost(&e) => return Err(e),
+                Err(_) => {}
+            }
+            // Narrow the failure down to single blocks
+            let mut pos = logical;
+            while pos < logical + len {
+                let chunk = (REMAP_BLOCK_SIZE - pos % REMAP_BLOCK_SIZE).min(logical + len - pos);
+                let s = (pos - offset) as usize;
+                if let Err(e) = self.read_chunk(pos, &mut dst[s..s + chunk as usize]) {
+                    result = result.and(Err(e));
+                }
+                pos += chunk;
+            }
+        }
+        result
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.size() {
+            bail!("Attempted to write past end of remapped device");
+        }
+        for (logical, phys, len) in self.segments(offset, src.len() as u64) {
+            let start = (logical - offset) as usize;
+            let buf = &src[start..start + len as usize];
+            match self.inner.write_at(phys, buf) {
+                Ok(()) => continue,
+                Err(e) if is_device_lost(&e) => return Err(e),
+                Err(_) => {}
+            }
+            // Narrow the failure down to single blocks
+            let mut pos = logical;
+            while pos < logical + len {
+                let chunk = (REMAP_BLOCK_SIZE - pos % REMAP_BLOCK_SIZE).min(logical + len - pos);
+                let s = (pos - offset) as usize;
+                self.write_chunk(pos, &src[s..s + chunk as usize])?;
+                pos += chunk;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{DeviceLostError, RamBuffer};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const BLOCK: u64 = REMAP_BLOCK_SIZE;
+    const SPARES: u64 = 4;
+    const DATA_BLOCKS: u64 = 12;
+    /// Fails every time
+    const ALWAYS: u32 = u32::MAX;
+
+    /// Backend whose physical blocks fail a set number of times
+    struct Faulty {
+        inner: RamBuffer,
+        /// Physical block -> failures left
+        failures: Mutex<HashMap<u64, u32>>,
+        lost: AtomicBool,
+    }
+
+    impl Faulty {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                inner: RamBuffer::new((DATA_BLOCKS + SPARES) * BLOCK),
+                failures: Mutex::new(HashMap::new()),
+                lost: AtomicBool::new(false),
+            })
+        }
+
+        fn fail(&self, block: u64, times: u32) {
+            self.failures.lock().unwrap().insert(block, times);
+        }
+
+        fn check(&self, offset: u64, len: usize) -> Result<()> {
+            if self.lost.load(Ordering::SeqCst) {
+                return Err(DeviceLostError.into());
+            }
+            let mut failures = self.failures.lock().unwrap();
+            for block in offset / BLOCK..(offset + len as u64).div_ceil(BLOCK) {
+                if let Some(left) = failures.get_mut(&block).filter(|left| **left > 0) {
+                    if *left != ALWAYS {
+                        *left -= 1;
+                    }
+                    bail!("Injected failure in block {}", block);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl BlockBackend for Faulty {
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+            self.check(offset, dst.len())?;
+            self.inner.read_at(offset, dst)
+        }
+
+        fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+            self.check(offset, src.len())?;
+            self.inner.write_at(offset, src)
+        }
+    }
+
+    fn remap(
+        inner: &Arc<Faulty>,
+        table: Option<PathBuf>,
+    ) -> (BadBlockRemapBackend<Arc<Faulty>>, Arc<Metrics>) {
+        let metrics = Arc::new(Metrics::default());
+        let backend = BadBlockRemapBackend::new(
+            inner.clone(),
+            SPARES,
+            &BTreeSet::new(),
+            table,
+            metrics.clone(),
+        )
+        .unwrap();
+        (backend, metrics)
+    }
+
+    fn physical(inner: &Faulty, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        inner.inner.read_at(offset, &mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn transient_failures_are_not_remapped() {
+        let inner = Faulty::new();
+        let (backend, metrics) = remap(&inner, None);
+        backend.write_at(2 * BLOCK, &[5; 4096]).unwrap();
+
+        inner.fail(2, 1);
+        backend.write_at(2 * BLOCK + 100, &[6; 100]).unwrap();
+        inner.fail(2, 1);
+        let mut data = vec![0u8; 4096];
+        backend.read_at(2 * BLOCK, &mut data).unwrap();
+        assert_eq!(data[..100], [5; 100]);
+        assert_eq!(data[100..200], [6; 100]);
+
+        // The data stayed in place
+        assert_eq!(metrics.stats().bad_blocks, 0);
+        assert_eq!(physical(&inner, 2 * BLOCK, 4096), data);
+    }
+
+    #[test]
+    fn repeated_write_failure_moves_the_block() {
+        let inner = Faulty::new();
+        let (backend, metrics) = remap(&inner, None);
+        backend.write_at(3 * BLOCK, &[1; 4096]).unwrap();
+
+        // The request, the block on its own, and the retry
+        inner.fail(3, 3);
+        backend.write_at(3 * BLOCK + 10, &[2; 10]).unwrap();
+        assert_eq!(metrics.stats().bad_blocks, 1);
+        let mut data = vec![0u8; 4096];
+        backend.read_at(3 * BLOCK, &mut data).unwrap();
+        let mut expected = vec![1u8; 4096];
+        expected[10..20].fill(2);
+        assert_eq!(data, expected);
+        // In the first spare, carried over from the old block
+        assert_eq!(physical(&inner, DATA_BLOCKS * BLOCK, 4096), expected);
+    }
+
+    #[test]
+    fn repeated_read_failure_zeroes_the_block() {
+        let inner = Faulty::new();
+        let (backend, metrics) = remap(&inner, None);
+        backend.write_at(4 * BLOCK, &[1; 8192]).unwrap();
+
+        inner.fail(4, ALWAYS);
+        let mut data = vec![0u8; 8192];
+        assert!(backend.read_at(4 * BLOCK, &mut data).is_err());
+        assert_eq!(metrics.stats().bad_blocks, 1);
+        backend.read_at(4 * BLOCK, &mut data).unwrap();
+        assert_eq!(data[..4096], [0; 4096]);
+        assert_eq!(data[4096..], [1; 4096]);
+    }
+
+    #[test]
+    fn lost_device_is_not_remapped() {
+        let inner = Faulty::new();
+        let (backend, metrics) = remap(&inner, None);
+        inner.lost.store(true, Ordering::SeqCst);
+        let error = backend.write_at(0, &[1; 4096]).unwrap_err();
+        assert!(is_device_lost(&error));
+        let error = backend.read_at(0, &mut [0; 4096]).unwrap_err();
+        assert!(is_device_lost(&error));
+        assert_eq!(metrics.stats().bad_blocks, 0);
+        assert_eq!(backend.spares_left(), SPARES);
+    }
+
+    #[test]
+    fn failed_spare_keeps_the_old_mapping() {
+        let inner = Faulty::new();
+        let (backend, metrics) = remap(&inner, None);
+        inner.fail(5, ALWAYS);
+        inner.fail(DATA_BLOCKS, ALWAYS);
+        assert!(backend.write_at(5 * BLOCK, &[1; 4096]).is_err());
+        assert_eq!(metrics.stats().bad_blocks, 0);
+        assert_eq!(backend.physical_block(5), 5 * BLOCK);
+
+        // The failed spare is not handed out again
+        backend.write_at(5 * BLOCK, &[1; 4096]).unwrap();
+        assert_eq!(backend.physical_block(5), (DATA_BLOCKS + 1) * BLOCK);
+        assert_eq!(metrics.stats().bad_blocks, 1);
+    }
+
+    #[test]
+    fn table_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("vramblk-remap-{}", std::process::id()));
+        let inner = Faulty::new();
+        {
+            let (backend, _) = remap(&inner, Some(path.clone()));
+            inner.fail(1, ALWAYS);
+            inner.fail(7, ALWAYS);
+            backend.write_at(BLOCK, &[1; 4096]).unwrap();
+            backend.write_at(7 * BLOCK, &[7; 4096]).unwrap();
+        }
+
+        let (backend, metrics) = remap(&inner, Some(path.clone()));
+        let saved = std::fs::read_to_string(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(metrics.stats().bad_blocks, 2, "{:?}", saved);
+        let mut data = vec![0u8; 4096];
+        backend.read_at(BLOCK, &mut data).unwrap();
+        assert_eq!(data, [1; 4096]);
+        backend.read_at(7 * BLOCK, &mut data).unwrap();
+        assert_eq!(data, [7; 4096]);
+        assert_eq!(backend.spares_left(), SPARES - 2);
+    }
+
+    #[test]
+    fn invalid_tables_are_refused() {
+        let path = std::env::temp_dir().join(format!("vramblk-remap-bad-{}", std::process::id()));
+        for table in [
+            "next 1\n0 0\n1 0\n",
+            "next 1\n12 0\n",
+            "next 1\n0 1\n",
+            "next 5\n",
+            "0\n",
+        ] {
+            std::fs::write(&path, table).unwrap();
+            let loaded = RemapTable::load(&path, DATA_BLOCKS, SPARES);
+            assert!(loaded.is_err(), "{:?}", table);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,354 @@
+//! Backend that can be resized at runtime
+//!
+//! A GPU buffer can't grow in place, so `resize` allocates a new buffer of
+//! the new size, copies the old contents into it and swaps it in. While both
+//! exist the device needs the old and the new size in memory at once. I/O
+//! waits for the copy to finish, so it sees either the old buffer or the
+//! complete new one. Space added by growing reads as zeros.
+//!
+//! `add_member` grows the device without a copy instead: a buffer on another
+//! GPU is appended after the current one, which keeps its data in place.
+
+use super::BlockBackend;
+use anyhow::{bail, Context, Result};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Contents are copied to the new buffer in transfers of this size
+const COPY_CHUNK: u64 = 16 * 1024 * 1024;
+
+/// Provides the buffers of a `ResizableBackend`
+pub trait Allocator: Send + Sync {
+    /// A new buffer of `size` bytes; its contents may be anything
+    fn allocate(&self, size: u64) -> Result<Arc<dyn BlockBackend>>;
+
+    /// A new buffer of `size` bytes on GPU `device` of OpenCL `platform`,
+    /// for `add_member`
+    fn allocate_member(
+        &self,
+        _platform: usize,
+        _device: usize,
+        _size: u64,
+    ) -> Result<Arc<dyn BlockBackend>> {
+        bail!("This device can't take extra members")
+    }
+
+    /// A buffer of `size` bytes is no longer used by the device
+    fn release(&self, _size: u64) {}
+}
+
+/// Backend wrapper whose buffer can be swapped for one of another size
+pub struct ResizableBackend {
+    current: RwLock<Arc<dyn BlockBackend>>,
+    allocator: Box<dyn Allocator>,
+    /// Held for the whole of a resize so resizes don't race
+    resizing: Mutex<()>,
+}
+
+impl ResizableBackend {
+    /// Serve `initial`, which counts as allocated by `allocator`; later
+    /// buffers come from `allocator`
+    pub fn new(initial: Arc<dyn BlockBackend>, allocator: Box<dyn Allocator>) -> Self {
+        Self {
+            current: RwLock::new(initial),
+            allocator,
+            resizing: Mutex::new(()),
+        }
+    }
+
+    /// Run `f` on the current buffer; a resize waits until it returns
+    fn with<R>(&self, f: impl FnOnce(&dyn BlockBackend) -> R) -> R {
+        f(&**self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Move the device to a new buffer of `new_size` bytes and return the old
+    /// size. Shrinking drops the data past `new_size` and is refused unless
+    /// `force` is set.
+    pub fn resize(&self, new_size: u64, force: bool) -> Result<u64> {
+        let _resizing = self.resizing.lock().unwrap_or_else(|e| e.into_inner());
+        let old_size = self.size();
+        if new_size == old_size {
+            return Ok(old_size);
+        }
+        if new_size < old_size && !force {
+            bail!(
+                "Shrinking from {} to {} bytes discards data; force is required",
+                old_size,
+                new_size
+            );
+        }
+
+        let new = self
+            .allocator
+            .allocate(new_size)
+            .with_context(|| format!("Failed to allocate {} bytes for resize", new_size))?;
+        // A fresh buffer may still hold another user's data
+        if new_size > old_size
+            && let Err(e) = new.write_zeroes_at(old_size, new_size - old_size)
+        {
+            drop(new);
+            self.allocator.release(new_size);
+            return Err(e.context("Failed to zero the space added by resize"));
+        }
+
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = copy(&**current, &*new, old_size.min(new_size)) {
+            drop(new);
+            self.allocator.release(new_size);
+            return Err(e.context("Failed to copy the device contents for resize"));
+        }
+        *current = new;
+        drop(current);
+        self.allocator.release(old_size);
+
+        log::info!("Resized device from {} to {} bytes", old_size, new_size);
+        Ok(old_size)
+    }
+
+    /// Append a zeroed buffer of `size` bytes on GPU `device` of `platform`
+    /// to the end of the device and return the new size. Existing data stays
+    /// where it is, so nothing is copied.
+    pub fn add_member(&self, platform: usize, device: usize, size: u64) -> Result<u64> {
+        let _resizing = self.resizing.lock().unwrap_or_else(|e| e.into_inner());
+        let member = self
+            .allocator
+            .allocate_member(platform, device, size)
+            .with_context(|| {
+                format!(
+                    "Failed to allocate {} bytes on device {}:{}",
+                    size, platform, device
+                )
+            })?;
+        if let Err(e) = member.write_zeroes_at(0, size) {
+            drop(member);
+            self.allocator.release(size);
+            return Err(e.context("Failed to zero the new member"));
+        }
+
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        let old_size = current.size();
+        *current = Arc::new(Appended {
+            head: current.clone(),
+            tail: member,
+            split: old_size,
+        });
+        drop(current);
+
+        log::info!(
+            "Added {} bytes on device {}:{}; device grew from {} to {} bytes",
+            size,
+            platform,
+            device,
+            old_size,
+            old_size + size
+        );
+        Ok(old_size + size)
+    }
+}
+
+/// A buffer with another appended after it, from `add_member`
+struct Appended {
+    head: Arc<dyn BlockBackend>,
+    tail: Arc<dyn BlockBackend>,
+    /// Size of `head`, where `tail` starts
+    split: u64,
+}
+
+impl Appended {
+    /// Split `[offset, offset + len)` into the parts in `head` and in `tail`,
+    /// each as (offset within that buffer, length)
+    fn parts(&self, offset: u64, len: u64) -> Result<((u64, u64), (u64, u64))> {
+        if offset.checked_add(len).is_none_or(|end| end > self.size()) {
+            bail!("Attempted to access past end of device");
+        }
+        let head_len = self.split.saturating_sub(offset).min(len);
+        let tail_offset = offset.max(self.split) - self.split;
+        Ok(((offset, head_len), (tail_offset, len - head_len)))
+    }
+}
+
+impl BlockBackend for Appended {
+    fn size(&self) -> u64 {
+        self.split + self.tail.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        let ((head_offset, head_len), (tail_offset, tail_len)) =
+            self.parts(offset, dst.len() as u64)?;
+        let (head, tail) = dst.split_at_mut(head_len as usize);
+        if head_len > 0 {
+            self.head.read_at(head_offset, head)?;
+        }
+        if tail_len > 0 {
+            self.tail.read_at(tail_offset, tail)?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        let ((head_offset, head_len), (tail_offset, tail_len)) =
+            self.parts(offset, src.len() as u64)?;
+        let (head, tail) = src.split_at(head_len as usize);
+        if head_len > 0 {
+            self.head.write_at(head_offset, head)?;
+        }
+        if tail_len > 0 {
+            self.tail.write_at(tail_offset, tail)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.head.flush()?;
+        self.tail.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        let ((head_offset, head_len), (tail_offset, tail_len)) = self.parts(offset, len)?;
+        if head_len > 0 {
+            self.head.write_zeroes_at(head_offset, head_len)?;
+        }
+        if tail_len > 0 {
+            self.tail.write_zeroes_at(tail_offset, tail_len)?;
+        }
+        Ok(())
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.head.fast_zero() && self.tail.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        let ((head_offset, head_len), (tail_offset, tail_len)) = self.parts(offset, len)?;
+        if head_len > 0 {
+            self.head.discard_at(head_offset, head_len)?;
+        }
+        if tail_len > 0 {
+            self.tail.discard_at(tail_offset, tail_len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy the first `len` bytes of `from` to `to`
+fn copy(from: &dyn BlockBackend, to: &dyn BlockBackend, len: u64) -> Result<()> {
+    let mut data = vec![0u8; COPY_CHUNK.min(len) as usize];
+    let mut offset = 0;
+    while offset < len {
+        let n = COPY_CHUNK.min(len - offset) as usize;
+        from.read_at(offset, &mut data[..n])?;
+        to.write_at(offset, &data[..n])?;
+        offset += n as u64;
+    }
+    to.flush()
+}
+
+impl BlockBackend for ResizableBackend {
+    fn size(&self) -> u64 {
+        self.with(|b| b.size())
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.with(|b| b.read_at(offset, dst))
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.with(|b| b.write_at(offset, src))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.with(|b| b.flush())
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.with(|b| b.write_zeroes_at(offset, len))
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.with(|b| b.fast_zero())
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.with(|b| b.discard_at(offset, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+
+    /// Allocates host RAM filled with a non-zero byte, as a fresh GPU
+    /// buffer may hold anything
+    struct Dirty;
+
+    impl Allocator for Dirty {
+        fn allocate(&self, size: u64) -> Result<Arc<dyn BlockBackend>> {
+            let buffer = RamBuffer::new(size);
+            buffer.write_at(0, &vec![0xAA; size as usize])?;
+            Ok(Arc::new(buffer))
+        }
+
+        fn allocate_member(
+            &self,
+            _platform: usize,
+            _device: usize,
+            size: u64,
+        ) -> Result<Arc<dyn BlockBackend>> {
+            self.allocate(size)
+        }
+    }
+
+    #[test]
+    fn grown_space_reads_as_zeros() {
+        let device = ResizableBackend::new(Arc::new(RamBuffer::new(4096)), Box::new(Dirty));
+        device.write_at(0, &[7; 4096]).unwrap();
+
+        assert_eq!(device.resize(16384, false).unwrap(), 4096);
+        let mut back = vec![1u8; 16384];
+        device.read_at(0, &mut back).unwrap();
+        assert!(back[..4096].iter().all(|&b| b == 7));
+        assert!(back[4096..].iter().all(|&b| b == 0));
+
+        // Shrinking needs no zeroing, and keeps what still fits
+        device.resize(2048, true).unwrap();
+        let mut back = vec![0u8; 2048];
+        device.read_at(0, &mut back).unwrap();
+        assert!(back.iter().all(|&b| b == 7));
+    }
+
+    #[test]
+    fn added_member_extends_the_device_in_place() {
+        let initial = Arc::new(RamBuffer::new(4096));
+        let device = ResizableBackend::new(initial, Box::new(Dirty));
+        device.write_at(0, &[7; 4096]).unwrap();
+
+        assert_eq!(device.add_member(0, 1, 8192).unwrap(), 12288);
+        assert_eq!(device.size(), 12288);
+        let mut back = vec![0u8; 12288];
+        device.read_at(0, &mut back).unwrap();
+        assert!(back[..4096].iter().all(|&b| b == 7));
+        assert!(back[4096..].iter().all(|&b| b == 0));
+
+        // Across the join between the two buffers
+        device.write_at(4000, &[9; 200]).unwrap();
+        let mut part = vec![0u8; 300];
+        device.read_at(3950, &mut part).unwrap();
+        assert_eq!(&part[..50], &[7; 50]);
+        assert_eq!(&part[50..250], &[9; 200]);
+        assert_eq!(&part[250..], &[0; 50]);
+        assert!(device.write_at(12200, &[0; 100]).is_err());
+    }
+
+    #[test]
+    fn members_can_be_added_repeatedly() {
+        let device = ResizableBackend::new(Arc::new(RamBuffer::new(4096)), Box::new(Dirty));
+        device.add_member(0, 1, 4096).unwrap();
+        device.add_member(0, 2, 4096).unwrap();
+        device.write_zeroes_at(0, 12288).unwrap();
+        device.write_at(8190, &[5; 4]).unwrap();
+        let mut back = vec![1u8; 12288];
+        device.read_at(0, &mut back).unwrap();
+        assert_eq!(&back[8190..8194], &[5; 4]);
+        assert_eq!(back.iter().filter(|&&b| b != 0).count(), 4);
+    }
+}
@@ -0,0 +1,163 @@
+//! Read-modify-write of partial blocks
+//!
+//! Backends that store data in fixed-size blocks, such as encrypted sectors,
+//! can only write whole blocks. A write that covers a block only partly
+//! reads that block, patches in the new bytes and writes the whole aligned
+//! region back. `RangeLockBackend` only holds the bytes a request touches,
+//! so two writes to different bytes of one block could both read it and the
+//! second write-back would undo the first. `PartialWrites` serializes the
+//! read-modify-write of such edge blocks; aligned writes take no lock.
+
+use anyhow::Result;
+use std::sync::{Mutex, MutexGuard};
+
+/// Number of locks edge blocks are hashed onto
+const LOCK_STRIPES: usize = 64;
+
+/// Block-aligned range `(start, end)` covering `len` bytes at `offset`
+pub fn aligned(offset: u64, len: u64, block_size: u64) -> (u64, u64) {
+    let start = offset / block_size * block_size;
+    let end = (offset + len).div_ceil(block_size) * block_size;
+    (start, end)
+}
+
+/// Serializes read-modify-write of blocks of `block_size` bytes
+pub struct PartialWrites {
+    block_size: u64,
+    locks: Box<[Mutex<()>]>,
+}
+
+impl PartialWrites {
+    pub fn new(block_size: u64) -> Self {
+        Self {
+            block_size,
+            locks: (0..LOCK_STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    /// Write `src` at `offset` as whole blocks. `read` fills a buffer with
+    /// the current contents of one block at the given offset; `write` stores
+    /// the patched, block-aligned region at the given offset.
+    pub fn write<R, W>(&self, offset: u64, src: &[u8], mut read: R, write: W) -> Result<()>
+    where
+        R: FnMut(u64, &mut [u8]) -> Result<()>,
+        W: FnOnce(u64, &mut [u8]) -> Result<()>,
+    {
+        if src.is_empty() {
+            return Ok(());
+        }
+        let block = self.block_size as usize;
+        let (start, end) = aligned(offset, src.len() as u64, self.block_size);
+        let head = offset != start;
+        let tail = offset + src.len() as u64 != end;
+        let first = start / self.block_size;
+        let last = end / self.block_size - 1;
+
+        let mut edges = Vec::with_capacity(2);
+        if head {
+            edges.push(first);
+        }
+        if tail {
+            edges.push(last);
+        }
+        let _guards = self.lock(&edges);
+
+        let mut buf = vec![0u8; (end - start) as usize];
+        if head {
+            read(start, &mut buf[..block])?;
+        }
+        // A single block partly covered at both ends was read above
+        if tail && (last != first || !head) {
+            let at = buf.len() - block;
+            read(end - self.block_size, &mut buf[at..])?;
+        }
+        let skip = (offset - start) as usize;
+        buf[skip..skip + src.len()].copy_from_slice(src);
+        write(start, &mut buf)
+    }
+
+    /// Keep partial writes off `block` until the guard is dropped
+    pub fn lock_block(&self, block: u64) -> MutexGuard<'_, ()> {
+        self.locks[(block % LOCK_STRIPES as u64) as usize]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Lock the stripes of `blocks`, in stripe order so writers don't deadlock
+    fn lock(&self, blocks: &[u64]) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = blocks
+            .iter()
+            .map(|&block| (block % LOCK_STRIPES as u64) as usize)
+            .collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        stripes
+            .into_iter()
+            .map(|i| self.locks[i].lock().unwrap_or_else(|e| e.into_inner()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{BlockBackend, RamBuffer};
+
+    const BLOCK: u64 = 512;
+
+    /// A buffer of `blocks` blocks holding a nonzero background pattern
+    fn background(blocks: u64) -> (RamBuffer, Vec<u8>) {
+        let pattern: Vec<u8> = (0..blocks * BLOCK).map(|i| (i % 253) as u8 + 1).collect();
+        let buffer = RamBuffer::new(blocks * BLOCK);
+        buffer.write_at(0, &pattern).unwrap();
+        (buffer, pattern)
+    }
+
+    /// Write through `PartialWrites`, checking only aligned regions are
+    /// written back
+    fn write(writes: &PartialWrites, buffer: &RamBuffer, offset: u64, src: &[u8]) {
+        writes
+            .write(
+                offset,
+                src,
+                |at, dst| buffer.read_at(at, dst),
+                |at, data| {
+                    assert_eq!(at % BLOCK, 0);
+                    assert_eq!(data.len() as u64 % BLOCK, 0);
+                    buffer.write_at(at, data)
+                },
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn write_inside_one_block_keeps_its_surroundings() {
+        let (buffer, mut expected) = background(4);
+        let data = [0xee; 100];
+        write(&PartialWrites::new(BLOCK), &buffer, 7, &data);
+        expected[7..107].copy_from_slice(&data);
+
+        let mut back = vec![0u8; expected.len()];
+        buffer.read_at(0, &mut back).unwrap();
+        assert_eq!(back, expected);
+    }
+
+    #[test]
+    fn write_across_blocks_keeps_both_edges() {
+        let (buffer, mut expected) = background(4);
+        let data = [0xee; 600];
+        write(&PartialWrites::new(BLOCK), &buffer, 450, &data);
+        expected[450..1050].copy_from_slice(&data);
+
+        let mut back = vec![0u8; expected.len()];
+        buffer.read_at(0, &mut back).unwrap();
+        assert_eq!(back, expected);
+    }
+
+    #[test]
+    fn aligned_range_covers_partial_blocks() {
+        assert_eq!(aligned(7, 100, BLOCK), (0, 512));
+        assert_eq!(aligned(512, 512, BLOCK), (512, 1024));
+        assert_eq!(aligned(511, 2, BLOCK), (0, 1024));
+    }
+}
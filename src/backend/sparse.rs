@@ -0,0 +1,501 @@
+//! Sparse container file for device contents
+//!
+//! `SparseFileBackend` keeps a device in a host file that only grows with
+//! the data written to it, whatever the filesystem's support for holes.
+//! Layout, all integers little-endian:
+//!
+//! - offset 0: a `HEADER_SIZE` byte header: the magic `VRBLKSPR`, the
+//!   format version (u32), the block size (u32), the device size (u64), and
+//!   the offset (u64) and number of entries (u64) of the block allocation
+//!   table (BAT)
+//! - the BAT: one u64 per block of the device, the file offset of the
+//!   block's data, or 0 for a block never written
+//! - data blocks, at multiples of the block size after the BAT
+//!
+//! Blocks are appended on their first write, the data before its BAT entry.
+//! Reads of unallocated blocks return zeros without touching the file. A
+//! write-zeroes or discard covering a whole block clears its entry and
+//! punches out its data; that space is not handed out again. The BAT is
+//! checked against the file when an existing container is opened.
+
+use super::BlockBackend;
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+
+const MAGIC: &[u8; 8] = b"VRBLKSPR";
+const VERSION: u32 = 1;
+/// Bytes reserved for the header; the BAT follows
+const HEADER_SIZE: u64 = 4096;
+/// Block size of newly created containers
+pub const SPARSE_BLOCK_SIZE: u64 = 64 * 1024;
+
+/// Allocation state, cached in host memory
+struct Meta {
+    /// File offset of each block's data, 0 if unallocated
+    bat: Vec<u64>,
+    /// Where the next block is appended
+    next_free: u64,
+}
+
+/// A backend on a sparse container file
+pub struct SparseFileBackend {
+    file: File,
+    size: u64,
+    block_size: u64,
+    bat_offset: u64,
+    // Serializes allocation and deallocation of blocks
+    meta: Mutex<Meta>,
+}
+
+impl SparseFileBackend {
+    /// Create an empty container for a `size`-byte device at `path`,
+    /// replacing any file there
+    pub fn create(path: &Path, size: u64, block_size: u64) -> Result<Self> {
+        if !block_size.is_power_of_two() || block_size < 512 || block_size > u32::MAX as u64 {
+            bail!("Sparse file block size must be a power of two of at least 512 bytes");
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        let entries = size.div_ceil(block_size);
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        header[0..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(block_size as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&size.to_le_bytes());
+        header[24..32].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        header[32..40].copy_from_slice(&entries.to_le_bytes());
+        file.write_all_at(&header, 0)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        let data_start = data_start(HEADER_SIZE, entries, block_size);
+        // The BAT starts out all zeros
+        file.set_len(data_start)
+            .with_context(|| format!("Failed to size {}", path.display()))?;
+        Ok(Self {
+            file,
+            size,
+            block_size,
+            bat_offset: HEADER_SIZE,
+            meta: Mutex::new(Meta {
+                bat: vec![0; entries as usize],
+                next_free: data_start,
+            }),
+        })
+    }
+
+    /// Open the existing container at `path`, which must hold a `size`-byte
+    /// device and a BAT consistent with the file
+    pub fn open(path: &Path, size: u64) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let file_len = file.metadata()?.len();
+        let mut header = vec![0u8; HEADER_SIZE as usize];
+        if file_len < HEADER_SIZE {
+            bail!("{} is too short to be a sparse file", path.display());
+        }
+        file.read_exact_at(&mut header, 0)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if &header[0..8] != MAGIC {
+            bail!("{} is not a sparse file", path.display());
+        }
+        let u32_at = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        let version = u32_at(8);
+        if version != VERSION {
+            bail!(
+                "{} is sparse file version {}; only version {} is supported",
+                path.display(),
+                version,
+                VERSION
+            );
+        }
+        let block_size = u64::from(u32_at(12));
+        let device_size = u64_at(16);
+        let bat_offset = u64_at(24);
+        let entries = u64_at(32);
+        if !block_size.is_power_of_two() || block_size < 512 {
+            bail!(
+                "{} has an invalid block size of {}",
+                path.display(),
+                block_size
+            );
+        }
+        if device_size != size {
+            bail!(
+                "{} holds a {} byte device but the device is {} bytes",
+                path.display(),
+                device_size,
+                size
+            );
+        }
+        if entries != size.div_ceil(block_size) {
+            bail!(
+                "{} has {} BAT entries but a {} byte device needs {}",
+                path.display(),
+                entries,
+                size,
+                size.div_ceil(block_size)
+            );
+        }
+        let bat_end = entries
+            .checked_mul(8)
+            .and_then(|len| bat_offset.checked_add(len));
+        if bat_offset < HEADER_SIZE || bat_end.is_none_or(|end| end > file_len) {
+            bail!(
+                "{}: BAT at offset {} does not fit in the file ({} bytes)",
+                path.display(),
+                bat_offset,
+                file_len
+            );
+        }
+
+        let mut raw = vec![0u8; (entries * 8) as usize];
+        file.read_exact_at(&mut raw, bat_offset)
+            .with_context(|| format!("Failed to read the BAT of {}", path.display()))?;
+        let bat: Vec<u64> = raw
+            .chunks_exact(8)
+            .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+        let data_start = data_start(bat_offset, entries, block_size);
+        validate_bat(&bat, data_start, block_size, file_len)
+            .with_context(|| format!("{} is corrupt", path.display()))?;
+
+        let allocated = bat.iter().filter(|&&offset| offset != 0).count();
+        log::info!(
+            "Opened sparse file {}: {} of {} blocks of {} bytes allocated",
+            path.display(),
+            allocated,
+            entries,
+            block_size
+        );
+        Ok(Self {
+            file,
+            size,
+            block_size,
+            bat_offset,
+            meta: Mutex::new(Meta {
+                bat,
+                next_free: file_len.next_multiple_of(block_size).max(data_start),
+            }),
+        })
+    }
+
+    /// Split `len` bytes at `offset` into (block, offset in block, range in
+    /// the caller's buffer) pieces
+    fn pieces(&self, offset: u64, len: u64) -> impl Iterator<Item = (u64, u64, usize, usize)> {
+        let block_size = self.block_size;
+        let end = offset + len;
+        let mut at = offset;
+        std::iter::from_fn(move || {
+            if at >= end {
+                return None;
+            }
+            let block = at / block_size;
+            let within = at % block_size;
+            let next = ((block + 1) * block_size).min(end);
+            let piece = (
+                block,
+                within,
+                (at - offset) as usize,
+                (next - offset) as usize,
+            );
+            at = next;
+            Some(piece)
+        })
+    }
+
+    /// Bytes of the device in `block`; the last block may be short
+    fn block_len(&self, block: u64) -> u64 {
+        self.block_size.min(self.size - block * self.block_size)
+    }
+
+    fn entry(&self, block: u64) -> Result<u64> {
+        Ok(self.lock_meta()?.bat[block as usize])
+    }
+
+    fn lock_meta(&self) -> Result<std::sync::MutexGuard<'_, Meta>> {
+        self.meta
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock sparse file metadata mutex"))
+    }
+
+    fn write_entry(&self, block: u64, offset: u64) -> Result<()> {
+        self.file
+            .write_all_at(&offset.to_le_bytes(), self.bat_offset + block * 8)?;
+        Ok(())
+    }
+
+    /// Write `src` at `within` an unallocated block, allocating it
+    fn allocate(&self, block: u64, within: u64, src: &[u8]) -> Result<()> {
+        let mut meta = self.lock_meta()?;
+        let existing = meta.bat[block as usize];
+        if existing != 0 {
+            // Allocated by another write meanwhile
+            drop(meta);
+            self.file.write_all_at(src, existing + within)?;
+            return Ok(());
+        }
+        let offset = meta.next_free;
+        let mut data = vec![0u8; self.block_size as usize];
+        data[within as usize..within as usize + src.len()].copy_from_slice(src);
+        self.file.write_all_at(&data, offset)?;
+        self.write_entry(block, offset)?;
+        meta.bat[block as usize] = offset;
+        meta.next_free = offset + self.block_size;
+        Ok(())
+    }
+
+    /// Drop the data of `block`, which then reads as zeros
+    fn deallocate(&self, block: u64) -> Result<()> {
+        let mut meta = self.lock_meta()?;
+        let offset = meta.bat[block as usize];
+        if offset == 0 {
+            return Ok(());
+        }
+        self.write_entry(block, 0)?;
+        meta.bat[block as usize] = 0;
+        // Only frees disk space; filesystems that can't punch holes keep it
+        unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                self.block_size as libc::off_t,
+            )
+        };
+        Ok(())
+    }
+}
+
+/// First data offset after a BAT of `entries` at `bat_offset`
+fn data_start(bat_offset: u64, entries: u64, block_size: u64) -> u64 {
+    (bat_offset + entries * 8).next_multiple_of(block_size)
+}
+
+/// Check that every allocated block lies whole within the file, after the
+/// BAT, and belongs to one block only
+fn validate_bat(bat: &[u64], data_start: u64, block_size: u64, file_len: u64) -> Result<()> {
+    let mut seen = HashSet::new();
+    for (block, &offset) in bat.iter().enumerate() {
+        if offset == 0 {
+            continue;
+        }
+        if offset < data_start || !offset.is_multiple_of(block_size) {
+            bail!("BAT entry {} has the invalid offset {}", block, offset);
+        }
+        if offset
+            .checked_add(block_size)
+            .is_none_or(|end| end > file_len)
+        {
+            bail!(
+                "BAT entry {} points to offset {}, past the end of the file ({} bytes)",
+                block,
+                offset,
+                file_len
+            );
+        }
+        if !seen.insert(offset) {
+            bail!(
+                "BAT entry {} shares offset {} with another block",
+                block,
+                offset
+            );
+        }
+    }
+    Ok(())
+}
+
+impl BlockBackend for SparseFileBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.size {
+            bail!("Attempted to read past end of sparse file");
+        }
+        for (block, within, start, end) in self.pieces(offset, dst.len() as u64) {
+            match self.entry(block)? {
+                0 => dst[start..end].fill(0),
+                data => self
+                    .file
+                    .read_exact_at(&mut dst[start..end], data + within)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.size {
+            bail!("Attempted to write past end of sparse file");
+        }
+        for (block, within, start, end) in self.pieces(offset, src.len() as u64) {
+            match self.entry(block)? {
+                0 => self.allocate(block, within, &src[start..end])?,
+                data => self.file.write_all_at(&src[start..end], data + within)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.file.sync_data().context("fdatasync failed")
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to write past end of sparse file");
+        }
+        for (block, within, start, end) in self.pieces(offset, len) {
+            let piece = (end - start) as u64;
+            let data = self.entry(block)?;
+            if piece == self.block_len(block) {
+                self.deallocate(block)?;
+            } else if data != 0 {
+                self.file
+                    .write_all_at(&vec![0u8; piece as usize], data + within)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to discard past end of sparse file");
+        }
+        // Parts of blocks keep their data; discarded contents are unspecified
+        for (block, _, start, end) in self.pieces(offset, len) {
+            if (end - start) as u64 == self.block_len(block) {
+                self.deallocate(block)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_known_zero(&self, offset: u64, len: u64) -> bool {
+        let first = (offset / self.block_size) as usize;
+        let end = (offset + len).div_ceil(self.block_size) as usize;
+        // A poisoned lock just means the range isn't known to be zero
+        let Ok(meta) = self.meta.lock() else {
+            return false;
+        };
+        meta.bat
+            .get(first..end)
+            .is_some_and(|entries| entries.iter().all(|&data| data == 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const BLOCK: u64 = 4096;
+    const SIZE: u64 = 16 * BLOCK;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vramblk-sparse-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn contents_survive_reopening() {
+        let path = temp_path("reopen");
+        {
+            let backend = SparseFileBackend::create(&path, SIZE, BLOCK).unwrap();
+            backend.write_at(BLOCK * 3 + 100, &[1; 200]).unwrap();
+            // Across a block boundary, allocating two blocks
+            backend.write_at(BLOCK * 8 - 10, &[2; 20]).unwrap();
+            backend.write_at(BLOCK * 12, &[3; BLOCK as usize]).unwrap();
+            backend.write_zeroes_at(BLOCK * 12, BLOCK).unwrap();
+            backend.flush().unwrap();
+        }
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        let backend = SparseFileBackend::open(&path, SIZE).unwrap();
+        let mut back = vec![9u8; SIZE as usize];
+        backend.read_at(0, &mut back).unwrap();
+        let mut expected = vec![0u8; SIZE as usize];
+        expected[(BLOCK * 3 + 100) as usize..][..200].fill(1);
+        expected[(BLOCK * 8 - 10) as usize..][..20].fill(2);
+        assert!(back == expected);
+        assert!(backend.is_known_zero(BLOCK * 12, BLOCK));
+        assert!(!backend.is_known_zero(BLOCK * 3, BLOCK));
+
+        // New blocks go after the existing ones
+        backend.write_at(0, &[4; 10]).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len + BLOCK);
+        drop(backend);
+        let backend = SparseFileBackend::open(&path, SIZE).unwrap();
+        let mut part = [0u8; 10];
+        backend.read_at(0, &mut part).unwrap();
+        assert_eq!(part, [4; 10]);
+        backend.read_at(BLOCK * 8 - 5, &mut part).unwrap();
+        assert_eq!(part, [2, 2, 2, 2, 2, 2, 2, 2, 2, 2]);
+
+        assert!(SparseFileBackend::open(&path, SIZE * 2).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bat_pointing_outside_the_file_or_at_a_shared_block_is_rejected() {
+        let start = data_start(HEADER_SIZE, 4, BLOCK);
+        let file_len = start + 2 * BLOCK;
+        assert!(validate_bat(&[0, start, start + BLOCK, 0], start, BLOCK, file_len).is_ok());
+        // Past the end of the file
+        assert!(validate_bat(&[start + 2 * BLOCK, 0, 0, 0], start, BLOCK, file_len).is_err());
+        // So far past it that the end wraps around
+        let wraps = u64::MAX - BLOCK + 1;
+        assert!(validate_bat(&[wraps, 0, 0, 0], start, BLOCK, file_len).is_err());
+        // Two blocks sharing their data
+        assert!(validate_bat(&[start, 0, start, 0], start, BLOCK, file_len).is_err());
+        // Inside the BAT, and unaligned
+        assert!(validate_bat(&[HEADER_SIZE, 0, 0, 0], start, BLOCK, file_len).is_err());
+        assert!(validate_bat(&[start + 1, 0, 0, 0], start, BLOCK, file_len).is_err());
+    }
+
+    #[test]
+    fn corrupt_containers_are_refused_on_open() {
+        let path = temp_path("corrupt");
+        {
+            let backend = SparseFileBackend::create(&path, SIZE, BLOCK).unwrap();
+            backend.write_at(0, &[1; 10]).unwrap();
+            backend.write_at(BLOCK, &[2; 10]).unwrap();
+        }
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        let good = std::fs::read(&path).unwrap();
+
+        // Block 1 pointing at block 0's data
+        let first = u64::from_le_bytes(good[HEADER_SIZE as usize..][..8].try_into().unwrap());
+        file.write_all_at(&first.to_le_bytes(), HEADER_SIZE + 8)
+            .unwrap();
+        assert!(SparseFileBackend::open(&path, SIZE).is_err());
+
+        // Block 1 pointing past the end of the file
+        let len = good.len() as u64;
+        file.write_all_at(&len.to_le_bytes(), HEADER_SIZE + 8)
+            .unwrap();
+        assert!(SparseFileBackend::open(&path, SIZE).is_err());
+
+        // A BAT offset that wraps around when its length is added
+        file.write_all_at(&good, 0).unwrap();
+        file.write_all_at(&(u64::MAX - 8).to_le_bytes(), 24)
+            .unwrap();
+        assert!(SparseFileBackend::open(&path, SIZE).is_err());
+
+        file.write_all_at(&good, 0).unwrap();
+        assert!(SparseFileBackend::open(&path, SIZE).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
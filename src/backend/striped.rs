@@ -0,0 +1,259 @@
+//! Striping across several GPUs (RAID0)
+//!
+//! `StripedBackend` interleaves fixed-size chunks over its member backends
+//! round-robin: chunk 0 on member 0, chunk 1 on member 1, and so on. A
+//! request that spans members is split and the parts run in parallel, so
+//! large sequential transfers use every GPU's PCIe link at once. Losing any
+//! member loses the whole device, as with any RAID0.
+
+use super::BlockBackend;
+use anyhow::{bail, Result};
+
+/// A contiguous run on one member: (member index, member offset, length)
+type Segment = (usize, u64, usize);
+
+/// Backend that stripes chunks across equally sized members
+pub struct StripedBackend<B> {
+    members: Vec<B>,
+    chunk: u64,
+    size: u64,
+}
+
+impl<B: BlockBackend> StripedBackend<B> {
+    /// Stripe `members` in `chunk`-byte units. Members larger than the
+    /// smallest one are only used up to its size.
+    pub fn new(members: Vec<B>, chunk: u64) -> Result<Self> {
+        if members.is_empty() {
+            bail!("A striped device needs at least one member");
+        }
+        if chunk == 0 {
+            bail!("Stripe chunk size must be non-zero");
+        }
+        let member_size = members.iter().map(|m| m.size()).min().unwrap_or(0);
+        let stripes = member_size / chunk;
+        if stripes == 0 {
+            bail!(
+                "Members of {} bytes are smaller than one {} byte chunk",
+                member_size,
+                chunk
+            );
+        }
+        let size = stripes * chunk * members.len() as u64;
+        Ok(Self {
+            members,
+            chunk,
+            size,
+        })
+    }
+
+    /// Map `[offset, offset + len)` onto the members, merging chunks that
+    /// are contiguous on the same member.
+    fn segments(&self, offset: u64, len: usize) -> Vec<Segment> {
+        let width = self.members.len() as u64;
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let index = pos / self.chunk;
+            let in_chunk = pos % self.chunk;
+            let len_here = ((self.chunk - in_chunk) as usize).min(len - done);
+            let member = (index % width) as usize;
+            let member_offset = (index / width) * self.chunk + in_chunk;
+            match segments.last_mut() {
+                Some((last_member, last_offset, last_len))
+                    if *last_member == member
+                        && *last_offset + *last_len as u64 == member_offset =>
+                {
+                    *last_len += len_here;
+                }
+                _ => segments.push((member, member_offset, len_here)),
+            }
+            done += len_here;
+        }
+        segments
+    }
+
+    /// Run the parts of a request, one thread per member involved
+    fn dispatch<T: Send>(
+        &self,
+        parts: Vec<(usize, u64, T)>,
+        io: impl Fn(&B, u64, T) -> Result<()> + Sync,
+    ) -> Result<()> {
+        let mut by_member: Vec<Vec<(u64, T)>> = self.members.iter().map(|_| Vec::new()).collect();
+        for (member, offset, part) in parts {
+            by_member[member].push((offset, part));
+        }
+        let mut busy: Vec<(&B, Vec<(u64, T)>)> = self
+            .members
+            .iter()
+            .zip(by_member)
+            .filter(|(_, side)| !side.is_empty())
+            .collect();
+        let run_member = |member: &B, side: Vec<(u64, T)>| {
+            side.into_iter()
+                .try_for_each(|(offset, part)| io(member, offset, part))
+        };
+
+        let Some((first, first_side)) = busy.pop() else {
+            return Ok(());
+        };
+        if busy.is_empty() {
+            return run_member(first, first_side);
+        }
+        let run_member = &run_member;
+        std::thread::scope(|scope| {
+            let others: Vec<_> = busy
+                .into_iter()
+                .map(|(member, side)| scope.spawn(move || run_member(member, side)))
+                .collect();
+            let mut result = run_member(first, first_side);
+            for other in others {
+                let joined = other
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("Striped transfer panicked"))?;
+                result = result.and(joined);
+            }
+            result
+        })
+    }
+
+    /// Split a range without data into per-member parts
+    fn range_parts(&self, offset: u64, len: u64) -> Vec<(usize, u64, u64)> {
+        self.segments(offset, len as usize)
+            .into_iter()
+            .map(|(member, member_offset, len)| (member, member_offset, len as u64))
+            .collect()
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for StripedBackend<B> {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        if offset + dst.len() as u64 > self.size {
+            bail!("Attempted to read past end of striped device");
+        }
+        let mut rest = dst;
+        let mut parts = Vec::new();
+        for (member, member_offset, len) in self.segments(offset, rest.len()) {
+            let (head, tail) = std::mem::take(&mut rest).split_at_mut(len);
+            parts.push((member, member_offset, head));
+            rest = tail;
+        }
+        self.dispatch(parts, |member, offset, buf| member.read_at(offset, buf))
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        if offset + src.len() as u64 > self.size {
+            bail!("Attempted to write past end of striped device");
+        }
+        let mut rest = src;
+        let mut parts = Vec::new();
+        for (member, member_offset, len) in self.segments(offset, rest.len()) {
+            let (head, tail) = rest.split_at(len);
+            parts.push((member, member_offset, head));
+            rest = tail;
+        }
+        self.dispatch(parts, |member, offset, buf| member.write_at(offset, buf))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.members.iter().try_for_each(|m| m.flush())
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to zero past end of striped device");
+        }
+        self.dispatch(self.range_parts(offset, len), |member, offset, len| {
+            member.write_zeroes_at(offset, len)
+        })
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.members.iter().all(|m| m.fast_zero())
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to discard past end of striped device");
+        }
+        self.dispatch(self.range_parts(offset, len), |member, offset, len| {
+            member.discard_at(offset, len)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+    use std::sync::Arc;
+
+    const CHUNK: u64 = 4096;
+
+    fn striped(members: usize) -> (StripedBackend<Arc<RamBuffer>>, Vec<Arc<RamBuffer>>) {
+        let buffers: Vec<_> = (0..members)
+            .map(|_| Arc::new(RamBuffer::new(4 * CHUNK)))
+            .collect();
+        let backend = StripedBackend::new(buffers.clone(), CHUNK).unwrap();
+        (backend, buffers)
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    #[test]
+    fn write_across_boundary_lands_in_both_members() {
+        let (backend, members) = striped(2);
+        let data = pattern(200);
+        // 100 bytes at the end of chunk 0 (member 0), 100 at the start of
+        // chunk 1 (member 1)
+        backend.write_at(CHUNK - 100, &data).unwrap();
+
+        let mut first = vec![0u8; CHUNK as usize];
+        members[0].read_at(0, &mut first).unwrap();
+        assert_eq!(&first[CHUNK as usize - 100..], &data[..100]);
+        assert!(first[..CHUNK as usize - 100].iter().all(|&b| b == 0));
+
+        let mut second = vec![0u8; CHUNK as usize];
+        members[1].read_at(0, &mut second).unwrap();
+        assert_eq!(&second[..100], &data[100..]);
+        assert!(second[100..].iter().all(|&b| b == 0));
+
+        let mut back = vec![0u8; data.len()];
+        backend.read_at(CHUNK - 100, &mut back).unwrap();
+        assert_eq!(back, data);
+    }
+
+    #[test]
+    fn write_spanning_a_full_stripe_wraps_to_the_first_member() {
+        let (backend, members) = striped(3);
+        // From the middle of chunk 2 (member 2, stripe 0) to the middle of
+        // chunk 4 (member 1, stripe 1)
+        let offset = 2 * CHUNK + CHUNK / 2;
+        let data = pattern(2 * CHUNK as usize);
+        backend.write_at(offset, &data).unwrap();
+
+        let half = CHUNK as usize / 2;
+        let mut part = vec![0u8; half];
+        members[2].read_at(CHUNK / 2, &mut part).unwrap();
+        assert_eq!(part, &data[..half]);
+        let mut whole = vec![0u8; CHUNK as usize];
+        members[0].read_at(CHUNK, &mut whole).unwrap();
+        assert_eq!(whole, &data[half..half + CHUNK as usize]);
+        members[1].read_at(CHUNK, &mut part).unwrap();
+        assert_eq!(part, &data[half + CHUNK as usize..]);
+    }
+
+    #[test]
+    fn size_is_whole_stripes() {
+        let members = vec![RamBuffer::new(4 * CHUNK + 100), RamBuffer::new(5 * CHUNK)];
+        let backend = StripedBackend::new(members, CHUNK).unwrap();
+        assert_eq!(backend.size(), 8 * CHUNK);
+        assert!(backend.write_at(8 * CHUNK - 1, &[0, 0]).is_err());
+    }
+}
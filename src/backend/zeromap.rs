@@ -0,0 +1,245 @@
+//! Tracking of written blocks
+//!
+//! `ZeroMapBackend` keeps a bitmap of the blocks that may hold data. A block
+//! is marked allocated before any write to it reaches the inner backend and
+//! cleared again when a write-zeroes or a discard (NBD trim, ublk discard)
+//! covers it completely, so a clear bit means the block reads as zeros.
+//! Reads of such blocks are answered with zeros without touching the inner
+//! backend, the NBD frontend sends them as holes, and snapshots skip them.
+//!
+//! A discarded block may still hold its old contents in the inner backend.
+//! The first write that covers such a block only partly zeroes the rest of
+//! it first, so the old contents never show through.
+//!
+//! The inner backend must read as zeros when the wrapper is created, as a
+//! freshly allocated device does; one restored from a file does not.
+//!
+//! A second bitmap of the same blocks records which ones changed, by a
+//! write, write-zeroes or discard, since `take_dirty` last collected them,
+//! for incremental backups. A block is marked dirty once the change has
+//! completed, so a backup that reads it after collecting it sees the new
+//! data, and a change still in progress shows up in the next collection.
+
+use super::BlockBackend;
+use anyhow::{bail, Result};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Backend wrapper that tracks which blocks have been written
+pub struct ZeroMapBackend<B> {
+    inner: B,
+    block_size: u64,
+    /// One bit per block, set once the block may hold data
+    allocated: Vec<AtomicU64>,
+    /// One bit per block, set once the block has changed since the last
+    /// `take_dirty`
+    dirty: Vec<AtomicU64>,
+    /// Blocks covered by `allocated` and `dirty`; the device may grow past
+    /// them
+    blocks: u64,
+    /// Held by writes that allocate a block they cover only partly, so two
+    /// of them can't zero each other's data
+    filling: Mutex<()>,
+}
+
+impl<B: BlockBackend> ZeroMapBackend<B> {
+    /// Track `inner` in blocks of `block_size` bytes; `inner` must currently
+    /// read as zeros
+    pub fn new(inner: B, block_size: u64) -> Result<Self> {
+        if block_size == 0 || !block_size.is_multiple_of(512) {
+            bail!("Allocation block size must be a non-zero multiple of 512 bytes");
+        }
+        let blocks = inner.size().div_ceil(block_size);
+        Ok(Self {
+            allocated: (0..blocks.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            dirty: (0..blocks.div_ceil(64))
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            inner,
+            block_size,
+            blocks,
+            filling: Mutex::new(()),
+        })
+    }
+
+    /// Whether `block` may hold data. Blocks past the bitmap, added by a
+    /// resize, always count as allocated.
+    pub fn is_allocated(&self, block: u64) -> bool {
+        block >= self.blocks
+            || self.allocated[(block / 64) as usize].load(Ordering::SeqCst) & (1 << (block % 64))
+                != 0
+    }
+
+    /// Bytes of the device in allocated blocks
+    pub fn allocated_bytes(&self) -> u64 {
+        let size = self.inner.size();
+        (0..size.div_ceil(self.block_size))
+            .filter(|&block| self.is_allocated(block))
+            .map(|block| self.block_size.min(size - block * self.block_size))
+            .sum()
+    }
+
+    /// Size of the tracked blocks in bytes
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    /// Byte ranges of the blocks changed since the previous call, merged
+    /// where adjacent, and start over with none. Each 64 blocks are swapped
+    /// out atomically, so a concurrent change is returned by this call or
+    /// the next. Blocks past the bitmap, added by a resize, are always
+    /// returned.
+    pub fn take_dirty(&self) -> Vec<Range<u64>> {
+        let size = self.inner.size();
+        let mut ranges: Vec<Range<u64>> = Vec::new();
+        let mut add = |start: u64, end: u64| {
+            let end = end.min(size);
+            if start >= end {
+                return;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == start => last.end = end,
+                _ => ranges.push(start..end),
+            }
+        };
+        for (index, word) in self.dirty.iter().enumerate() {
+            let mut bits = word.swap(0, Ordering::SeqCst);
+            while bits != 0 {
+                let block = index as u64 * 64 + bits.trailing_zeros() as u64;
+                bits &= bits - 1;
+                add(block * self.block_size, (block + 1) * self.block_size);
+            }
+        }
+        add(self.blocks * self.block_size, size);
+        ranges
+    }
+
+    fn mark_dirty(&self, offset: u64, len: u64) {
+        let blocks = self.overlapping(offset, len);
+        for block in blocks.start.min(self.blocks)..blocks.end.min(self.blocks) {
+            self.dirty[(block / 64) as usize].fetch_or(1 << (block % 64), Ordering::SeqCst);
+        }
+    }
+
+    /// Blocks overlapping `len` bytes at `offset`
+    fn overlapping(&self, offset: u64, len: u64) -> Range<u64> {
+        offset / self.block_size..(offset + len).div_ceil(self.block_size)
+    }
+
+    fn mark(&self, offset: u64, len: u64) {
+        let blocks = self.overlapping(offset, len);
+        for block in blocks.start.min(self.blocks)..blocks.end.min(self.blocks) {
+            self.allocated[(block / 64) as usize].fetch_or(1 << (block % 64), Ordering::SeqCst);
+        }
+    }
+
+    /// Mark the blocks `len` bytes at `offset` cover completely as reading
+    /// as zeros
+    fn clear(&self, offset: u64, len: u64) {
+        let first = offset.div_ceil(self.block_size);
+        let end = ((offset + len) / self.block_size).min(self.blocks);
+        for block in first..end {
+            self.allocated[(block / 64) as usize].fetch_and(!(1 << (block % 64)), Ordering::SeqCst);
+        }
+    }
+
+    /// Parts of unallocated blocks that a write of `len` bytes at `offset`
+    /// doesn't cover: at most one before the write and one after it
+    fn uncovered(&self, offset: u64, len: u64) -> Vec<Range<u64>> {
+        let end = offset + len;
+        let head = offset / self.block_size * self.block_size;
+        let tail = end
+            .div_ceil(self.block_size)
+            .saturating_mul(self.block_size)
+            .min(self.inner.size());
+        let mut parts = Vec::new();
+        if head < offset && !self.is_allocated(offset / self.block_size) {
+            parts.push(head..offset);
+        }
+        if end < tail && !self.is_allocated(end / self.block_size) {
+            parts.push(end..tail);
+        }
+        parts
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for ZeroMapBackend<B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        // Read runs of allocated blocks and zero-fill the rest
+        let end = offset + dst.len() as u64;
+        let mut at = offset;
+        while at < end {
+            let allocated = self.is_allocated(at / self.block_size);
+            let mut next = at;
+            while next < end && self.is_allocated(next / self.block_size) == allocated {
+                next = ((next / self.block_size + 1) * self.block_size).min(end);
+            }
+            let part = &mut dst[(at - offset) as usize..(next - offset) as usize];
+            if allocated {
+                self.inner.read_at(at, part)?;
+            } else {
+                if end > self.inner.size() {
+                    bail!("Attempted to read past end of device");
+                }
+                part.fill(0);
+            }
+            at = next;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        let len = src.len() as u64;
+        if self.uncovered(offset, len).is_empty() {
+            self.mark(offset, len);
+            self.inner.write_at(offset, src)?;
+            self.mark_dirty(offset, len);
+            return Ok(());
+        }
+        let _filling = self.filling.lock().unwrap_or_else(|e| e.into_inner());
+        // Another write may have allocated the blocks meanwhile
+        for part in self.uncovered(offset, len) {
+            self.inner
+                .write_zeroes_at(part.start, part.end - part.start)?;
+        }
+        self.mark(offset, len);
+        self.inner.write_at(offset, src)?;
+        self.mark_dirty(offset, len);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.inner.write_zeroes_at(offset, len)?;
+        // Only blocks the range covers completely are now all zeros
+        self.clear(offset, len);
+        self.mark_dirty(offset, len);
+        Ok(())
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.inner.discard_at(offset, len)?;
+        self.clear(offset, len);
+        self.mark_dirty(offset, len);
+        Ok(())
+    }
+
+    fn is_known_zero(&self, offset: u64, len: u64) -> bool {
+        self.overlapping(offset, len)
+            .all(|block| !self.is_allocated(block))
+    }
+}
@@ -0,0 +1,188 @@
+//! Backend throughput benchmark
+//!
+//! `vramblk bench` runs timed passes straight against the `BlockBackend`,
+//! without NBD or ublk in between: sequential write, sequential read, random
+//! write and random read, in that order, so the reads see written data.
+//! Each pass keeps `queue_depth` requests in flight from as many threads.
+//! Sequential passes hand out consecutive blocks and wrap at the end of the
+//! device; random passes pick block-aligned offsets uniformly.
+
+use crate::backend::BlockBackend;
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Parameters of a benchmark run
+#[derive(Debug, Clone)]
+pub struct BenchConfig {
+    /// Bytes per request
+    pub block_size: u64,
+    /// How long each pass runs
+    pub duration: Duration,
+    /// Requests in flight at once
+    pub queue_depth: usize,
+}
+
+/// Result of one pass
+#[derive(Debug, Serialize)]
+pub struct PassResult {
+    pub pass: &'static str,
+    pub block_size: u64,
+    pub queue_depth: usize,
+    pub ops: u64,
+    pub bytes: u64,
+    pub seconds: f64,
+    pub mb_per_sec: f64,
+    pub iops: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Pass {
+    SeqWrite,
+    SeqRead,
+    RandWrite,
+    RandRead,
+}
+
+impl Pass {
+    fn name(self) -> &'static str {
+        match self {
+            Pass::SeqWrite => "seq-write",
+            Pass::SeqRead => "seq-read",
+            Pass::RandWrite => "rand-write",
+            Pass::RandRead => "rand-read",
+        }
+    }
+
+    fn writes(self) -> bool {
+        matches!(self, Pass::SeqWrite | Pass::RandWrite)
+    }
+
+    fn random(self) -> bool {
+        matches!(self, Pass::RandWrite | Pass::RandRead)
+    }
+}
+
+/// Run all passes against `backend` and print the results, as JSON with `json`
+pub fn run_bench(backend: Arc<dyn BlockBackend>, config: &BenchConfig, json: bool) -> Result<()> {
+    if config.block_size == 0 || config.block_size > backend.size() {
+        bail!(
+            "Benchmark block size must be between 1 byte and the device size ({} bytes)",
+            backend.size()
+        );
+    }
+    if config.queue_depth == 0 {
+        bail!("Benchmark queue depth must be at least 1");
+    }
+    if !json {
+        println!(
+            "Benchmarking {} bytes: {}-byte requests, queue depth {}, {:?} per pass",
+            backend.size(),
+            config.block_size,
+            config.queue_depth,
+            config.duration
+        );
+    }
+
+    let mut results = Vec::new();
+    for pass in [
+        Pass::SeqWrite,
+        Pass::SeqRead,
+        Pass::RandWrite,
+        Pass::RandRead,
+    ] {
+        let result = run_pass(&backend, config, pass)?;
+        if !json {
+            println!(
+                "{:<10} {:>10.1} MB/s {:>12.0} IOPS  ({} ops in {:.2}s)",
+                result.pass, result.mb_per_sec, result.iops, result.ops, result.seconds
+            );
+        }
+        results.push(result);
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    }
+    Ok(())
+}
+
+fn run_pass(
+    backend: &Arc<dyn BlockBackend>,
+    config: &BenchConfig,
+    pass: Pass,
+) -> Result<PassResult> {
+    let blocks = backend.size() / config.block_size;
+    let next = Arc::new(AtomicU64::new(0));
+    let stop = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+
+    let workers: Vec<_> = (0..config.queue_depth)
+        .map(|worker| {
+            let backend = backend.clone();
+            let next = next.clone();
+            let stop = stop.clone();
+            let block_size = config.block_size;
+            std::thread::spawn(move || -> Result<u64> {
+                let mut buf = vec![0u8; block_size as usize];
+                if pass.writes() {
+                    buf.iter_mut()
+                        .enumerate()
+                        .for_each(|(i, b)| *b = (i as u8) ^ (worker as u8));
+                }
+                let mut rng = 0x9e37_79b9_7f4a_7c15u64 ^ (worker as u64 + 1);
+                let mut ops = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    let block = if pass.random() {
+                        xorshift(&mut rng) % blocks
+                    } else {
+                        next.fetch_add(1, Ordering::Relaxed) % blocks
+                    };
+                    let offset = block * block_size;
+                    if pass.writes() {
+                        backend.write_at(offset, &buf)?;
+                    } else {
+                        backend.read_at(offset, &mut buf)?;
+                    }
+                    ops += 1;
+                }
+                Ok(ops)
+            })
+        })
+        .collect();
+
+    std::thread::sleep(config.duration);
+    stop.store(true, Ordering::Relaxed);
+    let mut ops = 0;
+    for worker in workers {
+        match worker.join() {
+            Ok(done) => ops += done?,
+            Err(_) => bail!("Benchmark thread panicked"),
+        }
+    }
+    if pass.writes() {
+        backend.flush()?;
+    }
+
+    let seconds = start.elapsed().as_secs_f64();
+    let bytes = ops * config.block_size;
+    Ok(PassResult {
+        pass: pass.name(),
+        block_size: config.block_size,
+        queue_depth: config.queue_depth,
+        ops,
+        bytes,
+        seconds,
+        mb_per_sec: bytes as f64 / seconds / 1e6,
+        iops: ops as f64 / seconds,
+    })
+}
+
+/// Next value of a xorshift64 generator; plenty for spreading offsets
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
@@ -0,0 +1,129 @@
+//! Settings from a TOML file
+//!
+//! `--config <path>` reads a TOML file whose keys are the option names with
+//! underscores (`size`, `listen_addr`, `read_only`, ...). Switches take
+//! booleans, options that may be repeated take arrays, and everything else
+//! takes a string or a number, written as on the command line:
+//!
+//! ```toml
+//! size = "4G"
+//! device = [0, 1]
+//! listen_addr = "0.0.0.0:10809"
+//! read_only = true
+//! ```
+//!
+//! Each setting is turned back into its option and parsed along with the
+//! command line, so values are validated exactly as if they had been typed.
+//! Options given on the command line win: a file setting is dropped when the
+//! same option, or one that conflicts with it, was passed as a flag.
+
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+/// `argv` with the settings of the `--config` file, if any, inserted ahead
+/// of the command line's own options
+pub fn merge_config_file(cmd: &Command, argv: Vec<OsString>) -> Result<Vec<OsString>> {
+    let matches = cmd.clone().get_matches_from(&argv);
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return Ok(argv);
+    };
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let table: toml::Table = toml::from_str(&text)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+    let unknown: Vec<&str> = table
+        .keys()
+        .filter(|key| settable(cmd, key).is_none())
+        .map(String::as_str)
+        .collect();
+    if !unknown.is_empty() {
+        bail!(
+            "Unknown keys in config file {}: {}",
+            path.display(),
+            unknown.join(", ")
+        );
+    }
+
+    let mut merged = vec![argv[0].clone()];
+    for (key, value) in &table {
+        let arg = settable(cmd, key).expect("unknown keys were rejected");
+        if overridden(cmd, &matches, arg) {
+            log::debug!(
+                "Config file setting `{}` overridden on the command line",
+                key
+            );
+            continue;
+        }
+        merged.extend(
+            to_options(arg, value)
+                .with_context(|| format!("Invalid `{}` in config file {}", key, path.display()))?,
+        );
+    }
+    merged.extend(argv.into_iter().skip(1));
+    Ok(merged)
+}
+
+/// The option `key` names, if a config file may set it
+fn settable<'a>(cmd: &'a Command, key: &str) -> Option<&'a Arg> {
+    cmd.get_arguments().find(|arg| {
+        arg.get_id() == key
+            && arg.get_long().is_some()
+            && key != "config"
+            && !matches!(arg.get_action(), ArgAction::Help | ArgAction::Version)
+    })
+}
+
+/// Whether the command line sets `arg` or an option that conflicts with it
+fn overridden(cmd: &Command, matches: &ArgMatches, arg: &Arg) -> bool {
+    let given = |other: &Arg| {
+        matches.value_source(other.get_id().as_str()) == Some(ValueSource::CommandLine)
+    };
+    given(arg)
+        || cmd.get_arg_conflicts_with(arg).into_iter().any(given)
+        || cmd
+            .get_arguments()
+            .filter(|other| given(other))
+            .any(|other| {
+                cmd.get_arg_conflicts_with(other)
+                    .iter()
+                    .any(|conflict| conflict.get_id() == arg.get_id())
+            })
+}
+
+/// Command line options equivalent to setting `arg` to `value`
+fn to_options(arg: &Arg, value: &toml::Value) -> Result<Vec<OsString>> {
+    let long = arg.get_long().expect("settable options have a long name");
+    if matches!(arg.get_action(), ArgAction::SetTrue) {
+        return match value {
+            toml::Value::Boolean(true) => Ok(vec![format!("--{}", long).into()]),
+            toml::Value::Boolean(false) => Ok(Vec::new()),
+            _ => bail!("expected true or false"),
+        };
+    }
+    let values = match value {
+        toml::Value::Array(items) if matches!(arg.get_action(), ArgAction::Append) => {
+            items.iter().map(scalar).collect::<Result<Vec<_>>>()?
+        }
+        toml::Value::Array(_) => bail!("expected a single value, not a list"),
+        value => vec![scalar(value)?],
+    };
+    Ok(values
+        .into_iter()
+        .map(|value| format!("--{}={}", long, value).into())
+        .collect())
+}
+
+/// A string, number or boolean as it would be written on the command line
+fn scalar(value: &toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => bail!("expected a string or a number"),
+    }
+}
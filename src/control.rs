@@ -0,0 +1,290 @@
+//! Admin commands over a Unix socket
+//!
+//! With `--control-socket`, a line-based text protocol is served on a Unix
+//! domain socket; filesystem permissions control who may connect. Each line
+//! is one command and gets one line back:
+//!
+//! - `size`: the device size in bytes
+//! - `resize <SIZE> [force]`: resize the device (sizes as for `--size`);
+//!   shrinking discards data and needs `force`
+//! - `add-member <PLATFORM>:<DEVICE>:<SIZE>`: allocate `SIZE` on another GPU
+//!   and append it to the end of the device
+//!
+//! Replies are `ok <bytes>` with the resulting size, or `error: <message>`.
+//!
+//! A line starting with `{` is a JSON command instead, answered with one
+//! line of JSON: `{"ok":true,...}` with the command's results, or
+//! `{"ok":false,"error":"<message>"}`. The `cmd` field names the command:
+//!
+//! - `size`: `size` in bytes
+//! - `resize`: takes `size` (bytes, or a string as for `--size`) and
+//!   optionally `force`; returns the resulting `size`
+//! - `add-member`: takes `member` as `PLATFORM:DEVICE:SIZE`; returns the
+//!   resulting `size`
+//! - `stats`: the `backend` type, the device `size` and the counters also
+//!   exported as metrics, such as `nbd_clients` and `bytes_written`
+//! - `flush`: flush the device through to its backing files
+//! - `snapshot`: write a snapshot now and return its `path`
+//! - `dirty-reset`: with `--track-allocation`, the `ranges` of the device
+//!   changed since the previous `dirty-reset` (or since startup), as
+//!   `[offset, length]` pairs in bytes at `block_size` granularity, and
+//!   start tracking afresh
+
+use crate::backend::{BlockBackend, ResizableBackend, ZeroMapBackend};
+use crate::metrics::Metrics;
+use crate::nbd::remove_stale_socket;
+use crate::parse_size_string;
+use crate::snapshot::SnapshotRequest;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Longest command line accepted
+const MAX_LINE: usize = 1024;
+
+/// What the control commands act on
+pub struct ControlState {
+    /// The resizable buffer
+    pub device: Arc<ResizableBackend>,
+    /// The device as the frontend sees it, for flushes
+    pub backend: Arc<dyn BlockBackend>,
+    pub metrics: Arc<Metrics>,
+    /// Storage type reported by `stats`, such as `opencl` or `mem`
+    pub kind: String,
+    /// Requests to the snapshot task
+    pub snapshots: mpsc::Sender<SnapshotRequest>,
+    /// Written blocks with --track-allocation, for `dirty-reset`
+    pub allocation: Option<Arc<ZeroMapBackend<Arc<dyn BlockBackend>>>>,
+}
+
+/// A JSON command
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+enum Command {
+    Size,
+    Resize {
+        size: SizeArg,
+        #[serde(default)]
+        force: bool,
+    },
+    Stats,
+    Flush,
+    Snapshot,
+    #[serde(rename = "dirty-reset")]
+    DirtyReset,
+    #[serde(rename = "add-member")]
+    AddMember {
+        member: String,
+    },
+}
+
+/// A size given in bytes or as a string such as `4G`
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SizeArg {
+    Bytes(u64),
+    Text(String),
+}
+
+/// Bind `path` and serve control connections until `cancel` is cancelled.
+/// The socket file is removed when the server stops.
+pub fn spawn_control_server(
+    path: PathBuf,
+    state: ControlState,
+    cancel: CancellationToken,
+) -> Result<JoinHandle<()>> {
+    remove_stale_socket(&path)?;
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    log::info!("Accepting control commands on {}", path.display());
+
+    let state = Arc::new(state);
+    Ok(tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                _ = cancel.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("Control socket accept error: {}", e);
+                        continue;
+                    }
+                },
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, state).await {
+                    log::debug!("Control connection error: {}", e);
+                }
+            });
+        }
+        if let Err(e) = std::fs::remove_file(&path) {
+            log::warn!("Failed to remove control socket {}: {}", path.display(), e);
+        }
+    }))
+}
+
+/// Answer commands until the client closes the connection
+async fn serve(stream: UnixStream, state: Arc<ControlState>) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if read.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        if line.len() > MAX_LINE {
+            bail!("Control command too long");
+        }
+        let command = line.trim();
+        let reply = if command.starts_with('{') {
+            let reply = match execute_json(command, &state).await {
+                Ok(mut reply) => {
+                    reply["ok"] = true.into();
+                    reply
+                }
+                Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+            };
+            format!("{}\n", reply)
+        } else {
+            match execute(command, &state.device).await {
+                Ok(size) => format!("ok {}\n", size),
+                Err(e) => format!("error: {:#}\n", e),
+            }
+        };
+        write.write_all(reply.as_bytes()).await?;
+    }
+}
+
+/// Run one text command and return the device size after it
+async fn execute(command: &str, device: &Arc<ResizableBackend>) -> Result<u64> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    match words.as_slice() {
+        ["size"] => Ok(device.size()),
+        ["resize", size, rest @ ..] => {
+            let force = match rest {
+                [] => false,
+                ["force"] => true,
+                _ => bail!("Usage: resize <SIZE> [force]"),
+            };
+            resize(device, parse_size_string(size)?, force).await
+        }
+        ["add-member", member] => add_member(device, member).await,
+        _ => bail!(
+            "Unknown command '{}'; expected size, resize <SIZE> [force] \
+             or add-member <PLATFORM:DEVICE:SIZE>",
+            command
+        ),
+    }
+}
+
+/// Run one JSON command and return the fields of its reply
+async fn execute_json(command: &str, state: &ControlState) -> Result<Value> {
+    let command: Command = serde_json::from_str(command).context("Invalid command")?;
+    match command {
+        Command::Size => Ok(json!({ "size": state.device.size() })),
+        Command::Resize { size, force } => {
+            let size = match size {
+                SizeArg::Bytes(size) => size,
+                SizeArg::Text(size) => parse_size_string(&size)?,
+            };
+            let size = resize(&state.device, size, force).await?;
+            Ok(json!({ "size": size }))
+        }
+        Command::Stats => {
+            let mut reply = serde_json::to_value(state.metrics.stats())?;
+            reply["backend"] = state.kind.clone().into();
+            reply["size"] = state.device.size().into();
+            Ok(reply)
+        }
+        Command::Flush => {
+            log::info!("Control: flushing device");
+            let backend = state.backend.clone();
+            tokio::task::spawn_blocking(move || backend.flush()).await??;
+            Ok(json!({}))
+        }
+        Command::Snapshot => {
+            log::info!("Control: taking a snapshot");
+            let (reply, written) = oneshot::channel();
+            state
+                .snapshots
+                .send(reply)
+                .await
+                .map_err(|_| anyhow!("Snapshots have stopped"))?;
+            let path = written
+                .await
+                .map_err(|_| anyhow!("Snapshots have stopped"))??;
+            Ok(json!({ "path": path }))
+        }
+        Command::DirtyReset => {
+            let Some(allocation) = &state.allocation else {
+                bail!("Dirty-region tracking needs --track-allocation");
+            };
+            let ranges: Vec<[u64; 2]> = allocation
+                .take_dirty()
+                .into_iter()
+                .map(|range| [range.start, range.end - range.start])
+                .collect();
+            log::info!("Control: collected {} dirty ranges", ranges.len());
+            Ok(json!({ "block_size": allocation.block_size(), "ranges": ranges }))
+        }
+        Command::AddMember { member } => {
+            let size = add_member(&state.device, &member).await?;
+            Ok(json!({ "size": size }))
+        }
+    }
+}
+
+/// Resize the device to `size` bytes and return the new size
+async fn resize(device: &Arc<ResizableBackend>, size: u64, force: bool) -> Result<u64> {
+    if size == 0 {
+        bail!("Size must be greater than zero");
+    }
+    log::info!(
+        "Control: resizing device to {} bytes{}",
+        size,
+        if force { " (forced)" } else { "" }
+    );
+    let device = device.clone();
+    tokio::task::spawn_blocking(move || device.resize(size, force)).await??;
+    Ok(size)
+}
+
+/// Append a buffer given as `PLATFORM:DEVICE:SIZE` to the device and return
+/// the new size
+async fn add_member(device: &Arc<ResizableBackend>, member: &str) -> Result<u64> {
+    let (platform, index, size) = parse_member(member)?;
+    log::info!(
+        "Control: adding {} bytes on device {}:{}",
+        size,
+        platform,
+        index
+    );
+    let device = device.clone();
+    tokio::task::spawn_blocking(move || device.add_member(platform, index, size)).await?
+}
+
+/// Split `PLATFORM:DEVICE:SIZE` into its parts
+fn parse_member(member: &str) -> Result<(usize, usize, u64)> {
+    let usage = || anyhow!("Invalid member '{}'; expected PLATFORM:DEVICE:SIZE", member);
+    let mut parts = member.splitn(3, ':');
+    let (Some(platform), Some(device), Some(size)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(usage());
+    };
+    let platform = platform.parse().map_err(|_| usage())?;
+    let device = device.parse().map_err(|_| usage())?;
+    let size = parse_size_string(size)?;
+    if size == 0 {
+        bail!("Size must be greater than zero");
+    }
+    Ok((platform, device, size))
+}
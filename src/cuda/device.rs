@@ -0,0 +1,37 @@
+//! CUDA device enumeration
+
+use anyhow::{Context, Result};
+use cust::device::Device;
+use cust::CudaFlags;
+
+/// A CUDA device as shown by `--list-devices`
+#[derive(Debug, Clone)]
+pub struct CudaDeviceInfo {
+    pub index: u32,
+    pub name: String,
+    pub total_memory: usize,
+}
+
+/// Initialize the CUDA driver; safe to call more than once
+pub(super) fn init() -> Result<()> {
+    cust::init(CudaFlags::empty()).context("Failed to initialize the CUDA driver")
+}
+
+/// All CUDA devices, in device index order
+pub fn cuda_devices() -> Result<Vec<CudaDeviceInfo>> {
+    init()?;
+    let count = Device::num_devices().context("Failed to count CUDA devices")?;
+    (0..count)
+        .map(|index| {
+            let device = Device::get_device(index)
+                .with_context(|| format!("Failed to open CUDA device {}", index))?;
+            Ok(CudaDeviceInfo {
+                index,
+                name: device
+                    .name()
+                    .unwrap_or_else(|_| "Unknown device".to_string()),
+                total_memory: device.total_memory().unwrap_or(0),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,122 @@
+//! GPU memory management via CUDA
+//!
+//! `CudaBuffer` mirrors `VRamBuffer`: a single device allocation with
+//! blocking copies to and from host memory. The allocation lives in the
+//! device's primary context, which is made current on whichever thread
+//! issues a transfer, so the buffer can be used from any frontend thread.
+
+use super::device::init;
+use anyhow::{bail, Context as _, Result};
+use cust::context::{Context, CurrentContext};
+use cust::device::Device;
+use cust::memory::{CopyDestination, DeviceBuffer};
+
+/// A buffer allocated in GPU memory via CUDA
+pub struct CudaBuffer {
+    // Declared before `context` so it is freed while the context is alive
+    buffer: DeviceBuffer<u8>,
+    context: Context,
+    device: Device,
+    size: usize,
+}
+
+impl CudaBuffer {
+    /// Allocate `size` bytes on CUDA device `device_index`
+    pub fn new(device_index: usize, size: usize) -> Result<Self> {
+        init()?;
+        let count = Device::num_devices().context("Failed to count CUDA devices")?;
+        if device_index >= count as usize {
+            bail!(
+                "CUDA device index {} is out of bounds ({} device(s) found)",
+                device_index,
+                count
+            );
+        }
+        let device = Device::get_device(device_index as u32)
+            .with_context(|| format!("Failed to open CUDA device {}", device_index))?;
+        // Retains the primary context and makes it current on this thread
+        let context = Context::new(device).context("Failed to create CUDA context")?;
+        let buffer = DeviceBuffer::zeroed(size).context("Failed to allocate GPU memory")?;
+
+        log::info!(
+            "Created CUDA buffer of size {} bytes on device: {}",
+            size,
+            device
+                .name()
+                .unwrap_or_else(|_| "Unknown device".to_string())
+        );
+
+        Ok(Self {
+            buffer,
+            context,
+            device,
+            size,
+        })
+    }
+
+    /// Get the buffer size in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Read data from the GPU buffer
+    pub fn read(&self, offset: usize, data: &mut [u8]) -> Result<()> {
+        if offset + data.len() > self.size {
+            bail!("Attempted to read past end of buffer");
+        }
+        self.make_current()?;
+        self.buffer
+            .index(offset..offset + data.len())
+            .copy_to(data)
+            .context("CUDA read from buffer failed")
+    }
+
+    /// Write data to the GPU buffer
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<()> {
+        if offset + data.len() > self.size {
+            bail!("Attempted to write past end of buffer");
+        }
+        self.make_current()?;
+        self.buffer
+            .index(offset..offset + data.len())
+            .copy_from(data)
+            .context("CUDA write to buffer failed")
+    }
+
+    /// Zero a range on the device without transferring data from the host
+    pub fn fill_zeroes(&self, offset: usize, len: usize) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to fill past end of buffer");
+        }
+        self.make_current()?;
+        self.buffer
+            .index(offset..offset + len)
+            .set_8(0)
+            .context("CUDA buffer fill failed")
+    }
+
+    /// Wait for all outstanding work on the device to complete
+    pub fn finish(&self) -> Result<()> {
+        self.make_current()?;
+        CurrentContext::synchronize().context("CUDA synchronize failed")
+    }
+
+    /// Get the device name
+    pub fn device_name(&self) -> String {
+        self.device
+            .name()
+            .unwrap_or_else(|_| "Unknown device".to_string())
+    }
+
+    fn make_current(&self) -> Result<()> {
+        CurrentContext::set_current(&self.context).context("Failed to make CUDA context current")
+    }
+}
+
+impl Drop for CudaBuffer {
+    fn drop(&mut self) {
+        // The allocation is freed in whatever context is current
+        let _ = CurrentContext::set_current(&self.context);
+        log::debug!("Freeing CUDA memory buffer");
+    }
+}
@@ -0,0 +1,11 @@
+//! CUDA module for GPU memory allocation on NVIDIA hardware
+//!
+//! An alternative to the OpenCL module using the CUDA driver API, which on
+//! NVIDIA GPUs often allows larger allocations and faster host transfers
+//! than the OpenCL ICD.
+
+mod device;
+mod memory;
+
+pub use device::cuda_devices;
+pub use memory::CudaBuffer;
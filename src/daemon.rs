@@ -0,0 +1,145 @@
+//! Running in the background
+//!
+//! With `--daemonize`, the process forks before anything else is set up: a
+//! fork only carries over the calling thread, and memory locked with
+//! `mlockall` is not locked in the child, so the runtime, the GPU context
+//! and the memory lock all have to be created in the daemon itself. The
+//! child starts a new session and detaches from the terminal. The parent
+//! stays in the foreground until the child reports over a pipe that the
+//! device is being served, then exits 0; if startup fails, it prints the
+//! child's error and exits 1. Scripts can therefore rely on the exit status.
+
+use anyhow::{Context, Result};
+use nix::unistd::{dup2, fork, pipe, setsid, ForkResult};
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Sent by the child once the device is served; anything else is an error
+const READY: u8 = b'R';
+
+/// The daemon's end of the startup pipe
+#[derive(Debug)]
+pub struct Readiness {
+    pipe: Mutex<Option<File>>,
+    pid_file: Option<PathBuf>,
+}
+
+impl Readiness {
+    /// Tell the waiting parent that the device is served. Only the first
+    /// report counts.
+    pub fn ready(&self) {
+        if let Some(mut pipe) = self.pipe.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            let _ = pipe.write_all(&[READY]);
+        }
+    }
+
+    /// Pass a startup error to the parent, unless it was told the device is
+    /// ready already
+    pub fn fail(&self, error: &anyhow::Error) {
+        if let Some(mut pipe) = self.pipe.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            let _ = write!(pipe, "{:#}", error);
+        }
+    }
+}
+
+impl Drop for Readiness {
+    fn drop(&mut self) {
+        if let Some(path) = &self.pid_file
+            && let Err(e) = std::fs::remove_file(path)
+        {
+            log::warn!("Failed to remove pid file {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Fork into the background. Returns in the daemon only; the parent exits
+/// once the daemon reports readiness or fails.
+pub fn daemonize(pid_file: Option<&Path>) -> Result<Readiness> {
+    let (read_fd, write_fd) = pipe().context("Failed to create startup pipe")?;
+    let (mut from_child, to_parent) =
+        unsafe { (File::from_raw_fd(read_fd), File::from_raw_fd(write_fd)) };
+
+    match unsafe { fork() }.context("Failed to fork")? {
+        ForkResult::Parent { .. } => {
+            drop(to_parent);
+            let mut report = Vec::new();
+            let _ = from_child.read_to_end(&mut report);
+            match report.split_first() {
+                Some((&READY, _)) => std::process::exit(0),
+                Some(_) => eprintln!("Error: {}", String::from_utf8_lossy(&report)),
+                None => eprintln!("Error: daemon exited during startup; see its log"),
+            }
+            std::process::exit(1);
+        }
+        ForkResult::Child => {}
+    }
+    drop(from_child);
+
+    let mut readiness = Readiness {
+        pipe: Mutex::new(Some(to_parent)),
+        pid_file: None,
+    };
+    if let Err(e) = detach(pid_file) {
+        readiness.fail(&e);
+        return Err(e);
+    }
+    readiness.pid_file = pid_file.map(Path::to_path_buf);
+    Ok(readiness)
+}
+
+/// Leave the terminal's session, point the standard streams at /dev/null
+/// (logging goes to --log-file or syslog) and write the pid file
+fn detach(pid_file: Option<&Path>) -> Result<()> {
+    setsid().context("Failed to start a new session")?;
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+    for fd in 0..=2 {
+        dup2(null.as_raw_fd(), fd).context("Failed to redirect standard streams")?;
+    }
+    if let Some(path) = pid_file {
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Failed to write pid file {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Log writer that passes each line to syslog
+pub struct Syslog {
+    line: Vec<u8>,
+}
+
+impl Syslog {
+    /// Open the connection to syslog
+    pub fn open() -> Self {
+        unsafe { libc::openlog(c"vramblk".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+        Self { line: Vec::new() }
+    }
+}
+
+impl Write for Syslog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self
+                .line
+                .drain(..=end)
+                .filter(|&b| b != b'\n' && b != 0)
+                .collect();
+            if let Ok(message) = CString::new(line) {
+                unsafe { libc::syslog(libc::LOG_INFO, c"%s".as_ptr(), message.as_ptr()) };
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+//! Environment diagnostics for bug reports
+//!
+//! Gathers OpenCL platform/device details, memory locking limits and
+//! kernel ublk support into one report, printed as text or JSON.
+
+use anyhow::Result;
+use nix::sys::resource::{getrlimit, Resource};
+use opencl3::device::{get_device_ids, Device, CL_DEVICE_TYPE_GPU};
+use serde::Serialize;
+use std::path::Path;
+
+use crate::opencl::{device_free_memory, platforms};
+
+/// Linux capability number of `CAP_IPC_LOCK`
+const CAP_IPC_LOCK: u32 = 14;
+
+#[derive(Debug, Serialize)]
+struct Diagnostics {
+    version: &'static str,
+    kernel: Option<String>,
+    memlock: MemlockInfo,
+    ublk: UblkInfo,
+    opencl: OpenClInfo,
+}
+
+#[derive(Debug, Serialize)]
+struct MemlockInfo {
+    /// Soft RLIMIT_MEMLOCK in bytes (`None` = unlimited)
+    soft_limit: Option<u64>,
+    /// Hard RLIMIT_MEMLOCK in bytes (`None` = unlimited)
+    hard_limit: Option<u64>,
+    has_cap_ipc_lock: Option<bool>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct UblkInfo {
+    module_loaded: bool,
+    control_device: bool,
+    control_device_accessible: bool,
+    driver_features: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenClInfo {
+    icd_files: Vec<String>,
+    platforms: Vec<PlatformInfo>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PlatformInfo {
+    index: usize,
+    name: Option<String>,
+    vendor: Option<String>,
+    version: Option<String>,
+    devices: Vec<DeviceInfo>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct DeviceInfo {
+    index: usize,
+    name: Option<String>,
+    vendor: Option<String>,
+    version: Option<String>,
+    driver_version: Option<String>,
+    opencl_c_version: Option<String>,
+    global_mem_size: Option<u64>,
+    max_mem_alloc_size: Option<u64>,
+    free_memory: Option<u64>,
+    extensions: Vec<String>,
+}
+
+/// Run the `diag` subcommand: gather the report and print it.
+pub fn run_diag(json: bool) -> Result<()> {
+    let diag = gather();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diag)?);
+        return Ok(());
+    }
+
+    println!("vramblk {}", diag.version);
+    println!("Kernel: {}", diag.kernel.as_deref().unwrap_or("unknown"));
+
+    println!("\nMemory locking:");
+    match &diag.memlock.error {
+        Some(e) => println!("  RLIMIT_MEMLOCK: error: {}", e),
+        None => println!(
+            "  RLIMIT_MEMLOCK: soft {}, hard {}",
+            format_limit(diag.memlock.soft_limit),
+            format_limit(diag.memlock.hard_limit)
+        ),
+    }
+    println!(
+        "  CAP_IPC_LOCK: {}",
+        match diag.memlock.has_cap_ipc_lock {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "unknown",
+        }
+    );
+
+    println!("\nublk:");
+    println!("  ublk_drv loaded: {}", yes_no(diag.ublk.module_loaded));
+    println!(
+        "  /dev/ublk-control: {}{}",
+        if diag.ublk.control_device {
+            "present"
+        } else {
+            "missing"
+        },
+        if diag.ublk.control_device && !diag.ublk.control_device_accessible {
+            " (not accessible by this user)"
+        } else {
+            ""
+        }
+    );
+    if let Some(features) = diag.ublk.driver_features {
+        println!("  driver features: 0x{:x}", features);
+    }
+
+    println!("\nOpenCL ICD files:");
+    if diag.opencl.icd_files.is_empty() {
+        println!("  none found in /etc/OpenCL/vendors");
+    }
+    for icd in &diag.opencl.icd_files {
+        println!("  {}", icd);
+    }
+    println!();
+
+    // Summary listing, then the extended per-device details
+    if let Err(e) = crate::list_opencl_devices() {
+        println!("Error listing OpenCL devices: {:#}", e);
+    }
+
+    for platform in &diag.opencl.platforms {
+        for device in &platform.devices {
+            println!(
+                "\nPlatform {} ({}) Device {} details:",
+                platform.index,
+                platform.version.as_deref().unwrap_or("unknown version"),
+                device.index
+            );
+            println!(
+                "  Version: {}",
+                device.version.as_deref().unwrap_or("unknown")
+            );
+            println!(
+                "  Driver version: {}",
+                device.driver_version.as_deref().unwrap_or("unknown")
+            );
+            println!(
+                "  OpenCL C version: {}",
+                device.opencl_c_version.as_deref().unwrap_or("unknown")
+            );
+            println!("  Global memory: {}", format_bytes(device.global_mem_size));
+            println!(
+                "  Max alloc size: {}",
+                format_bytes(device.max_mem_alloc_size)
+            );
+            println!("  Free memory: {}", format_bytes(device.free_memory));
+            println!("  Extensions: {}", device.extensions.join(" "));
+        }
+    }
+
+    Ok(())
+}
+
+fn gather() -> Diagnostics {
+    Diagnostics {
+        version: env!("CARGO_PKG_VERSION"),
+        kernel: nix::sys::utsname::uname()
+            .ok()
+            .map(|u| u.release().to_string_lossy().into_owned()),
+        memlock: gather_memlock(),
+        ublk: gather_ublk(),
+        opencl: gather_opencl(),
+    }
+}
+
+fn gather_memlock() -> MemlockInfo {
+    let to_limit = |v: u64| (v != libc::RLIM_INFINITY).then_some(v);
+    let has_cap_ipc_lock = effective_capabilities().map(|caps| caps & (1 << CAP_IPC_LOCK) != 0);
+
+    match getrlimit(Resource::RLIMIT_MEMLOCK) {
+        Ok((soft, hard)) => MemlockInfo {
+            soft_limit: to_limit(soft),
+            hard_limit: to_limit(hard),
+            has_cap_ipc_lock,
+            error: None,
+        },
+        Err(e) => MemlockInfo {
+            soft_limit: None,
+            hard_limit: None,
+            has_cap_ipc_lock,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+/// Parse the effective capability mask from /proc/self/status
+fn effective_capabilities() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|hex| u64::from_str_radix(hex.trim(), 16).ok())
+}
+
+fn gather_ublk() -> UblkInfo {
+    let control = Path::new("/dev/ublk-control");
+    let control_device = control.exists();
+    let control_device_accessible = control_device
+        && std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(control)
+            .is_ok();
+
+    UblkInfo {
+        module_loaded: Path::new("/sys/module/ublk_drv").exists(),
+        control_device,
+        control_device_accessible,
+        driver_features: if control_device_accessible {
+            libublk::ctrl::UblkCtrl::get_features()
+        } else {
+            None
+        },
+    }
+}
+
+fn gather_opencl() -> OpenClInfo {
+    let mut icd_files: Vec<String> = std::fs::read_dir("/etc/OpenCL/vendors")
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path().display().to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+    icd_files.sort();
+
+    let platforms = match platforms() {
+        Ok(platforms) => platforms,
+        Err(e) => {
+            return OpenClInfo {
+                icd_files,
+                platforms: Vec::new(),
+                error: Some(format!("{:#}", e)),
+            };
+        }
+    };
+
+    let platforms = platforms
+        .iter()
+        .enumerate()
+        .map(|(plat_idx, platform)| {
+            let (devices, error) = match get_device_ids(platform.id(), CL_DEVICE_TYPE_GPU) {
+                Ok(ids) => (
+                    ids.iter()
+                        .enumerate()
+                        .map(|(dev_idx, id)| gather_device(dev_idx, &Device::new(*id)))
+                        .collect(),
+                    None,
+                ),
+                Err(e) => (Vec::new(), Some(e.to_string())),
+            };
+            PlatformInfo {
+                index: plat_idx,
+                name: platform.name().ok(),
+                vendor: platform.vendor().ok(),
+                version: platform.version().ok(),
+                devices,
+                error,
+            }
+        })
+        .collect();
+
+    OpenClInfo {
+        icd_files,
+        platforms,
+        error: None,
+    }
+}
+
+fn gather_device(index: usize, device: &Device) -> DeviceInfo {
+    DeviceInfo {
+        index,
+        name: device.name().ok(),
+        vendor: device.vendor().ok(),
+        version: device.version().ok(),
+        driver_version: device.driver_version().ok(),
+        opencl_c_version: device.opencl_c_version().ok(),
+        global_mem_size: device.global_mem_size().ok(),
+        max_mem_alloc_size: device.max_mem_alloc_size().ok(),
+        free_memory: device_free_memory(device),
+        extensions: device
+            .extensions()
+            .map(|e| e.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default(),
+    }
+}
+
+fn format_limit(limit: Option<u64>) -> String {
+    match limit {
+        Some(bytes) => format!("{} bytes ({} KB)", bytes, bytes / 1024),
+        None => "unlimited".to_string(),
+    }
+}
+
+fn format_bytes(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(bytes) => format!("{} bytes ({} MB)", bytes, bytes / (1024 * 1024)),
+        None => "unknown".to_string(),
+    }
+}
+
+fn yes_no(v: bool) -> &'static str {
+    if v {
+        "yes"
+    } else {
+        "no"
+    }
+}
@@ -0,0 +1,426 @@
+//! FUSE frontend: the device as a single file
+//!
+//! With `--driver fuse` the backend is served as one regular file,
+//! `disk.img`, in a FUSE filesystem mounted on `--mountpoint`. It needs
+//! neither ublk nor an NBD client: the file can be attached with `losetup`
+//! or used directly. The file is opened in direct I/O mode, so every read
+//! and write goes to the backend rather than the page cache, and other
+//! frontends serving the same device stay coherent with it. Its size is the
+//! device size and can't be changed; hole punching and zero-range
+//! `fallocate` calls (which a loop device sends for discards) are passed
+//! on as discards and write-zeroes.
+
+use crate::backend::{is_no_space, is_read_only, BlockBackend};
+use crate::daemon::Readiness;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use anyhow::{bail, Context, Result};
+use fuser::{
+    Config, Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, Generation, INodeNo,
+    LockOwner, MountOption, OpenAccMode, OpenFlags, ReplyAttr, ReplyData, ReplyDirectory,
+    ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, Session, TimeOrNow, WriteFlags,
+};
+use nix::mount::{umount2, MntFlags};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio_util::sync::CancellationToken;
+
+/// Name of the file holding the device
+pub const FUSE_FILE_NAME: &str = "disk.img";
+
+/// How long the kernel may cache attributes and the directory entry
+const TTL: Duration = Duration::from_secs(1);
+
+const FILE_INO: INodeNo = INodeNo(2);
+
+/// Configuration for the FUSE frontend
+#[derive(Debug, Clone)]
+pub struct FuseConfig {
+    /// Directory the filesystem is mounted on
+    pub mountpoint: PathBuf,
+    /// Serve the file read-only
+    pub read_only: bool,
+    /// How long shutdown waits for users of the file to close it
+    pub shutdown_grace: Duration,
+    /// Told once the filesystem is mounted and any other frontend is up,
+    /// when running as a daemon
+    pub ready: Option<Arc<Readiness>>,
+    /// Counters updated as requests complete
+    pub metrics: Arc<Metrics>,
+    /// Marked ready once the filesystem is mounted
+    pub health: Arc<Health>,
+}
+
+/// The filesystem: a root directory holding the device file
+struct DeviceFs<B: ?Sized> {
+    backend: Arc<B>,
+    read_only: bool,
+    metrics: Arc<Metrics>,
+    uid: u32,
+    gid: u32,
+    mounted_at: SystemTime,
+}
+
+impl<B: BlockBackend + ?Sized> DeviceFs<B> {
+    fn attr(&self, ino: INodeNo) -> FileAttr {
+        let (kind, size, perm, nlink) = if ino == FILE_INO {
+            let perm = if self.read_only { 0o400 } else { 0o600 };
+            (FileType::RegularFile, self.backend.size(), perm, 1)
+        } else {
+            (FileType::Directory, 0, 0o755, 2)
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mounted_at,
+            mtime: self.mounted_at,
+            ctime: self.mounted_at,
+            crtime: self.mounted_at,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+
+    /// Whether `len` bytes at `offset` lie inside the file
+    fn in_bounds(&self, offset: u64, len: u64) -> bool {
+        offset
+            .checked_add(len)
+            .is_some_and(|end| end <= self.backend.size())
+    }
+
+    /// Errno for a failed backend write, write-zeroes or discard
+    fn write_errno(&self, e: &anyhow::Error) -> Errno {
+        self.metrics.record_error();
+        if is_read_only(e) {
+            Errno::EROFS
+        } else if is_no_space(e) {
+            Errno::ENOSPC
+        } else {
+            Errno::EIO
+        }
+    }
+}
+
+impl<B: BlockBackend + ?Sized + 'static> Filesystem for DeviceFs<B> {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        if parent == INodeNo::ROOT && name == FUSE_FILE_NAME {
+            reply.entry(&TTL, &self.attr(FILE_INO), Generation(0));
+        } else {
+            reply.error(Errno::ENOENT);
+        }
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        if ino == INodeNo::ROOT || ino == FILE_INO {
+            reply.attr(&TTL, &self.attr(ino));
+        } else {
+            reply.error(Errno::ENOENT);
+        }
+    }
+
+    fn setattr(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<FileHandle>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<fuser::BsdFileFlags>,
+        reply: ReplyAttr,
+    ) {
+        // The size is the device's; timestamps are accepted and ignored
+        if size.is_some_and(|size| ino != FILE_INO || size != self.backend.size()) {
+            reply.error(Errno::EINVAL);
+        } else if mode.is_some() || uid.is_some() || gid.is_some() {
+            reply.error(Errno::EPERM);
+        } else {
+            reply.attr(&TTL, &self.attr(ino));
+        }
+    }
+
+    fn open(&self, _req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        if ino != FILE_INO {
+            reply.error(Errno::EISDIR);
+        } else if self.read_only && flags.acc_mode() != OpenAccMode::O_RDONLY {
+            reply.error(Errno::EROFS);
+        } else {
+            reply.opened(FileHandle(0), FopenFlags::FOPEN_DIRECT_IO);
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyData,
+    ) {
+        if ino != FILE_INO {
+            reply.error(Errno::EISDIR);
+            return;
+        }
+        // Reads stop at the end of the file
+        let len = self.backend.size().saturating_sub(offset).min(size as u64);
+        let mut buf = vec![0u8; len as usize];
+        match self.backend.read_at(offset, &mut buf) {
+            Ok(()) => {
+                self.metrics.record_read(len);
+                reply.data(&buf);
+            }
+            Err(_) => {
+                self.metrics.record_error();
+                reply.error(Errno::EIO);
+            }
+        }
+    }
+
+    fn write(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        data: &[u8],
+        _write_flags: WriteFlags,
+        _flags: OpenFlags,
+        _lock_owner: Option<LockOwner>,
+        reply: ReplyWrite,
+    ) {
+        if ino != FILE_INO {
+            reply.error(Errno::EISDIR);
+        } else if self.read_only {
+            reply.error(Errno::EROFS);
+        } else if !self.in_bounds(offset, data.len() as u64) {
+            // The file can't grow; refuse rather than write part of it
+            reply.error(Errno::ENOSPC);
+        } else {
+            match self.backend.write_at(offset, data) {
+                Ok(()) => {
+                    self.metrics.record_write(data.len() as u64);
+                    reply.written(data.len() as u32);
+                }
+                Err(e) => reply.error(self.write_errno(&e)),
+            }
+        }
+    }
+
+    fn flush(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _lock_owner: LockOwner,
+        reply: ReplyEmpty,
+    ) {
+        // Writes are complete when they return; fsync commits buffered ones
+        reply.ok();
+    }
+
+    fn fsync(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.backend.flush() {
+            Ok(()) => {
+                self.metrics.record_flush();
+                reply.ok();
+            }
+            Err(_) => {
+                self.metrics.record_error();
+                reply.error(Errno::EIO);
+            }
+        }
+    }
+
+    fn fallocate(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        length: u64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        if ino != FILE_INO {
+            reply.error(Errno::EISDIR);
+            return;
+        }
+        if !self.in_bounds(offset, length) {
+            reply.error(if mode & libc::FALLOC_FL_KEEP_SIZE != 0 {
+                Errno::EINVAL
+            } else {
+                Errno::EFBIG
+            });
+            return;
+        }
+        let result = match mode & !libc::FALLOC_FL_KEEP_SIZE {
+            // Preallocating: every byte of the file is always there
+            0 => Ok(()),
+            libc::FALLOC_FL_PUNCH_HOLE if self.read_only => {
+                return reply.error(Errno::EROFS);
+            }
+            libc::FALLOC_FL_PUNCH_HOLE => self.backend.discard_at(offset, length),
+            libc::FALLOC_FL_ZERO_RANGE if self.read_only => {
+                return reply.error(Errno::EROFS);
+            }
+            libc::FALLOC_FL_ZERO_RANGE => self.backend.write_zeroes_at(offset, length),
+            _ => return reply.error(Errno::EOPNOTSUPP),
+        };
+        match result {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(self.write_errno(&e)),
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        _fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        if ino != INodeNo::ROOT {
+            reply.error(Errno::ENOTDIR);
+            return;
+        }
+        let entries = [
+            (INodeNo::ROOT, FileType::Directory, "."),
+            (INodeNo::ROOT, FileType::Directory, ".."),
+            (FILE_INO, FileType::RegularFile, FUSE_FILE_NAME),
+        ];
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // The offset passed back is that of the next entry
+            if reply.add(ino, (i + 1) as u64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mount `backend` as `disk.img` on `cfg.mountpoint` and serve it until
+/// `cancel` is cancelled or the filesystem is unmounted from outside
+pub async fn start_fuse_server<B>(
+    backend: Arc<B>,
+    cfg: FuseConfig,
+    cancel: CancellationToken,
+) -> Result<()>
+where
+    B: BlockBackend + ?Sized + 'static,
+{
+    if !cfg.mountpoint.is_dir() {
+        bail!(
+            "FUSE mountpoint {} is not a directory",
+            cfg.mountpoint.display()
+        );
+    }
+    let size = backend.size();
+    let fs = DeviceFs {
+        backend,
+        read_only: cfg.read_only,
+        metrics: cfg.metrics.clone(),
+        uid: nix::unistd::getuid().as_raw(),
+        gid: nix::unistd::getgid().as_raw(),
+        mounted_at: SystemTime::now(),
+    };
+    let mut options = Config::default();
+    options.mount_options = vec![
+        MountOption::FSName("vramblk".to_string()),
+        MountOption::Subtype("vramblk".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    if cfg.read_only {
+        options.mount_options.push(MountOption::RO);
+    }
+    // Requests are served on several threads, as with ublk queues
+    options.n_threads = Some(crate::ublk::ublk_queue_count() as usize);
+
+    let mountpoint = cfg.mountpoint.clone();
+    let (session, mut unmounter) = tokio::task::spawn_blocking(move || {
+        let mut session = Session::new(fs, &mountpoint, &options)?;
+        let unmounter = session.unmount_callable();
+        Ok::<_, std::io::Error>((session, unmounter))
+    })
+    .await?
+    .with_context(|| {
+        format!(
+            "Failed to mount FUSE filesystem on {}",
+            cfg.mountpoint.display()
+        )
+    })?;
+
+    let file = cfg.mountpoint.join(FUSE_FILE_NAME);
+    log::info!("FUSE: serving {} bytes as {}", size, file.display());
+    if cfg.health.frontend_ready() {
+        if let Some(ready) = &cfg.ready {
+            ready.ready();
+        }
+        crate::systemd::notify_ready();
+    }
+
+    let mut running = tokio::task::spawn_blocking(move || session.run());
+    tokio::select! {
+        result = &mut running => {
+            log::warn!("FUSE: {} was unmounted from outside", cfg.mountpoint.display());
+            return result?.context("FUSE session failed");
+        }
+        _ = cancel.cancelled() => {}
+    }
+
+    log::info!(
+        "FUSE: shutdown requested, unmounting {}",
+        cfg.mountpoint.display()
+    );
+    if let Err(e) = unmounter.unmount() {
+        // Busy, e.g. a loop device still holds the file: detach the mount
+        // now, and the session ends once the last user closes the file
+        log::warn!(
+            "FUSE: {} is busy ({}); detaching it",
+            cfg.mountpoint.display(),
+            e
+        );
+        umount2(&cfg.mountpoint, MntFlags::MNT_DETACH)
+            .with_context(|| format!("Failed to unmount {}", cfg.mountpoint.display()))?;
+    }
+    match tokio::time::timeout(cfg.shutdown_grace, &mut running).await {
+        Ok(result) => result?.context("FUSE session failed")?,
+        Err(_) => {
+            // Exiting closes /dev/fuse, which fails whatever is still queued
+            log::warn!(
+                "FUSE: shutdown grace period of {:?} expired with {} still open; aborting the session",
+                cfg.shutdown_grace,
+                file.display()
+            );
+            return Ok(());
+        }
+    }
+    log::info!("FUSE: unmounted {}", cfg.mountpoint.display());
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Health check endpoint for liveness and readiness probes
+//!
+//! With `--health-addr`, a small HTTP server answers `GET /healthz`:
+//! `503 starting` until the device is served (the NBD listener accepts
+//! connections and the ublk device is up, whichever are in use), `200 ok`
+//! from then on, and
+//! `503 failing` while the backend is failing every read and write, and
+//! `503 device lost` for good once the GPU context is gone (a driver reset
+//! or device hang): only a restart, which restores the device from
+//! `--mirror-file`, `--backing-file` or `--persist-file`, brings it back.
+//! The state is a few atomics, so probing is cheap.
+//!
+//! Failures are counted just above the GPU buffer by `HealthBackend`, so
+//! requests refused higher up, such as writes to a read-only device or past
+//! `--write-budget`, don't make the device look broken.
+
+use crate::metrics::spawn_http_server;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Response, StatusCode};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Consecutive failed reads and writes after which the backend counts as failing
+pub const FAILURE_THRESHOLD: u64 = 8;
+
+/// Whether the device is served and its backend still works
+#[derive(Debug, Default)]
+pub struct Health {
+    ready: AtomicBool,
+    /// Frontends not up yet; 0 counts as the one
+    starting: AtomicUsize,
+    failures: AtomicU64,
+    /// Cancelled once the GPU device is lost
+    lost: CancellationToken,
+}
+
+impl Health {
+    /// The device will be served by `count` frontends at once
+    pub fn expect_frontends(&self, count: usize) {
+        self.starting.store(count, Ordering::Relaxed);
+    }
+
+    /// A frontend is up. Returns whether it was the last one, so the device
+    /// is now served and readiness should be reported.
+    pub fn frontend_ready(&self) -> bool {
+        let before = self
+            .starting
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_sub(1))
+            })
+            .unwrap_or_default();
+        if before > 1 {
+            return false;
+        }
+        self.ready.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// A backend read or write succeeded
+    pub fn record_success(&self) {
+        if self.failures.load(Ordering::Relaxed) != 0 {
+            self.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// A backend read or write failed
+    pub fn record_failure(&self) {
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 == FAILURE_THRESHOLD {
+            log::error!(
+                "The last {} backend reads and writes failed; reporting unhealthy",
+                FAILURE_THRESHOLD
+            );
+        }
+    }
+
+    /// The GPU device was lost; this is permanent
+    pub fn set_device_lost(&self) {
+        if !self.lost.is_cancelled() {
+            log::error!("GPU device lost; reporting unhealthy until restarted");
+            self.lost.cancel();
+        }
+    }
+
+    /// Whether the GPU device was lost
+    pub fn is_device_lost(&self) -> bool {
+        self.lost.is_cancelled()
+    }
+
+    /// Wait until the GPU device is lost
+    pub async fn device_lost(&self) {
+        self.lost.cancelled().await
+    }
+
+    /// HTTP status and body of the health check
+    fn status(&self) -> (StatusCode, &'static str) {
+        if self.is_device_lost() {
+            (StatusCode::SERVICE_UNAVAILABLE, "device lost\n")
+        } else if self.failures.load(Ordering::Relaxed) >= FAILURE_THRESHOLD {
+            (StatusCode::SERVICE_UNAVAILABLE, "failing\n")
+        } else if self.ready.load(Ordering::Relaxed) {
+            (StatusCode::OK, "ok\n")
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, "starting\n")
+        }
+    }
+}
+
+/// Serve `health` over HTTP on `addr` until `cancel` is cancelled. A bind
+/// failure is logged as a warning and the task ends.
+pub fn spawn_health_server(
+    addr: String,
+    health: Arc<Health>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    spawn_http_server("health checks", "/healthz", addr, cancel, move || {
+        let (status, body) = health.status();
+        let mut response = Response::new(Full::new(Bytes::from_static(body.as_bytes())));
+        *response.status_mut() = status;
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        response
+    })
+}
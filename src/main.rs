@@ -5,76 +5,674 @@
 //! It attempts to lock its memory to prevent being swapped out.
 
 mod backend;
+mod bench;
+mod config;
+mod control;
+#[cfg(feature = "cuda")]
+mod cuda;
+mod daemon;
+mod diag;
+#[cfg(feature = "fuse")]
+mod fuse;
+mod health;
+mod metrics;
 mod nbd;
+mod numa;
 mod opencl;
+mod retry;
+mod selftest;
+mod snapshot;
+mod systemd;
+mod trace;
 mod ublk;
+#[cfg(feature = "vulkan")]
+mod vulkan;
 
-use crate::nbd::{start_nbd_server, NbdConfig};
-use crate::opencl::{VRamBuffer, VRamBufferConfig};
-use crate::ublk::{start_ublk_server, UblkConfig};
+use crate::backend::{
+    hash_backend, parse_stripe_ratio, Allocator, BadBlockRemapBackend, BlockBackend, CacheBackend,
+    CacheMode, ChecksumAlgorithm, ChecksumBackend, CompressedBackend, Compression,
+    EncryptedBackend, FileBackend, HashAlgorithm, HealthBackend, HybridStripeBackend,
+    LogicalSizeBackend, MirrorBackend, PersistentBackend, Qcow2Backend, RamBuffer,
+    RangeLockBackend, ReadaheadBackend, ResizableBackend, SparseFileBackend, StripeRatio,
+    StripedBackend, WriteBudgetBackend, WriteCombineBackend, ZeroMapBackend, SPARSE_BLOCK_SIZE,
+    STRIPE_UNIT,
+};
+use crate::bench::{run_bench, BenchConfig};
+use crate::config::merge_config_file;
+use crate::control::{spawn_control_server, ControlState};
+use crate::daemon::{daemonize, Readiness, Syslog};
+#[cfg(feature = "fuse")]
+use crate::fuse::{start_fuse_server, FuseConfig};
+use crate::health::{spawn_health_server, Health};
+use crate::metrics::{spawn_metrics_server, Metrics};
+use crate::nbd::{check_nbd_config, start_nbd_server, NbdConfig, NbdExport, NbdTls, NbdTransport};
+use crate::numa::{bind_memory, parse_numa_node, pci_device_node, NumaNode};
+use crate::opencl::{
+    auto_select_device, contexts_in_use, device_available_memory, device_pci_address,
+    find_device_by_name, platforms, OpenClUnavailable, QueueLayout, QueueTopology, VRamBuffer,
+    VRamBufferConfig,
+};
+use crate::retry::RetryPolicy;
+use crate::selftest::{run_self_test, SelfTestConfig, TestPattern};
+use crate::snapshot::{spawn_snapshots, SnapshotCompression};
+use crate::trace::TraceBackend;
+use crate::ublk::{start_ublk_server, ublk_queue_count, UblkConfig, UBLK_DEFAULT_DEPTH};
 use tokio_util::sync::CancellationToken;
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, ValueEnum};
-use opencl3::{
-    device::{get_device_ids, Device, CL_DEVICE_TYPE_GPU},
-    platform::get_platforms,
-};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use opencl3::device::{get_device_ids, Device, CL_DEVICE_TYPE_GPU};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use zeroize::Zeroizing;
 // Correct import name: MlockAllFlags
 use nix::sys::mman::{mlockall, MlockAllFlags};
+use nix::sys::resource::{getrlimit, setrlimit, Resource};
 
 //// Frontend driver selection
-#[derive(Copy, Clone, Debug, ValueEnum)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
 pub enum Driver {
     /// Network Block Device (existing implementation)
     Nbd,
+    /// NBD tunneled over WebSocket (requires the `websocket` feature)
+    NbdWs,
     /// Userspace Block (ublk) using libublk
     Ublk,
+    /// A single file in a FUSE filesystem (requires the `fuse` feature)
+    Fuse,
+}
+
+/// Where the device's data is stored
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum StorageBackend {
+    /// GPU memory allocated through OpenCL
+    Opencl,
+    /// Plain host RAM; for testing without a GPU
+    Mem,
+}
+
+/// GPU compute API used to allocate device memory
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum GpuApi {
+    /// OpenCL, for any vendor
+    Opencl,
+    /// CUDA driver API, for NVIDIA GPUs (requires the `cuda` feature)
+    Cuda,
+    /// Vulkan, for GPUs without good OpenCL drivers (requires the `vulkan` feature)
+    Vulkan,
+}
+
+/// Output format of --list-devices
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum ListFormat {
+    /// Human-readable listing
+    Text,
+    /// JSON array of platforms and their devices
+    Json,
+}
+
+/// A `--device` entry
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceChoice {
+    /// Device index on the platform
+    Index(usize),
+    /// The GPU with the most headroom for the allocation
+    Auto,
+}
+
+/// An `--export` entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportSpec {
+    pub name: String,
+    pub size: u64,
+    /// Simultaneous connections allowed (`None` = unlimited)
+    pub max_connections: Option<usize>,
+    /// Only one connection may write
+    pub single_writer: bool,
+}
+
+/// A `--size`: bytes, or a share of the GPU's available memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSize {
+    Bytes(u64),
+    /// Percent of the selected GPU's free (or global) memory
+    Percent(u64),
+}
+
+/// Layout of the data stored in the GPU buffer
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum ImageFormat {
+    /// Expose the buffer bytes directly
+    Raw,
+    /// Interpret the buffer as a qcow2 image and expose its virtual disk
+    Qcow2,
+}
+
+/// Auxiliary subcommands that run instead of the block device server
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Dump OpenCL, memory locking and ublk diagnostics for bug reports
+    Diag {
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Replay a trace recorded with --capture-trace and report failed requests
+    Replay {
+        /// Trace file to replay
+        #[arg(long)]
+        trace: PathBuf,
+        /// Replay against GPU memory (--size, --device, --platform) instead of host RAM
+        #[arg(long)]
+        gpu: bool,
+        /// Wait between requests as recorded instead of replaying back to back
+        #[arg(long)]
+        timing: bool,
+    },
+    /// Measure read and write throughput of the buffer (--size, --backend,
+    /// --device) directly, without NBD or ublk
+    Bench {
+        /// Bytes per request (e.g., 4K, 1M)
+        #[arg(long, value_parser = parse_size_string, default_value = "1M")]
+        block_size: u64,
+        /// How long each pass runs (e.g., 5s, 500ms)
+        #[arg(long, value_parser = parse_duration_string, default_value = "5s")]
+        duration: Duration,
+        /// Requests in flight at once, each from its own thread
+        #[arg(long, default_value_t = 4)]
+        queue_depth: usize,
+        /// Print the results as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 /// Command line arguments for the VRAM Block Device
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(
     name = "vramblk",
     about = "Expose GPU memory as a block device using a NBD server. Locks memory using mlockall.",
     version
 )]
 struct Args {
-    /// Size of the block device (e.g., 512M, 2G, 1024). Defaults to MB if no suffix.
-    #[arg(short, long, value_parser = parse_size_string, default_value = "2048M")]
+    /// Size of the block device (e.g., 512M, 2G, 1024), or a percentage of
+    /// the GPU's free memory (e.g., 90%). Defaults to MiB if no suffix.
+    #[arg(
+        short = 's',
+        long = "size",
+        id = "size",
+        value_name = "SIZE",
+        value_parser = parse_device_size,
+        default_value = "2048M"
+    )]
+    size_spec: DeviceSize,
+
+    /// --size in bytes, with a percentage resolved against the GPU
+    #[arg(skip)]
     size: u64, // Store size in bytes
 
-    /// GPU device index to use (0 for first GPU)
-    #[arg(short, long, default_value = "0")]
-    device: usize,
+    /// Storage for the device: GPU memory, or host RAM for testing without a GPU
+    #[arg(long, value_enum, default_value_t = StorageBackend::Opencl)]
+    backend: StorageBackend,
+
+    /// GPU API used to allocate the device memory
+    #[arg(long, value_enum, default_value_t = GpuApi::Opencl)]
+    api: GpuApi,
+
+    /// GPU device index to use (0 for first GPU); a comma-separated list
+    /// stripes the device across several GPUs (RAID0); `auto` picks the GPU
+    /// with the most memory left over after the allocation
+    #[arg(short, long, value_delimiter = ',', default_value = "0", value_parser = parse_device)]
+    device: Vec<DeviceChoice>,
 
     /// OpenCL platform index
     #[arg(short, long, default_value = "0")]
     platform: usize,
 
-    /// Listen address for the NBD server (e.g., 127.0.0.1:10809 or [::1]:10809)
+    /// Use the GPU whose name contains this text (case-insensitive, on any
+    /// platform) instead of --device and --platform
+    #[arg(long, conflicts_with_all = ["device", "platform"])]
+    device_name: Option<String>,
+
+    /// Listen address for the NBD server (e.g., 127.0.0.1:10809 or [::1]:10809);
+    /// may be repeated to listen on several addresses
     #[arg(short, long, default_value = "127.0.0.1:10809")]
-    listen_addr: String,
+    listen_addr: Vec<String>,
+
+    /// Listen on this Unix domain socket instead of TCP (for same-host clients
+    /// such as qemu); a stale socket file is replaced and removed on exit
+    #[arg(long, conflicts_with = "listen_addr")]
+    unix_socket: Option<PathBuf>,
 
     /// Export name advertised over NBD
     #[arg(short, long, default_value = "vram")]
     export_name: String,
 
+    /// Additional NBD export with its own buffer, as NAME=SIZE (e.g., scratch=1G),
+    /// optionally followed by ",max-conn=N" and ",single-writer"; may be repeated
+    #[arg(long = "export", value_parser = parse_export_spec)]
+    exports: Vec<ExportSpec>,
+
+    /// Maximum simultaneous NBD connections to the main export (unlimited if unset)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_connections: Option<u64>,
+
+    /// Maximum simultaneous NBD connections to the server across all
+    /// exports, counted from accept; further ones are closed right away
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_clients: Option<u64>,
+
+    /// Export the device read-only (NBD clients and the ublk block device)
+    #[arg(long)]
+    read_only: bool,
+
+    /// Allow only one read-write NBD connection to the main export; additional
+    /// ones are served read-only
+    #[arg(long)]
+    single_writer: bool,
+
+    /// Advertise the device as rotational (a spinning disk) to NBD clients
+    /// and the ublk block device, for testing how they schedule I/O
+    #[arg(long)]
+    rotational: bool,
+
+    /// Serve the export for any requested NBD export name, not just --export-name
+    #[arg(long)]
+    default_export: bool,
+
+    /// Minimum (logical) block size in bytes advertised to NBD clients
+    #[arg(long, default_value = "512")]
+    min_block_size: u32,
+
+    /// Preferred block size in bytes advertised to NBD clients
+    #[arg(long, default_value = "4096")]
+    preferred_block_size: u32,
+
+    /// Largest NBD read/write request (e.g., 32M); advertised as the maximum block size
+    #[arg(long, value_parser = parse_size_string, default_value = "32M")]
+    max_io_size: u64,
+
+    /// Prefetch up to this much data (e.g., 4M) ahead of sequential NBD
+    /// reads, in two halves
+    #[arg(long, value_parser = parse_size_string)]
+    readahead: Option<u64>,
+
+    /// PEM certificate chain for NBD over TLS; clients must then upgrade with
+    /// STARTTLS (requires the `tls` feature)
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Follow each data chunk of a structured NBD read reply with a CRC32C of
+    /// the data, for clients that verify it (a vramblk extension)
+    #[arg(long)]
+    wire_checksum: bool,
+
     /// Enable verbose logging
     #[arg(short, long)]
     verbose: bool,
 
-    /// List available OpenCL platforms and devices and exit
+    /// List available GPU devices for the selected --api and exit
     #[arg(long)]
     list_devices: bool,
 
-    /// Frontend driver to use
-    #[arg(long, value_enum, default_value_t = Driver::Nbd)]
-    driver: Driver,
+    /// Output format of --list-devices
+    #[arg(long, value_enum, default_value_t = ListFormat::Text, requires = "list_devices")]
+    format: ListFormat,
+
+    /// Frontend driver(s) to use; `nbd,ublk` (or `nbd-ws,ublk`) serves the
+    /// same device over NBD and as a local ublk device at once, and `fuse`
+    /// can be added to either
+    #[arg(
+        long,
+        alias = "frontend",
+        value_enum,
+        value_delimiter = ',',
+        default_values_t = [Driver::Nbd]
+    )]
+    driver: Vec<Driver>,
+
+    /// With --driver ublk, write the block device path (e.g., /dev/ublkb0) to
+    /// this file once the device is up; the file is removed on exit
+    #[arg(long)]
+    dev_path_file: Option<PathBuf>,
+
+    /// With --driver ublk, create a filesystem of this type (e.g., ext4, xfs)
+    /// with mkfs.<FSTYPE> once the device is up, unless it already holds one
+    #[arg(long, value_name = "FSTYPE")]
+    mkfs: Option<String>,
+
+    /// Let --mkfs reformat a device that already holds a filesystem
+    #[arg(long, requires = "mkfs")]
+    force_format: bool,
+
+    /// With --driver ublk, mount the device on this directory once it is up
+    /// (after --mkfs) and unmount it on shutdown
+    #[arg(long, value_name = "DIR")]
+    mount: Option<PathBuf>,
+
+    /// With --driver fuse, directory to mount the filesystem holding the
+    /// device file (disk.img) on
+    #[arg(long, value_name = "DIR")]
+    mountpoint: Option<PathBuf>,
+
+    /// With --driver ublk, number of ublk queues, each served by its own
+    /// thread (defaults to one per CPU, at most 8)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=libublk::sys::UBLK_MAX_NR_QUEUES as i64))]
+    ublk_queues: Option<u16>,
+
+    /// With --driver ublk, requests each ublk queue can have in flight
+    /// (defaults to 64)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=libublk::sys::UBLK_MAX_QUEUE_DEPTH as i64))]
+    ublk_depth: Option<u16>,
+
+    /// Layout of the data in the GPU buffer
+    #[arg(long, value_enum, default_value_t = ImageFormat::Raw)]
+    image_format: ImageFormat,
+
+    /// Encrypt data in the GPU buffer with AES-256-XTS, keyed by the
+    /// passphrase in this file (a trailing newline is ignored)
+    #[arg(long, value_name = "PATH")]
+    encrypt_key_file: Option<PathBuf>,
+
+    /// Store data compressed in the GPU buffer, so a larger device fits when
+    /// the data compresses (writes fail with ENOSPC once the buffer is full)
+    #[arg(long, value_enum)]
+    compress: Option<Compression>,
+
+    /// Device size presented with --compress (e.g., 16G; defaults to --size)
+    #[arg(long, value_parser = parse_size_string, requires = "compress")]
+    compressed_size: Option<u64>,
+
+    /// Virtual disk size for a newly formatted qcow2 image (defaults to --size)
+    #[arg(long, value_parser = parse_size_string)]
+    virtual_size: Option<u64>,
+
+    /// TESTING ONLY: advertise this device size instead of the allocated size
+    /// (reads past the allocation return zeros, writes past it fail with ENOSPC)
+    #[arg(long, value_parser = parse_size_string)]
+    logical_size: Option<u64>,
+
+    /// Hold small writes this long to merge adjacent ones before committing
+    /// them to the GPU (e.g., 200us, 1ms)
+    #[arg(long, value_parser = parse_duration_string)]
+    write_combine_delay: Option<Duration>,
+
+    /// Switch to read-only after this many bytes have been written (e.g., 512M, 2G)
+    #[arg(long, value_parser = parse_size_string)]
+    write_budget: Option<u64>,
+
+    /// Switch to read-only this long after startup (e.g., 90s, 30m, 2h)
+    #[arg(long, value_parser = parse_duration_string)]
+    write_window: Option<Duration>,
+
+    /// Chunk size when striping across several GPUs (e.g., 512K, 1M)
+    #[arg(long, value_parser = parse_size_string, default_value = "512K")]
+    stripe_chunk: u64,
+
+    /// Stripe the device across VRAM and host RAM in this VRAM:RAM ratio (e.g., 3:1)
+    #[arg(long, value_parser = parse_stripe_ratio)]
+    hybrid_ratio: Option<StripeRatio>,
+
+    /// Reserve this many 4 KiB blocks at the end of the buffer as spares for bad-block remapping
+    #[arg(long, default_value = "0")]
+    spare_blocks: u64,
+
+    /// Known bad 4 KiB blocks to remap at startup (comma-separated block numbers)
+    #[arg(long, value_delimiter = ',')]
+    bad_blocks: Vec<u64>,
+
+    /// Load the bad-block remap table from this file at startup and save it
+    /// on every remap (default: <FILE>.remap next to --backing-file,
+    /// --mirror-file, --persist-file or --cache-backing, if given)
+    #[arg(long, value_name = "PATH", requires = "spare_blocks")]
+    remap_table: Option<PathBuf>,
+
+    /// Total attempts for GPU transfers failing with a transient error such
+    /// as CL_OUT_OF_RESOURCES (1 disables retries)
+    #[arg(long, default_value = "3", value_parser = clap::value_parser!(u32).range(1..))]
+    retry_attempts: u32,
+
+    /// Retries for GPU transfers failing with a transient error; the same as
+    /// --retry-attempts N+1
+    #[arg(long, value_name = "N", conflicts_with = "retry_attempts")]
+    io_retries: Option<u32>,
+
+    /// Delay before the first retry in milliseconds (doubles on each retry)
+    #[arg(long, default_value = "10")]
+    retry_base_delay: u64,
+
+    /// Longest delay between retries in milliseconds
+    #[arg(long, default_value = "1000")]
+    retry_max_delay: u64,
+
+    /// Command queue layout for GPU transfers (auto picks from device capabilities)
+    #[arg(long, value_enum, default_value_t = QueueLayout::Auto)]
+    queue_layout: QueueLayout,
+
+    /// Read/write command queue pairs per GPU, used in turn by concurrent
+    /// transfers (defaults to the number of ublk queues)
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    command_queues: Option<usize>,
+
+    /// Host buffer alignment in bytes for direct GPU transfers; misaligned
+    /// buffers are bounced (defaults to the device's base address alignment)
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    host_alignment: Option<usize>,
+
+    /// Transfer straight from client buffers instead of through pinned
+    /// staging buffers of --max-io-size bytes, one for reads and one for
+    /// writes per GPU buffer
+    #[arg(long)]
+    no_pinned_staging: bool,
+
+    /// Allocate the pinned write staging buffer as write-combining memory,
+    /// which speeds up sequential writes on some drivers (OpenCL only)
+    #[arg(long, conflicts_with = "no_pinned_staging")]
+    write_combine: bool,
+
+    /// Allocate every buffer on a GPU, including those of --export, in one
+    /// OpenCL context with one set of command queues and pinned staging,
+    /// instead of a context per buffer (OpenCL only)
+    #[arg(long)]
+    shared_context: bool,
+
+    /// Record every request (op, offset, length, time) to this file for `vramblk replay`
+    #[arg(long)]
+    capture_trace: Option<PathBuf>,
+
+    /// Shut down with an error as soon as the GPU device is lost (driver
+    /// reset or hang), so a supervisor can restart and restore it
+    #[arg(long)]
+    exit_on_device_lost: bool,
+
+    /// Write every write through to this file as well; reads stay on the GPU.
+    /// The file is recreated zeroed at startup unless --mirror-restore is set
+    #[arg(long)]
+    mirror_file: Option<PathBuf>,
+
+    /// Load the device from the existing --mirror-file at startup instead of
+    /// starting empty
+    #[arg(long, requires = "mirror_file")]
+    mirror_restore: bool,
+
+    /// Like --mirror-file, but to a sparse container file that only grows
+    /// with the data written; the device is loaded from it at startup if it
+    /// exists and created empty otherwise
+    #[arg(long, value_name = "PATH", conflicts_with = "mirror_file")]
+    backing_file: Option<PathBuf>,
+
+    /// Serve this file or block device, using the --size bytes of VRAM as a
+    /// block cache in front of it; the device takes the size of the file
+    #[arg(long, value_name = "PATH")]
+    cache_backing: Option<PathBuf>,
+
+    /// When writes reach --cache-backing: before completing (writethrough),
+    /// or when evicted from the cache and on flush (writeback)
+    #[arg(long, value_enum, default_value_t = CacheMode::Writethrough, requires = "cache_backing")]
+    cache_mode: CacheMode,
+
+    /// Discard everything in --backing-file, --mirror-file or --cache-backing
+    /// at startup, before anything is served: the device starts out zeroed
+    /// and the file's space is freed
+    #[arg(long)]
+    discard_all_on_start: bool,
+
+    /// Keep the device at this offset (e.g., 1M) into the existing file or
+    /// block device of --mirror-file or --cache-backing, to skip a header or
+    /// use one region of a disk
+    #[arg(long, value_parser = parse_size_string)]
+    backing_offset: Option<u64>,
+
+    /// Load the device from this file at startup (if it exists) and save it
+    /// back on graceful shutdown; the file must match --size exactly
+    #[arg(long)]
+    persist_file: Option<PathBuf>,
+
+    /// Copy the whole device to a snapshot file this often (e.g., 300 or 5m),
+    /// alternating between two files so one complete snapshot always remains
+    #[arg(long, value_parser = parse_interval_string)]
+    snapshot_interval: Option<Duration>,
+
+    /// Base path of the snapshot files; snapshots go to <PATH>.0 and <PATH>.1
+    #[arg(long, default_value = "snapshot")]
+    snapshot_path: PathBuf,
+
+    /// Compress snapshot files; compressed snapshots are detected and
+    /// decompressed when loaded with --persist-file
+    #[arg(long, value_enum, default_value_t = SnapshotCompression::None)]
+    snapshot_compress: SnapshotCompression,
+
+    /// Track which blocks have been written: reads of blocks never written
+    /// return zeros without touching the GPU, and NBD clients that negotiate
+    /// structured replies get them as holes. Needs a device that starts empty
+    #[arg(long, conflicts_with_all = [
+        "persist_file", "mirror_restore", "backing_file", "cache_backing", "encrypt_key_file",
+    ])]
+    track_allocation: bool,
+
+    /// Block size of --track-allocation; the bitmap takes one bit per block
+    #[arg(long, value_parser = parse_size_string, default_value = "64K", requires = "track_allocation")]
+    allocation_block_size: u64,
+
+    /// Serve Prometheus metrics over HTTP on this address (e.g., 127.0.0.1:9100);
+    /// the device keeps running if the address can't be bound
+    #[arg(long)]
+    metrics_addr: Option<String>,
+
+    /// Answer health checks over HTTP on this address (e.g., 0.0.0.0:8080):
+    /// GET /healthz returns 200 once the device is served and 503 while
+    /// starting or while the backend keeps failing
+    #[arg(long)]
+    health_addr: Option<String>,
+
+    /// Accept admin commands such as `resize <SIZE> [force]` and JSON
+    /// `stats`, `flush` and `snapshot` commands on this Unix socket; the
+    /// device is then resizable at runtime
+    #[arg(long, value_name = "PATH", conflicts_with_all = [
+        "hybrid_ratio", "compress", "spare_blocks", "logical_size", "mirror_file",
+        "backing_file", "persist_file", "cache_backing",
+    ])]
+    control_socket: Option<PathBuf>,
+
+    /// Log a hash of the full device contents on graceful shutdown
+    #[arg(long)]
+    hash_on_shutdown: bool,
+
+    /// Digest used by --hash-on-shutdown
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Blake3)]
+    hash_algorithm: HashAlgorithm,
+
+    /// On shutdown, wait this long for in-flight I/O to finish before forcing
+    /// the frontend down (seconds, or with a suffix such as 500ms)
+    #[arg(long, default_value = "10s", value_parser = parse_duration_string)]
+    shutdown_grace: Duration,
+
+    /// Keep a checksum of every 4 KiB block of the GPU buffer in host memory
+    /// and fail reads whose data no longer matches (detects bit flips)
+    #[arg(long)]
+    checksum: bool,
+
+    /// Checksum used by --checksum
+    #[arg(long, value_enum, default_value_t = ChecksumAlgorithm::Crc32c, requires = "checksum")]
+    checksum_algo: ChecksumAlgorithm,
+
+    /// Before serving, write a test pattern over the whole GPU buffer and
+    /// read it back, logging every 4 KiB block that comes back wrong
+    #[arg(long)]
+    self_test: bool,
+
+    /// Pattern written by --self-test
+    #[arg(long, value_enum, default_value_t = TestPattern::Address, requires = "self_test")]
+    self_test_pattern: TestPattern,
+
+    /// Write-then-verify passes over the buffer made by --self-test
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..), requires = "self_test")]
+    self_test_passes: u32,
+
+    /// Bad blocks --self-test tolerates before aborting startup
+    #[arg(long, default_value_t = 0, requires = "self_test")]
+    self_test_max_errors: u64,
+
+    /// Check the configuration without serving: allocate the device (and
+    /// any --export) and free it again, bind the listen address, print a
+    /// summary and exit
+    #[arg(long, conflicts_with_all = ["daemonize", "self_test"])]
+    dry_run: bool,
+
+    /// Abort startup if process memory can't be locked with mlockall,
+    /// instead of warning and carrying on
+    #[arg(long, conflicts_with = "no_mlock")]
+    require_mlock: bool,
+
+    /// Don't lock process memory at all, for setups where swapping the
+    /// server out is acceptable
+    #[arg(long)]
+    no_mlock: bool,
+
+    /// Number of tokio worker threads (defaults to the CPUs available to the process)
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    worker_threads: Option<usize>,
+
+    /// Bind host memory allocations (staging and I/O buffers, locked pages)
+    /// to this NUMA node; `auto` uses the node the GPU is attached to
+    #[arg(long, value_name = "NODE", value_parser = parse_numa_node)]
+    numa_node: Option<NumaNode>,
+
+    /// Run in the background once the device is served; the command exits
+    /// 0 when it is, or prints the startup error and exits 1. Logs go to
+    /// --log-file, or to syslog without it
+    #[arg(long)]
+    daemonize: bool,
+
+    /// With --daemonize, write the daemon's process ID to this file; it is
+    /// removed on exit
+    #[arg(long, value_name = "PATH", requires = "daemonize")]
+    pid_file: Option<PathBuf>,
+
+    /// Append log messages to this file instead of standard error
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Read settings from this TOML file; keys are option names with
+    /// underscores (e.g., listen_addr = "0.0.0.0:10809"), and options given
+    /// on the command line take precedence
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// Parses a size string (e.g., "512M", "2G") into bytes.
+/// Parses a size string (e.g., "512K", "512MiB", "2G", "500GB") into bytes.
+/// K, M, G and T, with or without "iB", are powers of 1024; KB, MB, GB and
+/// TB are powers of 1000. Defaults to MiB if no suffix.
 pub(crate) fn parse_size_string(size_str: &str) -> Result<u64> {
     let size_str = size_str.trim().to_uppercase();
     let (num_part, suffix) = size_str.split_at(
@@ -85,21 +683,174 @@ pub(crate) fn parse_size_string(size_str: &str) -> Result<u64> {
 
     let num: u64 = num_part.parse().context("Invalid size number")?;
 
-    match suffix {
-        "" | "M" | "MB" => Ok(num * 1024 * 1024),
-        "G" | "GB" => Ok(num * 1024 * 1024 * 1024),
-        _ => bail!("Invalid size suffix: '{}'. Use M/MB or G/GB.", suffix),
+    let multiplier: u64 = match suffix.trim_start() {
+        "K" | "KIB" => 1 << 10,
+        "" | "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        "KB" => 1_000,
+        "MB" => 1_000_000,
+        "GB" => 1_000_000_000,
+        "TB" => 1_000_000_000_000,
+        _ => bail!(
+            "Invalid size suffix: '{}'. Use K/KiB, M/MiB, G/GiB or T/TiB (powers of 1024), \
+             or KB, MB, GB or TB (powers of 1000).",
+            suffix
+        ),
+    };
+    num.checked_mul(multiplier)
+        .with_context(|| format!("Size {} is too large", size_str))
+}
+
+/// Parses a `--size`: a size string, or a percentage (e.g., "90%") of the
+/// GPU's available memory.
+fn parse_device_size(size_str: &str) -> Result<DeviceSize> {
+    let Some(percent) = size_str.trim().strip_suffix('%') else {
+        return parse_size_string(size_str).map(DeviceSize::Bytes);
+    };
+    let percent: u64 = percent.trim().parse().context("Invalid size percentage")?;
+    if !(1..=100).contains(&percent) {
+        bail!("Size percentage must be between 1% and 100%");
+    }
+    Ok(DeviceSize::Percent(percent))
+}
+
+/// Parses a `--device` entry: a device index or "auto".
+fn parse_device(device: &str) -> Result<DeviceChoice> {
+    if device.eq_ignore_ascii_case("auto") {
+        return Ok(DeviceChoice::Auto);
+    }
+    device
+        .parse()
+        .map(DeviceChoice::Index)
+        .context("Device must be an index or `auto`")
+}
+
+/// Parses an extra export (e.g., "scratch=1G" or "db=4G,max-conn=2,single-writer")
+/// into its name, size in bytes and connection limits.
+fn parse_export_spec(spec: &str) -> Result<ExportSpec> {
+    let mut parts = spec.split(',');
+    let (name, size) = parts
+        .next()
+        .and_then(|export| export.split_once('='))
+        .context("Export must be in NAME=SIZE form, e.g. scratch=1G")?;
+    if name.is_empty() {
+        bail!("Export name must not be empty");
+    }
+    let mut export = ExportSpec {
+        name: name.to_string(),
+        size: parse_size_string(size)?,
+        max_connections: None,
+        single_writer: false,
+    };
+    for option in parts {
+        match option.split_once('=') {
+            Some(("max-conn", max)) => {
+                let max: usize = max.parse().context("Invalid max-conn")?;
+                if max == 0 {
+                    bail!("max-conn must be at least 1");
+                }
+                export.max_connections = Some(max);
+            }
+            None if option == "single-writer" => export.single_writer = true,
+            _ => bail!(
+                "Unknown export option '{}'; expected max-conn=N or single-writer",
+                option
+            ),
+        }
+    }
+    Ok(export)
+}
+
+/// Parses a duration string (e.g., "200us", "90s", "30m", "2h") into a Duration.
+/// Defaults to seconds if no suffix.
+pub(crate) fn parse_duration_string(duration_str: &str) -> Result<Duration> {
+    let duration_str = duration_str.trim().to_lowercase();
+    let (num_part, suffix) = duration_str.split_at(
+        duration_str
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(duration_str.len()),
+    );
+
+    let num: u64 = num_part.parse().context("Invalid duration number")?;
+
+    let secs_per_unit = match suffix {
+        "us" => return Ok(Duration::from_micros(num)),
+        "ms" => return Ok(Duration::from_millis(num)),
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!(
+            "Invalid duration suffix: '{}'. Use us, ms, s, m, h or d.",
+            suffix
+        ),
+    };
+    num.checked_mul(secs_per_unit)
+        .map(Duration::from_secs)
+        .with_context(|| format!("Duration '{}' is too large", duration_str))
+}
+
+/// Parse a duration that must not be zero, such as a period
+fn parse_interval_string(interval_str: &str) -> Result<Duration> {
+    let interval = parse_duration_string(interval_str)?;
+    if interval.is_zero() {
+        bail!("Interval must be greater than zero");
+    }
+    Ok(interval)
+}
+
+/// Formats an RLIMIT value for logging.
+fn format_rlimit(limit: u64) -> String {
+    if limit == libc::RLIM_INFINITY {
+        "unlimited".to_string()
+    } else {
+        format!("{} KB", limit / 1024)
+    }
+}
+
+/// Raises the RLIMIT_MEMLOCK soft limit to the hard limit so mlockall() is
+/// less likely to fail, and warns if the hard limit is below `required` bytes.
+fn raise_memlock_limit(required: u64) {
+    let (soft, hard) = match getrlimit(Resource::RLIMIT_MEMLOCK) {
+        Ok(limits) => limits,
+        Err(e) => {
+            log::warn!("Failed to read RLIMIT_MEMLOCK: {}", e);
+            return;
+        }
+    };
+    log::info!(
+        "RLIMIT_MEMLOCK: soft {}, hard {}",
+        format_rlimit(soft),
+        format_rlimit(hard)
+    );
+
+    if soft != hard {
+        match setrlimit(Resource::RLIMIT_MEMLOCK, hard, hard) {
+            Ok(_) => log::info!(
+                "Raised RLIMIT_MEMLOCK soft limit from {} to {}",
+                format_rlimit(soft),
+                format_rlimit(hard)
+            ),
+            Err(e) => log::warn!("Failed to raise RLIMIT_MEMLOCK soft limit: {}", e),
+        }
+    }
+
+    if hard != libc::RLIM_INFINITY && hard < required {
+        log::warn!(
+            "RLIMIT_MEMLOCK hard limit ({}) is below the device size; run with \
+             `ulimit -l {}` (or `ulimit -l unlimited`), set memlock in \
+             /etc/security/limits.conf, or grant CAP_IPC_LOCK",
+            format_rlimit(hard),
+            required.div_ceil(1024)
+        );
     }
 }
 
 /// Lists available OpenCL devices.
 fn list_opencl_devices() -> Result<()> {
     println!("Available OpenCL Platforms and Devices:");
-    let platforms = get_platforms().context("Failed to get OpenCL platforms")?;
-    if platforms.is_empty() {
-        println!("  No OpenCL platforms found.");
-        return Ok(());
-    }
+    let platforms = platforms()?;
 
     for (plat_idx, platform) in platforms.iter().enumerate() {
         let plat_name = platform
@@ -128,6 +879,15 @@ fn list_opencl_devices() -> Result<()> {
                             dev_vendor,
                             dev_mem / (1024 * 1024)
                         );
+                        let topology = QueueTopology::probe(&device);
+                        println!(
+                            "    Queues: {} - recommended layout: {}",
+                            topology,
+                            topology.recommended_layout()
+                        );
+                        if let Ok(bits) = device.mem_base_addr_align() {
+                            println!("    Host alignment: {} bytes", (bits / 8).max(1));
+                        }
                     }
                 }
             }
@@ -139,113 +899,1564 @@ fn list_opencl_devices() -> Result<()> {
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+/// An OpenCL platform in the JSON output of --list-devices
+#[derive(Debug, Serialize)]
+struct ListedPlatform {
+    index: usize,
+    name: Option<String>,
+    vendor: Option<String>,
+    devices: Vec<ListedDevice>,
+    error: Option<String>,
+}
 
-    if args.list_devices {
-        return list_opencl_devices();
+/// An OpenCL GPU device in the JSON output of --list-devices
+#[derive(Debug, Serialize)]
+struct ListedDevice {
+    index: usize,
+    name: Option<String>,
+    vendor: Option<String>,
+    global_mem_size: Option<u64>,
+}
+
+/// Prints available OpenCL platforms and devices as JSON.
+fn list_opencl_devices_json() -> Result<()> {
+    let listed: Vec<ListedPlatform> = platforms()?
+        .iter()
+        .enumerate()
+        .map(|(index, platform)| {
+            let (devices, error) = match get_device_ids(platform.id(), CL_DEVICE_TYPE_GPU) {
+                Ok(device_ids) => (
+                    device_ids
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, device_id)| {
+                            let device = Device::new(device_id);
+                            ListedDevice {
+                                index,
+                                name: device.name().ok(),
+                                vendor: device.vendor().ok(),
+                                global_mem_size: device.global_mem_size().ok(),
+                            }
+                        })
+                        .collect(),
+                    None,
+                ),
+                Err(e) => (Vec::new(), Some(e.to_string())),
+            };
+            ListedPlatform {
+                index,
+                name: platform.name().ok(),
+                vendor: platform.vendor().ok(),
+                devices,
+                error,
+            }
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&listed)?);
+    Ok(())
+}
+
+/// Lists available CUDA devices.
+#[cfg(feature = "cuda")]
+fn list_cuda_devices() -> Result<()> {
+    println!("Available CUDA Devices:");
+    let devices = cuda::cuda_devices()?;
+    if devices.is_empty() {
+        println!("  No CUDA devices found.");
+    }
+    for device in devices {
+        println!(
+            "  Device {}: {} - Memory: {} MB",
+            device.index,
+            device.name,
+            device.total_memory / (1024 * 1024)
+        );
     }
+    Ok(())
+}
 
-    if args.verbose {
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("debug")).init();
-    } else {
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+#[cfg(not(feature = "cuda"))]
+fn list_cuda_devices() -> Result<()> {
+    bail!("--api cuda requires building with the `cuda` feature")
+}
+
+/// Lists available Vulkan devices.
+#[cfg(feature = "vulkan")]
+fn list_vulkan_devices() -> Result<()> {
+    println!("Available Vulkan Devices:");
+    let devices = vulkan::vulkan_devices()?;
+    if devices.is_empty() {
+        println!("  No Vulkan devices found.");
     }
+    for device in devices {
+        println!(
+            "  Device {}: {} ({:?}) - Device-local memory: {} MB",
+            device.index,
+            device.name,
+            device.device_type,
+            device.device_local_memory / (1024 * 1024)
+        );
+        println!(
+            "    Staging memory: {}",
+            if device.coherent_staging {
+                "host-coherent"
+            } else {
+                "non-coherent (explicit flushes)"
+            }
+        );
+    }
+    Ok(())
+}
 
-    let driver_str = match args.driver {
-        Driver::Nbd => "NBD Server",
-        Driver::Ublk => "Ublk",
+#[cfg(not(feature = "vulkan"))]
+fn list_vulkan_devices() -> Result<()> {
+    bail!("--api vulkan requires building with the `vulkan` feature")
+}
+
+/// Exit code used when no OpenCL runtime or GPU device is available
+const EXIT_NO_OPENCL: i32 = 3;
+
+fn main() -> Result<()> {
+    let mut args = Args::parse_from(merge_config_file(
+        &Args::command(),
+        std::env::args_os().collect(),
+    )?);
+
+    // Fork before any thread, GPU context or memory lock exists: none of
+    // them carry over into the child
+    let readiness = if args.daemonize {
+        if args.list_devices || args.command.is_some() {
+            bail!("--daemonize only applies to serving a device");
+        }
+        Some(Arc::new(daemonize(args.pid_file.as_deref())?))
+    } else {
+        None
     };
-    log::info!("Starting VRAM Block Device ({})", driver_str);
 
-    // --- Lock process memory ---
-    log::info!("Attempting to lock process memory using mlockall()...");
-    // Use correct flag names from the MlockAllFlags type
-    match mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE) {
-        Ok(_) => log::info!("Successfully locked process memory."),
-        Err(e) => {
+    // The memory policy is inherited by threads started from here on, so it
+    // has to be set before the runtime starts its workers
+    if let Some(node) = args.numa_node
+        && !args.list_devices
+        && args.command.is_none()
+    {
+        init_logger(&args)?;
+        let node = match node {
+            NumaNode::Node(node) => Some(node),
+            NumaNode::Auto => gpu_numa_node(&mut args)?,
+        };
+        if let Some(node) = node {
+            bind_memory(node)?;
+        }
+    }
+
+    // available_parallelism() honors CPU affinity and cgroup CPU quotas,
+    // unlike tokio's default of one worker per online core
+    let worker_threads = args.worker_threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    });
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .enable_all()
+        .build()
+        .context("Failed to build tokio runtime")?;
+
+    let shutdown_grace = args.shutdown_grace;
+    let result = runtime.block_on(run(args, worker_threads, readiness.clone()));
+    // Blocking tasks still stuck in a GPU transfer must not hold up exit
+    runtime.shutdown_timeout(shutdown_grace);
+    if let (Some(readiness), Err(e)) = (&readiness, &result) {
+        readiness.fail(e);
+    }
+    if let Err(e) = &result
+        && e.chain().any(|cause| cause.is::<OpenClUnavailable>())
+    {
+        eprintln!("Error: {:#}", e);
+        std::process::exit(EXIT_NO_OPENCL);
+    }
+    result
+}
+
+/// GPU buffer settings from the command line, for a buffer of `size` bytes on `device_index`
+fn buffer_config(args: &Args, device_index: usize, size: u64) -> VRamBufferConfig {
+    VRamBufferConfig {
+        size: size as usize, // VRamBufferConfig expects usize
+        device_index,
+        platform_index: args.platform,
+        retry: RetryPolicy {
+            max_attempts: args
+                .io_retries
+                .map_or(args.retry_attempts, |retries| retries.saturating_add(1)),
+            base_delay: Duration::from_millis(args.retry_base_delay),
+            max_delay: Duration::from_millis(args.retry_max_delay),
+            ..RetryPolicy::default()
+        },
+        queue_layout: args.queue_layout,
+        command_queues: args
+            .command_queues
+            .unwrap_or_else(|| args.ublk_queues.unwrap_or_else(ublk_queue_count) as usize),
+        host_alignment: args.host_alignment,
+        pinned_staging: if args.no_pinned_staging {
+            0
+        } else {
+            args.max_io_size as usize
+        },
+        write_combine: args.write_combine,
+        shared_context: args.shared_context,
+    }
+}
+
+/// Allocate a single `size` byte buffer on GPU `device_index` with the selected API
+fn allocate_gpu_buffer(
+    args: &Args,
+    device_index: usize,
+    size: u64,
+) -> Result<Arc<dyn BlockBackend>> {
+    match args.api {
+        GpuApi::Opencl => {
+            let buffer = VRamBuffer::new(&buffer_config(args, device_index, size))?;
+            log::info!(
+                "Successfully allocated {} bytes ({} MB) on {} ({} queue layout)",
+                size,
+                size / (1024 * 1024), // Log MB for readability
+                buffer.device_name(),
+                buffer.queue_layout()
+            );
+            Ok(Arc::new(buffer))
+        }
+        #[cfg(feature = "cuda")]
+        GpuApi::Cuda => {
+            let buffer = cuda::CudaBuffer::new(device_index, size as usize)?;
+            log::info!(
+                "Successfully allocated {} bytes ({} MB) on {} (CUDA)",
+                size,
+                size / (1024 * 1024), // Log MB for readability
+                buffer.device_name()
+            );
+            Ok(Arc::new(buffer))
+        }
+        #[cfg(not(feature = "cuda"))]
+        GpuApi::Cuda => bail!("--api cuda requires building with the `cuda` feature"),
+        #[cfg(feature = "vulkan")]
+        GpuApi::Vulkan => {
+            let buffer = vulkan::VulkanBuffer::new(device_index, size as usize)?;
+            log::info!(
+                "Successfully allocated {} bytes ({} MB) on {} (Vulkan)",
+                size,
+                size / (1024 * 1024), // Log MB for readability
+                buffer.device_name()
+            );
+            Ok(Arc::new(buffer))
+        }
+        #[cfg(not(feature = "vulkan"))]
+        GpuApi::Vulkan => bail!("--api vulkan requires building with the `vulkan` feature"),
+    }
+}
+
+/// Device indices from `--device`, with `auto` resolved to the GPU that fits
+/// `size` bytes with the most headroom
+fn resolve_devices(args: &Args, size: u64) -> Result<Vec<usize>> {
+    let mut devices = Vec::with_capacity(args.device.len());
+    for device in &args.device {
+        devices.push(match device {
+            DeviceChoice::Index(index) => *index,
+            DeviceChoice::Auto if args.device.len() > 1 => {
+                bail!(
+                    "--device auto selects a single GPU and cannot be combined with other devices"
+                )
+            }
+            DeviceChoice::Auto => match args.api {
+                GpuApi::Opencl => auto_select_device(args.platform, size)?,
+                _ => bail!("--device auto is only supported with --api opencl"),
+            },
+        });
+    }
+    Ok(devices)
+}
+
+/// Log to --log-file, syslog when daemonized, or standard error. Only the
+/// first call has an effect.
+fn init_logger(args: &Args) -> Result<()> {
+    let mut logger = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(if args.verbose { "debug" } else { "info" }),
+    );
+    if let Some(path) = &args.log_file {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        logger.target(env_logger::Target::Pipe(Box::new(file)));
+    } else if args.daemonize {
+        logger.target(env_logger::Target::Pipe(Box::new(Syslog::open())));
+    }
+    // Already set up before the runtime for --numa-node
+    let _ = logger.try_init();
+    Ok(())
+}
+
+/// Replace --device-name with the --platform and --device it names
+fn resolve_device_name(args: &mut Args) -> Result<()> {
+    if let Some(name) = args.device_name.take() {
+        if !matches!(args.api, GpuApi::Opencl) {
+            bail!("--device-name is only supported with --api opencl");
+        }
+        let (platform, device) = find_device_by_name(&name)?;
+        args.platform = platform;
+        args.device = vec![DeviceChoice::Index(device)];
+    }
+    Ok(())
+}
+
+/// NUMA node of the GPU for `--numa-node auto`: that of the first GPU when
+/// striping, with a warning if the others are elsewhere. `None`, after a
+/// warning, when the node can't be told.
+fn gpu_numa_node(args: &mut Args) -> Result<Option<u32>> {
+    if !matches!(args.backend, StorageBackend::Opencl) || !matches!(args.api, GpuApi::Opencl) {
+        bail!("--numa-node auto is only supported with GPU memory allocated through OpenCL");
+    }
+    resolve_device_name(args)?;
+    let mut nodes = Vec::with_capacity(args.device.len());
+    for device in &args.device {
+        let DeviceChoice::Index(device_index) = *device else {
+            bail!("--numa-node auto needs a --device index or --device-name, not --device auto");
+        };
+        let node = match device_pci_address(args.platform, device_index)? {
+            Some(address) => pci_device_node(&address)?,
+            None => None,
+        };
+        nodes.push((device_index, node));
+    }
+    let Some(&(first, Some(node))) = nodes.first() else {
+        log::warn!("--numa-node auto ignored: the GPU's NUMA node is unknown");
+        return Ok(None);
+    };
+    for &(device_index, other) in &nodes[1..] {
+        if other != Some(node) {
             log::warn!(
-                "Failed to lock process memory (requires root or CAP_IPC_LOCK): {}",
-                e
+                "GPU {} is not on NUMA node {} with GPU {}; host memory stays on node {}",
+                device_index,
+                node,
+                first,
+                node
             );
         }
     }
-    // -------------------------
+    log::info!("GPU {} is attached to NUMA node {}", first, node);
+    Ok(Some(node))
+}
 
-    // Size is already parsed into bytes
+/// `--size` in bytes. A percentage is taken of the memory available on the
+/// selected GPU, or on the smallest one when striping, and rounded down to
+/// whole MiB (whole stripe chunks per GPU when striping), so the result never
+/// exceeds what was measured.
+fn resolve_size(args: &mut Args) -> Result<u64> {
+    let percent = match args.size_spec {
+        DeviceSize::Bytes(bytes) => return Ok(bytes),
+        DeviceSize::Percent(percent) => percent,
+    };
+    if !matches!(args.backend, StorageBackend::Opencl) || !matches!(args.api, GpuApi::Opencl) {
+        bail!("--size as a percentage is only supported with GPU memory allocated through OpenCL");
+    }
+    if args.hybrid_ratio.is_some() {
+        bail!("--size as a percentage can't be combined with --hybrid-ratio");
+    }
+    // With `auto`, the GPU with the most available memory, kept from here on
+    let devices = resolve_devices(args, 0)?;
+    args.device = devices.iter().copied().map(DeviceChoice::Index).collect();
+    let mut available = u64::MAX;
+    for &device_index in &devices {
+        available = available.min(device_available_memory(args.platform, device_index)?);
+    }
+    let unit = if devices.len() > 1 {
+        args.stripe_chunk
+    } else {
+        1024 * 1024
+    };
+    let share = (available as u128 * percent as u128 / 100) as u64;
+    let size = share / unit * unit * devices.len() as u64;
+    if size == 0 {
+        bail!(
+            "--size {}% of {} bytes available is less than {} bytes",
+            percent,
+            available,
+            unit
+        );
+    }
     log::info!(
-        "Allocating {} bytes ({} MB) on GPU device {} (Platform {})",
-        args.size,
-        args.size / (1024 * 1024), // Log MB for readability
-        args.device,
-        args.platform
+        "--size {}% of {} bytes available per GPU: {} bytes ({} MB)",
+        percent,
+        available,
+        size,
+        size / (1024 * 1024)
     );
+    Ok(size)
+}
 
-    let buffer_config = VRamBufferConfig {
-        size: args.size as usize, // VRamBufferConfig expects usize
-        device_index: args.device,
-        platform_index: args.platform,
+/// Whether device memory is allocated through OpenCL
+fn uses_opencl(args: &Args) -> bool {
+    matches!(args.backend, StorageBackend::Opencl) && matches!(args.api, GpuApi::Opencl)
+}
+
+/// Name of the storage in use: `mem`, or the GPU API
+fn backend_kind(args: &Args) -> String {
+    let kind = match args.backend {
+        StorageBackend::Mem => args.backend.to_possible_value(),
+        StorageBackend::Opencl => args.api.to_possible_value(),
     };
+    kind.map_or_else(String::new, |kind| kind.get_name().to_string())
+}
 
-    let buffer =
-        Arc::new(VRamBuffer::new(&buffer_config).context("Failed to allocate GPU memory")?);
+/// Discard the whole of the persistent store at `path` for --discard-all-on-start
+fn discard_all(backend: &dyn BlockBackend, path: &Path) -> Result<()> {
+    log::warn!(
+        "--discard-all-on-start: discarding all {} bytes of {}; its previous contents are gone",
+        backend.size(),
+        path.display()
+    );
+    backend
+        .discard_at(0, backend.size())
+        .with_context(|| format!("Failed to discard {}", path.display()))
+}
+
+/// Where the remap table is kept: --remap-table, or beside the persistent
+/// store whose remapped blocks it locates
+fn remap_table_path(args: &Args) -> Option<PathBuf> {
+    args.remap_table.clone().or_else(|| {
+        let store = [
+            &args.backing_file,
+            &args.mirror_file,
+            &args.persist_file,
+            &args.cache_backing,
+        ]
+        .into_iter()
+        .find_map(|path| path.as_ref())?;
+        let mut path = store.clone().into_os_string();
+        path.push(".remap");
+        Some(PathBuf::from(path))
+    })
+}
 
+/// Allocate `vram_size` bytes on the selected GPU(s), striped if there are several
+fn allocate_vram(args: &Args, vram_size: u64) -> Result<Arc<dyn BlockBackend>> {
+    let devices = resolve_devices(args, vram_size)?;
+    // Size is already parsed into bytes
+    let api = match args.api {
+        GpuApi::Opencl => format!("OpenCL platform {}", args.platform),
+        GpuApi::Cuda => "CUDA".to_string(),
+        GpuApi::Vulkan => "Vulkan".to_string(),
+    };
     log::info!(
-        "Successfully allocated {} bytes ({} MB) on {}",
-        args.size,
-        args.size / (1024 * 1024), // Log MB for readability
-        buffer.device_name()
+        "Allocating {} bytes ({} MB) on GPU device(s) {:?} ({})",
+        vram_size,
+        vram_size / (1024 * 1024), // Log MB for readability
+        devices,
+        api
     );
 
+    let stripe_width = devices.len() as u64;
+    if stripe_width > 1 && !vram_size.is_multiple_of(args.stripe_chunk * stripe_width) {
+        bail!(
+            "The VRAM part of the device ({} bytes) must be a multiple of --stripe-chunk \
+             times the number of GPUs ({} x {})",
+            vram_size,
+            args.stripe_chunk,
+            stripe_width
+        );
+    }
+    let member_size = vram_size / stripe_width;
+
+    let mut members = Vec::with_capacity(devices.len());
+    for &device_index in &devices {
+        let buffer = allocate_gpu_buffer(args, device_index, member_size)
+            .with_context(|| format!("Failed to allocate GPU memory on device {}", device_index))?;
+        members.push(buffer);
+    }
+
+    Ok(if members.len() > 1 {
+        log::info!(
+            "Striping across {} GPUs in {} KiB chunks",
+            members.len(),
+            args.stripe_chunk / 1024
+        );
+        Arc::new(StripedBackend::new(members, args.stripe_chunk)?)
+    } else {
+        members.remove(0)
+    })
+}
+
+/// Allocates the device buffer, at startup and on resize, and keeps the VRAM
+/// gauge up to date
+struct DeviceAllocator {
+    args: Args,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+}
+
+impl DeviceAllocator {
+    /// Health tracking and, with --checksum, block checksums around a new buffer
+    fn wrap(&self, buffer: Arc<dyn BlockBackend>) -> Result<Arc<dyn BlockBackend>> {
+        let buffer = Arc::new(HealthBackend::new(buffer, self.health.clone()));
+        Ok(if self.args.checksum {
+            Arc::new(ChecksumBackend::new(
+                buffer,
+                self.args.checksum_algo,
+                self.metrics.clone(),
+            )?)
+        } else {
+            buffer
+        })
+    }
+}
+
+impl Allocator for DeviceAllocator {
+    fn allocate(&self, size: u64) -> Result<Arc<dyn BlockBackend>> {
+        let buffer: Arc<dyn BlockBackend> = match self.args.backend {
+            StorageBackend::Opencl => {
+                let buffer = allocate_vram(&self.args, size)?;
+                self.metrics.add_vram(size);
+                buffer
+            }
+            StorageBackend::Mem => Arc::new(RamBuffer::new(size)),
+        };
+        self.wrap(buffer)
+    }
+
+    fn allocate_member(
+        &self,
+        platform: usize,
+        device: usize,
+        size: u64,
+    ) -> Result<Arc<dyn BlockBackend>> {
+        let buffer: Arc<dyn BlockBackend> = match self.args.backend {
+            StorageBackend::Opencl => {
+                let mut args = self.args.clone();
+                args.platform = platform;
+                let buffer = allocate_gpu_buffer(&args, device, size)?;
+                self.metrics.add_vram(size);
+                buffer
+            }
+            StorageBackend::Mem => Arc::new(RamBuffer::new(size)),
+        };
+        self.wrap(buffer)
+    }
+
+    fn release(&self, size: u64) {
+        if matches!(self.args.backend, StorageBackend::Opencl) {
+            self.metrics.remove_vram(size);
+        }
+    }
+}
+
+/// Replay a captured trace against host RAM or a GPU buffer
+fn run_replay(args: &Args, path: &Path, gpu: bool, timing: bool) -> Result<()> {
+    let records = trace::read_trace(path)?;
+    let extent = trace::trace_extent(&records);
+    let backend: Arc<dyn BlockBackend> = if gpu {
+        if extent > args.size {
+            bail!(
+                "Trace reaches offset {} but --size is only {} bytes",
+                extent,
+                args.size
+            );
+        }
+        allocate_gpu_buffer(args, resolve_devices(args, args.size)?[0], args.size)
+            .context("Failed to allocate GPU memory")?
+    } else {
+        Arc::new(RamBuffer::new(extent))
+    };
+    trace::replay(&records, backend, timing)
+}
+
+async fn run(
+    mut args: Args,
+    worker_threads: usize,
+    readiness: Option<Arc<Readiness>>,
+) -> Result<()> {
+    if args.list_devices {
+        return match (args.api, args.format) {
+            (GpuApi::Opencl, ListFormat::Text) => list_opencl_devices(),
+            (GpuApi::Opencl, ListFormat::Json) => list_opencl_devices_json(),
+            (_, ListFormat::Json) => bail!("--format json is only supported with --api opencl"),
+            (GpuApi::Cuda, ListFormat::Text) => list_cuda_devices(),
+            (GpuApi::Vulkan, ListFormat::Text) => list_vulkan_devices(),
+        };
+    }
+
+    if let Some(Command::Diag { json }) = args.command {
+        return diag::run_diag(json);
+    }
+
+    init_logger(&args)?;
+    resolve_device_name(&mut args)?;
+
+    args.size = resolve_size(&mut args)?;
+
+    if let Some(Command::Replay { trace, gpu, timing }) = &args.command {
+        return run_replay(&args, trace, *gpu, *timing);
+    }
+
+    if let Some(Command::Bench {
+        block_size,
+        duration,
+        queue_depth,
+        json,
+    }) = args.command
+    {
+        let config = BenchConfig {
+            block_size,
+            duration,
+            queue_depth,
+        };
+        let allocator = DeviceAllocator {
+            args: args.clone(),
+            metrics: Arc::new(Metrics::default()),
+            health: Arc::new(Health::default()),
+        };
+        let backend = allocator
+            .allocate(args.size)
+            .context("Failed to allocate the benchmark buffer")?;
+        return run_bench(backend, &config, json);
+    }
+
+    // At most one NBD flavour, served alongside ublk and FUSE if those are
+    // given too
+    let nbd_driver = args
+        .driver
+        .iter()
+        .copied()
+        .find(|d| matches!(d, Driver::Nbd | Driver::NbdWs));
+    let ublk = args.driver.contains(&Driver::Ublk);
+    let fuse = args.driver.contains(&Driver::Fuse);
+    if args.driver.len()
+        != usize::from(nbd_driver.is_some()) + usize::from(ublk) + usize::from(fuse)
+    {
+        bail!("--driver takes at most one NBD driver, plus ublk and fuse (e.g., nbd,ublk)");
+    }
+    let driver_str = args
+        .driver
+        .iter()
+        .map(|driver| match driver {
+            Driver::Nbd => "NBD Server",
+            Driver::NbdWs => "NBD over WebSocket",
+            Driver::Ublk => "Ublk",
+            Driver::Fuse => "FUSE",
+        })
+        .collect::<Vec<_>>()
+        .join(" + ");
+    log::info!("Starting VRAM Block Device ({})", driver_str);
+    log::info!("Using {} tokio worker thread(s)", worker_threads);
+
+    let transport = match nbd_driver {
+        #[cfg(feature = "websocket")]
+        Some(Driver::NbdWs) => NbdTransport::WebSocket,
+        #[cfg(not(feature = "websocket"))]
+        Some(Driver::NbdWs) => {
+            bail!("--driver nbd-ws requires building with the `websocket` feature")
+        }
+        _ => NbdTransport::Tcp,
+    };
+    if args.discard_all_on_start
+        && args.backing_file.is_none()
+        && args.mirror_file.is_none()
+        && args.cache_backing.is_none()
+    {
+        bail!("--discard-all-on-start needs --backing-file, --mirror-file or --cache-backing");
+    }
+    if args.backing_offset.is_some() && args.mirror_file.is_some() == args.cache_backing.is_some() {
+        bail!("--backing-offset needs exactly one of --mirror-file and --cache-backing");
+    }
+    if nbd_driver.is_none() && !args.exports.is_empty() {
+        bail!("--export is only supported with the NBD drivers");
+    }
+    if nbd_driver.is_none() && args.tls_cert.is_some() {
+        bail!("--tls-cert is only supported with the NBD drivers");
+    }
+    if args.readahead.is_some() && (nbd_driver.is_none() || ublk || fuse) {
+        // Writes through the other frontends would miss the prefetched data
+        bail!("--readahead is only supported with the NBD drivers alone");
+    }
+    if args.readahead.is_some_and(|window| window < 8192) {
+        bail!("--readahead must be at least 8K");
+    }
+    if nbd_driver.is_none() && args.unix_socket.is_some() {
+        bail!("--unix-socket is only supported with the NBD drivers");
+    }
+    if args.control_socket.is_some() {
+        // libublk can't send UBLK_U_CMD_UPDATE_SIZE, and parameters can't be
+        // changed once the device is live
+        if ublk {
+            bail!("--control-socket is only supported with the NBD drivers; ublk devices can't be resized");
+        }
+        if matches!(args.image_format, ImageFormat::Qcow2) {
+            bail!("--control-socket can't resize qcow2 images");
+        }
+    }
+    if !ublk && args.dev_path_file.is_some() {
+        bail!("--dev-path-file is only supported with --driver ublk");
+    }
+    if !ublk && (args.mkfs.is_some() || args.mount.is_some()) {
+        bail!("--mkfs and --mount are only supported with --driver ublk");
+    }
+    if !ublk && (args.ublk_queues.is_some() || args.ublk_depth.is_some()) {
+        bail!("--ublk-queues and --ublk-depth are only supported with --driver ublk");
+    }
+    if fuse != args.mountpoint.is_some() {
+        bail!("--driver fuse and --mountpoint must be given together");
+    }
+    #[cfg(not(feature = "fuse"))]
+    if fuse {
+        bail!("--driver fuse requires building with the `fuse` feature");
+    }
+    if args.read_only && args.mkfs.is_some() {
+        bail!("--mkfs can't format a --read-only device");
+    }
+
+    // --- Lock process memory ---
+    if args.no_mlock {
+        log::info!("Not locking process memory (--no-mlock); the server may be swapped out");
+    } else {
+        raise_memlock_limit(args.size);
+        log::info!("Attempting to lock process memory using mlockall()...");
+        // Use correct flag names from the MlockAllFlags type
+        match mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE) {
+            Ok(_) => log::info!("Successfully locked process memory."),
+            Err(e) if args.require_mlock => {
+                bail!(
+                    "Failed to lock process memory (requires root or CAP_IPC_LOCK), \
+                     and --require-mlock is set: {}",
+                    e
+                );
+            }
+            Err(e) => {
+                log::warn!(
+                    "Failed to lock process memory (requires root or CAP_IPC_LOCK): {}",
+                    e
+                );
+            }
+        }
+    }
+    // -------------------------
+
+    let (vram_size, ram_size) = match args.hybrid_ratio {
+        Some(ratio) => {
+            if !args.size.is_multiple_of(STRIPE_UNIT) {
+                bail!(
+                    "--size must be a multiple of the {} KiB stripe unit with --hybrid-ratio",
+                    STRIPE_UNIT / 1024
+                );
+            }
+            ratio.split_size(args.size)
+        }
+        None => (args.size, 0),
+    };
+
+    let metrics = Arc::new(Metrics::default());
+
+    // Up before the allocation, so probes see the device starting
+    let health = Arc::new(Health::default());
+    health.expect_frontends(args.driver.len());
+    let health_stop = CancellationToken::new();
+    let health_server = args
+        .health_addr
+        .clone()
+        .map(|addr| spawn_health_server(addr, health.clone(), health_stop.clone()));
+
     let nbd_config = NbdConfig {
         listen_addr: args.listen_addr.clone(),
-        export_name: args.export_name.clone(),
+        unix_socket: args.unix_socket.clone(),
+        transport,
+        max_clients: args.max_clients.map(|n| n as usize),
+        read_only: args.read_only,
+        rotational: args.rotational,
+        default_export: args.default_export,
+        min_block_size: args.min_block_size,
+        preferred_block_size: args.preferred_block_size,
+        max_io_size: u32::try_from(args.max_io_size).context("--max-io-size must be below 4G")?,
+        shutdown_grace: args.shutdown_grace,
+        tls: args
+            .tls_cert
+            .clone()
+            .zip(args.tls_key.clone())
+            .map(|(cert, key)| NbdTls { cert, key }),
+        wire_checksum: args.wire_checksum,
+        metrics: metrics.clone(),
+        ready: readiness.clone(),
+        health: health.clone(),
+    };
+
+    let allocator = DeviceAllocator {
+        args: args.clone(),
+        metrics: metrics.clone(),
+        health: health.clone(),
+    };
+    if matches!(args.backend, StorageBackend::Mem) {
+        log::warn!(
+            "Using host RAM instead of GPU memory for {} bytes ({} MB)",
+            vram_size,
+            vram_size / (1024 * 1024)
+        );
+    }
+    let buffer = allocator.allocate(vram_size)?;
+
+    if args.dry_run {
+        // Everything a serving run holds at once, released again on return
+        let mut buffers = vec![buffer];
+        for export in &args.exports {
+            let buffer = allocator
+                .allocate(export.size)
+                .with_context(|| format!("Failed to allocate export '{}'", export.name))?;
+            buffers.push(buffer);
+        }
+        let contexts = contexts_in_use();
+        let mut listen = Vec::new();
+        if nbd_driver.is_some() {
+            listen.push(check_nbd_config(&nbd_config).await?);
+        }
+        if ublk {
+            let control = Path::new("/dev/ublk-control");
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(control)
+                .with_context(|| format!("Failed to open {}", control.display()))?;
+            listen.push(control.display().to_string());
+        }
+        if let Some(mountpoint) = &args.mountpoint {
+            if !mountpoint.is_dir() {
+                bail!(
+                    "FUSE mountpoint {} is not a directory",
+                    mountpoint.display()
+                );
+            }
+            let fuse_dev = Path::new("/dev/fuse");
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(fuse_dev)
+                .with_context(|| format!("Failed to open {}", fuse_dev.display()))?;
+            listen.push(mountpoint.display().to_string());
+        }
+        let listen = listen.join(", ");
+        drop(buffers);
+        if let Some(health_server) = health_server {
+            health_stop.cancel();
+            health_server.await?;
+        }
+
+        println!("Dry run passed");
+        println!("  driver:  {}", driver_str);
+        println!("  listen:  {}", listen);
+        println!("  backend: {}", backend_kind(&args));
+        println!(
+            "  size:    {} bytes ({} MB)",
+            args.size,
+            args.size / (1024 * 1024)
+        );
+        for export in &args.exports {
+            println!("  export:  {} ({} bytes)", export.name, export.size);
+        }
+        if uses_opencl(&args) {
+            println!("  OpenCL contexts: {}", contexts);
+        }
+        return Ok(());
+    }
+
+    if args.self_test {
+        let config = SelfTestConfig {
+            pattern: args.self_test_pattern,
+            passes: args.self_test_passes,
+            max_errors: args.self_test_max_errors,
+        };
+        let buffer = buffer.clone();
+        tokio::task::spawn_blocking(move || run_self_test(&*buffer, &config)).await??;
+    }
+
+    // Beneath every other wrapper, all of which follow its size
+    let resizable = args
+        .control_socket
+        .is_some()
+        .then(|| Arc::new(ResizableBackend::new(buffer.clone(), Box::new(allocator))));
+    let buffer: Arc<dyn BlockBackend> = match &resizable {
+        Some(resizable) => resizable.clone(),
+        None => buffer,
     };
 
-    // Start selected frontend
-    match args.driver {
-        Driver::Nbd => {
-            // NBD server runs until shutdown
-            start_nbd_server(buffer, &nbd_config).await?;
+    let backend: Arc<dyn BlockBackend> = match args.hybrid_ratio {
+        Some(ratio) => {
+            log::info!(
+                "Striping {} VRAM:RAM with {} bytes ({} MB) of host RAM",
+                ratio,
+                ram_size,
+                ram_size / (1024 * 1024)
+            );
+            Arc::new(HybridStripeBackend::new(
+                buffer,
+                RamBuffer::new(ram_size),
+                ratio,
+            )?)
+        }
+        None => buffer,
+    };
+
+    let backend: Arc<dyn BlockBackend> = match &args.cache_backing {
+        Some(path) => {
+            let backing = FileBackend::open_window(path, args.backing_offset.unwrap_or(0), None)
+                .context("Failed to open --cache-backing")?;
+            if args.discard_all_on_start {
+                discard_all(&backing, path)?;
+            }
+            log::info!(
+                "Caching {} ({} bytes) in {} bytes of VRAM ({})",
+                path.display(),
+                backing.size(),
+                backend.size(),
+                args.cache_mode
+            );
+            Arc::new(CacheBackend::new(backend, backing, args.cache_mode)?)
+        }
+        None => backend,
+    };
+
+    // Below compression, which would gain nothing from ciphertext
+    let backend: Arc<dyn BlockBackend> = match &args.encrypt_key_file {
+        Some(path) => {
+            let mut passphrase = Zeroizing::new(
+                std::fs::read(path)
+                    .with_context(|| format!("Failed to read key file {}", path.display()))?,
+            );
+            while passphrase.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+                passphrase.pop();
+            }
+            log::info!(
+                "Encrypting data with AES-256-XTS (key from {})",
+                path.display()
+            );
+            Arc::new(EncryptedBackend::new(backend, &passphrase)?)
+        }
+        None => backend,
+    };
+
+    let backend: Arc<dyn BlockBackend> = match args.compress {
+        Some(compression) => {
+            let size = args.compressed_size.unwrap_or(args.size);
+            log::info!(
+                "Compressing data ({}): {} byte device stored in {} bytes",
+                compression,
+                size,
+                backend.size()
+            );
+            Arc::new(CompressedBackend::new(backend, size, compression.codec()?)?)
         }
-        Driver::Ublk => {
-            // Default logical block size: 4096 bytes
-            let ublk_cfg = UblkConfig {
-                logical_block_size: 4096,
+        None => backend,
+    };
+
+    let backend: Arc<dyn BlockBackend> = match &args.mirror_file {
+        Some(path) => {
+            log::info!(
+                "Mirroring writes to {}{}",
+                path.display(),
+                if args.mirror_restore {
+                    ", restoring the device from it"
+                } else {
+                    ""
+                }
+            );
+            let mirror = match args.backing_offset {
+                Some(offset) => {
+                    let mirror = FileBackend::open_window(path, offset, Some(backend.size()))
+                        .context("Failed to open --mirror-file")?;
+                    // Not truncated, so cleared to match the empty device
+                    if !args.mirror_restore && !args.discard_all_on_start {
+                        mirror
+                            .discard_at(0, mirror.size())
+                            .context("Failed to clear --mirror-file")?;
+                    }
+                    mirror
+                }
+                None => FileBackend::open(path, backend.size(), !args.mirror_restore)
+                    .context("Failed to open --mirror-file")?,
             };
+            if args.discard_all_on_start {
+                discard_all(&mirror, path)?;
+            }
+            // Nothing left to restore from a discarded mirror
+            let restore = args.mirror_restore && !args.discard_all_on_start;
+            Arc::new(MirrorBackend::new(backend, mirror, restore)?)
+        }
+        None => backend,
+    };
+
+    let backend: Arc<dyn BlockBackend> = match &args.backing_file {
+        Some(path) => {
+            let restore = path.exists();
+            log::info!(
+                "Writing through to sparse file {}{}",
+                path.display(),
+                if restore {
+                    ", restoring the device from it"
+                } else {
+                    ""
+                }
+            );
+            let backing = if restore {
+                SparseFileBackend::open(path, backend.size())
+            } else {
+                SparseFileBackend::create(path, backend.size(), SPARSE_BLOCK_SIZE)
+            }
+            .context("Failed to open --backing-file")?;
+            if args.discard_all_on_start {
+                discard_all(&backing, path)?;
+            }
+            Arc::new(MirrorBackend::new(
+                backend,
+                backing,
+                restore && !args.discard_all_on_start,
+            )?)
+        }
+        None => backend,
+    };
+
+    // Kept to save the device once the frontend has stopped
+    let persist = match &args.persist_file {
+        Some(path) => Some(Arc::new(
+            PersistentBackend::open(backend.clone(), path)
+                .context("Failed to restore the device from --persist-file")?,
+        )),
+        None => None,
+    };
+    let backend: Arc<dyn BlockBackend> = match &persist {
+        Some(persist) => persist.clone(),
+        None => backend,
+    };
+
+    let backend: Arc<dyn BlockBackend> = if args.spare_blocks > 0 {
+        let known_bad = args.bad_blocks.iter().copied().collect();
+        Arc::new(
+            BadBlockRemapBackend::new(
+                backend,
+                args.spare_blocks,
+                &known_bad,
+                remap_table_path(&args),
+                metrics.clone(),
+            )
+            .context("Failed to set up bad-block remapping")?,
+        )
+    } else if !args.bad_blocks.is_empty() {
+        bail!("--bad-blocks needs spare blocks to remap into (--spare-blocks)");
+    } else {
+        backend
+    };
 
-            // Cooperative shutdown: Ctrl-C cancels token; server exits cleanly
-            let token = CancellationToken::new();
-            let cancel_task = {
-                let t = token.clone();
-                tokio::spawn(async move {
-                    #[cfg(unix)]
-                    {
-                        let mut term = tokio::signal::unix::signal(
-                            tokio::signal::unix::SignalKind::terminate(),
-                        )
+    let backend: Arc<dyn BlockBackend> = match args.image_format {
+        ImageFormat::Raw => backend,
+        ImageFormat::Qcow2 => Arc::new(
+            Qcow2Backend::open_or_format(backend, args.virtual_size.unwrap_or(args.size))
+                .context("Failed to set up qcow2 image")?,
+        ),
+    };
+
+    let backend: Arc<dyn BlockBackend> = match args.logical_size {
+        Some(logical_size) if logical_size != backend.size() => {
+            log::warn!(
+                "--logical-size is a testing feature: advertising {} bytes backed by {} bytes; \
+                 writes past the backing fail with ENOSPC and data there reads as zeros",
+                logical_size,
+                backend.size()
+            );
+            Arc::new(LogicalSizeBackend::new(backend, logical_size))
+        }
+        _ => backend,
+    };
+
+    let backend: Arc<dyn BlockBackend> = match args.write_combine_delay {
+        Some(delay) => {
+            log::info!("Combining small writes for up to {:?}", delay);
+            Arc::new(WriteCombineBackend::new(backend, delay))
+        }
+        None => backend,
+    };
+
+    let backend: Arc<dyn BlockBackend> =
+        if args.write_budget.is_some() || args.write_window.is_some() {
+            log::info!(
+                "Write budget: {}, write window: {}",
+                args.write_budget
+                    .map_or("unlimited".to_string(), |b| format!("{} bytes", b)),
+                args.write_window
+                    .map_or("unlimited".to_string(), |w| format!("{:?}", w))
+            );
+            Arc::new(WriteBudgetBackend::new(
+                backend,
+                args.write_budget,
+                args.write_window,
+            ))
+        } else {
+            backend
+        };
+
+    // Kept to report how much of the device was written
+    let allocation = if args.track_allocation {
+        log::info!(
+            "Tracking written blocks of {} bytes",
+            args.allocation_block_size
+        );
+        Some(Arc::new(
+            ZeroMapBackend::new(backend.clone(), args.allocation_block_size)
+                .context("Failed to set up --track-allocation")?,
+        ))
+    } else {
+        None
+    };
+    let backend: Arc<dyn BlockBackend> = match &allocation {
+        Some(allocation) => allocation.clone(),
+        None => backend,
+    };
+
+    // Snapshots read below the trace wrapper so they don't show up in traces
+    let snapshot_backend = backend.clone();
+
+    let backend: Arc<dyn BlockBackend> = match &args.capture_trace {
+        Some(path) => {
+            log::info!("Capturing I/O trace to {}", path.display());
+            Arc::new(TraceBackend::create(backend, path)?)
+        }
+        None => backend,
+    };
+
+    log::debug!(
+        "Write-zeroes requests are {}",
+        if backend.fast_zero() {
+            "filled on the device (fast zero)"
+        } else {
+            "written from the host"
+        }
+    );
+
+    // Overlapping requests from concurrent clients (or ublk queues) must not
+    // interleave below this point
+    let backend: Arc<dyn BlockBackend> = Arc::new(RangeLockBackend::new(backend));
+
+    // Kept to hash the device once the frontend has stopped
+    let shutdown_backend = backend.clone();
+
+    // Cooperative shutdown: Ctrl-C or SIGTERM cancels the token and the
+    // frontend drains, escalating after --shutdown-grace
+    let token = CancellationToken::new();
+    let signal_task = {
+        let t = token.clone();
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            {
+                let mut term =
+                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
                         .expect("failed to install SIGTERM handler");
-                        tokio::select! {
-                            _ = tokio::signal::ctrl_c() => {},
-                            _ = term.recv() => {},
-                        }
-                    }
-                    #[cfg(not(unix))]
-                    {
-                        let _ = tokio::signal::ctrl_c().await;
-                    }
-                    t.cancel();
-                })
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = term.recv() => {},
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+            t.cancel();
+        })
+    };
+
+    if args.exit_on_device_lost {
+        let health = health.clone();
+        let t = token.clone();
+        tokio::spawn(async move {
+            health.device_lost().await;
+            t.cancel();
+        });
+    }
+
+    // Stops with the frontend, whether on a signal or on a frontend error.
+    // The control socket can ask for snapshots even without an interval.
+    let snapshot_stop = token.child_token();
+    let (snapshot_requests, snapshot_queue) = mpsc::channel(1);
+    let snapshots =
+        (args.snapshot_interval.is_some() || args.control_socket.is_some()).then(|| {
+            spawn_snapshots(
+                snapshot_backend,
+                args.snapshot_path.clone(),
+                args.snapshot_interval,
+                args.snapshot_compress,
+                snapshot_queue,
+                snapshot_stop.clone(),
+            )
+        });
+
+    let metrics_stop = CancellationToken::new();
+    let metrics_server = args
+        .metrics_addr
+        .clone()
+        .map(|addr| spawn_metrics_server(addr, metrics.clone(), metrics_stop.clone()));
+
+    let control_stop = CancellationToken::new();
+    let control_server = match (&args.control_socket, &resizable) {
+        (Some(path), Some(device)) => {
+            let state = ControlState {
+                device: device.clone(),
+                backend: shutdown_backend.clone(),
+                metrics: metrics.clone(),
+                kind: backend_kind(&args),
+                snapshots: snapshot_requests,
+                allocation: allocation.clone(),
             };
+            Some(spawn_control_server(
+                path.clone(),
+                state,
+                control_stop.clone(),
+            )?)
+        }
+        _ => None,
+    };
 
-            // ublk server runs until shutdown
-            start_ublk_server(buffer, ublk_cfg, token).await?;
-            // Best-effort: stop the cancel task if still running
-            cancel_task.abort();
+    // Start the selected frontends. Both serve the same backend; when one
+    // stops, the token stops the other
+    let mut exports = Vec::new();
+    if nbd_driver.is_some() {
+        if let Some(window) = args.readahead {
+            log::info!(
+                "Reading up to {} bytes ahead of sequential NBD reads",
+                window
+            );
+        }
+        let readahead = |backend: Arc<dyn BlockBackend>| -> Arc<dyn BlockBackend> {
+            match args.readahead {
+                Some(window) => Arc::new(ReadaheadBackend::new(backend, window)),
+                None => backend,
+            }
+        };
+        let mut export = NbdExport::new(args.export_name.clone(), readahead(backend.clone()));
+        export.resizable = resizable.clone();
+        export.max_connections = args.max_connections.map(|n| n as usize);
+        export.single_writer = args.single_writer;
+        exports.push(export);
+        // Extra exports get their own buffers, allocated like the main one
+        let allocator = DeviceAllocator {
+            args: args.clone(),
+            metrics: metrics.clone(),
+            health: health.clone(),
+        };
+        for spec in &args.exports {
+            log::info!("Allocating {} bytes for export '{}'", spec.size, spec.name);
+            let backend = allocator.allocate(spec.size)?;
+            let backend: Arc<dyn BlockBackend> = if args.track_allocation {
+                Arc::new(ZeroMapBackend::new(backend, args.allocation_block_size)?)
+            } else {
+                backend
+            };
+            let backend = Arc::new(RangeLockBackend::new(backend));
+            let mut export = NbdExport::new(spec.name.clone(), readahead(backend));
+            export.max_connections = spec.max_connections;
+            export.single_writer = spec.single_writer;
+            exports.push(export);
+        }
+    }
+    if uses_opencl(&args) {
+        log::info!(
+            "{} OpenCL context(s) in use{}",
+            contexts_in_use(),
+            if args.shared_context { ", shared" } else { "" }
+        );
+    }
+    let nbd_server = async {
+        if nbd_driver.is_none() {
+            return Ok(());
+        }
+        // NBD server runs until shutdown
+        let result = start_nbd_server(exports, &nbd_config, token.clone()).await;
+        token.cancel();
+        result
+    };
+    let ublk_server = async {
+        if !ublk {
+            return Ok(());
+        }
+        // Default logical block size: 4096 bytes
+        let ublk_cfg = UblkConfig {
+            logical_block_size: 4096,
+            read_only: args.read_only,
+            rotational: args.rotational,
+            shutdown_grace: args.shutdown_grace,
+            dev_path_file: args.dev_path_file.clone(),
+            mkfs: args.mkfs.clone(),
+            force_format: args.force_format,
+            mount_dir: args.mount.clone(),
+            queues: args.ublk_queues.unwrap_or_else(ublk_queue_count),
+            depth: args.ublk_depth.unwrap_or(UBLK_DEFAULT_DEPTH),
+            ready: readiness.clone(),
+            metrics: metrics.clone(),
+            health: health.clone(),
+        };
+
+        // ublk server runs until shutdown
+        let result = start_ublk_server(backend.clone(), ublk_cfg, token.clone()).await;
+        token.cancel();
+        result
+    };
+    let fuse_server = async {
+        #[cfg(feature = "fuse")]
+        if let Some(mountpoint) = args.mountpoint.clone() {
+            let fuse_cfg = FuseConfig {
+                mountpoint,
+                read_only: args.read_only,
+                shutdown_grace: args.shutdown_grace,
+                ready: readiness.clone(),
+                metrics: metrics.clone(),
+                health: health.clone(),
+            };
+            // FUSE filesystem is served until shutdown or an outside unmount
+            let result = start_fuse_server(backend.clone(), fuse_cfg, token.clone()).await;
+            token.cancel();
+            return result;
         }
+        Ok::<_, anyhow::Error>(())
+    };
+    let (nbd_result, ublk_result, fuse_result) = tokio::join!(nbd_server, ublk_server, fuse_server);
+    nbd_result?;
+    ublk_result?;
+    fuse_result?;
+    // Best-effort: stop the signal task if still running
+    signal_task.abort();
+
+    if let Some(control_server) = control_server {
+        control_stop.cancel();
+        control_server.await?;
+    }
+
+    if let Some(metrics_server) = metrics_server {
+        metrics_stop.cancel();
+        metrics_server.await?;
+    }
+
+    if let Some(health_server) = health_server {
+        health_stop.cancel();
+        health_server.await?;
+    }
+
+    if let Some(snapshots) = snapshots {
+        snapshot_stop.cancel();
+        snapshots.await?;
+    }
+
+    if health.is_device_lost() {
+        // Nothing can be read back from the GPU; --mirror-file,
+        // --backing-file and --persist-file keep their last good contents
+        bail!("The GPU device was lost; restart vramblk to restore the device");
+    }
+
+    if args.cache_mode == CacheMode::Writeback {
+        let top = shutdown_backend.clone();
+        tokio::task::spawn_blocking(move || top.flush())
+            .await?
+            .context("Failed to write back the cache to --cache-backing")?;
+    }
+
+    if let Some(persist) = persist {
+        let top = shutdown_backend.clone();
+        tokio::task::spawn_blocking(move || {
+            // Commit writes still held by wrappers above the persisted layer
+            top.flush()?;
+            persist.save()
+        })
+        .await?
+        .context("Failed to save the device to --persist-file")?;
+    }
+
+    if let Some(allocation) = allocation {
+        log::info!(
+            "{} of {} bytes of the device were written",
+            allocation.allocated_bytes(),
+            allocation.size()
+        );
+    }
+
+    if args.hash_on_shutdown {
+        let algorithm = args.hash_algorithm;
+        log::info!(
+            "Hashing {} bytes of device contents ({})...",
+            shutdown_backend.size(),
+            algorithm
+        );
+        let digest =
+            tokio::task::spawn_blocking(move || hash_backend(&*shutdown_backend, algorithm))
+                .await??;
+        log::info!("Device hash ({}): {}", algorithm, digest);
     }
 
     log::info!("VRAM Block Device server has shut down.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discard_all_empties_a_backing_file() {
+        let path = std::env::temp_dir().join(format!("vramblk-discard-{}", std::process::id()));
+        let size = 4 * SPARSE_BLOCK_SIZE;
+        let backing = SparseFileBackend::create(&path, size, SPARSE_BLOCK_SIZE).unwrap();
+        backing.write_at(100, &[7; 4096]).unwrap();
+        backing.write_at(3 * SPARSE_BLOCK_SIZE, &[8; 4096]).unwrap();
+        assert!(!backing.is_known_zero(0, size));
+
+        discard_all(&backing, &path).unwrap();
+        assert!(backing.is_known_zero(0, size));
+        drop(backing);
+        let reopened = SparseFileBackend::open(&path, size).unwrap();
+        let mut data = vec![1u8; size as usize];
+        reopened.read_at(0, &mut data).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(data.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn duration_suffixes() {
+        assert_eq!(
+            parse_duration_string("200us").unwrap(),
+            Duration::from_micros(200)
+        );
+        assert_eq!(
+            parse_duration_string("15ms").unwrap(),
+            Duration::from_millis(15)
+        );
+        assert_eq!(
+            parse_duration_string("90").unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            parse_duration_string("90s").unwrap(),
+            Duration::from_secs(90)
+        );
+        assert_eq!(
+            parse_duration_string("5M").unwrap(),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            parse_duration_string("2h").unwrap(),
+            Duration::from_secs(7200)
+        );
+        assert_eq!(
+            parse_duration_string("1d").unwrap(),
+            Duration::from_secs(86400)
+        );
+        for bad in ["", "s", "5w", "-1s"] {
+            assert!(parse_duration_string(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn zero_interval_is_rejected() {
+        assert!(parse_interval_string("0").is_err());
+        assert!(parse_interval_string("0ms").is_err());
+        assert_eq!(
+            parse_interval_string("5m").unwrap(),
+            Duration::from_secs(300)
+        );
+    }
+
+    #[test]
+    fn duration_overflow_is_rejected() {
+        let max = u64::MAX.to_string();
+        assert_eq!(
+            parse_duration_string(&max).unwrap(),
+            Duration::from_secs(u64::MAX)
+        );
+        for suffix in ["m", "h", "d"] {
+            assert!(parse_duration_string(&format!("{}{}", max, suffix)).is_err());
+        }
+        assert!(parse_duration_string(&format!("{}d", u64::MAX / 86400 + 1)).is_err());
+        assert!(parse_duration_string(&format!("{}d", u64::MAX / 86400)).is_ok());
+    }
+
+    #[test]
+    fn export_spec_options() {
+        assert_eq!(
+            parse_export_spec("scratch=1G").unwrap(),
+            ExportSpec {
+                name: "scratch".to_string(),
+                size: 1 << 30,
+                max_connections: None,
+                single_writer: false,
+            }
+        );
+        let export = parse_export_spec("db=4G,max-conn=2,single-writer").unwrap();
+        assert_eq!(export.size, 4 << 30);
+        assert_eq!(export.max_connections, Some(2));
+        assert!(export.single_writer);
+        for bad in [
+            "db",
+            "=1G",
+            "db=4G,max-conn=0",
+            "db=4G,max-conn=x",
+            "db=4G,writer",
+            "db=4G,",
+        ] {
+            assert!(parse_export_spec(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn size_binary_suffixes() {
+        for (suffix, shift) in [("K", 10), ("M", 20), ("G", 30), ("T", 40)] {
+            let expected = 3u64 << shift;
+            assert_eq!(
+                parse_size_string(&format!("3{}", suffix)).unwrap(),
+                expected
+            );
+            assert_eq!(
+                parse_size_string(&format!("3{}iB", suffix)).unwrap(),
+                expected
+            );
+            assert_eq!(
+                parse_size_string(&format!("3{}", suffix.to_lowercase())).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn size_decimal_suffixes() {
+        assert_eq!(parse_size_string("3KB").unwrap(), 3_000);
+        assert_eq!(parse_size_string("3MB").unwrap(), 3_000_000);
+        assert_eq!(parse_size_string("3GB").unwrap(), 3_000_000_000);
+        assert_eq!(parse_size_string("3TB").unwrap(), 3_000_000_000_000);
+        assert_eq!(parse_size_string("3 gb").unwrap(), 3_000_000_000);
+    }
+
+    #[test]
+    fn size_bare_number_is_mib() {
+        assert_eq!(parse_size_string("2048").unwrap(), 2048 << 20);
+        assert_eq!(parse_size_string(" 1 ").unwrap(), 1 << 20);
+    }
+
+    #[test]
+    fn size_percentage() {
+        assert_eq!(parse_device_size("90%").unwrap(), DeviceSize::Percent(90));
+        assert_eq!(
+            parse_device_size("100 %").unwrap(),
+            DeviceSize::Percent(100)
+        );
+        assert_eq!(parse_device_size("1G").unwrap(), DeviceSize::Bytes(1 << 30));
+        assert!(parse_device_size("0%").is_err());
+        assert!(parse_device_size("101%").is_err());
+    }
+
+    #[test]
+    fn size_rejects_invalid() {
+        assert!(parse_size_string("").is_err());
+        assert!(parse_size_string("G").is_err());
+        assert!(parse_size_string("3X").is_err());
+        assert!(parse_size_string("3PB").is_err());
+        assert!(parse_size_string("18446744073709551615K").is_err());
+        assert!(parse_size_string("99999999999999999999").is_err());
+        assert!(parse_size_string("16777216T").is_err());
+    }
+}
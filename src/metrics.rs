@@ -0,0 +1,298 @@
+//! Prometheus metrics for the block device
+//!
+//! `Metrics` holds counters shared by the frontends, which update them as
+//! they complete requests, and a couple of gauges. With `--metrics-addr`, a
+//! small HTTP server renders them in the Prometheus text format at
+//! `/metrics`. The server is an optional extra: failing to bind it is only
+//! a warning and never stops the block device.
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// A copy of the metrics at one point in time, as reported by the control
+/// socket's `stats` command
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Stats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub read_ops: u64,
+    pub write_ops: u64,
+    pub flush_ops: u64,
+    pub io_errors: u64,
+    pub nbd_clients: u64,
+    pub nbd_rejected: u64,
+    pub vram_allocated_bytes: u64,
+    pub checksum_errors: u64,
+    pub bad_blocks: u64,
+}
+
+/// Counters and gauges exported to Prometheus
+#[derive(Debug, Default)]
+pub struct Metrics {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_ops: AtomicU64,
+    write_ops: AtomicU64,
+    flush_ops: AtomicU64,
+    io_errors: AtomicU64,
+    nbd_clients: AtomicU64,
+    nbd_rejected: AtomicU64,
+    vram_bytes: AtomicU64,
+    checksum_errors: AtomicU64,
+    bad_blocks: AtomicU64,
+}
+
+impl Metrics {
+    /// A read of `bytes` completed
+    pub fn record_read(&self, bytes: u64) {
+        self.read_ops.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A write of `bytes` completed
+    pub fn record_write(&self, bytes: u64) {
+        self.write_ops.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// A flush completed
+    pub fn record_flush(&self) {
+        self.flush_ops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The backend failed a request
+    pub fn record_error(&self) {
+        self.io_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A block failed checksum verification
+    pub fn record_checksum_error(&self) {
+        self.checksum_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `count` blocks are remapped to spares
+    pub fn set_bad_blocks(&self, count: u64) {
+        self.bad_blocks.store(count, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` more of allocated GPU memory
+    pub fn add_vram(&self, bytes: u64) {
+        self.vram_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count `bytes` less of allocated GPU memory
+    pub fn remove_vram(&self, bytes: u64) {
+        self.vram_bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Count an NBD client as connected until the guard is dropped
+    pub fn client_connected(self: &Arc<Self>) -> ClientGuard {
+        self.nbd_clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(self.clone())
+    }
+
+    /// An NBD connection was closed because --max-clients were open
+    pub fn record_rejected_client(&self) {
+        self.nbd_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current values of all counters and gauges
+    pub fn stats(&self) -> Stats {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+        Stats {
+            bytes_read: load(&self.bytes_read),
+            bytes_written: load(&self.bytes_written),
+            read_ops: load(&self.read_ops),
+            write_ops: load(&self.write_ops),
+            flush_ops: load(&self.flush_ops),
+            io_errors: load(&self.io_errors),
+            nbd_clients: load(&self.nbd_clients),
+            nbd_rejected: load(&self.nbd_rejected),
+            vram_allocated_bytes: load(&self.vram_bytes),
+            checksum_errors: load(&self.checksum_errors),
+            bad_blocks: load(&self.bad_blocks),
+        }
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "vramblk_read_bytes_total",
+                "counter",
+                "Bytes read by clients",
+                &self.bytes_read,
+            ),
+            (
+                "vramblk_written_bytes_total",
+                "counter",
+                "Bytes written by clients",
+                &self.bytes_written,
+            ),
+            (
+                "vramblk_read_ops_total",
+                "counter",
+                "Completed read requests",
+                &self.read_ops,
+            ),
+            (
+                "vramblk_write_ops_total",
+                "counter",
+                "Completed write requests",
+                &self.write_ops,
+            ),
+            (
+                "vramblk_flush_ops_total",
+                "counter",
+                "Completed flush requests",
+                &self.flush_ops,
+            ),
+            (
+                "vramblk_io_errors_total",
+                "counter",
+                "Requests failed by the backend",
+                &self.io_errors,
+            ),
+            (
+                "vramblk_nbd_clients",
+                "gauge",
+                "Connected NBD clients",
+                &self.nbd_clients,
+            ),
+            (
+                "vramblk_nbd_rejected_total",
+                "counter",
+                "NBD connections refused because --max-clients were open",
+                &self.nbd_rejected,
+            ),
+            (
+                "vramblk_vram_allocated_bytes",
+                "gauge",
+                "GPU memory allocated for the devices",
+                &self.vram_bytes,
+            ),
+            (
+                "vramblk_checksum_errors_total",
+                "counter",
+                "Blocks that failed checksum verification (--checksum)",
+                &self.checksum_errors,
+            ),
+            (
+                "vramblk_bad_blocks",
+                "gauge",
+                "Blocks remapped to spares (--spare-blocks)",
+                &self.bad_blocks,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// A connected NBD client; decrements the client gauge when dropped
+pub struct ClientGuard(Arc<Metrics>);
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.0.nbd_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serve `metrics` over HTTP on `addr` until `cancel` is cancelled. A bind
+/// failure is logged as a warning and the task ends.
+pub fn spawn_metrics_server(
+    addr: String,
+    metrics: Arc<Metrics>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    spawn_http_server("Prometheus metrics", "/metrics", addr, cancel, move || {
+        let mut response = Response::new(Full::new(Bytes::from(metrics.render())));
+        response.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        );
+        response
+    })
+}
+
+/// Serve `respond` at `path` over HTTP on `addr` until `cancel` is
+/// cancelled; other paths get a 404. `what` names the server in the logs. A
+/// bind failure is logged as a warning and the task ends.
+pub fn spawn_http_server<F>(
+    what: &'static str,
+    path: &'static str,
+    addr: String,
+    cancel: CancellationToken,
+    respond: F,
+) -> JoinHandle<()>
+where
+    F: Fn() -> Response<Full<Bytes>> + Clone + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        let listener = match TcpListener::bind(&addr).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                log::warn!(
+                    "Failed to bind {} server on {}: {}; continuing without it",
+                    what,
+                    addr,
+                    e
+                );
+                return;
+            }
+        };
+        log::info!("Serving {} on http://{}{}", what, addr, path);
+
+        loop {
+            let stream = tokio::select! {
+                _ = cancel.cancelled() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        log::warn!("{} server accept error: {}", what, e);
+                        continue;
+                    }
+                },
+            };
+            let respond = respond.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let response = if request.uri().path() == path {
+                        respond()
+                    } else {
+                        let mut response =
+                            Response::new(Full::new(Bytes::from_static(b"Not Found\n")));
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        response
+                    };
+                    async move { Ok::<_, Infallible>(response) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    log::debug!("{} connection error: {}", what, e);
+                }
+            });
+        }
+    })
+}
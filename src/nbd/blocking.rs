@@ -0,0 +1,637 @@
+//! Blocking NBD connection handling using the `nbd` crate v0.3.1.
+//!
+//! The handshake is done here so option negotiation (export listing in
+//! particular) knows about all exports; the transmission phase is the
+//! crate's. Each connection occupies a thread from tokio's blocking pool for its
+//! whole lifetime. Used for WebSocket connections and, with the `sync-nbd`
+//! feature, for plain TCP connections as a fallback to the async path.
+//!
+//! The crate seeks to a request's offset and then streams the data, sending
+//! the reply header with the first chunk, so a read running past the end of
+//! the export would be cut short after a success reply. `RequestTracker`
+//! follows the request headers on the client stream so that `VramSeeker`
+//! can refuse such requests when the crate seeks: reads with EINVAL and
+//! writes with ENOSPC, as the async path does.
+
+use super::listener::Connection;
+use super::server::{
+    find_export, log_client_result, parse_info_request, ConnectionSlot, NbdConfig, NbdExport,
+};
+use crate::backend::{is_no_space, is_read_only, BlockBackend};
+use crate::metrics::Metrics;
+use anyhow::{bail, Context, Result};
+use nbd;
+use std::cell::Cell;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::net::{Shutdown, TcpStream as StdTcpStream};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::rc::Rc;
+use std::sync::Arc;
+use tokio::task;
+use tokio_util::either::Either;
+
+// Handshake
+const IHAVEOPT: u64 = 0x4948_4156_454F_5054;
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+const MAX_OPTION_LEN: u32 = 64 * 1024;
+
+// Options and replies
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+const REP_ERR_POLICY: u32 = (1 << 31) | 2;
+const REP_ERR_INVALID: u32 = (1 << 31) | 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+// Transmission flags
+const TFLAG_HAS_FLAGS: u16 = 1 << 0;
+const TFLAG_READ_ONLY: u16 = 1 << 1;
+const TFLAG_SEND_FLUSH: u16 = 1 << 2;
+const TFLAG_ROTATIONAL: u16 = 1 << 4;
+const TFLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+// Transmission requests
+const REQUEST_LEN: usize = 28;
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+
+/// Header fields of a transmission request
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    command: u16,
+    offset: u64,
+    len: u32,
+}
+
+/// State shared by the client stream and the `VramSeeker` of a connection
+#[derive(Debug, Default)]
+struct RequestState {
+    /// The request being served
+    current: Cell<Option<Request>>,
+    /// Set when the current write was refused; its payload is then skipped
+    refused_write: Cell<bool>,
+}
+
+/// Client stream that parses the request headers the crate reads
+struct RequestTracker<S> {
+    inner: S,
+    header: [u8; REQUEST_LEN],
+    filled: usize,
+    /// Write payload bytes still to pass before the next header
+    payload: u64,
+    state: Rc<RequestState>,
+}
+
+impl<S> RequestTracker<S> {
+    fn new(inner: S, state: Rc<RequestState>) -> Self {
+        Self {
+            inner,
+            header: [0; REQUEST_LEN],
+            filled: 0,
+            payload: 0,
+            state,
+        }
+    }
+}
+
+impl<S: Read> Read for RequestTracker<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.state.refused_write.take() {
+            let mut sink = [0u8; 4096];
+            while self.payload > 0 {
+                let chunk = self.payload.min(sink.len() as u64) as usize;
+                let n = self.inner.read(&mut sink[..chunk])?;
+                if n == 0 {
+                    return Ok(0);
+                }
+                self.payload -= n as u64;
+            }
+        }
+
+        let n = self.inner.read(buf)?;
+        let mut data = &buf[..n];
+        while !data.is_empty() {
+            if self.payload > 0 {
+                let skip = self.payload.min(data.len() as u64) as usize;
+                self.payload -= skip as u64;
+                data = &data[skip..];
+                continue;
+            }
+            let take = (REQUEST_LEN - self.filled).min(data.len());
+            self.header[self.filled..self.filled + take].copy_from_slice(&data[..take]);
+            self.filled += take;
+            data = &data[take..];
+            if self.filled == REQUEST_LEN {
+                self.filled = 0;
+                let h = &self.header;
+                let request = Request {
+                    command: u16::from_be_bytes([h[6], h[7]]),
+                    offset: u64::from_be_bytes(h[16..24].try_into().unwrap()),
+                    len: u32::from_be_bytes(h[24..28].try_into().unwrap()),
+                };
+                if request.command == CMD_WRITE {
+                    self.payload = request.len as u64;
+                }
+                self.state.current.set(Some(request));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for RequestTracker<S> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+// --- Wrapper struct implementing Read/Write/Seek for a BlockBackend ---
+struct VramSeeker<B: ?Sized> {
+    buffer: Arc<B>,
+    pos: u64,
+    size: u64,
+    readonly: bool,
+    metrics: Arc<Metrics>,
+    requests: Rc<RequestState>,
+}
+
+impl<B: BlockBackend + ?Sized> VramSeeker<B> {
+    fn new(
+        buffer: Arc<B>,
+        readonly: bool,
+        metrics: Arc<Metrics>,
+        requests: Rc<RequestState>,
+    ) -> Self {
+        let size = buffer.size();
+        VramSeeker {
+            buffer,
+            pos: 0,
+            size,
+            readonly,
+            metrics,
+            requests,
+        }
+    }
+
+    /// Refuse the current request if it starts at `offset` but doesn't fit
+    /// in the export
+    fn check_bounds(&self, offset: u64) -> IoResult<()> {
+        let Some(request) = self.requests.current.get() else {
+            return Ok(());
+        };
+        let in_bounds = offset
+            .checked_add(request.len as u64)
+            .is_some_and(|end| end <= self.size);
+        if request.offset != offset || in_bounds {
+            return Ok(());
+        }
+        match request.command {
+            CMD_READ => Err(IoError::from_raw_os_error(libc::EINVAL)),
+            CMD_WRITE => {
+                self.requests.refused_write.set(true);
+                Err(IoError::from_raw_os_error(if self.readonly {
+                    libc::EPERM
+                } else {
+                    libc::ENOSPC
+                }))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<B: BlockBackend + ?Sized> Read for VramSeeker<B> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let remaining = self.size.saturating_sub(self.pos);
+        if remaining == 0 {
+            return Ok(0);
+        }
+
+        let read_len = std::cmp::min(buf.len() as u64, remaining) as usize;
+        let read_buf = &mut buf[..read_len];
+
+        match self.buffer.read_at(self.pos, read_buf) {
+            Ok(_) => {
+                self.pos += read_len as u64;
+                self.metrics.record_read(read_len as u64);
+                log::trace!("VramSeeker read {} bytes, new pos {}", read_len, self.pos);
+                Ok(read_len)
+            }
+            Err(e) => {
+                self.metrics.record_error();
+                log::error!("VRAM read error during NBD Read: {}", e);
+                Err(IoError::new(ErrorKind::Other, "VRAM read failed"))
+            }
+        }
+    }
+}
+
+impl<B: BlockBackend + ?Sized> Write for VramSeeker<B> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.readonly {
+            return Err(IoError::new(
+                ErrorKind::PermissionDenied,
+                "Write on read-only connection",
+            ));
+        }
+
+        let remaining = self.size.saturating_sub(self.pos);
+        if remaining == 0 {
+            return Err(IoError::new(
+                ErrorKind::WriteZero,
+                "Write past end of VRAM buffer",
+            ));
+        }
+
+        let write_len = std::cmp::min(buf.len() as u64, remaining) as usize;
+        if write_len == 0 {
+            return Ok(0);
+        }
+        let write_buf = &buf[..write_len];
+
+        let result = self.buffer.write_at(self.pos, write_buf);
+        if result.is_err() {
+            self.metrics.record_error();
+        }
+        match result {
+            Ok(_) => {
+                self.pos += write_len as u64;
+                self.metrics.record_write(write_len as u64);
+                log::trace!("VramSeeker wrote {} bytes, new pos {}", write_len, self.pos);
+                Ok(write_len)
+            }
+            // Replied to the client as EPERM, like a read-only export
+            Err(e) if is_read_only(&e) => Err(IoError::from_raw_os_error(libc::EPERM)),
+            Err(e) if is_no_space(&e) => Err(IoError::from_raw_os_error(libc::ENOSPC)),
+            Err(e) => {
+                log::error!("VRAM write error during NBD Write: {}", e);
+                Err(IoError::new(ErrorKind::Other, "VRAM write failed"))
+            }
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        log::trace!("VramSeeker flush");
+        self.buffer
+            .flush()
+            .inspect(|_| self.metrics.record_flush())
+            .map_err(|e| {
+                self.metrics.record_error();
+                log::error!("Backend flush error during NBD Flush: {:#}", e);
+                IoError::other("backend flush failed")
+            })
+    }
+}
+
+impl<B: BlockBackend + ?Sized> Seek for VramSeeker<B> {
+    fn seek(&mut self, style: SeekFrom) -> IoResult<u64> {
+        let (base_pos, offset) = match style {
+            SeekFrom::Start(n) => {
+                self.check_bounds(n)?;
+                self.pos = n;
+                log::trace!("VramSeeker seek to Start({}), new pos {}", n, self.pos);
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.size, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let new_pos = if offset >= 0 {
+            base_pos.checked_add(offset as u64)
+        } else {
+            base_pos.checked_sub((offset.wrapping_neg()) as u64)
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                log::trace!("VramSeeker seek relative({}), new pos {}", offset, self.pos);
+                Ok(self.pos)
+            }
+            None => Err(IoError::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Blocking std counterpart of an accepted connection
+pub(super) enum StdConnection {
+    Tcp(StdTcpStream),
+    Unix(StdUnixStream),
+}
+
+impl StdConnection {
+    fn from_tokio(stream: Connection) -> IoResult<Self> {
+        let std_stream = match stream {
+            Either::Left(tcp) => StdConnection::Tcp(tcp.into_std()?),
+            Either::Right(unix) => StdConnection::Unix(unix.into_std()?),
+        };
+        match &std_stream {
+            StdConnection::Tcp(s) => s.set_nonblocking(false)?,
+            StdConnection::Unix(s) => s.set_nonblocking(false)?,
+        }
+        Ok(std_stream)
+    }
+
+    fn try_clone(&self) -> IoResult<Self> {
+        Ok(match self {
+            StdConnection::Tcp(s) => StdConnection::Tcp(s.try_clone()?),
+            StdConnection::Unix(s) => StdConnection::Unix(s.try_clone()?),
+        })
+    }
+
+    fn shutdown(&self) -> IoResult<()> {
+        match self {
+            StdConnection::Tcp(s) => s.shutdown(Shutdown::Both),
+            StdConnection::Unix(s) => s.shutdown(Shutdown::Both),
+        }
+    }
+}
+
+impl Read for StdConnection {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self {
+            StdConnection::Tcp(s) => s.read(buf),
+            StdConnection::Unix(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for StdConnection {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match self {
+            StdConnection::Tcp(s) => s.write(buf),
+            StdConnection::Unix(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self {
+            StdConnection::Tcp(s) => s.flush(),
+            StdConnection::Unix(s) => s.flush(),
+        }
+    }
+}
+
+/// Shuts a socket down when dropped, so a blocking handler still using a
+/// clone of it fails out of its read or write
+struct ShutdownOnDrop(StdConnection);
+
+impl Drop for ShutdownOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.shutdown();
+    }
+}
+
+/// Handle an accepted connection on the blocking pool: convert the socket to
+/// a blocking std stream and run `handler` on it. Completes when the handler
+/// returns; dropping the future before that closes the connection.
+pub(super) async fn run_blocking_client<F>(stream: Connection, client: String, handler: F)
+where
+    F: FnOnce(StdConnection) -> Result<()> + Send + 'static,
+{
+    let std_stream = match StdConnection::from_tokio(stream) {
+        Ok(std_stream) => std_stream,
+        Err(e) => {
+            log::error!(
+                "Failed to convert Tokio stream to std stream for {}: {}",
+                client,
+                e
+            );
+            return;
+        }
+    };
+    let _closer = match std_stream.try_clone() {
+        Ok(clone) => ShutdownOnDrop(clone),
+        Err(e) => {
+            log::error!("Failed to clone stream for {}: {}", client, e);
+            return;
+        }
+    };
+
+    let name = client.clone();
+    let handle = task::spawn_blocking(move || {
+        log::info!("Handling client {} in blocking task...", name);
+        log_client_result(&name, handler(std_stream));
+    });
+    if let Err(e) = handle.await {
+        log::error!("Blocking task for {} failed: {}", client, e);
+    }
+}
+
+pub(super) fn handle_connection<S: Read + Write>(
+    mut stream: S,
+    exports: Arc<[NbdExport]>,
+    config: NbdConfig,
+) -> Result<()> {
+    let Some((export, slot)) =
+        handshake(&mut stream, &exports, &config).context("NBD handshake failed")?
+    else {
+        return Ok(());
+    };
+    log::info!(
+        "Handshake successful for export '{}' ({})",
+        export.name,
+        if slot.writable {
+            "read-write"
+        } else {
+            "read-only"
+        }
+    );
+
+    // The slot is held for the lifetime of the connection
+    let requests = Rc::new(RequestState::default());
+    let vram_seeker = VramSeeker::new(
+        export.backend,
+        !slot.writable,
+        config.metrics.clone(),
+        requests.clone(),
+    );
+    let stream = RequestTracker::new(stream, requests);
+    nbd::server::transmission(stream, vram_seeker).context("NBD transmission phase failed")?;
+
+    Ok(())
+}
+
+/// Fixed newstyle handshake in the form `nbd::server::transmission`
+/// expects, with NBD_OPT_LIST answered from our export list (the `nbd`
+/// crate only ever lists a placeholder name). Returns the chosen export and
+/// the connection slot claimed on it, or `None` if the client aborted.
+fn handshake<S: Read + Write>(
+    stream: &mut S,
+    exports: &[NbdExport],
+    config: &NbdConfig,
+) -> Result<Option<(NbdExport, ConnectionSlot)>> {
+    stream.write_all(b"NBDMAGIC")?;
+    stream.write_all(&IHAVEOPT.to_be_bytes())?;
+    stream.write_all(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes())?;
+    stream.flush()?;
+
+    let client_flags = read_u32(stream)?;
+    if client_flags & FLAG_C_FIXED_NEWSTYLE == 0 {
+        bail!("Client does not support fixed newstyle negotiation");
+    }
+    let no_zeroes = client_flags & FLAG_C_NO_ZEROES != 0;
+
+    loop {
+        let mut magic = [0u8; 8];
+        stream.read_exact(&mut magic)?;
+        if u64::from_be_bytes(magic) != IHAVEOPT {
+            bail!("Invalid option magic from client");
+        }
+        let option = read_u32(stream)?;
+        let len = read_u32(stream)?;
+        if len > MAX_OPTION_LEN {
+            bail!("Option {} payload too large ({} bytes)", option, len);
+        }
+        let mut data = vec![0u8; len as usize];
+        stream.read_exact(&mut data)?;
+
+        match option {
+            OPT_EXPORT_NAME => {
+                let name = String::from_utf8(data).context("Non-UTF8 export name requested")?;
+                let Some(export) = find_export(exports, &name, config) else {
+                    // NBD_OPT_EXPORT_NAME has no error reply; closing is the answer
+                    log::warn!("Client requested unknown export: {}", name);
+                    bail!("Export not found");
+                };
+                let slot = ConnectionSlot::acquire(export, config).inspect_err(|e| {
+                    log::warn!("Rejecting client for export '{}': {}", export.name, e);
+                })?;
+
+                stream.write_all(&export.backend.size().to_be_bytes())?;
+                stream
+                    .write_all(&transmission_flags(export, slot.writable, config).to_be_bytes())?;
+                if !no_zeroes {
+                    stream.write_all(&[0u8; 124])?;
+                }
+                stream.flush()?;
+                return Ok(Some((export.clone(), slot)));
+            }
+            OPT_ABORT => {
+                option_reply(stream, option, REP_ACK, &[])?;
+                return Ok(None);
+            }
+            OPT_INFO | OPT_GO => {
+                let Some(name) = parse_info_request(&data) else {
+                    option_reply(stream, option, REP_ERR_INVALID, b"Malformed request")?;
+                    continue;
+                };
+                let Some(export) = find_export(exports, &name, config) else {
+                    log::warn!("Client requested unknown export: {}", name);
+                    option_reply(stream, option, REP_ERR_UNKNOWN, b"Export not found")?;
+                    continue;
+                };
+
+                // Only NBD_OPT_GO enters transmission and takes a slot
+                let slot = if option == OPT_GO {
+                    match ConnectionSlot::acquire(export, config) {
+                        Ok(slot) => Some(slot),
+                        Err(e) => {
+                            log::warn!("Rejecting client for export '{}': {}", export.name, e);
+                            let msg = e.to_string();
+                            option_reply(stream, option, REP_ERR_POLICY, msg.as_bytes())?;
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+                let writable = match &slot {
+                    Some(slot) => slot.writable,
+                    None => ConnectionSlot::would_be_writable(export, config),
+                };
+
+                let mut info = Vec::with_capacity(12);
+                info.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+                info.extend_from_slice(&export.backend.size().to_be_bytes());
+                info.extend_from_slice(&transmission_flags(export, writable, config).to_be_bytes());
+                option_reply(stream, option, REP_INFO, &info)?;
+
+                let mut block_size = Vec::with_capacity(14);
+                block_size.extend_from_slice(&INFO_BLOCK_SIZE.to_be_bytes());
+                block_size.extend_from_slice(&config.min_block_size.to_be_bytes());
+                block_size.extend_from_slice(&config.preferred_block_size.to_be_bytes());
+                block_size.extend_from_slice(&config.max_io_size.to_be_bytes());
+                option_reply(stream, option, REP_INFO, &block_size)?;
+
+                option_reply(stream, option, REP_ACK, &[])?;
+                if let Some(slot) = slot {
+                    return Ok(Some((export.clone(), slot)));
+                }
+            }
+            OPT_LIST => {
+                if !data.is_empty() {
+                    option_reply(
+                        stream,
+                        option,
+                        REP_ERR_INVALID,
+                        b"NBD_OPT_LIST takes no data",
+                    )?;
+                    continue;
+                }
+                for export in exports {
+                    let name = export.name.as_bytes();
+                    let mut entry = Vec::with_capacity(4 + name.len());
+                    entry.extend_from_slice(&(name.len() as u32).to_be_bytes());
+                    entry.extend_from_slice(name);
+                    option_reply(stream, option, REP_SERVER, &entry)?;
+                }
+                option_reply(stream, option, REP_ACK, &[])?;
+            }
+            _ => {
+                log::debug!("Unsupported NBD option {}", option);
+                option_reply(stream, option, REP_ERR_UNSUP, &[])?;
+            }
+        }
+    }
+}
+
+/// Transmission flags advertised for a connection.
+/// `nbd::server::transmission` supports reads, writes and flush only.
+fn transmission_flags(export: &NbdExport, writable: bool, config: &NbdConfig) -> u16 {
+    let mut flags = if writable {
+        TFLAG_HAS_FLAGS | TFLAG_SEND_FLUSH
+    } else {
+        TFLAG_HAS_FLAGS | TFLAG_READ_ONLY
+    };
+    if config.rotational {
+        flags |= TFLAG_ROTATIONAL;
+    }
+    // Connections share the backend, whose flush is device-wide
+    if !export.single_writer {
+        flags |= TFLAG_CAN_MULTI_CONN;
+    }
+    flags
+}
+
+fn read_u32<S: Read>(stream: &mut S) -> IoResult<u32> {
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn option_reply<S: Write>(stream: &mut S, option: u32, reply: u32, data: &[u8]) -> IoResult<()> {
+    stream.write_all(&REPLY_MAGIC.to_be_bytes())?;
+    stream.write_all(&option.to_be_bytes())?;
+    stream.write_all(&reply.to_be_bytes())?;
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(data)?;
+    stream.flush()
+}
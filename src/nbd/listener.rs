@@ -0,0 +1,201 @@
+//! Listening sockets of the NBD server: one or more TCP addresses, or a
+//! Unix domain socket for same-host clients such as qemu, where filesystem
+//! permissions control who may connect. Under systemd socket activation,
+//! the socket passed by systemd is used instead of binding one.
+
+use super::server::NbdConfig;
+use crate::systemd;
+use anyhow::{bail, Context, Result};
+use nix::sys::socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage};
+use std::io::{ErrorKind, Result as IoResult};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_util::either::Either;
+
+/// An accepted client connection
+pub(super) type Connection = Either<TcpStream, UnixStream>;
+
+/// Bound listening sockets. A Unix socket file we bound is removed again on
+/// drop.
+pub(super) enum Listener {
+    Tcp(Vec<TcpListener>),
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+        /// Unix peers are unnamed; connections are numbered for the logs
+        accepted: AtomicU64,
+        /// Whether the socket file is ours to remove; systemd keeps its own
+        owned: bool,
+    },
+}
+
+impl Listener {
+    /// Use the socket passed by systemd if there is one, otherwise bind
+    /// `config.unix_socket` if set, or else every `config.listen_addr`
+    pub(super) async fn bind(config: &NbdConfig) -> Result<Self> {
+        if let Some(fd) = systemd::listen_socket()? {
+            log::info!("Using the listening socket passed by systemd");
+            return Self::adopt(fd);
+        }
+        Self::bind_configured(config).await
+    }
+
+    /// Bind `config.unix_socket` if set, or else every `config.listen_addr`
+    pub(super) async fn bind_configured(config: &NbdConfig) -> Result<Self> {
+        let Some(path) = &config.unix_socket else {
+            return Self::bind_tcp(&config.listen_addr).await;
+        };
+
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to bind Unix socket {}", path.display()))?;
+        Ok(Listener::Unix {
+            listener,
+            path: path.clone(),
+            accepted: AtomicU64::new(0),
+            owned: true,
+        })
+    }
+
+    /// Bind all of `addrs`. If any fail, the error names each of them.
+    async fn bind_tcp(addrs: &[String]) -> Result<Self> {
+        if addrs.is_empty() {
+            bail!("No listen address given");
+        }
+        let mut listeners = Vec::with_capacity(addrs.len());
+        let mut errors = Vec::new();
+        for addr in addrs {
+            let bound = async {
+                let addr: SocketAddr = addr
+                    .parse()
+                    .with_context(|| format!("Invalid listen address: {}", addr))?;
+                TcpListener::bind(addr)
+                    .await
+                    .with_context(|| format!("Failed to bind TCP listener to {}", addr))
+            };
+            match bound.await {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        if !errors.is_empty() {
+            bail!("{}", errors.join("; "));
+        }
+        Ok(Listener::Tcp(listeners))
+    }
+
+    /// Take over a listening TCP or Unix socket bound by someone else
+    fn adopt(fd: OwnedFd) -> Result<Self> {
+        let family = getsockname::<SockaddrStorage>(fd.as_raw_fd())
+            .context("Failed to inspect the socket passed by systemd")?
+            .family();
+        match family {
+            Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(vec![TcpListener::from_std(listener)?]))
+            }
+            Some(AddressFamily::Unix) => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                let path = listener
+                    .local_addr()?
+                    .as_pathname()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|| PathBuf::from("systemd"));
+                Ok(Listener::Unix {
+                    listener: UnixListener::from_std(listener)?,
+                    path,
+                    accepted: AtomicU64::new(0),
+                    owned: false,
+                })
+            }
+            _ => bail!("The socket passed by systemd is neither TCP nor a Unix socket"),
+        }
+    }
+
+    /// Wait for the next client. Returns the connection and a name for it
+    /// to use in log messages.
+    pub(super) async fn accept(&self) -> IoResult<(Connection, String)> {
+        match self {
+            Listener::Tcp(listeners) => {
+                let (stream, addr) = std::future::poll_fn(|cx| {
+                    listeners
+                        .iter()
+                        .find_map(|listener| match listener.poll_accept(cx) {
+                            Poll::Ready(accepted) => Some(accepted),
+                            Poll::Pending => None,
+                        })
+                        .map_or(Poll::Pending, Poll::Ready)
+                })
+                .await?;
+                Ok((Either::Left(stream), addr.to_string()))
+            }
+            Listener::Unix {
+                listener,
+                path,
+                accepted,
+                ..
+            } => {
+                let (stream, _) = listener.accept().await?;
+                let n = accepted.fetch_add(1, Ordering::Relaxed) + 1;
+                Ok((Either::Right(stream), format!("{}#{}", path.display(), n)))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(listeners) => {
+                for (i, listener) in listeners.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    match listener.local_addr() {
+                        Ok(addr) => write!(f, "{}", addr)?,
+                        Err(_) => f.write_str("TCP")?,
+                    }
+                }
+                Ok(())
+            }
+            Listener::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix {
+            path, owned: true, ..
+        } = self
+            && let Err(e) = std::fs::remove_file(&path)
+            && e.kind() != ErrorKind::NotFound
+        {
+            log::warn!("Failed to remove Unix socket {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Remove a socket file left behind by a previous run. Anything other than
+/// a socket at `path` is left alone.
+pub(crate) fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            log::info!("Removing stale Unix socket {}", path.display());
+            std::fs::remove_file(path)
+                .with_context(|| format!("Failed to remove stale socket {}", path.display()))
+        }
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => {
+            Err(e).with_context(|| format!("Failed to inspect socket path {}", path.display()))
+        }
+    }
+}
@@ -3,6 +3,16 @@
 //! This module handles the NBD server implementation using the `nbd` crate,
 //! exposing the GPU memory buffer over the network.
 
+#[cfg(any(feature = "sync-nbd", feature = "websocket"))]
+mod blocking;
+mod listener;
+#[cfg(not(feature = "sync-nbd"))]
+mod protocol;
 mod server;
+#[cfg(not(feature = "sync-nbd"))]
+mod tls;
+#[cfg(feature = "websocket")]
+mod websocket;
 
-pub use server::{NbdConfig, start_nbd_server};
+pub(crate) use listener::remove_stale_socket;
+pub use server::{check_nbd_config, start_nbd_server, NbdConfig, NbdExport, NbdTls, NbdTransport};
@@ -0,0 +1,1325 @@
+//! Async NBD protocol implementation on tokio.
+//!
+//! Implements the fixed newstyle handshake and the transmission phase
+//! directly on the socket instead of going through the synchronous `nbd`
+//! crate, so an idle connection costs a task rather than a blocking thread.
+//! Backend transfers are still blocking OpenCL calls and run as short
+//! `spawn_blocking` tasks, one per request, several per connection at once.
+//! The socket is read while they run; if the client goes away, the
+//! connection's cancellation token stops reads at the next chunk and keeps
+//! writes that haven't started from running. On server shutdown the drain
+//! token closes the connection once the requests being served have been
+//! answered.
+//! Clients that negotiate structured replies get reads of space the backend
+//! knows to be zero as holes (NBD_REPLY_TYPE_OFFSET_HOLE) instead of zeros.
+//! With `wire_checksum`, each of their data chunks is followed by a
+//! vramblk-specific chunk (`REPLY_TYPE_CHECKSUM`) holding the offset of the
+//! data and its CRC32C, so a cooperating client can tell corruption on the
+//! wire from corruption in the backend.
+//! NBD_OPT_STARTTLS upgrades the connection in place when TLS is configured.
+
+use super::server::{find_export, parse_info_request, ConnectionSlot, NbdConfig, NbdExport};
+use super::tls::TlsAcceptor;
+use crate::backend::{crc32c, is_no_space, is_read_only, BlockBackend};
+use anyhow::{bail, Context, Result};
+use std::sync::Arc;
+use tokio::io::{
+    self, AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream,
+};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+
+// Handshake
+const NBDMAGIC: &[u8; 8] = b"NBDMAGIC";
+const IHAVEOPT: u64 = 0x4948_4156_454F_5054;
+const REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_STARTTLS: u32 = 5;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+const REP_ERR_POLICY: u32 = (1 << 31) | 2;
+const REP_ERR_INVALID: u32 = (1 << 31) | 3;
+const REP_ERR_TLS_REQD: u32 = (1 << 31) | 5;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+/// Longest option payload accepted during the handshake
+const MAX_OPTION_LEN: u32 = 64 * 1024;
+
+// Transmission
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+const REPLY_FLAG_DONE: u16 = 1 << 0;
+
+const REPLY_TYPE_NONE: u16 = 0;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+/// Not part of the NBD protocol: the CRC32C of the preceding data chunk,
+/// with `wire_checksum`
+const REPLY_TYPE_CHECKSUM: u16 = 0x7e01;
+
+const TFLAG_HAS_FLAGS: u16 = 1 << 0;
+const TFLAG_READ_ONLY: u16 = 1 << 1;
+const TFLAG_SEND_FLUSH: u16 = 1 << 2;
+const TFLAG_ROTATIONAL: u16 = 1 << 4;
+const TFLAG_SEND_TRIM: u16 = 1 << 5;
+const TFLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+const TFLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+const TFLAG_SEND_RESIZE: u16 = 1 << 9;
+const TFLAG_SEND_FAST_ZERO: u16 = 1 << 11;
+
+const CMD_FLAG_FAST_ONLY: u16 = 1 << 5;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_WRITE_ZEROES: u16 = 6;
+const CMD_RESIZE: u16 = 8;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+const ENOTSUP: u32 = 95;
+
+/// Reads are split into transfers of this size so a cancelled read stops early
+const CANCEL_CHUNK: usize = 1024 * 1024;
+
+/// Most requests of one connection served at the same time
+const MAX_IN_FLIGHT: usize = 16;
+
+/// With structured replies, reads are checked for known-zero space in
+/// aligned pieces of this size, which are sent as holes
+const HOLE_CHUNK: u64 = 64 * 1024;
+
+/// Serve one client: handshake, then transmission until disconnect or
+/// until `drain` is cancelled. With `tls`, the client must upgrade the
+/// connection with NBD_OPT_STARTTLS before choosing an export.
+pub(super) async fn serve<S>(
+    stream: S,
+    exports: Arc<[NbdExport]>,
+    config: NbdConfig,
+    tls: Option<TlsAcceptor>,
+    drain: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Replies are assembled from many small writes; send them out on flush
+    let mut stream = BufStream::new(stream);
+
+    let no_zeroes = greet(&mut stream).await?;
+    let mode = match tls {
+        Some(_) => TlsMode::Required,
+        None => TlsMode::Off,
+    };
+    match handshake(&mut stream, &exports, &config, no_zeroes, mode).await? {
+        Negotiated::Export(export, slot, structured) => {
+            serve_export(&mut stream, export, slot, structured, &config, &drain).await
+        }
+        Negotiated::Aborted => Ok(()),
+        Negotiated::StartTls => {
+            let Some(acceptor) = tls else {
+                bail!("NBD_OPT_STARTTLS accepted without TLS configured");
+            };
+            start_tls(stream, &acceptor, &exports, &config, no_zeroes, &drain).await
+        }
+    }
+}
+
+/// Upgrade the connection to TLS after NBD_OPT_STARTTLS was acknowledged,
+/// then negotiate and serve the export inside the TLS session.
+#[cfg(feature = "tls")]
+async fn start_tls<S>(
+    stream: BufStream<S>,
+    acceptor: &TlsAcceptor,
+    exports: &[NbdExport],
+    config: &NbdConfig,
+    no_zeroes: bool,
+    drain: &CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // The client waits for our ACK before starting the TLS handshake; any
+    // plaintext it pipelined anyway is dropped with the buffer rather than
+    // being read as if it had arrived encrypted.
+    let stream = acceptor
+        .accept(stream.into_inner())
+        .await
+        .context("TLS handshake failed")?;
+    let mut stream = BufStream::new(stream);
+
+    match handshake(&mut stream, exports, config, no_zeroes, TlsMode::Active).await? {
+        Negotiated::Export(export, slot, structured) => {
+            serve_export(&mut stream, export, slot, structured, config, drain).await
+        }
+        Negotiated::Aborted => Ok(()),
+        Negotiated::StartTls => bail!("NBD_OPT_STARTTLS accepted on a TLS connection"),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+async fn start_tls<S>(
+    _stream: BufStream<S>,
+    acceptor: &TlsAcceptor,
+    _exports: &[NbdExport],
+    _config: &NbdConfig,
+    _no_zeroes: bool,
+    _drain: &CancellationToken,
+) -> Result<()> {
+    match *acceptor {}
+}
+
+/// Run the transmission phase for the export chosen during the handshake
+async fn serve_export<S>(
+    stream: &mut S,
+    export: NbdExport,
+    slot: ConnectionSlot,
+    structured: bool,
+    config: &NbdConfig,
+    drain: &CancellationToken,
+) -> Result<()>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    log::info!(
+        "Handshake successful for export '{}' ({}{})",
+        export.name,
+        if slot.writable {
+            "read-write"
+        } else {
+            "read-only"
+        },
+        if structured {
+            ", structured replies"
+        } else {
+            ""
+        }
+    );
+    if config.wire_checksum && !structured {
+        log::warn!(
+            "Client of export '{}' did not negotiate structured replies; \
+             its reads carry no wire checksum",
+            export.name
+        );
+    }
+
+    // Also stops in-flight transfers if this task is dropped
+    let cancel = CancellationToken::new();
+    let _cancel_on_exit = cancel.clone().drop_guard();
+
+    transmission(
+        stream,
+        export,
+        !slot.writable,
+        structured,
+        config,
+        &cancel,
+        drain,
+    )
+    .await
+}
+
+/// Where the connection stands with respect to TLS
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TlsMode {
+    /// TLS is not configured
+    Off,
+    /// TLS is configured and the client has not upgraded yet
+    Required,
+    /// The connection runs inside a TLS session
+    Active,
+}
+
+/// Outcome of option negotiation
+enum Negotiated {
+    /// The client chose an export and claimed a connection slot on it, and
+    /// whether it negotiated structured replies
+    Export(NbdExport, ConnectionSlot, bool),
+    /// The client aborted the handshake
+    Aborted,
+    /// NBD_OPT_STARTTLS was acknowledged; the TLS handshake comes next
+    StartTls,
+}
+
+/// Send the server greeting and read the client flags. Returns whether the
+/// client asked to omit the zero padding after NBD_OPT_EXPORT_NAME.
+async fn greet<S>(stream: &mut S) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(NBDMAGIC).await?;
+    stream.write_u64(IHAVEOPT).await?;
+    stream
+        .write_u16(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES)
+        .await?;
+    stream.flush().await?;
+
+    let client_flags = stream.read_u32().await?;
+    if client_flags & FLAG_C_FIXED_NEWSTYLE == 0 {
+        bail!("Client does not support fixed newstyle negotiation");
+    }
+    Ok(client_flags & FLAG_C_NO_ZEROES != 0)
+}
+
+/// Run the option haggling of the fixed newstyle handshake until the client
+/// chooses an export, aborts, or starts TLS.
+async fn handshake<S>(
+    stream: &mut S,
+    exports: &[NbdExport],
+    config: &NbdConfig,
+    no_zeroes: bool,
+    tls: TlsMode,
+) -> Result<Negotiated>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut structured = false;
+    loop {
+        if stream.read_u64().await? != IHAVEOPT {
+            bail!("Invalid option magic from client");
+        }
+        let option = stream.read_u32().await?;
+        let len = stream.read_u32().await?;
+        if len > MAX_OPTION_LEN {
+            bail!("Option {} payload too large ({} bytes)", option, len);
+        }
+        let mut data = vec![0u8; len as usize];
+        stream.read_exact(&mut data).await?;
+
+        if tls == TlsMode::Required && option != OPT_STARTTLS && option != OPT_ABORT {
+            if option == OPT_EXPORT_NAME {
+                // No error reply exists for NBD_OPT_EXPORT_NAME
+                bail!("Client requested an export without starting TLS");
+            }
+            log::debug!("Refusing NBD option {} before TLS", option);
+            option_reply(stream, option, REP_ERR_TLS_REQD, b"TLS is required").await?;
+            continue;
+        }
+
+        match option {
+            OPT_EXPORT_NAME => {
+                let name = String::from_utf8(data).context("Non-UTF8 export name requested")?;
+                let Some(export) = find_export(exports, &name, config) else {
+                    // NBD_OPT_EXPORT_NAME has no error reply; closing is the answer
+                    log::warn!("Client requested unknown export: {}", name);
+                    bail!("Export not found");
+                };
+                let slot = ConnectionSlot::acquire(export, config).inspect_err(|e| {
+                    log::warn!("Rejecting client for export '{}': {}", export.name, e);
+                })?;
+
+                let backend = &export.backend;
+                stream.write_u64(backend.size()).await?;
+                stream
+                    .write_u16(transmission_flags(export, slot.writable, config))
+                    .await?;
+                if !no_zeroes {
+                    stream.write_all(&[0u8; 124]).await?;
+                }
+                stream.flush().await?;
+                return Ok(Negotiated::Export(export.clone(), slot, structured));
+            }
+            OPT_ABORT => {
+                option_reply(stream, option, REP_ACK, &[]).await?;
+                return Ok(Negotiated::Aborted);
+            }
+            OPT_STARTTLS if tls == TlsMode::Required => {
+                if !data.is_empty() {
+                    option_reply(stream, option, REP_ERR_INVALID, b"Malformed request").await?;
+                    continue;
+                }
+                option_reply(stream, option, REP_ACK, &[]).await?;
+                return Ok(Negotiated::StartTls);
+            }
+            OPT_STARTTLS if tls == TlsMode::Active => {
+                option_reply(stream, option, REP_ERR_INVALID, b"TLS already active").await?;
+            }
+            OPT_INFO | OPT_GO => {
+                let Some(name) = parse_info_request(&data) else {
+                    option_reply(stream, option, REP_ERR_INVALID, b"Malformed request").await?;
+                    continue;
+                };
+                let Some(export) = find_export(exports, &name, config) else {
+                    log::warn!("Client requested unknown export: {}", name);
+                    option_reply(stream, option, REP_ERR_UNKNOWN, b"Export not found").await?;
+                    continue;
+                };
+
+                // Only NBD_OPT_GO enters transmission and takes a slot
+                let slot = if option == OPT_GO {
+                    match ConnectionSlot::acquire(export, config) {
+                        Ok(slot) => Some(slot),
+                        Err(e) => {
+                            log::warn!("Rejecting client for export '{}': {}", export.name, e);
+                            let msg = e.to_string();
+                            option_reply(stream, option, REP_ERR_POLICY, msg.as_bytes()).await?;
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+                let writable = match &slot {
+                    Some(slot) => slot.writable,
+                    None => ConnectionSlot::would_be_writable(export, config),
+                };
+                let size = export.backend.size();
+
+                // Block sizes are always sent so clients can align their I/O
+                let mut info = Vec::with_capacity(12);
+                info.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+                info.extend_from_slice(&size.to_be_bytes());
+                let flags = transmission_flags(export, writable, config);
+                info.extend_from_slice(&flags.to_be_bytes());
+                option_reply(stream, option, REP_INFO, &info).await?;
+
+                let mut block_size = Vec::with_capacity(14);
+                block_size.extend_from_slice(&INFO_BLOCK_SIZE.to_be_bytes());
+                block_size.extend_from_slice(&config.min_block_size.to_be_bytes());
+                block_size.extend_from_slice(&config.preferred_block_size.to_be_bytes());
+                block_size.extend_from_slice(&config.max_io_size.to_be_bytes());
+                option_reply(stream, option, REP_INFO, &block_size).await?;
+
+                option_reply(stream, option, REP_ACK, &[]).await?;
+                if let Some(slot) = slot {
+                    return Ok(Negotiated::Export(export.clone(), slot, structured));
+                }
+            }
+            OPT_STRUCTURED_REPLY => {
+                if !data.is_empty() {
+                    option_reply(stream, option, REP_ERR_INVALID, b"Malformed request").await?;
+                    continue;
+                }
+                structured = true;
+                option_reply(stream, option, REP_ACK, &[]).await?;
+            }
+            OPT_LIST => {
+                for export in exports {
+                    let name = export.name.as_bytes();
+                    let mut entry = Vec::with_capacity(4 + name.len());
+                    entry.extend_from_slice(&(name.len() as u32).to_be_bytes());
+                    entry.extend_from_slice(name);
+                    option_reply(stream, option, REP_SERVER, &entry).await?;
+                }
+                option_reply(stream, option, REP_ACK, &[]).await?;
+            }
+            _ => {
+                log::debug!("Unsupported NBD option {}", option);
+                option_reply(stream, option, REP_ERR_UNSUP, &[]).await?;
+            }
+        }
+    }
+}
+
+/// Transmission flags advertised for a connection to `export`
+fn transmission_flags(export: &NbdExport, writable: bool, config: &NbdConfig) -> u16 {
+    let mut flags = if writable {
+        TFLAG_HAS_FLAGS | TFLAG_SEND_FLUSH | TFLAG_SEND_TRIM | TFLAG_SEND_WRITE_ZEROES
+    } else {
+        TFLAG_HAS_FLAGS | TFLAG_READ_ONLY
+    };
+    if writable && export.backend.fast_zero() {
+        flags |= TFLAG_SEND_FAST_ZERO;
+    }
+    if writable && export.resizable.is_some() {
+        flags |= TFLAG_SEND_RESIZE;
+    }
+    if config.rotational {
+        flags |= TFLAG_ROTATIONAL;
+    }
+    // Every connection shares the export's backend and its flush is
+    // device-wide, so a flush on one connection covers writes completed on
+    // all of them. With --single-writer a client's extra connections would
+    // come up read-only, so it shouldn't open any.
+    if !export.single_writer {
+        flags |= TFLAG_CAN_MULTI_CONN;
+    }
+    flags
+}
+
+async fn option_reply<S>(stream: &mut S, option: u32, reply: u32, data: &[u8]) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_u64(REPLY_MAGIC).await?;
+    stream.write_u32(option).await?;
+    stream.write_u32(reply).await?;
+    stream.write_u32(data.len() as u32).await?;
+    stream.write_all(data).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Serve requests until the client disconnects or the server drains. On a
+/// resizable export, clients may grow it with NBD_CMD_RESIZE.
+///
+/// Up to `MAX_IN_FLIGHT` requests of the connection run at once, each on the
+/// blocking pool, and are answered as they complete; the client tells the
+/// replies apart by their handle. Requests are read while others run, so a
+/// disconnect is noticed at once and cancels in-flight transfers.
+async fn transmission<S>(
+    stream: &mut S,
+    export: NbdExport,
+    readonly: bool,
+    structured: bool,
+    config: &NbdConfig,
+    cancel: &CancellationToken,
+    drain: &CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut reader, writer) = io::split(stream);
+    let (replies, pending) = mpsc::channel(MAX_IN_FLIGHT);
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+
+    let max_io_size = config.max_io_size;
+    let metrics = &config.metrics;
+    let buffer = export.backend;
+    let resizable = export.resizable;
+    let fast_zero = buffer.fast_zero();
+    let wire_checksum = config.wire_checksum;
+
+    // Ends once every queued reply has been written, after the request loop
+    // and all requests it dispatched have dropped their senders
+    let requests = async move {
+        loop {
+            let magic = tokio::select! {
+                biased;
+                _ = drain.cancelled() => {
+                    log::info!("Server shutting down, closing connection");
+                    return Ok(());
+                }
+                magic = reader.read_u32() => magic?,
+            };
+            if magic != REQUEST_MAGIC {
+                bail!("Invalid request magic from client");
+            }
+            let flags = reader.read_u16().await?;
+            let command = reader.read_u16().await?;
+            let handle = reader.read_u64().await?;
+            let offset = reader.read_u64().await?;
+            let len = reader.read_u32().await?;
+
+            // Read per request, as the export may have been resized
+            let size = buffer.size();
+            let in_bounds = offset
+                .checked_add(len as u64)
+                .is_some_and(|end| end <= size);
+
+            let reply = |error: u32| Reply::simple(handle, error);
+            // Once negotiated, reads must be answered with structured replies
+            let read_error = move |error: u32| {
+                if structured {
+                    Reply::Error { handle, error }
+                } else {
+                    Reply::simple(handle, error)
+                }
+            };
+
+            match command {
+                CMD_READ => {
+                    if len > max_io_size || !in_bounds {
+                        replies.send(read_error(EINVAL)).await?;
+                        continue;
+                    }
+                    let buffer = buffer.clone();
+                    let metrics = metrics.clone();
+                    let token = cancel.clone();
+                    dispatch(&in_flight, &replies, move || {
+                        let result = if structured {
+                            read_chunks(&*buffer, offset, len, wire_checksum, &token)
+                                .map(|chunks| Reply::Read { handle, chunks })
+                        } else {
+                            read_data(&*buffer, offset, len as usize, &token).map(|data| {
+                                Reply::Simple {
+                                    handle,
+                                    error: 0,
+                                    data,
+                                }
+                            })
+                        };
+                        match result {
+                            Ok(reply) => {
+                                metrics.record_read(len as u64);
+                                reply
+                            }
+                            Err(_) if token.is_cancelled() => {
+                                log::debug!("Client disconnected during read; abandoning transfer");
+                                read_error(EIO)
+                            }
+                            Err(e) => {
+                                metrics.record_error();
+                                log::error!("VRAM read error during NBD Read: {}", e);
+                                read_error(EIO)
+                            }
+                        }
+                    })
+                    .await?;
+                }
+                CMD_WRITE => {
+                    if len > max_io_size {
+                        // The payload can't be skipped safely; drop the connection
+                        bail!("Write request of {} bytes exceeds the maximum", len);
+                    }
+                    let mut data = vec![0u8; len as usize];
+                    reader.read_exact(&mut data).await?;
+
+                    if readonly {
+                        replies.send(reply(EPERM)).await?;
+                    } else if !in_bounds {
+                        replies.send(reply(ENOSPC)).await?;
+                    } else {
+                        let buffer = buffer.clone();
+                        let metrics = metrics.clone();
+                        let token = cancel.clone();
+                        // Once started, a write runs to completion to avoid partial state
+                        dispatch(&in_flight, &replies, move || {
+                            if token.is_cancelled() {
+                                log::debug!("Client disconnected; dropping write");
+                                return Reply::simple(handle, EIO);
+                            }
+                            match buffer.write_at(offset, &data) {
+                                Ok(()) => {
+                                    metrics.record_write(len as u64);
+                                    Reply::simple(handle, 0)
+                                }
+                                Err(e) => {
+                                    metrics.record_error();
+                                    Reply::simple(handle, write_error(&e, "Write"))
+                                }
+                            }
+                        })
+                        .await?;
+                    }
+                }
+                CMD_WRITE_ZEROES | CMD_TRIM => {
+                    if readonly {
+                        replies.send(reply(EPERM)).await?;
+                    } else if !in_bounds {
+                        replies
+                            .send(reply(if command == CMD_TRIM { EINVAL } else { ENOSPC }))
+                            .await?;
+                    } else if command == CMD_WRITE_ZEROES
+                        && flags & CMD_FLAG_FAST_ONLY != 0
+                        && !fast_zero
+                    {
+                        // The client will fall back to writing zeros itself
+                        replies.send(reply(ENOTSUP)).await?;
+                    } else {
+                        let buffer = buffer.clone();
+                        let metrics = metrics.clone();
+                        dispatch(&in_flight, &replies, move || {
+                            let (result, name) = if command == CMD_TRIM {
+                                (buffer.discard_at(offset, len as u64), "Trim")
+                            } else {
+                                (buffer.write_zeroes_at(offset, len as u64), "Write Zeroes")
+                            };
+                            match result {
+                                Ok(()) => Reply::simple(handle, 0),
+                                Err(e) => {
+                                    metrics.record_error();
+                                    Reply::simple(handle, write_error(&e, name))
+                                }
+                            }
+                        })
+                        .await?;
+                    }
+                }
+                CMD_FLUSH => {
+                    let buffer = buffer.clone();
+                    let metrics = metrics.clone();
+                    dispatch(&in_flight, &replies, move || match buffer.flush() {
+                        Ok(()) => {
+                            metrics.record_flush();
+                            Reply::simple(handle, 0)
+                        }
+                        Err(e) => {
+                            metrics.record_error();
+                            log::error!("Backend flush error during NBD Flush: {:#}", e);
+                            Reply::simple(handle, EIO)
+                        }
+                    })
+                    .await?;
+                }
+                CMD_RESIZE => {
+                    // The new size travels in the offset field
+                    match &resizable {
+                        None => replies.send(reply(EINVAL)).await?,
+                        Some(_) if readonly => replies.send(reply(EPERM)).await?,
+                        // Shrinking discards data; only the control socket may force it
+                        Some(_) if offset < size || len != 0 => replies.send(reply(EINVAL)).await?,
+                        Some(device) => {
+                            let device = device.clone();
+                            let metrics = metrics.clone();
+                            dispatch(&in_flight, &replies, move || {
+                                match device.resize(offset, false) {
+                                    Ok(_) => Reply::simple(handle, 0),
+                                    Err(e) => {
+                                        metrics.record_error();
+                                        log::error!("Resize error during NBD Resize: {:#}", e);
+                                        Reply::simple(handle, ENOSPC)
+                                    }
+                                }
+                            })
+                            .await?;
+                        }
+                    }
+                }
+                // Requests already dispatched are still answered
+                CMD_DISC => return Ok(()),
+                _ => {
+                    log::debug!("Unsupported NBD command {}", command);
+                    replies.send(reply(EINVAL)).await?;
+                }
+            }
+        }
+    };
+
+    tokio::try_join!(requests, write_replies(writer, pending))?;
+    Ok(())
+}
+
+/// Answer to one request, queued for the connection's reply writer
+enum Reply {
+    /// Simple reply; `data` follows a successful read
+    Simple {
+        handle: u64,
+        error: u32,
+        data: Vec<u8>,
+    },
+    /// Structured reply to a successful read
+    Read { handle: u64, chunks: Vec<ReadChunk> },
+    /// Structured reply to a failed read
+    Error { handle: u64, error: u32 },
+}
+
+impl Reply {
+    fn simple(handle: u64, error: u32) -> Self {
+        Reply::Simple {
+            handle,
+            error,
+            data: Vec::new(),
+        }
+    }
+}
+
+/// Piece of a structured read reply
+enum ReadChunk {
+    Data {
+        offset: u64,
+        data: Vec<u8>,
+        /// CRC32C of `data`, with `wire_checksum`
+        crc: Option<u32>,
+    },
+    /// `len` bytes of zeros, sent without the zeros
+    Hole { offset: u64, len: u32 },
+}
+
+/// Run `work` on the blocking pool and queue its reply, waiting first if
+/// `MAX_IN_FLIGHT` requests are running
+async fn dispatch<F>(
+    in_flight: &Arc<Semaphore>,
+    replies: &mpsc::Sender<Reply>,
+    work: F,
+) -> Result<()>
+where
+    F: FnOnce() -> Reply + Send + 'static,
+{
+    let permit = in_flight.clone().acquire_owned().await?;
+    let replies = replies.clone();
+    task::spawn_blocking(move || {
+        // Fails only if the connection is gone
+        let _ = replies.blocking_send(work());
+        drop(permit);
+    });
+    Ok(())
+}
+
+/// Read `len` bytes at `offset` in `CANCEL_CHUNK` pieces, giving up once
+/// `cancel` is cancelled
+fn read_data(
+    buffer: &dyn BlockBackend,
+    offset: u64,
+    len: usize,
+    cancel: &CancellationToken,
+) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; len];
+    for (i, chunk) in data.chunks_mut(CANCEL_CHUNK).enumerate() {
+        if cancel.is_cancelled() {
+            bail!("Read abandoned after client disconnect");
+        }
+        buffer.read_at(offset + (i * CANCEL_CHUNK) as u64, chunk)?;
+    }
+    Ok(data)
+}
+
+/// Read `len` bytes at `offset` as structured reply chunks. Runs of
+/// `HOLE_CHUNK` pieces the backend knows to be zero become holes and are
+/// not read at all. With `checksum`, data chunks carry their CRC32C.
+fn read_chunks(
+    buffer: &dyn BlockBackend,
+    offset: u64,
+    len: u32,
+    checksum: bool,
+    cancel: &CancellationToken,
+) -> Result<Vec<ReadChunk>> {
+    let end = offset + len as u64;
+    // (start, length, known zero)
+    let mut runs: Vec<(u64, u64, bool)> = Vec::new();
+    let mut at = offset;
+    while at < end {
+        let next = ((at / HOLE_CHUNK + 1) * HOLE_CHUNK).min(end);
+        let zero = buffer.is_known_zero(at, next - at);
+        match runs.last_mut() {
+            Some((_, run_len, run_zero)) if *run_zero == zero => *run_len += next - at,
+            _ => runs.push((at, next - at, zero)),
+        }
+        at = next;
+    }
+    runs.into_iter()
+        .map(|(start, len, zero)| {
+            Ok(if zero {
+                ReadChunk::Hole {
+                    offset: start,
+                    len: len as u32,
+                }
+            } else {
+                let data = read_data(buffer, start, len as usize, cancel)?;
+                ReadChunk::Data {
+                    offset: start,
+                    crc: checksum.then(|| crc32c(&data)),
+                    data,
+                }
+            })
+        })
+        .collect()
+}
+
+/// Write replies as they are queued, flushing whenever the queue runs dry
+async fn write_replies<W>(mut writer: W, mut pending: mpsc::Receiver<Reply>) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(reply) = pending.recv().await {
+        match reply {
+            Reply::Simple {
+                handle,
+                error,
+                data,
+            } => {
+                writer.write_u32(SIMPLE_REPLY_MAGIC).await?;
+                writer.write_u32(error).await?;
+                writer.write_u64(handle).await?;
+                writer.write_all(&data).await?;
+            }
+            Reply::Read { handle, chunks } => {
+                if chunks.is_empty() {
+                    chunk_header(&mut writer, REPLY_FLAG_DONE, REPLY_TYPE_NONE, handle, 0).await?;
+                }
+                let last = chunks.len().saturating_sub(1);
+                for (i, chunk) in chunks.into_iter().enumerate() {
+                    let flags = if i == last { REPLY_FLAG_DONE } else { 0 };
+                    match chunk {
+                        ReadChunk::Data { offset, data, crc } => {
+                            // With a checksum, that chunk ends the reply instead
+                            let data_flags = if crc.is_some() { 0 } else { flags };
+                            let len = 8 + data.len() as u32;
+                            chunk_header(
+                                &mut writer,
+                                data_flags,
+                                REPLY_TYPE_OFFSET_DATA,
+                                handle,
+                                len,
+                            )
+                            .await?;
+                            writer.write_u64(offset).await?;
+                            writer.write_all(&data).await?;
+                            if let Some(crc) = crc {
+                                chunk_header(&mut writer, flags, REPLY_TYPE_CHECKSUM, handle, 12)
+                                    .await?;
+                                writer.write_u64(offset).await?;
+                                writer.write_u32(crc).await?;
+                            }
+                        }
+                        ReadChunk::Hole { offset, len } => {
+                            chunk_header(&mut writer, flags, REPLY_TYPE_OFFSET_HOLE, handle, 12)
+                                .await?;
+                            writer.write_u64(offset).await?;
+                            writer.write_u32(len).await?;
+                        }
+                    }
+                }
+            }
+            Reply::Error { handle, error } => {
+                // Error code and an empty message
+                chunk_header(&mut writer, REPLY_FLAG_DONE, REPLY_TYPE_ERROR, handle, 6).await?;
+                writer.write_u32(error).await?;
+                writer.write_u16(0).await?;
+            }
+        }
+        if pending.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Header of one structured reply chunk with a `len`-byte payload
+async fn chunk_header<W>(writer: &mut W, flags: u16, kind: u16, handle: u64, len: u32) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_u32(STRUCTURED_REPLY_MAGIC).await?;
+    writer.write_u16(flags).await?;
+    writer.write_u16(kind).await?;
+    writer.write_u64(handle).await?;
+    writer.write_u32(len).await?;
+    Ok(())
+}
+
+/// NBD error for a failed write-type request
+fn write_error(e: &anyhow::Error, command: &str) -> u32 {
+    if is_read_only(e) {
+        // NBD has no EROFS; EPERM is what read-only exports return
+        EPERM
+    } else if is_no_space(e) {
+        ENOSPC
+    } else {
+        log::error!("VRAM write error during NBD {}: {}", command, e);
+        EIO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    const SIZE: u64 = 64 * 1024;
+
+    /// Serve `backend` as the only export on one end of an in-memory pipe
+    fn serve_backend(
+        backend: Arc<dyn BlockBackend>,
+        config: NbdConfig,
+    ) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (client, server) = io::duplex(1024 * 1024);
+        let exports: Arc<[NbdExport]> = Arc::from([NbdExport::new("test", backend)]);
+        let server = tokio::spawn(serve(
+            server,
+            exports,
+            config,
+            None,
+            CancellationToken::new(),
+        ));
+        (client, server)
+    }
+
+    /// What NBD_OPT_GO told the client about the export
+    struct Export {
+        size: u64,
+        flags: u16,
+        /// Payloads of the NBD_REP_INFO replies
+        infos: Vec<Vec<u8>>,
+    }
+
+    /// Run the fixed newstyle handshake up to transmission with NBD_OPT_GO
+    async fn go(client: &mut DuplexStream) -> Export {
+        go_with(client, false).await
+    }
+
+    /// As `go`, negotiating structured replies first if `structured`
+    async fn go_with(client: &mut DuplexStream, structured: bool) -> Export {
+        let mut greeting = [0u8; 18];
+        client.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting[..8], NBDMAGIC);
+        client
+            .write_u32(FLAG_C_FIXED_NEWSTYLE | FLAG_C_NO_ZEROES)
+            .await
+            .unwrap();
+
+        if structured {
+            client.write_u64(IHAVEOPT).await.unwrap();
+            client.write_u32(OPT_STRUCTURED_REPLY).await.unwrap();
+            client.write_u32(0).await.unwrap();
+            assert_eq!(client.read_u64().await.unwrap(), REPLY_MAGIC);
+            assert_eq!(client.read_u32().await.unwrap(), OPT_STRUCTURED_REPLY);
+            assert_eq!(client.read_u32().await.unwrap(), REP_ACK);
+            assert_eq!(client.read_u32().await.unwrap(), 0);
+        }
+
+        let name = b"test";
+        client.write_u64(IHAVEOPT).await.unwrap();
+        client.write_u32(OPT_GO).await.unwrap();
+        client.write_u32(4 + name.len() as u32 + 2).await.unwrap();
+        client.write_u32(name.len() as u32).await.unwrap();
+        client.write_all(name).await.unwrap();
+        client.write_u16(0).await.unwrap();
+
+        let mut infos = Vec::new();
+        loop {
+            assert_eq!(client.read_u64().await.unwrap(), REPLY_MAGIC);
+            assert_eq!(client.read_u32().await.unwrap(), OPT_GO);
+            let reply = client.read_u32().await.unwrap();
+            let mut data = vec![0u8; client.read_u32().await.unwrap() as usize];
+            client.read_exact(&mut data).await.unwrap();
+            match reply {
+                REP_INFO => infos.push(data),
+                REP_ACK => break,
+                _ => panic!("NBD_OPT_GO failed with reply {:#x}", reply),
+            }
+        }
+        let export = infos
+            .iter()
+            .find(|info| info[..2] == INFO_EXPORT.to_be_bytes())
+            .expect("no NBD_INFO_EXPORT");
+        Export {
+            size: u64::from_be_bytes(export[2..10].try_into().unwrap()),
+            flags: u16::from_be_bytes(export[10..12].try_into().unwrap()),
+            infos,
+        }
+    }
+
+    async fn request(
+        client: &mut DuplexStream,
+        flags: u16,
+        command: u16,
+        handle: u64,
+        offset: u64,
+        len: u32,
+    ) {
+        client.write_u32(REQUEST_MAGIC).await.unwrap();
+        client.write_u16(flags).await.unwrap();
+        client.write_u16(command).await.unwrap();
+        client.write_u64(handle).await.unwrap();
+        client.write_u64(offset).await.unwrap();
+        client.write_u32(len).await.unwrap();
+    }
+
+    /// Error and handle of the next simple reply
+    async fn simple_reply(client: &mut DuplexStream) -> (u32, u64) {
+        assert_eq!(client.read_u32().await.unwrap(), SIMPLE_REPLY_MAGIC);
+        let error = client.read_u32().await.unwrap();
+        (error, client.read_u64().await.unwrap())
+    }
+
+    /// Flags, type, handle and payload of the next structured reply chunk
+    async fn chunk(client: &mut DuplexStream) -> (u16, u16, u64, Vec<u8>) {
+        assert_eq!(client.read_u32().await.unwrap(), STRUCTURED_REPLY_MAGIC);
+        let flags = client.read_u16().await.unwrap();
+        let kind = client.read_u16().await.unwrap();
+        let handle = client.read_u64().await.unwrap();
+        let mut payload = vec![0u8; client.read_u32().await.unwrap() as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (flags, kind, handle, payload)
+    }
+
+    #[tokio::test]
+    async fn read_past_end_is_rejected() {
+        let backend = Arc::new(RamBuffer::new(SIZE));
+        backend.write_at(SIZE - 4, b"tail").unwrap();
+        let (mut client, server) = serve_backend(backend, NbdConfig::default());
+        assert_eq!(go(&mut client).await.size, SIZE);
+
+        // The last four bytes are served in full
+        request(&mut client, 0, CMD_READ, 1, SIZE - 4, 4).await;
+        assert_eq!(simple_reply(&mut client).await, (0, 1));
+        let mut data = [0u8; 4];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"tail");
+
+        // One byte more fails without any data, rather than reading short
+        request(&mut client, 0, CMD_READ, 2, SIZE - 3, 4).await;
+        assert_eq!(simple_reply(&mut client).await, (EINVAL, 2));
+        request(&mut client, 0, CMD_READ, 3, SIZE, 1).await;
+        assert_eq!(simple_reply(&mut client).await, (EINVAL, 3));
+        request(&mut client, 0, CMD_READ, 4, u64::MAX, 1).await;
+        assert_eq!(simple_reply(&mut client).await, (EINVAL, 4));
+
+        // The connection is still in step
+        request(&mut client, 0, CMD_READ, 5, 0, 4).await;
+        assert_eq!(simple_reply(&mut client).await, (0, 5));
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [0; 4]);
+
+        request(&mut client, 0, CMD_DISC, 6, 0, 0).await;
+        server.await.unwrap().unwrap();
+    }
+    #[tokio::test]
+    async fn read_only_export_refuses_writes() {
+        let backend = Arc::new(RamBuffer::new(SIZE));
+        let config = NbdConfig {
+            read_only: true,
+            ..NbdConfig::default()
+        };
+        let (mut client, server) = serve_backend(backend.clone(), config);
+        let export = go(&mut client).await;
+        assert_ne!(export.flags & TFLAG_READ_ONLY, 0);
+        assert_eq!(export.flags & TFLAG_SEND_WRITE_ZEROES, 0);
+
+        request(&mut client, 0, CMD_WRITE, 1, 0, 4).await;
+        client.write_all(b"data").await.unwrap();
+        assert_eq!(simple_reply(&mut client).await, (EPERM, 1));
+        request(&mut client, 0, CMD_WRITE_ZEROES, 2, 0, 4096).await;
+        assert_eq!(simple_reply(&mut client).await, (EPERM, 2));
+        request(&mut client, 0, CMD_TRIM, 3, 0, 4096).await;
+        assert_eq!(simple_reply(&mut client).await, (EPERM, 3));
+
+        // Reads still work, and nothing was written
+        request(&mut client, 0, CMD_READ, 4, 0, 4).await;
+        assert_eq!(simple_reply(&mut client).await, (0, 4));
+        let mut data = [0xffu8; 4];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(data, [0; 4]);
+        backend.read_at(0, &mut data).unwrap();
+        assert_eq!(data, [0; 4]);
+
+        request(&mut client, 0, CMD_DISC, 5, 0, 0).await;
+        server.await.unwrap().unwrap();
+    }
+
+    /// Takes a while over every read, counting the reads it finished
+    struct SlowReads {
+        inner: RamBuffer,
+        started: AtomicUsize,
+        finished: AtomicUsize,
+    }
+
+    impl BlockBackend for SlowReads {
+        fn size(&self) -> u64 {
+            self.inner.size()
+        }
+
+        fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+            self.started.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            self.inner.read_at(offset, dst)?;
+            self.finished.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+            self.inner.write_at(offset, src)
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_abandons_read() {
+        let chunks = 8;
+        let len = chunks * CANCEL_CHUNK;
+        let backend = Arc::new(SlowReads {
+            inner: RamBuffer::new(len as u64),
+            started: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+        });
+        let config = NbdConfig {
+            max_io_size: len as u32,
+            ..NbdConfig::default()
+        };
+        let (mut client, server) = serve_backend(backend.clone(), config);
+        go(&mut client).await;
+
+        request(&mut client, 0, CMD_READ, 1, 0, len as u32).await;
+        while backend.started.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        drop(client);
+
+        // The connection closes without waiting for the transfer
+        let closed = tokio::time::timeout(Duration::from_millis(200), server).await;
+        assert!(closed.is_ok(), "server still serving after disconnect");
+        assert!(backend.finished.load(Ordering::SeqCst) < chunks);
+
+        // The transfer stops at the next chunk instead of running to the end
+        tokio::time::sleep(Duration::from_millis(50 * chunks as u64)).await;
+        let finished = backend.finished.load(Ordering::SeqCst);
+        assert!(finished <= 2, "{} of {} chunks read", finished, chunks);
+        assert_eq!(backend.started.load(Ordering::SeqCst), finished);
+    }
+
+    /// Minimum, preferred and maximum block size from NBD_INFO_BLOCK_SIZE
+    fn block_sizes(export: &Export) -> (u32, u32, u32) {
+        let info = export
+            .infos
+            .iter()
+            .find(|info| info[..2] == INFO_BLOCK_SIZE.to_be_bytes())
+            .expect("no NBD_INFO_BLOCK_SIZE");
+        assert_eq!(info.len(), 14);
+        let size = |at: usize| u32::from_be_bytes(info[at..at + 4].try_into().unwrap());
+        (size(2), size(6), size(10))
+    }
+
+    #[tokio::test]
+    async fn block_sizes_are_advertised() {
+        for (min, preferred, max) in [(512, 4096, 32 * 1024 * 1024), (4096, 65536, 1024 * 1024)] {
+            let config = NbdConfig {
+                min_block_size: min,
+                preferred_block_size: preferred,
+                max_io_size: max,
+                ..NbdConfig::default()
+            };
+            let backend = Arc::new(RamBuffer::new(SIZE));
+            let (mut client, server) = serve_backend(backend, config);
+            let export = go(&mut client).await;
+            assert_eq!(block_sizes(&export), (min, preferred, max));
+
+            request(&mut client, 0, CMD_DISC, 1, 0, 0).await;
+            server.await.unwrap().unwrap();
+        }
+    }
+
+    /// A backend that claims cheap zeroing
+    struct FastZero(RamBuffer);
+
+    impl BlockBackend for FastZero {
+        fn size(&self) -> u64 {
+            self.0.size()
+        }
+
+        fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+            self.0.read_at(offset, dst)
+        }
+
+        fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+            self.0.write_at(offset, src)
+        }
+
+        fn fast_zero(&self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn fast_zero_is_advertised_and_honored() {
+        let backend = Arc::new(FastZero(RamBuffer::new(SIZE)));
+        backend.write_at(0, &[1; 4096]).unwrap();
+        let (mut client, server) = serve_backend(backend.clone(), NbdConfig::default());
+        assert_ne!(go(&mut client).await.flags & TFLAG_SEND_FAST_ZERO, 0);
+
+        request(
+            &mut client,
+            CMD_FLAG_FAST_ONLY,
+            CMD_WRITE_ZEROES,
+            1,
+            0,
+            4096,
+        )
+        .await;
+        assert_eq!(simple_reply(&mut client).await, (0, 1));
+        let mut data = [1u8; 4096];
+        backend.read_at(0, &mut data).unwrap();
+        assert_eq!(data, [0; 4096]);
+
+        request(&mut client, 0, CMD_DISC, 2, 0, 0).await;
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn fast_only_zeroing_is_refused_without_fast_zero() {
+        let backend = Arc::new(RamBuffer::new(SIZE));
+        backend.write_at(0, &[1; 4096]).unwrap();
+        let (mut client, server) = serve_backend(backend.clone(), NbdConfig::default());
+        let export = go(&mut client).await;
+        assert_eq!(export.flags & TFLAG_SEND_FAST_ZERO, 0);
+        assert_ne!(export.flags & TFLAG_SEND_WRITE_ZEROES, 0);
+
+        // Refused untouched, so the client can write the zeros itself
+        request(
+            &mut client,
+            CMD_FLAG_FAST_ONLY,
+            CMD_WRITE_ZEROES,
+            1,
+            0,
+            4096,
+        )
+        .await;
+        assert_eq!(simple_reply(&mut client).await, (ENOTSUP, 1));
+        let mut data = [0u8; 4096];
+        backend.read_at(0, &mut data).unwrap();
+        assert_eq!(data, [1; 4096]);
+
+        // Without the flag the zeros are written the slow way
+        request(&mut client, 0, CMD_WRITE_ZEROES, 2, 0, 4096).await;
+        assert_eq!(simple_reply(&mut client).await, (0, 2));
+        backend.read_at(0, &mut data).unwrap();
+        assert_eq!(data, [0; 4096]);
+
+        request(&mut client, 0, CMD_DISC, 3, 0, 0).await;
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn wire_checksum_follows_read_data() {
+        let backend = Arc::new(RamBuffer::new(SIZE));
+        let data: Vec<u8> = (0..8192).map(|i| (i % 253) as u8).collect();
+        backend.write_at(4096, &data).unwrap();
+        let config = NbdConfig {
+            wire_checksum: true,
+            ..NbdConfig::default()
+        };
+        let (mut client, server) = serve_backend(backend, config);
+        go_with(&mut client, true).await;
+
+        request(&mut client, 0, CMD_READ, 7, 4096, 8192).await;
+        let (flags, kind, handle, payload) = chunk(&mut client).await;
+        assert_eq!((flags, kind, handle), (0, REPLY_TYPE_OFFSET_DATA, 7));
+        assert_eq!(payload[..8], 4096u64.to_be_bytes());
+        assert_eq!(payload[8..], data[..]);
+        let (flags, kind, handle, payload) = chunk(&mut client).await;
+        assert_eq!(
+            (flags, kind, handle),
+            (REPLY_FLAG_DONE, REPLY_TYPE_CHECKSUM, 7)
+        );
+        assert_eq!(payload[..8], 4096u64.to_be_bytes());
+        assert_eq!(payload[8..], crc32c(&data).to_be_bytes());
+
+        request(&mut client, 0, CMD_DISC, 8, 0, 0).await;
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn no_wire_checksum_without_the_option() {
+        let backend = Arc::new(RamBuffer::new(SIZE));
+        let (mut client, server) = serve_backend(backend, NbdConfig::default());
+        go_with(&mut client, true).await;
+
+        request(&mut client, 0, CMD_READ, 3, 0, 512).await;
+        let (flags, kind, handle, payload) = chunk(&mut client).await;
+        assert_eq!(
+            (flags, kind, handle),
+            (REPLY_FLAG_DONE, REPLY_TYPE_OFFSET_DATA, 3)
+        );
+        assert_eq!(payload.len(), 8 + 512);
+
+        request(&mut client, 0, CMD_DISC, 4, 0, 0).await;
+        server.await.unwrap().unwrap();
+    }
+}
@@ -1,190 +1,409 @@
-//! NBD server implementation using the `nbd` crate v0.3.1.
-
-use crate::opencl::VRamBuffer;
-use anyhow::{Context, Result};
-use nbd;
-use nbd::Export;
-use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
-use std::net::{SocketAddr, TcpStream as StdTcpStream};
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::signal;
-use tokio::task;
+//! NBD server: configuration, per-export connection accounting and the
+//! accept loop. Connections are served by the async protocol implementation
+//! in `protocol`, or by the blocking `nbd` crate path in `blocking`.
+
+#[cfg(any(feature = "sync-nbd", feature = "websocket"))]
+use super::blocking;
+use super::listener::Listener;
+#[cfg(not(feature = "sync-nbd"))]
+use super::protocol;
+#[cfg(not(feature = "sync-nbd"))]
+use super::tls;
+#[cfg(feature = "websocket")]
+use super::websocket;
+use crate::backend::{BlockBackend, ResizableBackend};
+use crate::daemon::Readiness;
+use crate::health::Health;
+use crate::metrics::{ClientGuard, Metrics};
+use crate::systemd;
+use anyhow::{bail, Result};
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio_util::either::Either;
+use tokio_util::sync::CancellationToken;
+
+/// How NBD traffic is carried over an accepted connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NbdTransport {
+    /// Plain NBD over TCP (or the Unix socket)
+    Tcp,
+    /// NBD tunneled through binary WebSocket messages
+    #[cfg(feature = "websocket")]
+    WebSocket,
+}
 
 /// Configuration for the NBD server
 #[derive(Debug, Clone)]
 pub struct NbdConfig {
-    /// Socket address to listen on (e.g., "127.0.0.1:10809")
-    pub listen_addr: String,
-    /// Export name advertised to clients (used during handshake)
-    pub export_name: String,
+    /// Socket addresses to listen on (e.g., "127.0.0.1:10809"), all at once
+    pub listen_addr: Vec<String>,
+    /// Listen on this Unix domain socket instead of `listen_addr`
+    pub unix_socket: Option<PathBuf>,
+    /// Transport used on accepted connections
+    pub transport: NbdTransport,
+    /// Maximum number of simultaneous connections to the server, including
+    /// ones still in the handshake (`None` = unlimited)
+    pub max_clients: Option<usize>,
+    /// Serve every connection read-only
+    pub read_only: bool,
+    /// Tell clients the export is rotational, so they schedule I/O as for a disk
+    pub rotational: bool,
+    /// Serve the first export whatever name the client requests
+    pub default_export: bool,
+    /// Minimum block size advertised to clients (logical sector size)
+    pub min_block_size: u32,
+    /// Preferred block size advertised to clients
+    pub preferred_block_size: u32,
+    /// Largest read/write request accepted, advertised as the maximum block size
+    pub max_io_size: u32,
+    /// How long shutdown waits for clients to finish before closing their sockets
+    pub shutdown_grace: Duration,
+    /// Require clients to upgrade to TLS (NBD_OPT_STARTTLS) with this certificate
+    pub tls: Option<NbdTls>,
+    /// Follow each data chunk of a structured read reply with a CRC32C of
+    /// its data, for clients that know the extension
+    pub wire_checksum: bool,
+    /// Counters updated as requests complete
+    pub metrics: Arc<Metrics>,
+    /// Told once the listener is bound and any other frontend is up, when
+    /// running as a daemon
+    pub ready: Option<Arc<Readiness>>,
+    /// Marked ready once the listener is bound
+    pub health: Arc<Health>,
 }
 
-impl Default for NbdConfig {
-    fn default() -> Self {
-        Self {
-            listen_addr: "127.0.0.1:10809".to_string(),
-            export_name: "vram".to_string(),
+/// Server certificate and private key for NBD over TLS, both PEM files
+#[derive(Debug, Clone)]
+pub struct NbdTls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl NbdConfig {
+    /// Check that the advertised block sizes form a valid NBD constraint set
+    pub fn validate(&self) -> Result<()> {
+        if !self.min_block_size.is_power_of_two() || !(512..=65536).contains(&self.min_block_size) {
+            bail!(
+                "Minimum block size {} must be a power of two between 512 and 65536",
+                self.min_block_size
+            );
+        }
+        if !self.preferred_block_size.is_power_of_two()
+            || self.preferred_block_size < self.min_block_size
+        {
+            bail!(
+                "Preferred block size {} must be a power of two and at least the minimum block size",
+                self.preferred_block_size
+            );
         }
+        if self.max_io_size < self.preferred_block_size
+            || !self.max_io_size.is_multiple_of(self.min_block_size)
+        {
+            bail!(
+                "Maximum I/O size {} must be a multiple of the minimum block size and at least the preferred block size",
+                self.max_io_size
+            );
+        }
+        if self.tls.is_some() {
+            if cfg!(feature = "sync-nbd") {
+                bail!("NBD over TLS is not available in builds with the `sync-nbd` feature");
+            }
+            if self.transport != NbdTransport::Tcp {
+                bail!("NBD over TLS is only supported with the TCP transport");
+            }
+        }
+        if self.wire_checksum {
+            if cfg!(feature = "sync-nbd") {
+                bail!("Wire checksums are not available in builds with the `sync-nbd` feature");
+            }
+            if self.transport != NbdTransport::Tcp {
+                bail!("Wire checksums are only supported with the TCP transport");
+            }
+        }
+        Ok(())
     }
 }
 
-// --- Wrapper struct implementing Read/Write/Seek for VRamBuffer ---
-struct VramSeeker {
-    buffer: Arc<VRamBuffer>,
-    pos: u64,
-    size: u64,
+/// Check `config` as `start_nbd_server` would: validate it, load the TLS
+/// certificate and bind the listen address, then let go of it again. A
+/// socket passed by systemd is left alone. Returns where the server would
+/// listen.
+pub async fn check_nbd_config(config: &NbdConfig) -> Result<String> {
+    config.validate()?;
+    #[cfg(not(feature = "sync-nbd"))]
+    config.tls.as_ref().map(tls::acceptor).transpose()?;
+    let listener = Listener::bind_configured(config).await?;
+    Ok(listener.to_string())
 }
 
-impl VramSeeker {
-    fn new(buffer: Arc<VRamBuffer>) -> Self {
-        let size = buffer.size() as u64;
-        VramSeeker {
-            buffer,
-            pos: 0,
-            size,
+/// A named export and the backend behind it
+#[derive(Clone)]
+pub struct NbdExport {
+    pub name: String,
+    pub backend: Arc<dyn BlockBackend>,
+    /// Lies beneath `backend` and lets clients grow the export with NBD_CMD_RESIZE
+    pub resizable: Option<Arc<ResizableBackend>>,
+    /// Maximum number of simultaneous connections to the export (`None` = unlimited)
+    pub max_connections: Option<usize>,
+    /// Allow only one read-write connection; further connections are served read-only
+    pub single_writer: bool,
+    pub(super) usage: Arc<Mutex<ExportUsage>>,
+}
+
+impl NbdExport {
+    pub fn new(name: impl Into<String>, backend: Arc<dyn BlockBackend>) -> Self {
+        Self {
+            name: name.into(),
+            backend,
+            resizable: None,
+            max_connections: None,
+            single_writer: false,
+            usage: Arc::new(Mutex::new(ExportUsage::default())),
         }
     }
 }
 
-impl Read for VramSeeker {
-    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        let remaining = self.size.saturating_sub(self.pos);
-        if remaining == 0 {
-            return Ok(0);
-        }
+/// Find the export a client asked for by `name`. The empty name is the NBD
+/// "default export" and maps to the first export, as does any unknown name
+/// with `default_export`.
+pub(super) fn find_export<'a>(
+    exports: &'a [NbdExport],
+    name: &str,
+    config: &NbdConfig,
+) -> Option<&'a NbdExport> {
+    let found = exports.iter().find(|export| export.name == name);
+    if found.is_none() && (name.is_empty() || config.default_export) {
+        let first = exports.first()?;
+        log::debug!(
+            "Client requested export '{}', serving default export '{}'",
+            name,
+            first.name
+        );
+        return Some(first);
+    }
+    found
+}
 
-        let read_len = std::cmp::min(buf.len() as u64, remaining) as usize;
-        let read_buf = &mut buf[..read_len];
+/// Extract the export name from an NBD_OPT_INFO/NBD_OPT_GO payload
+/// (`u32` name length, name, `u16` count, `count` x `u16` info types).
+pub(super) fn parse_info_request(data: &[u8]) -> Option<String> {
+    let name_len = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let name = data.get(4..4 + name_len)?;
+    let rest = &data[4 + name_len..];
+    let count = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+    if rest.len() != 2 + 2 * count {
+        return None;
+    }
+    String::from_utf8(name.to_vec()).ok()
+}
 
-        match self.buffer.read(self.pos as usize, read_buf) {
-            Ok(_) => {
-                self.pos += read_len as u64;
-                log::trace!("VramSeeker read {} bytes, new pos {}", read_len, self.pos);
-                Ok(read_len)
-            }
-            Err(e) => {
-                log::error!("VRAM read error during NBD Read: {}", e);
-                Err(IoError::new(ErrorKind::Other, "VRAM read failed"))
-            }
+impl Default for NbdConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: vec!["127.0.0.1:10809".to_string()],
+            unix_socket: None,
+            transport: NbdTransport::Tcp,
+            max_clients: None,
+            read_only: false,
+            rotational: false,
+            default_export: false,
+            min_block_size: 512,
+            preferred_block_size: 4096,
+            max_io_size: 32 * 1024 * 1024,
+            shutdown_grace: Duration::from_secs(10),
+            tls: None,
+            wire_checksum: false,
+            metrics: Arc::default(),
+            ready: None,
+            health: Arc::default(),
         }
     }
 }
 
-impl Write for VramSeeker {
-    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
-        let remaining = self.size.saturating_sub(self.pos);
-        if remaining == 0 {
+/// Connection counters of an export, shared by all client tasks
+#[derive(Debug, Default)]
+pub(super) struct ExportUsage {
+    connections: usize,
+    writers: usize,
+}
+
+/// A claimed connection slot; releases its counters when dropped
+pub(super) struct ConnectionSlot {
+    usage: Arc<Mutex<ExportUsage>>,
+    pub(super) writable: bool,
+    _client: ClientGuard,
+}
+
+impl ConnectionSlot {
+    /// Claim a slot on the export, or fail if its connection limit is reached.
+    /// In single-writer mode only the first concurrent connection is writable.
+    pub(super) fn acquire(export: &NbdExport, config: &NbdConfig) -> IoResult<Self> {
+        let mut counters = export
+            .usage
+            .lock()
+            .map_err(|_| IoError::other("Export usage mutex poisoned"))?;
+
+        if let Some(max) = export
+            .max_connections
+            .filter(|&max| counters.connections >= max)
+        {
             return Err(IoError::new(
-                ErrorKind::WriteZero,
-                "Write past end of VRAM buffer",
+                ErrorKind::ConnectionRefused,
+                format!("Export connection limit ({}) reached", max),
             ));
         }
 
-        let write_len = std::cmp::min(buf.len() as u64, remaining) as usize;
-        if write_len == 0 {
-            return Ok(0);
+        let writable = Self::writable_with(&counters, export, config);
+        counters.connections += 1;
+        if writable {
+            counters.writers += 1;
         }
-        let write_buf = &buf[..write_len];
 
-        match self.buffer.write(self.pos as usize, write_buf) {
-            Ok(_) => {
-                self.pos += write_len as u64;
-                log::trace!("VramSeeker wrote {} bytes, new pos {}", write_len, self.pos);
-                Ok(write_len)
-            }
-            Err(e) => {
-                log::error!("VRAM write error during NBD Write: {}", e);
-                Err(IoError::new(ErrorKind::Other, "VRAM write failed"))
-            }
-        }
+        Ok(Self {
+            usage: export.usage.clone(),
+            writable,
+            _client: config.metrics.client_connected(),
+        })
     }
 
-    fn flush(&mut self) -> IoResult<()> {
-        log::trace!("VramSeeker flush");
-        Ok(())
+    /// Whether a connection claimed now would be writable, without claiming it
+    pub(super) fn would_be_writable(export: &NbdExport, config: &NbdConfig) -> bool {
+        export
+            .usage
+            .lock()
+            .map(|counters| Self::writable_with(&counters, export, config))
+            .unwrap_or(false)
+    }
+
+    fn writable_with(counters: &ExportUsage, export: &NbdExport, config: &NbdConfig) -> bool {
+        !config.read_only && (!export.single_writer || counters.writers == 0)
     }
 }
 
-impl Seek for VramSeeker {
-    fn seek(&mut self, style: SeekFrom) -> IoResult<u64> {
-        let (base_pos, offset) = match style {
-            SeekFrom::Start(n) => {
-                self.pos = n;
-                log::trace!("VramSeeker seek to Start({}), new pos {}", n, self.pos);
-                return Ok(n);
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if let Ok(mut counters) = self.usage.lock() {
+            counters.connections -= 1;
+            if self.writable {
+                counters.writers -= 1;
             }
-            SeekFrom::End(n) => (self.size, n),
-            SeekFrom::Current(n) => (self.pos, n),
-        };
-        let new_pos = if offset >= 0 {
-            base_pos.checked_add(offset as u64)
-        } else {
-            base_pos.checked_sub((offset.wrapping_neg()) as u64)
-        };
-        match new_pos {
-            Some(n) => {
-                self.pos = n;
-                log::trace!("VramSeeker seek relative({}), new pos {}", offset, self.pos);
-                Ok(self.pos)
-            }
-            None => Err(IoError::new(
-                ErrorKind::InvalidInput,
-                "invalid seek to a negative or overflowing position",
-            )),
         }
     }
 }
 
-pub async fn start_nbd_server(buffer: Arc<VRamBuffer>, config: &NbdConfig) -> Result<()> {
-    let addr: SocketAddr = config
-        .listen_addr
-        .parse()
-        .with_context(|| format!("Invalid listen address: {}", config.listen_addr))?;
+/// Serve the exports until `shutdown` is cancelled, then drain clients:
+/// each connection finishes its current request and is closed. Connections
+/// still open after `config.shutdown_grace` are closed forcibly.
+pub async fn start_nbd_server(
+    exports: Vec<NbdExport>,
+    config: &NbdConfig,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    config.validate()?;
+    if exports.is_empty() {
+        bail!("No NBD exports configured");
+    }
+    for (i, export) in exports.iter().enumerate() {
+        if exports[..i].iter().any(|other| other.name == export.name) {
+            bail!("Duplicate NBD export name '{}'", export.name);
+        }
+    }
+    let exports: Arc<[NbdExport]> = exports.into();
+    #[cfg(not(feature = "sync-nbd"))]
+    let tls = config.tls.as_ref().map(tls::acceptor).transpose()?;
 
-    let listener = TcpListener::bind(addr)
-        .await
-        .with_context(|| format!("Failed to bind TCP listener to {}", addr))?;
+    let listener = Listener::bind(config).await?;
 
-    log::info!("NBD server listening on {}", addr);
     log::info!(
-        "Waiting for connections for export '{}' (size: {} bytes)",
-        config.export_name,
-        buffer.size()
+        "NBD server listening on {} (transport: {:?})",
+        listener,
+        config.transport
     );
+    if let Some(tls) = &config.tls {
+        log::info!(
+            "TLS required (certificate {}, key {})",
+            tls.cert.display(),
+            tls.key.display()
+        );
+    }
+    for export in exports.iter() {
+        log::info!(
+            "Waiting for connections for export '{}' (size: {} bytes)",
+            export.name,
+            export.backend.size()
+        );
+    }
+    if config.health.frontend_ready() {
+        if let Some(ready) = &config.ready {
+            ready.ready();
+        }
+        systemd::notify_ready();
+    }
+
+    let drain = CancellationToken::new();
+    let mut clients = JoinSet::new();
 
     loop {
         tokio::select! {
-            Ok((stream, client_addr)) = listener.accept() => {
-                log::info!("NBD client connected: {}", client_addr);
+            Ok((stream, client)) = listener.accept() => {
+                if let Some(max) = config.max_clients {
+                    // Reap finished tasks so that only live connections count
+                    while clients.try_join_next().is_some() {}
+                    if clients.len() >= max {
+                        log::warn!(
+                            "Refusing NBD client {}: {} connections open (--max-clients)",
+                            client,
+                            clients.len()
+                        );
+                        config.metrics.record_rejected_client();
+                        continue;
+                    }
+                }
+                log::info!("NBD client connected: {}", client);
 
-                let buffer_clone = buffer.clone();
+                if let Either::Left(tcp) = &stream
+                    && let Err(e) = tcp.set_nodelay(true)
+                {
+                    log::warn!("Failed to set TCP_NODELAY for {}: {}", client, e);
+                }
+                let exports_clone = exports.clone();
                 let config_clone = config.clone();
+                #[cfg(not(feature = "sync-nbd"))]
+                let drain_clone = drain.clone();
+                #[cfg(not(feature = "sync-nbd"))]
+                let tls_clone = tls.clone();
 
-                // Spawn a blocking task to handle the synchronous nbd crate logic
-                task::spawn_blocking(move || {
-                    match stream.into_std() {
-                        Ok(std_stream) => {
-                             if let Err(e) = std_stream.set_nonblocking(false) {
-                                 log::error!("Failed to set stream to blocking for {}: {}", client_addr, e);
-                                 return;
-                             }
-                             log::info!("Handling client {} in blocking task...", client_addr);
-                             if let Err(e) = handle_connection(std_stream, buffer_clone, config_clone) {
-                                 if e.downcast_ref::<IoError>().map_or(true, |ioe| ioe.kind() != ErrorKind::BrokenPipe) {
-                                     log::error!("Client {} error: {:?}", client_addr, e);
-                                 }
-                             }
-                             log::info!("Client {} disconnected.", client_addr);
-                        }
-                        Err(e) => {
-                            log::error!("Failed to convert Tokio stream to std stream for {}: {}", client_addr, e);
-                        }
+                match config_clone.transport {
+                    #[cfg(not(feature = "sync-nbd"))]
+                    NbdTransport::Tcp => {
+                        clients.spawn(async move {
+                            let result = protocol::serve(stream, exports_clone, config_clone, tls_clone, drain_clone).await;
+                            log_client_result(&client, result);
+                        });
+                    }
+                    #[cfg(feature = "sync-nbd")]
+                    NbdTransport::Tcp => {
+                        clients.spawn(blocking::run_blocking_client(stream, client, move |s| {
+                            blocking::handle_connection(s, exports_clone, config_clone)
+                        }));
                     }
-                });
+                    #[cfg(feature = "websocket")]
+                    NbdTransport::WebSocket => {
+                        clients.spawn(blocking::run_blocking_client(stream, client, move |s| {
+                            websocket::accept(s).and_then(|ws| {
+                                blocking::handle_connection(ws, exports_clone, config_clone)
+                            })
+                        }));
+                    }
+                }
             }
-            _ = signal::ctrl_c() => {
-                log::info!("Ctrl-C received, shutting down NBD server.");
+            // Reap finished client tasks
+            Some(_) = clients.join_next(), if !clients.is_empty() => {}
+            _ = shutdown.cancelled() => {
+                log::info!("Shutdown requested, stopping NBD server.");
                 break;
             }
             else => {
@@ -194,37 +413,99 @@ pub async fn start_nbd_server(buffer: Arc<VRamBuffer>, config: &NbdConfig) -> Re
         }
     }
 
+    drop(listener);
+    drain_clients(&mut clients, &drain, config.shutdown_grace).await;
+
     log::info!("NBD server loop finished.");
     Ok(())
 }
 
-fn handle_connection(
-    mut stream: StdTcpStream,
-    buffer: Arc<VRamBuffer>,
-    config: NbdConfig,
-) -> Result<()> {
-    let _export_data = nbd::server::handshake(&mut stream, |name| {
-        if name == config.export_name {
-            Ok(Export {
-                size: buffer.size() as u64,
-                readonly: false,
-                send_flush: true,
-                resizeable: false,
-                rotational: false,
-                send_trim: false,
-                data: (),
-            })
-        } else {
-            log::warn!("Client requested unknown export: {}", name);
-            Err(IoError::new(ErrorKind::NotFound, "Export not found"))
-        }
+/// Ask connected clients to finish, then close whatever is left after `grace`
+async fn drain_clients(clients: &mut JoinSet<()>, drain: &CancellationToken, grace: Duration) {
+    drain.cancel();
+    if clients.is_empty() {
+        return;
+    }
+    log::info!(
+        "Waiting up to {:?} for {} NBD connection(s) to finish",
+        grace,
+        clients.len()
+    );
+    let drained = tokio::time::timeout(grace, async {
+        while clients.join_next().await.is_some() {}
     })
-    .context("NBD handshake failed")?;
+    .await;
+    if drained.is_err() {
+        log::warn!(
+            "Shutdown grace period of {:?} expired; forcibly closing {} NBD connection(s)",
+            grace,
+            clients.len()
+        );
+        clients.shutdown().await;
+    }
+}
+
+/// Log how a client connection ended
+pub(super) fn log_client_result(client: &str, result: Result<()>) {
+    if let Err(e) = result {
+        let disconnected = e.downcast_ref::<IoError>().is_some_and(|ioe| {
+            matches!(
+                ioe.kind(),
+                ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset
+            )
+        });
+        if !disconnected {
+            log::error!("Client {} error: {:?}", client, e);
+        }
+    }
+    log::info!("Client {} disconnected.", client);
+}
 
-    log::info!("Handshake successful for export '{}'", config.export_name);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
 
-    let vram_seeker = VramSeeker::new(buffer);
-    nbd::server::transmission(&mut stream, vram_seeker).context("NBD transmission phase failed")?;
+    fn export(max_connections: Option<usize>, single_writer: bool) -> NbdExport {
+        let mut export = NbdExport::new("test", Arc::new(RamBuffer::new(4096)));
+        export.max_connections = max_connections;
+        export.single_writer = single_writer;
+        export
+    }
 
-    Ok(())
+    #[test]
+    fn limits_are_per_export() {
+        let config = NbdConfig::default();
+        let limited = export(Some(2), false);
+        let open = export(None, false);
+        let first = ConnectionSlot::acquire(&limited, &config).unwrap();
+        let _second = ConnectionSlot::acquire(&limited, &config).unwrap();
+        let refused = ConnectionSlot::acquire(&limited, &config).err().unwrap();
+        assert_eq!(refused.kind(), ErrorKind::ConnectionRefused);
+        // The other export is unaffected
+        let _many: Vec<_> = (0..5)
+            .map(|_| ConnectionSlot::acquire(&open, &config).unwrap())
+            .collect();
+
+        drop(first);
+        ConnectionSlot::acquire(&limited, &config).unwrap();
+    }
+
+    #[test]
+    fn single_writer_serves_the_rest_read_only() {
+        let config = NbdConfig::default();
+        let guarded = export(None, true);
+        let shared = export(None, false);
+        let writer = ConnectionSlot::acquire(&guarded, &config).unwrap();
+        assert!(writer.writable);
+        assert!(!ConnectionSlot::would_be_writable(&guarded, &config));
+        let reader = ConnectionSlot::acquire(&guarded, &config).unwrap();
+        assert!(!reader.writable);
+        assert!(ConnectionSlot::acquire(&shared, &config).unwrap().writable);
+        assert!(ConnectionSlot::acquire(&shared, &config).unwrap().writable);
+
+        // Once the writer leaves, the next connection may write
+        drop(writer);
+        assert!(ConnectionSlot::acquire(&guarded, &config).unwrap().writable);
+    }
 }
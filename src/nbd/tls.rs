@@ -0,0 +1,48 @@
+//! TLS for NBD connections.
+//!
+//! With a certificate configured the server runs in "forced TLS" mode: the
+//! client has to upgrade the connection with NBD_OPT_STARTTLS before it may
+//! list or choose exports, which is what `nbd-client -certfile` and qemu's
+//! `tls-creds` do. Negotiation then continues inside the TLS session.
+
+use super::server::NbdTls;
+use anyhow::Result;
+
+#[cfg(feature = "tls")]
+pub(super) use tokio_rustls::TlsAcceptor;
+
+/// Stand-in when built without the `tls` feature; can never be constructed
+#[cfg(not(feature = "tls"))]
+#[derive(Clone)]
+pub(super) enum TlsAcceptor {}
+
+/// Load the certificate chain and private key into a TLS acceptor
+#[cfg(feature = "tls")]
+pub(super) fn acceptor(tls: &NbdTls) -> Result<TlsAcceptor> {
+    use anyhow::{bail, Context};
+    use std::sync::Arc;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio_rustls::rustls::{crypto, ServerConfig};
+
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read TLS certificate {}", tls.cert.display()))?;
+    if certs.is_empty() {
+        bail!("No certificate found in {}", tls.cert.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(&tls.key)
+        .with_context(|| format!("Failed to read TLS private key {}", tls.key.display()))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("TLS certificate and private key do not form a valid pair")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+#[cfg(not(feature = "tls"))]
+pub(super) fn acceptor(_tls: &NbdTls) -> Result<TlsAcceptor> {
+    anyhow::bail!("--tls-cert requires building with the `tls` feature")
+}
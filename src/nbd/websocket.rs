@@ -0,0 +1,90 @@
+//! NBD over WebSocket
+//!
+//! Tunnels the NBD byte stream through binary WebSocket messages, for
+//! networks that only allow HTTP(S) egress and for browser-based tooling.
+//! Message boundaries carry no meaning: the payloads are concatenated into
+//! one stream and fed to the regular handshake/transmission code.
+
+use anyhow::{anyhow, Result};
+use std::io::{self, Read, Write};
+use tungstenite::{Error as WsError, Message, WebSocket};
+
+/// `Read + Write` adapter over a server-side WebSocket
+pub struct WsStream<S> {
+    ws: WebSocket<S>,
+    /// Payload of the last received message not yet consumed
+    incoming: Vec<u8>,
+    incoming_pos: usize,
+    /// Output collected until the next flush
+    outgoing: Vec<u8>,
+}
+
+/// Perform the HTTP upgrade on an accepted connection
+pub fn accept<S: Read + Write>(stream: S) -> Result<WsStream<S>> {
+    let ws = tungstenite::accept(stream).map_err(|e| anyhow!("WebSocket upgrade failed: {}", e))?;
+    Ok(WsStream {
+        ws,
+        incoming: Vec::new(),
+        incoming_pos: 0,
+        outgoing: Vec::new(),
+    })
+}
+
+fn to_io_error(e: WsError) -> io::Error {
+    match e {
+        WsError::Io(e) => e,
+        WsError::ConnectionClosed | WsError::AlreadyClosed => {
+            io::Error::new(io::ErrorKind::BrokenPipe, "WebSocket closed")
+        }
+        e => io::Error::other(e),
+    }
+}
+
+impl<S: Read + Write> Read for WsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The peer only answers once it has seen our pending output
+        if !self.outgoing.is_empty() {
+            self.flush()?;
+        }
+
+        while self.incoming_pos >= self.incoming.len() {
+            match self.ws.read() {
+                Ok(Message::Binary(data)) => {
+                    self.incoming = data;
+                    self.incoming_pos = 0;
+                }
+                Ok(Message::Close(_)) | Err(WsError::ConnectionClosed) => return Ok(0),
+                Ok(Message::Text(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected text message on NBD WebSocket",
+                    ));
+                }
+                // Pings are answered by tungstenite on the next write/flush
+                Ok(_) => {}
+                Err(e) => return Err(to_io_error(e)),
+            }
+        }
+
+        let available = &self.incoming[self.incoming_pos..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.incoming_pos += len;
+        Ok(len)
+    }
+}
+
+impl<S: Read + Write> Write for WsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.outgoing.is_empty() {
+            let data = std::mem::take(&mut self.outgoing);
+            self.ws.send(Message::Binary(data)).map_err(to_io_error)?;
+        }
+        self.ws.flush().map_err(to_io_error)
+    }
+}
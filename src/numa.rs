@@ -0,0 +1,107 @@
+//! Binding host memory to a NUMA node
+//!
+//! On a multi-socket machine, DMA between the GPU and host memory is fastest
+//! when that memory sits on the node closest to the GPU's PCIe root complex.
+//! `--numa-node` sets an `MPOL_BIND` memory policy (set_mempolicy(2)) before
+//! the runtime starts: the policy is per thread and inherited by new threads,
+//! so every worker, blocking and ublk queue thread allocates from that node,
+//! and with them the staging buffers, I/O buffers and locked pages. The node
+//! of a GPU is read from its PCI device in sysfs.
+
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+/// Where the NUMA nodes are listed
+const NODE_DIR: &str = "/sys/devices/system/node";
+
+/// A `--numa-node` setting
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum NumaNode {
+    /// The node of the GPU's PCI device
+    Auto,
+    Node(u32),
+}
+
+/// Parses a `--numa-node`: a node number or "auto".
+pub fn parse_numa_node(node: &str) -> Result<NumaNode> {
+    if node.eq_ignore_ascii_case("auto") {
+        return Ok(NumaNode::Auto);
+    }
+    node.parse()
+        .map(NumaNode::Node)
+        .context("NUMA node must be a number or `auto`")
+}
+
+/// Online NUMA nodes, or `None` if the kernel has no NUMA support
+fn online_nodes() -> Option<Vec<u32>> {
+    let list = std::fs::read_to_string(Path::new(NODE_DIR).join("online")).ok()?;
+    // A list of ranges such as "0-1,3"
+    let mut nodes = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        nodes.extend(first.parse::<u32>().ok()?..=last.parse().ok()?);
+    }
+    Some(nodes)
+}
+
+/// NUMA node of the PCI device at `address` (e.g., `0000:03:00.0`), or
+/// `None` where the platform doesn't report one
+pub fn pci_device_node(address: &str) -> Result<Option<u32>> {
+    let path = Path::new("/sys/bus/pci/devices")
+        .join(address)
+        .join("numa_node");
+    let node = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    // -1 when the firmware doesn't describe the device's locality
+    Ok(node.trim().parse().ok())
+}
+
+/// Bind the calling thread's future memory allocations, and those of the
+/// threads it starts, to `node`. Without NUMA, or on a single-node machine,
+/// this only logs a warning.
+pub fn bind_memory(node: u32) -> Result<()> {
+    let nodes = match online_nodes() {
+        Some(nodes) if nodes.len() > 1 => nodes,
+        _ => {
+            log::warn!(
+                "--numa-node {} ignored: this system has no NUMA nodes to choose from",
+                node
+            );
+            return Ok(());
+        }
+    };
+    if !nodes.contains(&node) {
+        bail!(
+            "NUMA node {} is not online (online nodes: {:?})",
+            node,
+            nodes
+        );
+    }
+
+    let bits = libc::c_ulong::BITS;
+    let mut mask = vec![0 as libc::c_ulong; (node / bits + 1) as usize];
+    mask[(node / bits) as usize] |= 1 << (node % bits);
+    // maxnode counts bits, and the kernel ignores the last one
+    let maxnode = (mask.len() as u32 * bits + 1) as libc::c_ulong;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            libc::MPOL_BIND,
+            mask.as_ptr(),
+            maxnode,
+        )
+    };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ENOSYS) {
+            log::warn!(
+                "--numa-node {} ignored: the kernel was built without NUMA support",
+                node
+            );
+            return Ok(());
+        }
+        return Err(err).with_context(|| format!("Failed to bind memory to NUMA node {}", node));
+    }
+    log::info!("Host memory bound to NUMA node {}", node);
+    Ok(())
+}
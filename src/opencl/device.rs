@@ -0,0 +1,275 @@
+//! OpenCL device queries shared by device listing and diagnostics
+
+use super::platform::{gpu_devices, platforms};
+use anyhow::{bail, Context, Result};
+use opencl3::device::Device;
+use opencl3::device::CL_DEVICE_TYPE_GPU;
+use std::fmt::Write;
+
+/// Extension that exposes `CL_DEVICE_GLOBAL_FREE_MEMORY_AMD`
+const AMD_ATTRIBUTE_QUERY_EXT: &str = "cl_amd_device_attribute_query";
+/// Extension that exposes `CL_DEVICE_GPU_OVERLAP_NV`
+const NV_ATTRIBUTE_QUERY_EXT: &str = "cl_nv_device_attribute_query";
+/// Extension that exposes `CL_DEVICE_PCI_BUS_INFO_KHR`
+const PCI_BUS_INFO_EXT: &str = "cl_khr_pci_bus_info";
+
+/// Check whether the device advertises the given extension
+fn has_extension(device: &Device, name: &str) -> bool {
+    device
+        .extensions()
+        .map(|exts| exts.split_whitespace().any(|ext| ext == name))
+        .unwrap_or(false)
+}
+
+/// Query the free global memory of a device in bytes, if the driver exposes it.
+///
+/// Only AMD drivers report this (via `cl_amd_device_attribute_query`, in KiB);
+/// other vendors return `None`.
+pub fn device_free_memory(device: &Device) -> Option<u64> {
+    if !has_extension(device, AMD_ATTRIBUTE_QUERY_EXT) {
+        return None;
+    }
+    device
+        .global_free_memory_amd()
+        .ok()
+        .map(|kib| kib as u64 * 1024)
+}
+
+/// Memory available for a new buffer on GPU `device_index` of
+/// `platform_index`: free memory where the driver reports it, and global
+/// memory otherwise.
+pub fn device_available_memory(platform_index: usize, device_index: usize) -> Result<u64> {
+    let platforms = platforms()?;
+    let Some(platform) = platforms.get(platform_index) else {
+        bail!(
+            "Platform index {} is out of bounds (max: {})",
+            platform_index,
+            platforms.len() - 1
+        );
+    };
+    let device_ids = gpu_devices(platform, platform_index)?;
+    let Some(&device_id) = device_ids.get(device_index) else {
+        bail!(
+            "Device index {} is out of bounds (max: {})",
+            device_index,
+            device_ids.len() - 1
+        );
+    };
+    let device = Device::new(device_id);
+    match device_free_memory(&device) {
+        Some(free) => {
+            log::debug!("GPU device {}: {} bytes free", device_index, free);
+            Ok(free)
+        }
+        None => {
+            let global = device
+                .global_mem_size()
+                .context("Failed to query GPU global memory size")?;
+            log::debug!(
+                "GPU device {}: free memory not reported, using {} bytes of global memory",
+                device_index,
+                global
+            );
+            Ok(global)
+        }
+    }
+}
+
+/// PCI address (e.g., `0000:03:00.0`) of GPU `device_index` of
+/// `platform_index`, from `cl_khr_pci_bus_info` or the AMD and NVIDIA
+/// attribute queries; `None` if the driver reports none of them.
+pub fn device_pci_address(platform_index: usize, device_index: usize) -> Result<Option<String>> {
+    let platforms = platforms()?;
+    let Some(platform) = platforms.get(platform_index) else {
+        bail!(
+            "Platform index {} is out of bounds (max: {})",
+            platform_index,
+            platforms.len() - 1
+        );
+    };
+    let device_ids = gpu_devices(platform, platform_index)?;
+    let Some(&device_id) = device_ids.get(device_index) else {
+        bail!(
+            "Device index {} is out of bounds (max: {})",
+            device_index,
+            device_ids.len() - 1
+        );
+    };
+    let device = Device::new(device_id);
+
+    // (domain, bus, device, function)
+    let address = if has_extension(&device, PCI_BUS_INFO_EXT) {
+        device.pcibusinfokhr_intel().ok().map(|info| {
+            (
+                info.pci_domain,
+                info.pci_bus,
+                info.pci_device,
+                info.pci_function,
+            )
+        })
+    } else if has_extension(&device, AMD_ATTRIBUTE_QUERY_EXT) {
+        device.topology_amd().ok().map(|topology| {
+            (
+                0,
+                topology.bus as u32,
+                topology.device as u32,
+                topology.function as u32,
+            )
+        })
+    } else if has_extension(&device, NV_ATTRIBUTE_QUERY_EXT) {
+        // The slot ID packs the device and function numbers
+        device
+            .pci_bus_id_nv()
+            .ok()
+            .zip(device.pci_slot_id_nv().ok())
+            .map(|(bus, slot)| (0, bus, slot >> 3, slot & 7))
+    } else {
+        None
+    };
+    Ok(address.map(|(domain, bus, device, function)| {
+        format!("{:04x}:{:02x}:{:02x}.{:x}", domain, bus, device, function)
+    }))
+}
+
+/// Number of asynchronous (DMA) queues reported by AMD drivers
+pub(super) fn amd_async_queues(device: &Device) -> Option<u32> {
+    if !has_extension(device, AMD_ATTRIBUTE_QUERY_EXT) {
+        return None;
+    }
+    device.available_async_queues_amd().ok()
+}
+
+/// Whether an NVIDIA device can overlap transfers with other work
+pub(super) fn nv_gpu_overlap(device: &Device) -> Option<bool> {
+    if !has_extension(device, NV_ATTRIBUTE_QUERY_EXT) {
+        return None;
+    }
+    device.gpu_overlap_nv().ok().map(|v| v != 0)
+}
+
+/// Pick the GPU on `platform_index` that can hold a `size` byte buffer with
+/// the most headroom. Free memory is used where the driver reports it, and
+/// global memory otherwise.
+pub fn auto_select_device(platform_index: usize, size: u64) -> Result<usize> {
+    let platforms = platforms()?;
+    let Some(platform) = platforms.get(platform_index) else {
+        bail!(
+            "Platform index {} is out of bounds (max: {})",
+            platform_index,
+            platforms.len() - 1
+        );
+    };
+
+    let mut best: Option<(usize, u64)> = None;
+    let mut capacities = String::new();
+    for (index, device_id) in gpu_devices(platform, platform_index)?
+        .into_iter()
+        .enumerate()
+    {
+        let device = Device::new(device_id);
+        let name = device
+            .name()
+            .unwrap_or_else(|_| "Unknown Device".to_string());
+        let global = device.global_mem_size().unwrap_or(0);
+        let free = device_free_memory(&device);
+        let available = free.unwrap_or(global);
+        log::debug!(
+            "GPU device {} ({}): {} bytes global, {:?} free",
+            index,
+            name,
+            global,
+            free
+        );
+
+        let _ = write!(
+            capacities,
+            "\n  Device {}: {} - {} MB global",
+            index,
+            name,
+            global / (1024 * 1024)
+        );
+        if let Some(free) = free {
+            let _ = write!(capacities, ", {} MB free", free / (1024 * 1024));
+        }
+
+        if size <= available {
+            let headroom = available - size;
+            if best.is_none_or(|(_, best_headroom)| headroom > best_headroom) {
+                best = Some((index, headroom));
+            }
+        }
+    }
+
+    match best {
+        Some((index, headroom)) => {
+            log::info!(
+                "Auto-selected GPU device {} on platform {} ({} MB headroom)",
+                index,
+                platform_index,
+                headroom / (1024 * 1024)
+            );
+            Ok(index)
+        }
+        None => bail!(
+            "No GPU device on platform {} has room for {} bytes ({} MB):{}",
+            platform_index,
+            size,
+            size / (1024 * 1024),
+            capacities
+        ),
+    }
+}
+
+/// Find the GPU whose name contains `pattern` (case-insensitive) across all
+/// platforms, as `(platform_index, device_index)`. More than one match is an
+/// error listing the candidates.
+pub fn find_device_by_name(pattern: &str) -> Result<(usize, usize)> {
+    let needle = pattern.to_lowercase();
+    let mut matches = Vec::new();
+    for (platform_index, platform) in platforms()?.iter().enumerate() {
+        // Platforms without GPUs are skipped, as in --list-devices
+        let Ok(device_ids) = platform.get_devices(CL_DEVICE_TYPE_GPU) else {
+            continue;
+        };
+        for (device_index, device_id) in device_ids.into_iter().enumerate() {
+            let name = Device::new(device_id)
+                .name()
+                .unwrap_or_else(|_| "Unknown Device".to_string());
+            if name.to_lowercase().contains(&needle) {
+                matches.push((platform_index, device_index, name));
+            }
+        }
+    }
+
+    match matches.as_slice() {
+        [] => bail!(
+            "No GPU device name contains {:?} (see --list-devices)",
+            pattern
+        ),
+        [(platform_index, device_index, name)] => {
+            log::info!(
+                "Selected GPU device {} on platform {} by name: {}",
+                device_index,
+                platform_index,
+                name
+            );
+            Ok((*platform_index, *device_index))
+        }
+        _ => {
+            let mut candidates = String::new();
+            for (platform_index, device_index, name) in &matches {
+                let _ = write!(
+                    candidates,
+                    "\n  Platform {} device {}: {}",
+                    platform_index, device_index, name
+                );
+            }
+            bail!(
+                "{} GPU devices match {:?}; use a longer name or --platform/--device:{}",
+                matches.len(),
+                pattern,
+                candidates
+            )
+        }
+    }
+}
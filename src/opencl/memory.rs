@@ -2,19 +2,45 @@
 //!
 //! This module provides functionality to allocate and manage
 //! GPU memory buffers that will be exposed as block devices.
+//!
+//! Many implementations cap a single allocation at
+//! `CL_DEVICE_MAX_MEM_ALLOC_SIZE`, often a quarter of the VRAM, so a large
+//! device is backed by several sub-buffers of at most that size laid end to
+//! end. Transfers crossing a sub-buffer boundary are split at the boundary.
+//!
+//! Each buffer normally gets an OpenCL context, command queues and pinned
+//! staging of its own. With `shared_context`, buffers on the same device
+//! share one `SharedClContext` instead, which saves driver resources when
+//! a process serves many small exports.
 
+use super::pinned::PinnedStaging;
+use super::platform::{gpu_devices, platforms};
+use super::queue::{QueueLayout, TransferQueues};
+use super::staging::{needs_bounce, StagingPool};
+use crate::backend::DeviceLostError;
+use crate::retry::RetryPolicy;
 use anyhow::{bail, Context, Result};
 use opencl3::{
-    command_queue::{self as cl_command_queue, CommandQueue},
+    command_queue as cl_command_queue,
     context::Context as ClContext,
-    device::{self as cl_device, Device},
-    memory::{self as cl_memory, Buffer},
-    platform::{self as cl_platform},
+    device::Device,
+    error_codes::{
+        ClError, CL_DEVICE_NOT_AVAILABLE, CL_INVALID_BUFFER_SIZE, CL_INVALID_COMMAND_QUEUE,
+        CL_INVALID_CONTEXT, CL_MEM_OBJECT_ALLOCATION_FAILURE, CL_OUT_OF_HOST_MEMORY,
+        CL_OUT_OF_RESOURCES,
+    },
+    event::Event,
+    memory::{self as cl_memory, Buffer, ClMem},
     types,
 };
-// Use std::sync::Mutex for thread-safe interior mutability
+use std::ffi::c_void;
 use std::ptr;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+/// Sub-buffer sizes are rounded down to a multiple of this, so block-sized
+/// requests at aligned offsets never straddle two sub-buffers
+const SUB_BUFFER_ALIGN: usize = 1024 * 1024;
 
 /// Configuration for a GPU memory buffer
 #[derive(Debug, Clone)]
@@ -25,6 +51,22 @@ pub struct VRamBufferConfig {
     pub device_index: usize,
     /// Optional platform index (defaults to 0)
     pub platform_index: usize,
+    /// Retry policy for failed transfers
+    pub retry: RetryPolicy,
+    /// How reads and writes are distributed over command queues
+    pub queue_layout: QueueLayout,
+    /// Number of read/write queue pairs transfers are spread over
+    pub command_queues: usize,
+    /// Host pointer alignment for direct transfers (defaults to the device's
+    /// base address alignment); misaligned transfers are bounced
+    pub host_alignment: Option<usize>,
+    /// Size of the pinned staging buffer per command queue (0 disables it)
+    pub pinned_staging: usize,
+    /// Allocate the pinned write staging buffer as write-combining memory
+    pub write_combine: bool,
+    /// Share the context, queues and pinned staging of other buffers on the
+    /// same device that set this too
+    pub shared_context: bool,
 }
 
 impl Default for VRamBufferConfig {
@@ -33,28 +75,44 @@ impl Default for VRamBufferConfig {
             size: 2048 * 1024 * 1024, // 2 GB default size
             device_index: 0,
             platform_index: 0,
+            retry: RetryPolicy::default(),
+            queue_layout: QueueLayout::Auto,
+            command_queues: 1,
+            host_alignment: None,
+            pinned_staging: 32 * 1024 * 1024,
+            write_combine: false,
+            shared_context: false,
         }
     }
 }
 
-/// A buffer allocated in GPU VRAM via OpenCL
-// Make VRamBuffer Send + Sync by using Mutex for the buffer
-pub struct VRamBuffer {
-    queue: Arc<CommandQueue>,
-    // Use Mutex instead of RefCell
-    buffer: Mutex<Buffer<u8>>,
-    size: usize,
-    device: Device,
+/// OpenCL contexts currently open
+static CONTEXTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Contexts open for buffers with `shared_context`, by platform and device
+static SHARED: Mutex<Vec<(usize, usize, Weak<SharedClContext>)>> = Mutex::new(Vec::new());
+
+/// Number of OpenCL contexts currently open
+pub fn contexts_in_use() -> usize {
+    CONTEXTS.load(Ordering::Relaxed)
 }
 
-impl VRamBuffer {
-    /// Create a new GPU memory buffer with the specified configuration
-    pub fn new(config: &VRamBufferConfig) -> Result<Self> {
-        let platforms = cl_platform::get_platforms().context("Failed to get OpenCL platforms")?;
+/// An OpenCL context on one device, with the command queues and pinned
+/// staging that transfers to its buffers go through
+pub struct SharedClContext {
+    context: Arc<ClContext>,
+    device: Device,
+    queues: TransferQueues,
+    /// Pinned staging for reads and writes (one shared buffer with the single queue layout)
+    pinned_read: Option<Arc<PinnedStaging>>,
+    pinned_write: Option<Arc<PinnedStaging>>,
+}
 
-        if platforms.is_empty() {
-            bail!("No OpenCL platforms available");
-        }
+impl SharedClContext {
+    /// Open a context on the device of `config`, with queues and pinned
+    /// staging as `config` asks for
+    fn new(config: &VRamBufferConfig) -> Result<Self> {
+        let platforms = platforms()?;
 
         if config.platform_index >= platforms.len() {
             bail!(
@@ -65,16 +123,7 @@ impl VRamBuffer {
         }
         let platform = &platforms[config.platform_index];
 
-        let device_ids = platform
-            .get_devices(cl_device::CL_DEVICE_TYPE_GPU)
-            .context("Failed to get device list")?;
-
-        if device_ids.is_empty() {
-            bail!(
-                "No GPU devices found for platform {}",
-                config.platform_index
-            );
-        }
+        let device_ids = gpu_devices(platform, config.platform_index)?;
 
         if config.device_index >= device_ids.len() {
             bail!(
@@ -89,25 +138,118 @@ impl VRamBuffer {
         let context =
             Arc::new(ClContext::from_device(&device).context("Failed to create OpenCL context")?);
 
-        let queue = Arc::new(unsafe {
-            CommandQueue::create_with_properties(
-                &context,
-                device.id(),
-                cl_command_queue::CL_QUEUE_PROFILING_ENABLE,
-                0,
-            )
-            .context("Failed to create command queue")?
+        let queues = TransferQueues::new(
+            &context,
+            &device,
+            config.queue_layout,
+            config.command_queues,
+        )?;
+
+        let (pinned_read, pinned_write) = if config.pinned_staging == 0 {
+            (None, None)
+        } else {
+            let pair = &queues.pairs[0];
+            let read = pinned_staging(&context, &pair.read, config.pinned_staging, false);
+            // Write-combined memory is too slow to read back, so it gets its own
+            let write = if Arc::ptr_eq(&pair.read, &pair.write) && !config.write_combine {
+                read.clone()
+            } else {
+                pinned_staging(
+                    &context,
+                    &pair.write,
+                    config.pinned_staging,
+                    config.write_combine,
+                )
+            };
+            (read, write)
+        };
+
+        CONTEXTS.fetch_add(1, Ordering::Relaxed);
+        Ok(Self {
+            context,
+            device,
+            queues,
+            pinned_read,
+            pinned_write,
+        })
+    }
+
+    /// The context for `config`: the open shared one on its device if there
+    /// is one and `config` asks to share, otherwise a new one
+    fn for_config(config: &VRamBufferConfig) -> Result<Arc<Self>> {
+        if !config.shared_context {
+            return Ok(Arc::new(Self::new(config)?));
+        }
+        let mut shared = SHARED.lock().unwrap_or_else(|e| e.into_inner());
+        shared.retain(|(_, _, context)| context.strong_count() > 0);
+        let open = shared.iter().find_map(|&(platform, device, ref context)| {
+            (platform == config.platform_index && device == config.device_index)
+                .then(|| context.upgrade())
+                .flatten()
         });
+        if let Some(context) = open {
+            return Ok(context);
+        }
+        let context = Arc::new(Self::new(config)?);
+        shared.push((
+            config.platform_index,
+            config.device_index,
+            Arc::downgrade(&context),
+        ));
+        Ok(context)
+    }
+}
 
-        let buffer = unsafe {
-            Buffer::<u8>::create(
-                &context,
-                cl_memory::CL_MEM_READ_WRITE,
-                config.size,
-                ptr::null_mut(),
-            )
-            .context("Failed to allocate GPU memory")?
+impl Drop for SharedClContext {
+    fn drop(&mut self) {
+        CONTEXTS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A buffer allocated in GPU VRAM via OpenCL
+///
+/// Transfers are blocking and OpenCL enqueue calls are thread-safe, so the
+/// buffer is not locked: transfers submitted on separate queues can be in
+/// flight at the same time.
+pub struct VRamBuffer {
+    /// Sub-buffers in order; all but the last are `sub_buffer_size` bytes
+    buffers: Vec<Buffer<u8>>,
+    sub_buffer_size: usize,
+    size: usize,
+    /// Context, queues and pinned staging, possibly shared with other buffers
+    shared: Arc<SharedClContext>,
+    retry: RetryPolicy,
+    staging: StagingPool,
+    /// Set once a transfer failed because the context is gone; every later
+    /// transfer fails at once with `DeviceLostError`
+    lost: AtomicBool,
+}
+
+impl VRamBuffer {
+    /// Create a new GPU memory buffer with the specified configuration
+    pub fn new(config: &VRamBufferConfig) -> Result<Self> {
+        let shared = SharedClContext::for_config(config)?;
+        let (context, device) = (&shared.context, &shared.device);
+
+        let sub_buffer_size = match device.max_mem_alloc_size() {
+            Ok(max) if (max as usize) < config.size => {
+                let size = max as usize / SUB_BUFFER_ALIGN * SUB_BUFFER_ALIGN;
+                if size == 0 {
+                    bail!("Device maximum allocation size {} bytes is too small", max);
+                }
+                size
+            }
+            _ => config.size.max(1),
         };
+        let mut buffers = Vec::with_capacity(config.size.div_ceil(sub_buffer_size));
+        for start in (0..config.size).step_by(sub_buffer_size) {
+            let len = sub_buffer_size.min(config.size - start);
+            let buffer = unsafe {
+                Buffer::<u8>::create(context, cl_memory::CL_MEM_READ_WRITE, len, ptr::null_mut())
+                    .map_err(|e| allocation_error(device, config.size, len, start, e))?
+            };
+            buffers.push(buffer);
+        }
 
         log::info!(
             "Created OpenCL buffer of size {} bytes on device: {}",
@@ -116,12 +258,35 @@ impl VRamBuffer {
                 .name()
                 .unwrap_or_else(|_| "Unknown device".to_string())
         );
+        if buffers.len() > 1 {
+            log::info!(
+                "Split into {} sub-buffers of up to {} bytes (device maximum allocation)",
+                buffers.len(),
+                sub_buffer_size
+            );
+        }
+
+        let host_alignment = match config.host_alignment {
+            Some(align) if !align.is_power_of_two() => {
+                bail!("Host alignment {} is not a power of two", align)
+            }
+            Some(align) => align,
+            // Reported in bits
+            None => (device.mem_base_addr_align().unwrap_or(8) as usize / 8).max(1),
+        };
+        log::debug!(
+            "Host buffers not aligned to {} bytes are bounced through staging buffers",
+            host_alignment
+        );
 
         Ok(Self {
-            queue,
-            buffer: Mutex::new(buffer),
+            buffers,
+            sub_buffer_size,
             size: config.size,
-            device,
+            shared,
+            retry: config.retry.clone(),
+            staging: StagingPool::new(host_alignment),
+            lost: AtomicBool::new(false),
         })
     }
 
@@ -130,23 +295,38 @@ impl VRamBuffer {
         self.size
     }
 
+    /// Host pointer alignment required for direct (unbounced) transfers
+    pub fn host_alignment(&self) -> usize {
+        self.staging.alignment()
+    }
+
     /// Read data from the GPU buffer
     pub fn read(&self, offset: usize, data: &mut [u8]) -> Result<()> {
         if offset + data.len() > self.size {
             bail!("Attempted to read past end of buffer");
         }
 
-        let buffer_guard = self
-            .buffer
-            .lock()
-            .map_err(|_| anyhow::anyhow!("Failed to lock buffer mutex for read"))?;
-
-        unsafe {
-            self.queue
-                .enqueue_read_buffer(&*buffer_guard, types::CL_TRUE, offset, data, &[])
-                .context("Failed to enqueue blocking read from buffer")?;
+        for piece in split_range(self.sub_buffer_size, offset, data.len()) {
+            let buffer = &self.buffers[piece.buffer];
+            let data = &mut data[piece.data_offset..piece.data_offset + piece.len];
+            if let Some(mut pinned) = self
+                .shared
+                .pinned_read
+                .as_ref()
+                .and_then(|pinned| pinned.try_get(data.len()))
+            {
+                self.read_direct(buffer, piece.offset, &mut pinned)?;
+                data.copy_from_slice(&pinned);
+                continue;
+            }
+            if !needs_bounce(data, self.host_alignment()) {
+                self.read_direct(buffer, piece.offset, data)?;
+                continue;
+            }
+            let mut staged = self.staging.get(data.len());
+            self.read_direct(buffer, piece.offset, &mut staged)?;
+            data.copy_from_slice(&staged);
         }
-
         Ok(())
     }
 
@@ -156,30 +336,371 @@ impl VRamBuffer {
             bail!("Attempted to write past end of buffer");
         }
 
-        let mut buffer_guard = self
-            .buffer
-            .lock()
-            .map_err(|_| anyhow::anyhow!("Failed to lock buffer mutex for write"))?;
+        for piece in split_range(self.sub_buffer_size, offset, data.len()) {
+            let buffer = &self.buffers[piece.buffer];
+            let data = &data[piece.data_offset..piece.data_offset + piece.len];
+            if let Some(mut pinned) = self
+                .shared
+                .pinned_write
+                .as_ref()
+                .and_then(|pinned| pinned.try_get(data.len()))
+            {
+                // Only ever written by the CPU; it may be write-combined
+                pinned.copy_from_slice(data);
+                self.write_direct(buffer, piece.offset, &pinned)?;
+                continue;
+            }
+            if !needs_bounce(data, self.host_alignment()) {
+                self.write_direct(buffer, piece.offset, data)?;
+                continue;
+            }
+            let mut staged = self.staging.get(data.len());
+            staged.copy_from_slice(data);
+            self.write_direct(buffer, piece.offset, &staged)?;
+        }
+        Ok(())
+    }
 
-        unsafe {
-            self.queue
-                .enqueue_write_buffer(&mut *buffer_guard, types::CL_TRUE, offset, data, &[])
-                .context("Failed to enqueue blocking write to buffer")?;
+    /// Zero a range on the device without transferring data from the host
+    pub fn fill_zeroes(&self, offset: usize, len: usize) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to fill past end of buffer");
         }
 
+        let pattern = 0u8;
+        let queue = &self.shared.queues.next().write;
+        for piece in split_range(self.sub_buffer_size, offset, len) {
+            self.transfer("VRAM fill", || unsafe {
+                cl_command_queue::enqueue_fill_buffer(
+                    queue.get(),
+                    self.buffers[piece.buffer].get(),
+                    &pattern as *const u8 as *const c_void,
+                    1,
+                    piece.offset,
+                    piece.len,
+                    0,
+                    ptr::null(),
+                )
+                .map(Event::new)
+                .map_err(ClError)
+                .context("Failed to enqueue buffer fill")?
+                .wait()
+                .context("Buffer fill failed")
+            })?;
+        }
         Ok(())
     }
 
+    /// Wait for all outstanding transfers on the device to complete
+    pub fn finish(&self) -> Result<()> {
+        self.check_lost(self.shared.queues.finish())
+    }
+
+    /// Run one transfer `op`, retrying transient failures, unless the
+    /// device is already lost
+    fn transfer<T>(&self, what: &str, op: impl FnMut() -> Result<T>) -> Result<T> {
+        if self.lost.load(Ordering::Relaxed) {
+            return Err(DeviceLostError.into());
+        }
+        self.check_lost(self.retry.run(what, is_transient, op))
+    }
+
+    /// Mark the device lost if `result` failed because the context is gone
+    fn check_lost<T>(&self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(e) if is_context_lost(&e) => {
+                if !self.lost.swap(true, Ordering::Relaxed) {
+                    log::error!("GPU device lost ({:#}); all further I/O to it will fail", e);
+                }
+                Err(e.context(DeviceLostError))
+            }
+            result => result,
+        }
+    }
+
+    /// Blocking read from one sub-buffer straight into `data`
+    fn read_direct(&self, buffer: &Buffer<u8>, offset: usize, data: &mut [u8]) -> Result<()> {
+        let queue = &self.shared.queues.next().read;
+        self.transfer("VRAM read", || unsafe {
+            cl_command_queue::enqueue_read_buffer(
+                queue.get(),
+                buffer.get(),
+                types::CL_TRUE,
+                offset,
+                data.len(),
+                data.as_mut_ptr() as *mut c_void,
+                0,
+                ptr::null(),
+            )
+            .map(Event::new)
+            .map_err(ClError)
+            .context("Failed to enqueue blocking read from buffer")
+        })?;
+
+        Ok(())
+    }
+
+    /// Blocking write to one sub-buffer straight from `data`
+    fn write_direct(&self, buffer: &Buffer<u8>, offset: usize, data: &[u8]) -> Result<()> {
+        let queue = &self.shared.queues.next().write;
+        self.transfer("VRAM write", || unsafe {
+            cl_command_queue::enqueue_write_buffer(
+                queue.get(),
+                buffer.get(),
+                types::CL_TRUE,
+                offset,
+                data.len(),
+                data.as_ptr() as *const c_void,
+                0,
+                ptr::null(),
+            )
+            .map(Event::new)
+            .map_err(ClError)
+            .context("Failed to enqueue blocking write to buffer")
+        })?;
+
+        Ok(())
+    }
+
+    /// Queue layout in use (never `Auto`)
+    pub fn queue_layout(&self) -> QueueLayout {
+        self.shared.queues.layout
+    }
+
     /// Get the device name
     pub fn device_name(&self) -> String {
-        self.device
+        self.shared
+            .device
             .name()
             .unwrap_or_else(|_| "Unknown device".to_string())
     }
 }
 
+/// Pinned staging buffer for `queue`, or `None` (with a warning) if the
+/// driver can't provide one
+fn pinned_staging(
+    context: &ClContext,
+    queue: &Arc<cl_command_queue::CommandQueue>,
+    len: usize,
+    write_combined: bool,
+) -> Option<Arc<PinnedStaging>> {
+    match PinnedStaging::new(context, queue, len, write_combined) {
+        Ok(pinned) => {
+            if write_combined {
+                log::info!(
+                    "Staging writes through {} bytes of write-combined memory",
+                    len
+                );
+            }
+            Some(Arc::new(pinned))
+        }
+        Err(e) => {
+            log::warn!("{:#}; transferring without pinned staging", e);
+            None
+        }
+    }
+}
+
+/// The part of a transfer that falls in one sub-buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Piece {
+    /// Index of the sub-buffer
+    buffer: usize,
+    /// Offset within the sub-buffer
+    offset: usize,
+    /// Offset within the transfer
+    data_offset: usize,
+    len: usize,
+}
+
+/// Whether a failed transfer may succeed if retried: the device or host
+/// was momentarily short of resources. Anything else (invalid arguments, a
+/// lost context) fails the same way again, so it is returned at once.
+fn is_transient(e: &anyhow::Error) -> bool {
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<ClError>())
+        .is_some_and(|e| {
+            matches!(
+                e.0,
+                CL_OUT_OF_RESOURCES | CL_OUT_OF_HOST_MEMORY | CL_MEM_OBJECT_ALLOCATION_FAILURE
+            )
+        })
+}
+
+/// Whether a failed transfer means the context is gone, as after a driver
+/// reset, so no transfer will succeed again
+fn is_context_lost(e: &anyhow::Error) -> bool {
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<ClError>())
+        .is_some_and(|e| {
+            matches!(
+                e.0,
+                CL_DEVICE_NOT_AVAILABLE | CL_INVALID_CONTEXT | CL_INVALID_COMMAND_QUEUE
+            )
+        })
+}
+
+/// Error for a failed `len`-byte allocation at `start` of a `requested`-byte
+/// buffer; out-of-memory failures carry the device limits, so it is clear
+/// whether the total or the single-allocation limit was hit
+fn allocation_error(
+    device: &Device,
+    requested: usize,
+    len: usize,
+    start: usize,
+    e: ClError,
+) -> anyhow::Error {
+    let out_of_memory = e.0 == CL_MEM_OBJECT_ALLOCATION_FAILURE || e.0 == CL_INVALID_BUFFER_SIZE;
+    let error = anyhow::Error::new(e).context(format!(
+        "Failed to allocate GPU memory ({} bytes at offset {})",
+        len, start
+    ));
+    if !out_of_memory {
+        return error;
+    }
+    let limit = |value: Result<u64, ClError>| {
+        value.map_or_else(|_| "unknown".to_string(), |v| format!("{} bytes", v))
+    };
+    error.context(format!(
+        "Not enough GPU memory for {} bytes: device has {} of global memory and \
+         a maximum single allocation of {}",
+        requested,
+        limit(device.global_mem_size()),
+        limit(device.max_mem_alloc_size())
+    ))
+}
+
+/// Split `len` bytes at `offset` at the boundaries of `sub_buffer_size`
+/// byte sub-buffers
+fn split_range(sub_buffer_size: usize, offset: usize, len: usize) -> impl Iterator<Item = Piece> {
+    let mut data_offset = 0;
+    std::iter::from_fn(move || {
+        if data_offset == len {
+            return None;
+        }
+        let at = offset + data_offset;
+        let within = at % sub_buffer_size;
+        let piece = Piece {
+            buffer: at / sub_buffer_size,
+            offset: within,
+            data_offset,
+            len: (len - data_offset).min(sub_buffer_size - within),
+        };
+        data_offset += piece.len;
+        Some(piece)
+    })
+}
+
 impl Drop for VRamBuffer {
     fn drop(&mut self) {
         log::debug!("Freeing GPU memory buffer");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUB: usize = 4096;
+
+    fn split(offset: usize, len: usize) -> Vec<Piece> {
+        split_range(SUB, offset, len).collect()
+    }
+
+    #[test]
+    fn range_inside_one_sub_buffer() {
+        assert_eq!(
+            split(SUB + 100, 200),
+            [Piece {
+                buffer: 1,
+                offset: 100,
+                data_offset: 0,
+                len: 200
+            }]
+        );
+    }
+
+    #[test]
+    fn range_ending_on_a_boundary() {
+        assert_eq!(
+            split(SUB - 100, 100),
+            [Piece {
+                buffer: 0,
+                offset: SUB - 100,
+                data_offset: 0,
+                len: 100
+            }]
+        );
+        assert_eq!(
+            split(SUB, SUB),
+            [Piece {
+                buffer: 1,
+                offset: 0,
+                data_offset: 0,
+                len: SUB
+            }]
+        );
+    }
+
+    #[test]
+    fn range_crossing_a_boundary() {
+        assert_eq!(
+            split(SUB - 100, 300),
+            [
+                Piece {
+                    buffer: 0,
+                    offset: SUB - 100,
+                    data_offset: 0,
+                    len: 100
+                },
+                Piece {
+                    buffer: 1,
+                    offset: 0,
+                    data_offset: 100,
+                    len: 200
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn range_spanning_three_sub_buffers() {
+        let pieces = split(SUB - 10, SUB + 20);
+        assert_eq!(
+            pieces,
+            [
+                Piece {
+                    buffer: 0,
+                    offset: SUB - 10,
+                    data_offset: 0,
+                    len: 10
+                },
+                Piece {
+                    buffer: 1,
+                    offset: 0,
+                    data_offset: 10,
+                    len: SUB
+                },
+                Piece {
+                    buffer: 2,
+                    offset: 0,
+                    data_offset: SUB + 10,
+                    len: 10
+                },
+            ]
+        );
+        // The pieces reassemble the range without gaps
+        let mut data_offset = 0;
+        for piece in &pieces {
+            assert_eq!(piece.data_offset, data_offset);
+            assert_eq!(piece.buffer * SUB + piece.offset, SUB - 10 + data_offset);
+            data_offset += piece.len;
+        }
+        assert_eq!(data_offset, SUB + 20);
+    }
+
+    #[test]
+    fn empty_range_has_no_pieces() {
+        assert!(split(SUB, 0).is_empty());
+    }
+}
@@ -3,6 +3,17 @@
 //! This module handles interaction with the GPU via OpenCL,
 //! including device selection, memory allocation, and data transfer.
 
+mod device;
 mod memory;
+mod pinned;
+mod platform;
+mod queue;
+mod staging;
 
-pub use memory::{VRamBuffer, VRamBufferConfig};
+pub use device::{
+    auto_select_device, device_available_memory, device_free_memory, device_pci_address,
+    find_device_by_name,
+};
+pub use memory::{contexts_in_use, VRamBuffer, VRamBufferConfig};
+pub use platform::{platforms, OpenClUnavailable};
+pub use queue::{set_queue_affinity, QueueLayout, QueueTopology};
@@ -0,0 +1,122 @@
+//! Pinned host staging buffers
+//!
+//! Transfers from ordinary (pageable) host memory are bounced by the driver
+//! through its own page-locked buffer. A buffer created with
+//! `CL_MEM_ALLOC_HOST_PTR` and kept mapped gives us page-locked memory the
+//! GPU can DMA to and from directly, so transfers copy through it instead.
+//! There is one for reads and one for writes (shared with a single queue
+//! layout), guarded by a lock; a transfer that finds it busy, or is larger
+//! than it, goes the unpinned way rather than wait.
+//!
+//! With `--write-combine` the write buffer is allocated host-write-only,
+//! which drivers such as AMD's back with uncached write-combining memory.
+//! The CPU's stores to it are gathered into full bursts instead of going
+//! through the cache, which speeds up the copy in. Reading it back from
+//! the CPU, however, is very slow. The write path only ever copies into it,
+//! and it is never shared with reads.
+
+use anyhow::{Context, Result};
+use opencl3::{
+    command_queue::CommandQueue,
+    context::Context as ClContext,
+    memory::{
+        Buffer, ClMem, CL_MAP_READ, CL_MAP_WRITE, CL_MAP_WRITE_INVALIDATE_REGION,
+        CL_MEM_ALLOC_HOST_PTR, CL_MEM_HOST_WRITE_ONLY, CL_MEM_READ_ONLY, CL_MEM_READ_WRITE,
+    },
+    types::CL_TRUE,
+};
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A page-locked buffer, mapped for its whole lifetime
+struct Mapping {
+    buffer: Buffer<u8>,
+    queue: Arc<CommandQueue>,
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping belongs to the buffer and is only touched under its lock
+unsafe impl Send for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let unmapped = unsafe {
+            self.queue
+                .enqueue_unmap_mem_object(self.buffer.get(), self.ptr.cast(), &[])
+        };
+        match unmapped.and_then(|event| event.wait()) {
+            Ok(()) => {}
+            Err(e) => log::warn!("Failed to unmap pinned staging buffer: {}", e),
+        }
+    }
+}
+
+/// Pinned staging buffer for transfers in one direction
+pub struct PinnedStaging {
+    mapping: Mutex<Mapping>,
+}
+
+impl PinnedStaging {
+    /// Allocate `len` bytes of pinned memory and map it through `queue`.
+    /// A `write_combined` buffer is only for writes: the host must never
+    /// read from it.
+    pub fn new(
+        context: &ClContext,
+        queue: &Arc<CommandQueue>,
+        len: usize,
+        write_combined: bool,
+    ) -> Result<Self> {
+        let (flags, map_flags) = if write_combined {
+            (
+                CL_MEM_READ_ONLY | CL_MEM_HOST_WRITE_ONLY | CL_MEM_ALLOC_HOST_PTR,
+                CL_MAP_WRITE_INVALIDATE_REGION,
+            )
+        } else {
+            (
+                CL_MEM_READ_WRITE | CL_MEM_ALLOC_HOST_PTR,
+                CL_MAP_READ | CL_MAP_WRITE,
+            )
+        };
+        let buffer = unsafe { Buffer::<u8>::create(context, flags, len, ptr::null_mut()) }
+            .context("Failed to allocate pinned staging buffer")?;
+        let mut mapped = ptr::null_mut();
+        unsafe { queue.enqueue_map_buffer(&buffer, CL_TRUE, map_flags, 0, len, &mut mapped, &[]) }
+            .context("Failed to map pinned staging buffer")?;
+        Ok(Self {
+            mapping: Mutex::new(Mapping {
+                buffer,
+                queue: queue.clone(),
+                ptr: mapped.cast(),
+                len,
+            }),
+        })
+    }
+
+    /// The first `len` bytes of the staging buffer, if it is free and large enough
+    pub fn try_get(&self, len: usize) -> Option<PinnedSlice<'_>> {
+        let mapping = self.mapping.try_lock().ok()?;
+        (len <= mapping.len).then_some(PinnedSlice { mapping, len })
+    }
+}
+
+/// Exclusive use of part of a pinned staging buffer
+pub struct PinnedSlice<'a> {
+    mapping: MutexGuard<'a, Mapping>,
+    len: usize,
+}
+
+impl Deref for PinnedSlice<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.mapping.ptr, self.len) }
+    }
+}
+
+impl DerefMut for PinnedSlice<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.mapping.ptr, self.len) }
+    }
+}
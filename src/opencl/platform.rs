@@ -0,0 +1,78 @@
+//! OpenCL platform enumeration with actionable errors
+//!
+//! A missing or broken OpenCL runtime surfaces as an opaque error code from
+//! the ICD loader. The helpers here turn the common cases into an
+//! `OpenClUnavailable` error with installation hints, which `main` maps to a
+//! dedicated exit code.
+
+use anyhow::{Context, Result};
+use opencl3::{
+    device::CL_DEVICE_TYPE_GPU,
+    error_codes::{ClError, CL_PLATFORM_NOT_FOUND_KHR},
+    platform::{get_platforms, Platform},
+    types::cl_device_id,
+};
+use std::fmt;
+
+const INSTALL_HINT: &str = "install an OpenCL ICD such as mesa-opencl-icd, \
+    rocm-opencl-runtime or intel-opencl-icd, or the vendor GPU driver, and check \
+    that /etc/OpenCL/vendors lists it";
+
+/// No usable OpenCL runtime or GPU device was found
+#[derive(Debug)]
+pub struct OpenClUnavailable {
+    reason: String,
+    hint: String,
+}
+
+impl OpenClUnavailable {
+    fn new(reason: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            reason: reason.into(),
+            hint: hint.into(),
+        }
+    }
+}
+
+impl fmt::Display for OpenClUnavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (hint: {})", self.reason, self.hint)
+    }
+}
+
+impl std::error::Error for OpenClUnavailable {}
+
+/// Enumerate OpenCL platforms, failing with `OpenClUnavailable` when there
+/// are none or no ICD is installed.
+pub fn platforms() -> Result<Vec<Platform>> {
+    match get_platforms() {
+        Ok(platforms) if platforms.is_empty() => {
+            Err(OpenClUnavailable::new("No OpenCL platforms found", INSTALL_HINT).into())
+        }
+        Ok(platforms) => Ok(platforms),
+        Err(ClError(CL_PLATFORM_NOT_FOUND_KHR)) => Err(OpenClUnavailable::new(
+            "No OpenCL ICD loaders found (CL_PLATFORM_NOT_FOUND_KHR)",
+            INSTALL_HINT,
+        )
+        .into()),
+        Err(e) => Err(e).context("Failed to get OpenCL platforms"),
+    }
+}
+
+/// Enumerate the GPU devices of a platform, failing with `OpenClUnavailable`
+/// when it has none.
+pub fn gpu_devices(platform: &Platform, platform_index: usize) -> Result<Vec<cl_device_id>> {
+    let device_ids = platform
+        .get_devices(CL_DEVICE_TYPE_GPU)
+        .with_context(|| format!("Failed to get devices for platform {}", platform_index))?;
+
+    if device_ids.is_empty() {
+        return Err(OpenClUnavailable::new(
+            format!("No GPU devices found for platform {}", platform_index),
+            "the platform only exposes non-GPU devices; select another one with \
+             --platform (see --list-devices) or install the GPU vendor's OpenCL runtime",
+        )
+        .into());
+    }
+    Ok(device_ids)
+}
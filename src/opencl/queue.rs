@@ -0,0 +1,236 @@
+//! Command queue topology
+//!
+//! GPUs with independent copy engines can run a host-to-device and a
+//! device-to-host transfer at the same time, but only if the two transfers
+//! are submitted on different command queues. This module probes what the
+//! device supports and creates the read/write queues accordingly.
+//!
+//! Concurrent requests (ublk queues, NBD clients) would still serialize in a
+//! single driver queue, so several read/write queue pairs can be created.
+//! A thread that has called `set_queue_affinity` (each ublk queue thread,
+//! with its queue id) always uses the same pair; other transfers take the
+//! next pair in turn.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use opencl3::{
+    command_queue::{self as cl_command_queue, CommandQueue},
+    context::Context as ClContext,
+    device::Device,
+};
+use std::cell::Cell;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use super::device::{amd_async_queues, nv_gpu_overlap};
+
+/// How transfers are distributed over OpenCL command queues
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum QueueLayout {
+    /// Pick a layout based on the probed device capabilities
+    Auto,
+    /// One in-order queue shared by reads and writes
+    Single,
+    /// Separate in-order queues for reads and writes
+    Split,
+    /// Separate out-of-order queues for reads and writes
+    SplitOutOfOrder,
+}
+
+impl fmt::Display for QueueLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            QueueLayout::Auto => "auto",
+            QueueLayout::Single => "single",
+            QueueLayout::Split => "split",
+            QueueLayout::SplitOutOfOrder => "split-out-of-order",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Queue-related capabilities of a device
+#[derive(Debug, Clone)]
+pub struct QueueTopology {
+    /// Host queues can be created with out-of-order execution
+    pub out_of_order: bool,
+    /// Asynchronous DMA queues reported by AMD drivers
+    pub amd_async_queues: Option<u32>,
+    /// Transfer overlap reported by NVIDIA drivers
+    pub nv_gpu_overlap: Option<bool>,
+}
+
+impl QueueTopology {
+    /// Query the queue capabilities of a device
+    pub fn probe(device: &Device) -> Self {
+        let out_of_order = device
+            .queue_on_host_properties()
+            .map(|props| props & cl_command_queue::CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE != 0)
+            .unwrap_or(false);
+
+        Self {
+            out_of_order,
+            amd_async_queues: amd_async_queues(device),
+            nv_gpu_overlap: nv_gpu_overlap(device),
+        }
+    }
+
+    /// Whether the device reports engines that can move data in both
+    /// directions at the same time
+    pub fn has_independent_copy_engines(&self) -> bool {
+        self.amd_async_queues.is_some_and(|n| n >= 2) || self.nv_gpu_overlap == Some(true)
+    }
+
+    /// Layout used for `QueueLayout::Auto` on this device
+    pub fn recommended_layout(&self) -> QueueLayout {
+        if !self.has_independent_copy_engines() {
+            QueueLayout::Single
+        } else if self.out_of_order {
+            QueueLayout::SplitOutOfOrder
+        } else {
+            QueueLayout::Split
+        }
+    }
+
+    /// Resolve a requested layout against the device capabilities
+    pub fn resolve(&self, requested: QueueLayout) -> QueueLayout {
+        match requested {
+            QueueLayout::Auto => self.recommended_layout(),
+            QueueLayout::SplitOutOfOrder if !self.out_of_order => {
+                log::warn!(
+                    "Device does not support out-of-order queues; using in-order split queues"
+                );
+                QueueLayout::Split
+            }
+            layout => layout,
+        }
+    }
+}
+
+impl fmt::Display for QueueTopology {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "out-of-order: {}",
+            if self.out_of_order { "yes" } else { "no" }
+        )?;
+        if let Some(n) = self.amd_async_queues {
+            write!(f, ", async queues: {}", n)?;
+        }
+        if let Some(overlap) = self.nv_gpu_overlap {
+            write!(f, ", gpu overlap: {}", if overlap { "yes" } else { "no" })?;
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    /// Queue pair index preferred by the current thread
+    static AFFINITY: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Submit this thread's transfers on queue pair `index` (modulo the number
+/// of pairs) instead of taking pairs in turn
+pub fn set_queue_affinity(index: usize) {
+    AFFINITY.with(|affinity| affinity.set(Some(index)));
+}
+
+/// A read and a write command queue
+pub struct QueuePair {
+    /// Queue used for device-to-host transfers
+    pub read: Arc<CommandQueue>,
+    /// Queue used for host-to-device transfers (same as `read` for `Single`)
+    pub write: Arc<CommandQueue>,
+}
+
+/// Command queues used for transfers, routed by direction
+pub struct TransferQueues {
+    /// Queue pairs; transfers use them round-robin unless the thread has
+    /// an affinity
+    pub pairs: Vec<QueuePair>,
+    next: AtomicUsize,
+    /// Layout the queues were created with (never `Auto`)
+    pub layout: QueueLayout,
+}
+
+impl TransferQueues {
+    /// Create `count` queue pairs for `requested` on the given device
+    pub fn new(
+        context: &ClContext,
+        device: &Device,
+        requested: QueueLayout,
+        count: usize,
+    ) -> Result<Self> {
+        let topology = QueueTopology::probe(device);
+        let layout = match topology.resolve(requested) {
+            QueueLayout::Auto => QueueLayout::Single,
+            layout => layout,
+        };
+
+        let mut pairs = Vec::with_capacity(count.max(1));
+        for _ in 0..count.max(1) {
+            pairs.push(match layout {
+                QueueLayout::Split | QueueLayout::SplitOutOfOrder => {
+                    let out_of_order = layout == QueueLayout::SplitOutOfOrder;
+                    QueuePair {
+                        read: Arc::new(create_queue(context, device, out_of_order)?),
+                        write: Arc::new(create_queue(context, device, out_of_order)?),
+                    }
+                }
+                QueueLayout::Single | QueueLayout::Auto => {
+                    let queue = Arc::new(create_queue(context, device, false)?);
+                    QueuePair {
+                        read: queue.clone(),
+                        write: queue,
+                    }
+                }
+            });
+        }
+
+        log::info!(
+            "Using '{}' queue layout with {} queue pair(s) (requested: {}, {})",
+            layout,
+            pairs.len(),
+            requested,
+            topology
+        );
+        Ok(Self {
+            pairs,
+            next: AtomicUsize::new(0),
+            layout,
+        })
+    }
+
+    /// Queues for the next transfer: the thread's own pair, if it has one
+    pub fn next(&self) -> &QueuePair {
+        let i = AFFINITY
+            .with(Cell::get)
+            .unwrap_or_else(|| self.next.fetch_add(1, Ordering::Relaxed));
+        &self.pairs[i % self.pairs.len()]
+    }
+
+    /// Block until every command submitted on the queues has completed
+    pub fn finish(&self) -> Result<()> {
+        for pair in &self.pairs {
+            pair.write
+                .finish()
+                .context("Failed to finish write queue")?;
+            if !Arc::ptr_eq(&pair.read, &pair.write) {
+                pair.read.finish().context("Failed to finish read queue")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn create_queue(context: &ClContext, device: &Device, out_of_order: bool) -> Result<CommandQueue> {
+    let mut properties = cl_command_queue::CL_QUEUE_PROFILING_ENABLE;
+    if out_of_order {
+        properties |= cl_command_queue::CL_QUEUE_OUT_OF_ORDER_EXEC_MODE_ENABLE;
+    }
+    unsafe {
+        CommandQueue::create_with_properties(context, device.id(), properties, 0)
+            .context("Failed to create command queue")
+    }
+}
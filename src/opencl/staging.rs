@@ -0,0 +1,164 @@
+//! Aligned host staging buffers
+//!
+//! Some OpenCL implementations fall back to slow paths, or fail outright,
+//! when the host pointer of a transfer is not aligned to the device's
+//! `CL_DEVICE_MEM_BASE_ADDR_ALIGN`. NBD and ublk hand us arbitrary client
+//! buffers, so misaligned transfers are bounced through an aligned staging
+//! buffer. Staging buffers are pooled and, like all process memory, locked
+//! by `mlockall`.
+
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::Mutex;
+
+/// Staging buffers kept around for reuse
+const MAX_POOLED: usize = 4;
+
+/// Whether `ptr` satisfies `align` (a power of two)
+pub fn is_aligned(ptr: *const u8, align: usize) -> bool {
+    (ptr as usize).is_multiple_of(align)
+}
+
+/// Whether a transfer from or to `data` has to go through a staging buffer
+/// to meet `align`
+pub fn needs_bounce(data: &[u8], align: usize) -> bool {
+    !is_aligned(data.as_ptr(), align)
+}
+
+/// Zeroed heap allocation with a fixed alignment
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    layout: Layout,
+}
+
+// The buffer is plain owned memory
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new(len: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(len.max(1), align).expect("invalid staging layout");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, layout }
+    }
+
+    fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
+}
+
+/// Pool of aligned staging buffers
+pub struct StagingPool {
+    align: usize,
+    free: Mutex<Vec<AlignedBuffer>>,
+}
+
+impl StagingPool {
+    /// `align` must be a power of two
+    pub fn new(align: usize) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        Self {
+            align,
+            free: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Alignment host pointers need for a direct transfer
+    pub fn alignment(&self) -> usize {
+        self.align
+    }
+
+    /// Borrow an aligned buffer of `len` bytes
+    pub fn get(&self, len: usize) -> Staged<'_> {
+        let pooled = self.free.lock().ok().and_then(|mut free| {
+            let i = free.iter().position(|b| b.capacity() >= len)?;
+            Some(free.swap_remove(i))
+        });
+        Staged {
+            pool: self,
+            buffer: Some(pooled.unwrap_or_else(|| AlignedBuffer::new(len, self.align))),
+            len,
+        }
+    }
+}
+
+/// Staging buffer on loan from a `StagingPool`; returned on drop
+pub struct Staged<'a> {
+    pool: &'a StagingPool,
+    buffer: Option<AlignedBuffer>,
+    len: usize,
+}
+
+impl Deref for Staged<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let buffer = self
+            .buffer
+            .as_ref()
+            .expect("staging buffer present until drop");
+        unsafe { std::slice::from_raw_parts(buffer.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for Staged<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        let buffer = self
+            .buffer
+            .as_mut()
+            .expect("staging buffer present until drop");
+        unsafe { std::slice::from_raw_parts_mut(buffer.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for Staged<'_> {
+    fn drop(&mut self) {
+        if let (Some(buffer), Ok(mut free)) = (self.buffer.take(), self.pool.free.lock())
+            && free.len() < MAX_POOLED
+        {
+            free.push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALIGN: usize = 4096;
+
+    #[test]
+    fn misaligned_slices_are_bounced() {
+        let pool = StagingPool::new(ALIGN);
+        let buffer = pool.get(2 * ALIGN);
+        assert!(!needs_bounce(&buffer, ALIGN));
+        assert!(!needs_bounce(&buffer[ALIGN..], ALIGN));
+        // One byte off an aligned allocation
+        assert!(needs_bounce(&buffer[1..], ALIGN));
+        assert!(needs_bounce(&buffer[1..ALIGN + 1], ALIGN));
+        assert!(needs_bounce(&buffer[ALIGN - 1..], ALIGN));
+        // Byte alignment never bounces
+        assert!(!needs_bounce(&buffer[1..], 1));
+    }
+
+    #[test]
+    fn staged_buffers_are_aligned() {
+        let pool = StagingPool::new(ALIGN);
+        for len in [1, 511, ALIGN, 3 * ALIGN + 1] {
+            let staged = pool.get(len);
+            assert_eq!(staged.len(), len);
+            assert!(!needs_bounce(&staged, pool.alignment()));
+        }
+        // Reused from the pool
+        let staged = pool.get(7);
+        assert_eq!(staged.len(), 7);
+        assert!(!needs_bounce(&staged, pool.alignment()));
+    }
+}
@@ -0,0 +1,167 @@
+//! Shared retry/backoff policy
+//!
+//! Every component that retries failed operations (GPU transfers, persistent
+//! backends, ...) goes through `RetryPolicy` so retry behavior is configured
+//! in one place.
+
+use anyhow::Result;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Exponential backoff retry policy
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one (1 = no retries)
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Factor the delay is multiplied by after each retry
+    pub multiplier: f64,
+    /// Longest delay between attempts, jitter included
+    pub max_delay: Duration,
+    /// Random jitter as a fraction of the delay (0.0 - 1.0), applied +/-
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(1),
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff delay before retry number `retry` (1-based), without jitter.
+    pub fn delay_for(&self, retry: u32) -> Duration {
+        if retry == 0 {
+            return Duration::ZERO;
+        }
+        let exponent = (retry - 1).min(i32::MAX as u32) as i32;
+        let factor = self.multiplier.max(1.0).powi(exponent);
+        // Past the cap, the factor may no longer fit a Duration
+        let max_factor = self.max_delay.as_secs_f64() / self.base_delay.as_secs_f64();
+        if factor >= max_factor {
+            return self.max_delay;
+        }
+        self.base_delay.mul_f64(factor)
+    }
+
+    /// Backoff delay before retry number `retry` with jitter applied.
+    pub fn jittered_delay_for(&self, retry: u32) -> Duration {
+        let delay = self.delay_for(retry);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        // Uniform in [-jitter, +jitter]
+        let unit = (random_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        delay
+            .mul_f64(1.0 + jitter * (2.0 * unit - 1.0))
+            .min(self.max_delay)
+    }
+
+    /// Run `op` until it succeeds, fails with an error `transient` doesn't
+    /// accept, or the attempts are exhausted, sleeping the backoff delay
+    /// between attempts. Returns the last error on failure.
+    pub fn run<T, F, P>(&self, what: &str, transient: P, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+        P: Fn(&anyhow::Error) -> bool,
+    {
+        let attempts = self.max_attempts.max(1);
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Ok(v) => return Ok(v),
+                Err(e) if attempt < attempts && transient(&e) => {
+                    let delay = self.jittered_delay_for(attempt);
+                    log::warn!(
+                        "{} failed (attempt {}/{}): {:#}; retrying in {:?}",
+                        what,
+                        attempt,
+                        attempts,
+                        e,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Cheap random number from std's randomly keyed hasher (no extra dependency)
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(10),
+            multiplier: 2.0,
+            max_delay: Duration::from_millis(100),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn delay_grows_exponentially() {
+        let policy = backoff(0.0);
+        assert_eq!(policy.delay_for(1), Duration::from_millis(10));
+        assert_eq!(policy.delay_for(2), Duration::from_millis(20));
+        assert_eq!(policy.delay_for(3), Duration::from_millis(40));
+        assert_eq!(policy.delay_for(4), Duration::from_millis(80));
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let policy = backoff(0.0);
+        assert_eq!(policy.delay_for(5), Duration::from_millis(100));
+        assert_eq!(policy.delay_for(60), Duration::from_millis(100));
+        assert_eq!(policy.delay_for(u32::MAX), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn retry_zero_has_no_delay() {
+        assert_eq!(backoff(0.0).delay_for(0), Duration::ZERO);
+        assert_eq!(backoff(0.5).jittered_delay_for(0), Duration::ZERO);
+    }
+
+    #[test]
+    fn multiplier_below_one_keeps_the_base_delay() {
+        let policy = RetryPolicy {
+            multiplier: 0.5,
+            ..backoff(0.0)
+        };
+        assert_eq!(policy.delay_for(3), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let policy = backoff(0.2);
+        for _ in 0..1000 {
+            let delay = policy.jittered_delay_for(2);
+            assert!(delay >= Duration::from_millis(16), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(24), "{:?}", delay);
+            // Jitter never takes a delay past the cap
+            assert!(policy.jittered_delay_for(4) <= Duration::from_millis(100));
+        }
+        assert_eq!(
+            backoff(0.0).jittered_delay_for(2),
+            Duration::from_millis(20)
+        );
+    }
+}
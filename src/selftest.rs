@@ -0,0 +1,164 @@
+//! Startup memory test of the device buffer
+//!
+//! With `--self-test`, the freshly allocated buffer is tested before it is
+//! served: each pass writes a pattern over the whole buffer through the
+//! `BlockBackend` interface and then reads all of it back, so a write that
+//! lands at the wrong address shows up as well as a flipped bit. The buffer
+//! is moved in chunks, so host memory use stays at two chunks whatever the
+//! device size. Every 4 KiB block that reads back wrong is logged with its
+//! offset. Afterwards the buffer is zeroed again, since the layers above
+//! expect a fresh allocation to read as zeros.
+
+use crate::backend::BlockBackend;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::fmt;
+use std::time::Instant;
+
+/// Bytes written or read per transfer
+const TEST_CHUNK: u64 = 8 * 1024 * 1024;
+
+/// Granularity at which mismatches are reported
+const REPORT_BLOCK: usize = 4096;
+
+/// Data written by the self-test
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TestPattern {
+    /// Each 8-byte word holds its own offset, inverted on every other pass;
+    /// catches writes that land at the wrong address
+    Address,
+    /// Alternating bits: 0xaa on the first pass, 0x55 on the second, and so on
+    Checkerboard,
+    /// Pseudo-random data, different on every pass
+    Random,
+}
+
+impl fmt::Display for TestPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TestPattern::Address => "address",
+            TestPattern::Checkerboard => "checkerboard",
+            TestPattern::Random => "random",
+        })
+    }
+}
+
+impl TestPattern {
+    /// The word at index `word` (offset / 8) on pass `pass`, counted from 0
+    fn word(self, pass: u32, word: u64) -> u64 {
+        match self {
+            TestPattern::Address if pass.is_multiple_of(2) => word * 8,
+            TestPattern::Address => !(word * 8),
+            TestPattern::Checkerboard if pass.is_multiple_of(2) => 0xaaaa_aaaa_aaaa_aaaa,
+            TestPattern::Checkerboard => 0x5555_5555_5555_5555,
+            TestPattern::Random => splitmix64(word ^ (u64::from(pass) << 56)),
+        }
+    }
+
+    /// Fill `buf`, which sits at the 8-byte aligned `offset`
+    fn fill(self, pass: u32, offset: u64, buf: &mut [u8]) {
+        for (i, chunk) in buf.chunks_mut(8).enumerate() {
+            let word = self.word(pass, offset / 8 + i as u64);
+            chunk.copy_from_slice(&word.to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+/// Parameters of the self-test
+#[derive(Debug, Clone, Copy)]
+pub struct SelfTestConfig {
+    pub pattern: TestPattern,
+    /// Write-then-verify rounds over the whole buffer
+    pub passes: u32,
+    /// Bad blocks tolerated before the test fails
+    pub max_errors: u64,
+}
+
+/// Test every byte of `backend`, which must not hold data yet, and leave
+/// it zeroed. Fails if more than `max_errors` blocks read back wrong.
+pub fn run_self_test(backend: &dyn BlockBackend, config: &SelfTestConfig) -> Result<()> {
+    let size = backend.size();
+    log::info!(
+        "Self-test: {} pass(es) of the {} pattern over {} bytes",
+        config.passes,
+        config.pattern,
+        size
+    );
+    let mut expected = vec![0u8; TEST_CHUNK.min(size) as usize];
+    let mut actual = vec![0u8; expected.len()];
+    let mut bad_blocks = 0;
+
+    for pass in 0..config.passes {
+        let start = Instant::now();
+        let mut offset = 0;
+        while offset < size {
+            let len = TEST_CHUNK.min(size - offset) as usize;
+            config.pattern.fill(pass, offset, &mut expected[..len]);
+            backend
+                .write_at(offset, &expected[..len])
+                .with_context(|| format!("Self-test failed to write at offset {}", offset))?;
+            offset += len as u64;
+        }
+        backend.flush()?;
+
+        let mut offset = 0;
+        while offset < size {
+            let len = TEST_CHUNK.min(size - offset) as usize;
+            config.pattern.fill(pass, offset, &mut expected[..len]);
+            backend
+                .read_at(offset, &mut actual[..len])
+                .with_context(|| format!("Self-test failed to read at offset {}", offset))?;
+            let blocks = expected[..len]
+                .chunks(REPORT_BLOCK)
+                .zip(actual[..len].chunks(REPORT_BLOCK));
+            for (i, (want, got)) in blocks.enumerate() {
+                if want == got {
+                    continue;
+                }
+                let differ = want.iter().zip(got).filter(|(a, b)| a != b).count();
+                log::error!(
+                    "Self-test pass {}: block at offset {} read back wrong ({} of {} bytes differ)",
+                    pass + 1,
+                    offset + (i * REPORT_BLOCK) as u64,
+                    differ,
+                    want.len()
+                );
+                bad_blocks += 1;
+                if bad_blocks > config.max_errors {
+                    bail!(
+                        "Self-test found more than {} bad block(s); not serving this device",
+                        config.max_errors
+                    );
+                }
+            }
+            offset += len as u64;
+        }
+        log::info!(
+            "Self-test pass {}/{} done in {:.1}s",
+            pass + 1,
+            config.passes,
+            start.elapsed().as_secs_f64()
+        );
+    }
+
+    if bad_blocks > 0 {
+        log::warn!(
+            "Self-test found {} bad block(s), within --self-test-max-errors",
+            bad_blocks
+        );
+    } else {
+        log::info!("Self-test passed");
+    }
+    backend
+        .write_zeroes_at(0, size)
+        .context("Failed to zero the device after the self-test")?;
+    backend.flush()
+}
+
+/// The splitmix64 mix of `x`; spreads nearby inputs over all bits
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
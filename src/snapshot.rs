@@ -0,0 +1,338 @@
+//! Periodic snapshots of the device to host files
+//!
+//! Every `interval`, and whenever one is requested over the control socket,
+//! the whole device is read through the `BlockBackend` interface and
+//! written to one of two files, `<base>.0` and `<base>.1`, alternating so
+//! the previous snapshot stays intact while the next one is written. A
+//! snapshot file is truncated before it is rewritten, so a torn snapshot is
+//! shorter than the device; a complete one is exactly its size. At startup a
+//! slot without a complete snapshot is overwritten first, so a snapshot torn
+//! by a crash never costs the good one next to it. Chunks the
+//! backend knows to be zeros (with `--track-allocation`) are left as holes,
+//! so snapshots of a sparsely used device are sparse files.
+//!
+//! With `--snapshot-compress zstd`, snapshots are written as a single zstd
+//! frame instead, recording the device size and a checksum of the contents,
+//! so a torn snapshot fails to load rather than loading short. Chunks known
+//! to be zeros are compressed without being read from the device.
+//!
+//! Clients keep writing while a snapshot is taken, so a snapshot is not a
+//! point-in-time image: each chunk is as of the moment it was read.
+
+use anyhow::{anyhow, Context, Result};
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{Interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use crate::backend::BlockBackend;
+use clap::ValueEnum;
+
+/// Bytes read from the backend per transfer
+const SNAPSHOT_CHUNK: u64 = 64 * 1024 * 1024;
+
+/// zstd level used for compressed snapshots; favours speed, as the
+/// device is read at GPU speeds
+const ZSTD_LEVEL: i32 = 3;
+
+/// Format of the snapshot files
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SnapshotCompression {
+    /// Raw image of the device, with holes where it is known to be zeros
+    None,
+    /// zstd-compressed image
+    Zstd,
+}
+
+/// A request for a snapshot now, answered with the file written
+pub type SnapshotRequest = oneshot::Sender<Result<PathBuf>>;
+
+/// Take a snapshot every `interval`, if set, and for each request on
+/// `requests` until `cancel` is cancelled. A snapshot in progress at
+/// cancellation stops at the next chunk.
+pub fn spawn_snapshots(
+    backend: Arc<dyn BlockBackend>,
+    base: PathBuf,
+    interval: Option<Duration>,
+    compression: SnapshotCompression,
+    mut requests: mpsc::Receiver<SnapshotRequest>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let slots = [slot_path(&base, 0), slot_path(&base, 1)];
+        let mut next = {
+            let slots = slots.clone();
+            let size = backend.size();
+            tokio::task::spawn_blocking(move || first_slot(&slots, size))
+                .await
+                .unwrap_or(0)
+        };
+        match interval {
+            Some(interval) => log::info!(
+                "Snapshotting the device every {:?} to {} / {}",
+                interval,
+                slots[0].display(),
+                slots[1].display()
+            ),
+            None => log::info!(
+                "Snapshotting the device on request to {} / {}",
+                slots[0].display(),
+                slots[1].display()
+            ),
+        }
+
+        let mut ticker = interval.map(|interval| {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+        if let Some(ticker) = &mut ticker {
+            // The first tick completes immediately
+            ticker.tick().await;
+        }
+        loop {
+            let request = tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = tick(&mut ticker) => None,
+                Some(request) = requests.recv() => Some(request),
+            };
+            if request.is_some()
+                && let Some(ticker) = &mut ticker
+            {
+                // The next periodic snapshot is due a full interval from now
+                ticker.reset();
+            }
+
+            let path = slots[next].clone();
+            let device = backend.clone();
+            let token = cancel.clone();
+            let result = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || {
+                    write_snapshot(&*device, &path, compression, &token)
+                })
+                .await
+            };
+            let (outcome, interrupted) = match result {
+                Ok(Ok(Some(elapsed))) => {
+                    let size = backend.size();
+                    log::info!(
+                        "Snapshot written to {}: {} MB in {:?} ({:.1} MB/s)",
+                        path.display(),
+                        size / (1024 * 1024),
+                        elapsed,
+                        size as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+                    );
+                    next = 1 - next;
+                    (Ok(path), false)
+                }
+                Ok(Ok(None)) => {
+                    log::warn!(
+                        "Snapshot to {} interrupted by shutdown and is incomplete",
+                        path.display()
+                    );
+                    (Err(anyhow!("Snapshot interrupted by shutdown")), true)
+                }
+                Ok(Err(e)) => {
+                    log::error!("Snapshot to {} failed: {:#}", path.display(), e);
+                    (Err(e), false)
+                }
+                Err(e) => {
+                    log::error!("Snapshot task failed: {}", e);
+                    (Err(anyhow!("Snapshot task failed: {}", e)), false)
+                }
+            };
+            if let Some(request) = request {
+                let _ = request.send(outcome);
+            }
+            if interrupted {
+                break;
+            }
+        }
+    })
+}
+
+/// Wait for the next tick, forever without a ticker
+async fn tick(ticker: &mut Option<Interval>) {
+    match ticker {
+        Some(ticker) => {
+            ticker.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn slot_path(base: &Path, slot: usize) -> PathBuf {
+    let mut path = base.as_os_str().to_owned();
+    path.push(format!(".{}", slot));
+    PathBuf::from(path)
+}
+
+/// Modification time of a snapshot slot, `None` if it doesn't exist yet
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The slot to overwrite first: one without a complete snapshot, or else
+/// the older one, so the newest good snapshot survives a crash during the
+/// first snapshot of this run
+fn first_slot(slots: &[PathBuf; 2], size: u64) -> usize {
+    match (is_complete(&slots[0], size), is_complete(&slots[1], size)) {
+        (true, false) => 1,
+        (false, true) => 0,
+        _ => usize::from(modified(&slots[0]) > modified(&slots[1])),
+    }
+}
+
+/// Whether `path` holds a complete snapshot of a `size`-byte device: a raw
+/// image of exactly that length, or a zstd frame that decodes to it
+fn is_complete(path: &Path, size: u64) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    if file.metadata().is_ok_and(|m| m.len() == size) {
+        return true;
+    }
+    // A torn frame fails to decode; a complete one passes its checksum
+    let Ok(decoder) = zstd::Decoder::new(file) else {
+        return false;
+    };
+    let mut decoder = decoder.single_frame();
+    matches!(std::io::copy(&mut decoder, &mut std::io::sink()), Ok(n) if n == size)
+}
+
+/// Where a snapshot is being written
+enum Output {
+    Raw(File),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+/// Copy the device to `path`. Returns how long it took, or `None` if
+/// `cancel` stopped it part way.
+fn write_snapshot(
+    backend: &dyn BlockBackend,
+    path: &Path,
+    compression: SnapshotCompression,
+    cancel: &CancellationToken,
+) -> Result<Option<Duration>> {
+    let start = Instant::now();
+    // Commit writes still held in memory by wrappers such as write combining
+    backend.flush()?;
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+
+    let size = backend.size();
+    let mut output = match compression {
+        SnapshotCompression::None => Output::Raw(file),
+        SnapshotCompression::Zstd => {
+            let mut encoder = zstd::Encoder::new(file, ZSTD_LEVEL)?;
+            encoder.include_checksum(true)?;
+            encoder.include_contentsize(true)?;
+            encoder.set_pledged_src_size(Some(size))?;
+            Output::Zstd(encoder)
+        }
+    };
+    let mut buf = vec![0u8; SNAPSHOT_CHUNK.min(size) as usize];
+    let mut offset = 0;
+    while offset < size {
+        if cancel.is_cancelled() {
+            return Ok(None);
+        }
+        let len = SNAPSHOT_CHUNK.min(size - offset) as usize;
+        let zero = backend.is_known_zero(offset, len as u64);
+        if zero {
+            buf[..len].fill(0);
+        } else {
+            backend
+                .read_at(offset, &mut buf[..len])
+                .with_context(|| format!("Failed to read device at offset {}", offset))?;
+        }
+        match &mut output {
+            // Leave a hole; the final length fills it with zeros
+            Output::Raw(file) if zero => file.seek(SeekFrom::Current(len as i64)).map(drop),
+            Output::Raw(file) => file.write_all(&buf[..len]),
+            Output::Zstd(encoder) => encoder.write_all(&buf[..len]),
+        }
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+        offset += len as u64;
+    }
+    let file = match output {
+        Output::Raw(file) => {
+            file.set_len(size)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            file
+        }
+        Output::Zstd(encoder) => encoder
+            .finish()
+            .with_context(|| format!("Failed to write {}", path.display()))?,
+    };
+    file.sync_all()
+        .with_context(|| format!("Failed to sync {}", path.display()))?;
+    Ok(Some(start.elapsed()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u64 = 256 * 1024;
+
+    fn slots(name: &str) -> [PathBuf; 2] {
+        let base =
+            std::env::temp_dir().join(format!("vramblk-snapshot-{}-{}", name, std::process::id()));
+        [slot_path(&base, 0), slot_path(&base, 1)]
+    }
+
+    fn zstd_image(len: u64) -> Vec<u8> {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        zstd::encode_all(&data[..], ZSTD_LEVEL).unwrap()
+    }
+
+    #[test]
+    fn torn_raw_snapshot_is_overwritten_first() {
+        let slots = slots("raw");
+        std::fs::write(&slots[1], vec![1u8; SIZE as usize]).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        // The newer file is the torn one
+        std::fs::write(&slots[0], vec![1u8; SIZE as usize / 2]).unwrap();
+        assert_eq!(first_slot(&slots, SIZE), 0);
+
+        std::fs::write(&slots[0], vec![1u8; SIZE as usize]).unwrap();
+        assert_eq!(first_slot(&slots, SIZE), 1, "both complete: the older one");
+        for slot in &slots {
+            std::fs::remove_file(slot).unwrap();
+        }
+    }
+
+    #[test]
+    fn torn_zstd_snapshot_is_overwritten_first() {
+        let slots = slots("zstd");
+        let image = zstd_image(SIZE);
+        std::fs::write(&slots[0], &image).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(&slots[1], &image[..image.len() - 8]).unwrap();
+        assert!(is_complete(&slots[0], SIZE));
+        assert!(!is_complete(&slots[1], SIZE));
+        assert_eq!(first_slot(&slots, SIZE), 1);
+
+        // A frame for a device of another size is no good either
+        std::fs::write(&slots[1], zstd_image(SIZE / 2)).unwrap();
+        assert_eq!(first_slot(&slots, SIZE), 1);
+        for slot in &slots {
+            std::fs::remove_file(slot).unwrap();
+        }
+    }
+
+    #[test]
+    fn missing_slot_is_written_first() {
+        let slots = slots("missing");
+        std::fs::write(&slots[0], vec![0u8; SIZE as usize]).unwrap();
+        assert_eq!(first_slot(&slots, SIZE), 1);
+        std::fs::remove_file(&slots[0]).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+//! systemd socket activation and readiness notification
+//!
+//! Under a `.socket` unit, systemd binds the listening socket and passes it
+//! to the service as file descriptor 3, announcing it through `LISTEN_PID`
+//! and `LISTEN_FDS` (sd_listen_fds(3)). Under `Type=notify`, the service
+//! reports that it is up by sending `READY=1` to the datagram socket named
+//! in `NOTIFY_SOCKET` (sd_notify(3)). Both protocols are spoken directly.
+
+use anyhow::{Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// First descriptor passed by systemd
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Set once the passed socket has been handed out
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// The listening socket systemd passed to this process, if it was socket
+/// activated. Only the first call returns it.
+pub fn listen_socket() -> Result<Option<OwnedFd>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    if !for_us || count < 1 || TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+    if count > 1 {
+        log::warn!("systemd passed {} sockets; only the first is used", count);
+    }
+    // Passed descriptors are inherited as is; keep them from mkfs and mount
+    fcntl(LISTEN_FDS_START, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+        .context("Failed to adopt the socket passed by systemd")?;
+    Ok(Some(unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) }))
+}
+
+/// Tell systemd the service is ready, if it asked to be notified
+pub fn notify_ready() {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = notify(&path, b"READY=1") {
+        log::warn!("Failed to notify systemd of readiness: {:#}", e);
+    }
+}
+
+fn notify(path: &std::ffi::OsStr, message: &[u8]) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    // A leading '@' names a socket in the abstract namespace
+    let addr = match path.as_bytes().split_first() {
+        Some((b'@', name)) => SocketAddr::from_abstract_name(name)?,
+        _ => SocketAddr::from_pathname(path)?,
+    };
+    let socket = UnixDatagram::unbound()?;
+    socket
+        .send_to_addr(message, &addr)
+        .with_context(|| format!("Failed to send to {}", path.to_string_lossy()))?;
+    Ok(())
+}
@@ -0,0 +1,382 @@
+//! I/O trace capture and replay
+//!
+//! `TraceBackend` records every read, write, flush, discard and write-zeroes
+//! the frontends issue (operation, offset, length and time since capture
+//! started) in a compact binary file. `vramblk replay` feeds such a trace to a fresh backend to
+//! reproduce a workload from a bug report.
+//!
+//! Format: the 8-byte magic `VRBTRACE`, a little-endian u32 version, then
+//! fixed-size little-endian records of op (u8), offset (u64), length (u64;
+//! u32 in version 1 traces, which are still read) and timestamp in
+//! microseconds (u64). Data is not captured; replayed writes use a fill
+//! pattern.
+
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::backend::BlockBackend;
+
+const MAGIC: &[u8; 8] = b"VRBTRACE";
+const VERSION: u32 = 2;
+const RECORD_LEN: usize = 25;
+/// Record length of version 1, whose length field is a u32
+const RECORD_LEN_V1: usize = 21;
+
+/// Operation recorded in a trace
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TraceOp {
+    Read,
+    Write,
+    Flush,
+    Discard,
+    WriteZeroes,
+}
+
+impl TraceOp {
+    fn code(self) -> u8 {
+        match self {
+            TraceOp::Read => 0,
+            TraceOp::Write => 1,
+            TraceOp::Flush => 2,
+            TraceOp::Discard => 3,
+            TraceOp::WriteZeroes => 4,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(TraceOp::Read),
+            1 => Some(TraceOp::Write),
+            2 => Some(TraceOp::Flush),
+            3 => Some(TraceOp::Discard),
+            4 => Some(TraceOp::WriteZeroes),
+            _ => None,
+        }
+    }
+}
+
+/// One recorded request
+#[derive(Copy, Clone, Debug)]
+pub struct TraceRecord {
+    pub op: TraceOp,
+    pub offset: u64,
+    pub len: u64,
+    /// Time since capture started
+    pub at: Duration,
+}
+
+impl TraceRecord {
+    fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0u8; RECORD_LEN];
+        buf[0] = self.op.code();
+        buf[1..9].copy_from_slice(&self.offset.to_le_bytes());
+        buf[9..17].copy_from_slice(&self.len.to_le_bytes());
+        buf[17..25].copy_from_slice(&(self.at.as_micros() as u64).to_le_bytes());
+        buf
+    }
+
+    /// Decode a record of either version; `buf` holds exactly one record
+    fn decode(buf: &[u8]) -> Result<Self> {
+        let op = TraceOp::from_code(buf[0])
+            .with_context(|| format!("Unknown trace operation {}", buf[0]))?;
+        let (len, at) = if buf.len() == RECORD_LEN_V1 {
+            (
+                u32::from_le_bytes(buf[9..13].try_into()?) as u64,
+                &buf[13..21],
+            )
+        } else {
+            (u64::from_le_bytes(buf[9..17].try_into()?), &buf[17..25])
+        };
+        Ok(Self {
+            op,
+            offset: u64::from_le_bytes(buf[1..9].try_into()?),
+            len,
+            at: Duration::from_micros(u64::from_le_bytes(at.try_into()?)),
+        })
+    }
+}
+
+/// Backend wrapper that records every request to a trace file
+pub struct TraceBackend<B> {
+    inner: B,
+    out: Mutex<BufWriter<File>>,
+    start: Instant,
+}
+
+impl<B: BlockBackend> TraceBackend<B> {
+    /// Create (or truncate) the trace file at `path`
+    pub fn create(inner: B, path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create trace file {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self {
+            inner,
+            out: Mutex::new(out),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&self, op: TraceOp, offset: u64, len: u64) {
+        let record = TraceRecord {
+            op,
+            offset,
+            len,
+            at: self.start.elapsed(),
+        };
+        if let Ok(mut out) = self.out.lock()
+            && let Err(e) = out.write_all(&record.encode())
+        {
+            log::warn!("Failed to write trace record: {}", e);
+        }
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for TraceBackend<B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+        self.record(TraceOp::Read, offset, dst.len() as u64);
+        self.inner.read_at(offset, dst)
+    }
+
+    fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+        self.record(TraceOp::Write, offset, src.len() as u64);
+        self.inner.write_at(offset, src)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.record(TraceOp::Flush, 0, 0);
+        if let Ok(mut out) = self.out.lock() {
+            out.flush().context("Failed to flush trace file")?;
+        }
+        self.inner.flush()
+    }
+
+    fn write_zeroes_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.record(TraceOp::WriteZeroes, offset, len);
+        self.inner.write_zeroes_at(offset, len)
+    }
+
+    fn fast_zero(&self) -> bool {
+        self.inner.fast_zero()
+    }
+
+    fn discard_at(&self, offset: u64, len: u64) -> Result<()> {
+        self.record(TraceOp::Discard, offset, len);
+        self.inner.discard_at(offset, len)
+    }
+
+    fn is_known_zero(&self, offset: u64, len: u64) -> bool {
+        self.inner.is_known_zero(offset, len)
+    }
+}
+
+impl<B> Drop for TraceBackend<B> {
+    fn drop(&mut self) {
+        if let Ok(mut out) = self.out.lock()
+            && let Err(e) = out.flush()
+        {
+            log::warn!("Failed to flush trace file: {}", e);
+        }
+    }
+}
+
+/// Load all records of a trace file
+pub fn read_trace(path: &Path) -> Result<Vec<TraceRecord>> {
+    let file =
+        File::open(path).with_context(|| format!("Failed to open trace {}", path.display()))?;
+    let mut input = BufReader::new(file);
+
+    let mut header = [0u8; 12];
+    input
+        .read_exact(&mut header)
+        .context("Trace file is too short")?;
+    if &header[..8] != MAGIC {
+        bail!("{} is not a vramblk trace", path.display());
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into()?);
+    let record_len = match version {
+        1 => RECORD_LEN_V1,
+        VERSION => RECORD_LEN,
+        _ => bail!("Unsupported trace version {}", version),
+    };
+
+    let mut records = Vec::new();
+    let mut buf = vec![0u8; record_len];
+    loop {
+        match input.read_exact(&mut buf) {
+            Ok(()) => records.push(TraceRecord::decode(&buf)?),
+            // A capture cut short by a crash may end in a partial record
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context("Failed to read trace"),
+        }
+    }
+    Ok(records)
+}
+
+/// Smallest device size that covers every request in the trace
+pub fn trace_extent(records: &[TraceRecord]) -> u64 {
+    records.iter().map(|r| r.offset + r.len).max().unwrap_or(0)
+}
+
+/// Replay `records` against `backend`, optionally honoring the recorded
+/// timing. Fails if any request failed.
+pub fn replay(records: &[TraceRecord], backend: Arc<dyn BlockBackend>, timing: bool) -> Result<()> {
+    let start = Instant::now();
+    let (mut reads, mut writes, mut flushes, mut errors) = (0u64, 0u64, 0u64, 0u64);
+    let (mut discards, mut zeroes) = (0u64, 0u64);
+    let mut buf = Vec::new();
+
+    for (index, record) in records.iter().enumerate() {
+        if timing && let Some(wait) = record.at.checked_sub(start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+
+        let result = match record.op {
+            TraceOp::Read => {
+                reads += 1;
+                buf.resize(record.len as usize, 0);
+                backend.read_at(record.offset, &mut buf)
+            }
+            TraceOp::Write => {
+                writes += 1;
+                buf.resize(record.len as usize, 0);
+                // Distinct per request so overlapping writes stay distinguishable
+                buf.fill(index as u8);
+                backend.write_at(record.offset, &buf)
+            }
+            TraceOp::Flush => {
+                flushes += 1;
+                backend.flush()
+            }
+            TraceOp::Discard => {
+                discards += 1;
+                backend.discard_at(record.offset, record.len)
+            }
+            TraceOp::WriteZeroes => {
+                zeroes += 1;
+                backend.write_zeroes_at(record.offset, record.len)
+            }
+        };
+        if let Err(e) = result {
+            errors += 1;
+            println!(
+                "#{} {:?} offset {} length {}: {:#}",
+                index, record.op, record.offset, record.len, e
+            );
+        }
+    }
+
+    println!(
+        "Replayed {} requests ({} reads, {} writes, {} flushes, {} discards, {} write-zeroes) in {:?}: {} error(s)",
+        records.len(),
+        reads,
+        writes,
+        flushes,
+        discards,
+        zeroes,
+        start.elapsed(),
+        errors
+    );
+    if errors > 0 {
+        bail!("{} request(s) failed during replay", errors);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::RamBuffer;
+
+    /// Inner backend that zeroes on the device, as a VRAM buffer does
+    struct FastZero(RamBuffer);
+
+    impl BlockBackend for FastZero {
+        fn size(&self) -> u64 {
+            self.0.size()
+        }
+
+        fn read_at(&self, offset: u64, dst: &mut [u8]) -> Result<()> {
+            self.0.read_at(offset, dst)
+        }
+
+        fn write_at(&self, offset: u64, src: &[u8]) -> Result<()> {
+            self.0.write_at(offset, src)
+        }
+
+        fn fast_zero(&self) -> bool {
+            true
+        }
+
+        fn is_known_zero(&self, offset: u64, _len: u64) -> bool {
+            offset == 0
+        }
+    }
+
+    #[test]
+    fn every_request_is_recorded_and_replayed() {
+        let path = std::env::temp_dir().join(format!("vramblk-trace-{}", std::process::id()));
+        {
+            let backend = TraceBackend::create(FastZero(RamBuffer::new(65536)), &path).unwrap();
+            assert!(backend.fast_zero());
+            assert!(backend.is_known_zero(0, 4096));
+            backend.write_at(4096, &[1u8; 4096]).unwrap();
+            backend.read_at(0, &mut [0u8; 512]).unwrap();
+            backend.discard_at(8192, 8192).unwrap();
+            backend.write_zeroes_at(16384, 32768).unwrap();
+            backend.flush().unwrap();
+        }
+
+        let records = read_trace(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let ops: Vec<_> = records.iter().map(|r| (r.op, r.offset, r.len)).collect();
+        assert_eq!(
+            ops,
+            [
+                (TraceOp::Write, 4096, 4096),
+                (TraceOp::Read, 0, 512),
+                (TraceOp::Discard, 8192, 8192),
+                (TraceOp::WriteZeroes, 16384, 32768),
+                (TraceOp::Flush, 0, 0),
+            ]
+        );
+        assert_eq!(trace_extent(&records), 49152);
+
+        let target = Arc::new(RamBuffer::new(65536));
+        target.write_at(16384, &[9u8; 32768]).unwrap();
+        replay(&records, target.clone(), false).unwrap();
+        let mut buf = vec![1u8; 32768];
+        target.read_at(16384, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn version_1_traces_are_still_read() {
+        let path = std::env::temp_dir().join(format!("vramblk-trace-v1-{}", std::process::id()));
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(&4096u64.to_le_bytes());
+        data.extend_from_slice(&512u32.to_le_bytes());
+        data.extend_from_slice(&7u64.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+
+        let records = read_trace(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            (records[0].op, records[0].offset, records[0].len),
+            (TraceOp::Write, 4096, 512)
+        );
+        assert_eq!(records[0].at, Duration::from_micros(7));
+    }
+}
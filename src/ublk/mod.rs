@@ -4,6 +4,7 @@
 // real start_ublk_server. For now we provide a stub that compiles and returns
 // a clear error at runtime if selected without the feature.
 
+mod mount;
 mod server;
 
-pub use server::{start_ublk_server, UblkConfig};
\ No newline at end of file
+pub use server::{start_ublk_server, ublk_queue_count, UblkConfig, UBLK_DEFAULT_DEPTH};
@@ -0,0 +1,100 @@
+//! Filesystem setup on the ublk device
+//!
+//! With `--mkfs` and `--mount`, the device is formatted and mounted once its
+//! block device node exists, by running the usual host tools (`blkid`,
+//! `wipefs`, `mkfs.<fstype>`, `mount`), and unmounted again on shutdown
+//! before the device is removed. A device that already carries a filesystem
+//! or other signature is never reformatted unless `--force-format` is set.
+
+use super::server::UblkConfig;
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Format and mount `bdev` as configured. Returns whether it was mounted.
+pub(super) fn prepare(bdev: &str, cfg: &UblkConfig) -> Result<bool> {
+    if let Some(fstype) = &cfg.mkfs {
+        match signature(bdev)? {
+            Some(existing) if !cfg.force_format => {
+                log::warn!(
+                    "ublk: {} already holds {}; not formatting it (use --force-format)",
+                    bdev,
+                    existing
+                );
+            }
+            existing => {
+                if let Some(existing) = existing {
+                    log::warn!("ublk: erasing the {} signature on {}", existing, bdev);
+                    run(Command::new("wipefs").arg("--all").arg(bdev))?;
+                }
+                log::info!("ublk: creating {} filesystem on {}", fstype, bdev);
+                run(Command::new(format!("mkfs.{}", fstype)).arg(bdev))?;
+            }
+        }
+    }
+
+    let Some(dir) = &cfg.mount_dir else {
+        return Ok(false);
+    };
+    let mut mount = Command::new("mount");
+    if cfg.read_only {
+        mount.args(["-o", "ro"]);
+    }
+    run(mount.arg(bdev).arg(dir))?;
+    log::info!("ublk: mounted {} on {}", bdev, dir.display());
+    Ok(true)
+}
+
+/// Unmount `dir`, logging rather than failing so shutdown carries on
+pub(super) fn unmount(dir: &Path) {
+    log::info!("ublk: unmounting {}", dir.display());
+    if let Err(e) = run(Command::new("umount").arg(dir)) {
+        log::error!("ublk: {:#}", e);
+    }
+}
+
+/// The filesystem or partition table type found on `bdev`, if any
+fn signature(bdev: &str) -> Result<Option<String>> {
+    let output = Command::new("blkid")
+        .args(["-p", "-o", "value", "-s", "TYPE", "-s", "PTTYPE"])
+        .arg(bdev)
+        .stdin(Stdio::null())
+        .output()
+        .context("Failed to run blkid")?;
+    match output.status.code() {
+        // Something was found, though blkid may not name it
+        Some(0) => {
+            let found = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Ok(Some(if found.is_empty() {
+                "an unknown signature".to_string()
+            } else {
+                found
+            }))
+        }
+        // Nothing recognized on the device
+        Some(2) => Ok(None),
+        _ => bail!(
+            "blkid failed on {}: {}",
+            bdev,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+/// Run `command` to completion, failing with its stderr if it fails
+fn run(command: &mut Command) -> Result<()> {
+    let name = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run {}", name))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed ({}): {}",
+            name,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
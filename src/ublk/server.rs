@@ -1,27 +1,142 @@
 use anyhow::{Context, Result};
 use std::sync::Arc;
 
-use crate::backend::BlockBackend;
+use crate::backend::{is_no_space, is_read_only, BlockBackend};
+use crate::daemon::Readiness;
+use crate::health::Health;
+use crate::metrics::Metrics;
+use crate::opencl::set_queue_affinity;
 
 use libublk::{
     ctrl::{UblkCtrl, UblkCtrlBuilder},
     io::{UblkDev, UblkIOCtx, UblkQueue},
     sys, UblkError, UblkFlags, UblkIORes,
 };
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::path::PathBuf;
+use std::sync::{mpsc, Mutex};
+use std::time::{Duration, Instant};
 use tokio_util::sync::CancellationToken;
 
+/// How long the device must be idle before a graceful shutdown removes it
+const DRAIN_QUIET: Duration = Duration::from_millis(200);
+
+/// Requests in flight per ublk queue unless configured (libublk's default)
+pub const UBLK_DEFAULT_DEPTH: u16 = 64;
+
+/// Default number of ublk queues: one per CPU, at most 8
+pub fn ublk_queue_count() -> u16 {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(8) as u16
+}
+
+/// Length of a `len`-byte request at `offset` that lies within a device of
+/// `cap` bytes. A request starting at or past the end is invalid; one that
+/// runs past it is cut short at the end.
+fn check_bounds(offset: u64, len: usize, cap: u64) -> Result<usize> {
+    if offset >= cap {
+        anyhow::bail!(
+            "Request at offset {} starts at or past the end of the device ({} bytes)",
+            offset,
+            cap
+        );
+    }
+    Ok(len.min((cap - offset) as usize))
+}
+
 /// Configuration for the ublk frontend
 #[derive(Debug, Clone)]
 pub struct UblkConfig {
     /// Logical block size in bytes (e.g., 4096)
     pub logical_block_size: u32,
+    /// Mark the block device read-only and refuse writes
+    pub read_only: bool,
+    /// Mark the block device rotational, so the kernel schedules I/O as for a disk
+    pub rotational: bool,
+    /// How long shutdown waits for I/O to go quiet before killing the device
+    pub shutdown_grace: Duration,
+    /// Write the block device path here once the device is up; removed on exit
+    pub dev_path_file: Option<PathBuf>,
+    /// Create a filesystem of this type once the device is up, unless it
+    /// already holds one
+    pub mkfs: Option<String>,
+    /// Reformat even a device that already holds a filesystem
+    pub force_format: bool,
+    /// Mount the device here once it is up; unmounted on shutdown
+    pub mount_dir: Option<PathBuf>,
+    /// Number of ublk queues, each served by its own thread
+    pub queues: u16,
+    /// Requests each queue can have in flight; every tag gets an I/O buffer
+    pub depth: u16,
+    /// Told once the device is up (and mounted) and any other frontend is
+    /// up, when running as a daemon
+    pub ready: Option<Arc<Readiness>>,
+    /// Counters updated as requests complete
+    pub metrics: Arc<Metrics>,
+    /// Marked ready once the device is up (and mounted)
+    pub health: Arc<Health>,
+}
+
+/// I/O activity shared by the queue threads and the shutdown waiter
+struct IoActivity {
+    start: Instant,
+    in_flight: AtomicUsize,
+    /// Milliseconds after `start` at which the last request completed
+    last_done: AtomicU64,
+}
+
+impl IoActivity {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            in_flight: AtomicUsize::new(0),
+            last_done: AtomicU64::new(0),
+        }
+    }
+
+    fn begin(&self) -> IoGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        IoGuard(self)
+    }
+
+    /// No request in flight and none completed within `quiet`
+    fn is_idle(&self, quiet: Duration) -> bool {
+        let since_last = (self.start.elapsed().as_millis() as u64)
+            .saturating_sub(self.last_done.load(Ordering::SeqCst));
+        self.in_flight.load(Ordering::SeqCst) == 0 && since_last >= quiet.as_millis() as u64
+    }
+
+    /// Wait until the device is idle; false if `grace` expired first
+    fn wait_idle(&self, grace: Duration) -> bool {
+        let deadline = Instant::now() + grace;
+        while !self.is_idle(DRAIN_QUIET) {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        true
+    }
+}
+
+/// Marks a request as in flight until dropped
+struct IoGuard<'a>(&'a IoActivity);
+
+impl Drop for IoGuard<'_> {
+    fn drop(&mut self) {
+        let now = self.0.start.elapsed().as_millis() as u64;
+        self.0.last_done.store(now, Ordering::SeqCst);
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 /// Start the ublk frontend server using libublk.
 ///
 /// Blocks the current task until device shutdown (Ctrl-C or SIGTERM).
-/// Shutdown is coordinated via a CancellationToken; on cancellation we call
+/// Shutdown is coordinated via a CancellationToken; on cancellation we wait
+/// up to `cfg.shutdown_grace` for in-flight I/O to drain, then call
 /// UblkCtrl::kill_dev() to stop the device and let run_target unwind cleanly.
 pub async fn start_ublk_server<B>(
     backend: Arc<B>,
@@ -29,7 +144,7 @@ pub async fn start_ublk_server<B>(
     cancel: CancellationToken,
 ) -> Result<()>
 where
-    B: BlockBackend + 'static,
+    B: BlockBackend + ?Sized + 'static,
 {
     let capacity = backend.size();
     if cfg.logical_block_size == 0 || (cfg.logical_block_size & (cfg.logical_block_size - 1)) != 0 {
@@ -51,26 +166,62 @@ where
     // Run libublk control/IO path on a blocking thread
     tokio::task::spawn_blocking(move || -> Result<()> {
         // 1) Create control device
-        let nrq: u16 = std::thread::available_parallelism()
-            .map(|n| n.get())
-            .unwrap_or(1)
-            .min(8) as u16;
-        log::info!("ublk: using {} queue(s)", nrq);
-
         let ctrl = std::sync::Arc::new(
             UblkCtrlBuilder::default()
                 .name("vram")
-                .nr_queues(nrq)
+                .nr_queues(cfg.queues)
+                .depth(cfg.depth)
                 .dev_flags(UblkFlags::UBLK_DEV_F_ADD_DEV)
                 .build()
-                .context("failed to build UblkCtrl")?,
+                .with_context(|| {
+                    format!(
+                        "failed to build UblkCtrl with {} queue(s) of depth {}",
+                        cfg.queues, cfg.depth
+                    )
+                })?,
+        );
+        // The kernel caps the queue count at the number of CPUs
+        let info = ctrl.dev_info();
+        log::info!(
+            "ublk: using {} queue(s) of depth {}",
+            info.nr_hw_queues,
+            info.queue_depth
         );
+        if info.nr_hw_queues < cfg.queues {
+            log::warn!(
+                "ublk: {} queue(s) requested, the kernel allows {}",
+                cfg.queues,
+                info.nr_hw_queues
+            );
+        }
 
-        // Shutdown waiter: on cancel, kill device (preferred; avoids deadlocks) and return
+        let activity = Arc::new(IoActivity::new());
+        let mounted = Arc::new(AtomicBool::new(false));
+
+        // Shutdown waiter: on cancel, let I/O drain, then kill device (preferred; avoids deadlocks)
         let ctrl_shutdown = ctrl.clone();
+        let activity_shutdown = activity.clone();
+        let grace = cfg.shutdown_grace;
+        let read_only = cfg.read_only;
+        let rotational = cfg.rotational;
+        let mounted_shutdown = mounted.clone();
+        let mount_dir = cfg.mount_dir.clone();
         let shutdown_thread = std::thread::spawn(move || {
             let _ = shutdown_rx.recv();
-            log::info!("ublk: shutdown requested, killing ublk device");
+            if let Some(dir) = &mount_dir
+                && mounted_shutdown.load(Ordering::SeqCst)
+            {
+                super::mount::unmount(dir);
+            }
+            log::info!("ublk: shutdown requested, waiting up to {:?} for I/O to drain", grace);
+            if activity_shutdown.wait_idle(grace) {
+                log::info!("ublk: device idle, removing ublk device");
+            } else {
+                log::warn!(
+                    "ublk: shutdown grace period of {:?} expired with I/O still active; forcing device removal",
+                    grace
+                );
+            }
             if let Err(e) = ctrl_shutdown.kill_dev() {
                 log::warn!("ublk: kill_dev failed: {:?}", e);
             } else {
@@ -79,8 +230,13 @@ where
             // Do not call std::process::exit(0); allow run_target to unwind cleanly
         });
 
-        // 2) Start the ublk target with init, per-queue IO handler, and post-start dump
+        // 2) Start the ublk target with init, per-queue IO handler, and post-start hook
         let backend_arc = backend.clone();
+        let dev_path_file = cfg.dev_path_file.clone();
+        let metrics_arc = cfg.metrics.clone();
+        let setup_cfg = cfg.clone();
+        let setup_error = Arc::new(Mutex::new(None));
+        let setup_error_hook = setup_error.clone();
 
         ctrl.run_target(
             // Init: set device params (size and logical block size)
@@ -93,11 +249,28 @@ where
                 dev.tgt.params.basic.physical_bs_shift = lbs_shift.max(12); // 4K or higher
                 dev.tgt.params.basic.io_min_shift = lbs_shift;
                 dev.tgt.params.basic.io_opt_shift = lbs_shift;
+                if read_only {
+                    dev.tgt.params.basic.attrs |= sys::UBLK_ATTR_READ_ONLY;
+                }
+                if rotational {
+                    dev.tgt.params.basic.attrs |= sys::UBLK_ATTR_ROTATIONAL;
+                }
+                // The default params declare a volatile cache; also take FUA writes
+                // instead of having the kernel follow each with a separate flush
+                dev.tgt.params.basic.attrs |= sys::UBLK_ATTR_FUA;
+                // Advertise DISCARD and WRITE_ZEROES, one range per request
+                dev.tgt.params.types |= sys::UBLK_PARAM_TYPE_DISCARD;
+                dev.tgt.params.discard.discard_granularity = 1 << lbs_shift;
+                dev.tgt.params.discard.max_discard_sectors = dev.tgt.params.basic.max_sectors;
+                dev.tgt.params.discard.max_discard_segments = 1;
+                dev.tgt.params.discard.max_write_zeroes_sectors = dev.tgt.params.basic.max_sectors;
                 Ok(())
             },
             // Per-queue IO handler
             move |qid: u16, dev: &UblkDev| {
-                // Each queue runs in its own thread context
+                // Each queue runs in its own thread context, and submits its
+                // GPU transfers on its own command queue pair
+                set_queue_affinity(qid as usize);
                 let q = UblkQueue::new(qid, dev).expect("Failed to create UblkQueue");
                 // Allocate one IoBuf per tag (depth)
                 let bufs = dev.alloc_queue_io_bufs();
@@ -107,9 +280,12 @@ where
 
                 // Share state with closure
                 let backend = backend_arc.clone();
+                let activity = activity.clone();
+                let metrics = metrics_arc.clone();
 
                 // IO loop: handle incoming CQEs
                 q.wait_and_handle_io(|q: &UblkQueue, tag: u16, _ctx: &UblkIOCtx| {
+                    let _io = activity.begin();
                     let iod = q.get_iod(tag);
                     let op = (iod.op_flags & 0xff) as u32; // op code is low bits
                     let offset = (iod.start_sector as u64) << 9;
@@ -117,18 +293,19 @@ where
 
                     // Bound by device capacity
                     let cap = backend.size();
-                    if offset > cap {
-                        // Past-end request: fail
-                        q.complete_io_cmd(tag, std::ptr::null_mut(), Err(UblkError::OtherError(-libc::EINVAL)));
-                        return;
-                    }
-                    if offset + len as u64 > cap {
-                        len = (cap - offset) as usize;
-                    }
+                    len = match check_bounds(offset, len, cap) {
+                        Ok(len) => len,
+                        Err(_) => {
+                            q.complete_io_cmd(tag, std::ptr::null_mut(), Err(UblkError::OtherError(-libc::EINVAL)));
+                            return;
+                        }
+                    };
 
-                    // Bound by IO buffer size
+                    // Bound by IO buffer size. Only reads and writes move data through
+                    // the buffer; zeroing and discard must cover the whole range, since
+                    // the kernel completes those requests in full whatever we return.
                     let max_io_buf = q.dev.dev_info.max_io_buf_bytes as usize;
-                    if len > max_io_buf {
+                    if len > max_io_buf && (op == sys::UBLK_IO_OP_READ || op == sys::UBLK_IO_OP_WRITE) {
                         len = max_io_buf;
                     }
 
@@ -138,40 +315,100 @@ where
                     );
 
                     let buf = &bufs[tag as usize];
+                    // The kernel already refuses writes to a read-only device; this is a safety net
+                    if read_only
+                        && (op == sys::UBLK_IO_OP_WRITE
+                            || op == sys::UBLK_IO_OP_WRITE_ZEROES
+                            || op == sys::UBLK_IO_OP_DISCARD)
+                    {
+                        q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EPERM)));
+                        return;
+                    }
                     match op {
                         // READ: fill buffer from backend, then complete OK(len)
                         x if x == sys::UBLK_IO_OP_READ => {
                             let dst = unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr(), len) };
                             match backend.read_at(offset, dst) {
                                 Ok(()) => {
+                                    metrics.record_read(len as u64);
                                     q.complete_io_cmd(tag, buf.as_mut_ptr(), Ok(UblkIORes::Result(len as i32)));
                                 }
                                 Err(_) => {
+                                    metrics.record_error();
                                     q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EIO)));
                                 }
                             }
                         }
-                        // WRITE: write from buffer into backend, then complete OK(len)
+                        // WRITE: write from buffer into backend, then complete OK(len).
+                        // FUA (UBLK_IO_F_FUA, bit 13 of op_flags) must be durable
+                        // before completion, so flush the backend first.
                         x if x == sys::UBLK_IO_OP_WRITE => {
                             let src = unsafe { std::slice::from_raw_parts(buf.as_mut_ptr(), len) };
-                            match backend.write_at(offset, src) {
+                            let fua = iod.op_flags & sys::UBLK_IO_F_FUA != 0;
+                            match backend.write_at(offset, src).and_then(|()| if fua { backend.flush() } else { Ok(()) }) {
                                 Ok(()) => {
+                                    metrics.record_write(len as u64);
                                     q.complete_io_cmd(tag, buf.as_mut_ptr(), Ok(UblkIORes::Result(len as i32)));
                                 }
+                                Err(e) if is_read_only(&e) => {
+                                    metrics.record_error();
+                                    q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EROFS)));
+                                }
+                                Err(e) if is_no_space(&e) => {
+                                    metrics.record_error();
+                                    q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::ENOSPC)));
+                                }
                                 Err(_) => {
+                                    metrics.record_error();
                                     q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EIO)));
                                 }
                             }
                         }
-                        // FLUSH: volatile backend; report success
-                        x if x == sys::UBLK_IO_OP_FLUSH => {
-                            q.complete_io_cmd(tag, buf.as_mut_ptr(), Ok(UblkIORes::Result(0)));
-                        }
+                        // FLUSH: commit anything the backend still buffers
+                        x if x == sys::UBLK_IO_OP_FLUSH => match backend.flush() {
+                            Ok(()) => {
+                                metrics.record_flush();
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Ok(UblkIORes::Result(0)));
+                            }
+                            Err(_) => {
+                                metrics.record_error();
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EIO)));
+                            }
+                        },
+                        // WRITE_ZEROES: zero on the backend (a device-side fill for VRAM)
+                        x if x == sys::UBLK_IO_OP_WRITE_ZEROES => match backend.write_zeroes_at(offset, len as u64) {
+                            Ok(()) => {
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Ok(UblkIORes::Result(len as i32)));
+                            }
+                            Err(e) if is_read_only(&e) => {
+                                metrics.record_error();
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EROFS)));
+                            }
+                            Err(e) if is_no_space(&e) => {
+                                metrics.record_error();
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::ENOSPC)));
+                            }
+                            Err(_) => {
+                                metrics.record_error();
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EIO)));
+                            }
+                        },
+                        // DISCARD: let the backend drop the range (zeroed for VRAM)
+                        x if x == sys::UBLK_IO_OP_DISCARD => match backend.discard_at(offset, len as u64) {
+                            Ok(()) => {
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Ok(UblkIORes::Result(0)));
+                            }
+                            Err(e) if is_read_only(&e) => {
+                                metrics.record_error();
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EROFS)));
+                            }
+                            Err(_) => {
+                                metrics.record_error();
+                                q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EIO)));
+                            }
+                        },
                         // Unsupported ops for now
-                        x if x == sys::UBLK_IO_OP_DISCARD
-                            || x == sys::UBLK_IO_OP_WRITE_ZEROES
-                            || x == sys::UBLK_IO_OP_WRITE_SAME =>
-                        {
+                        x if x == sys::UBLK_IO_OP_WRITE_SAME => {
                             q.complete_io_cmd(tag, buf.as_mut_ptr(), Err(UblkError::OtherError(-libc::EOPNOTSUPP)));
                         }
                         // Unknown op
@@ -181,17 +418,78 @@ where
                     }
                 });
             },
-            // After device started: optional post-start hook (no-op)
-            |_ctrl: &UblkCtrl| {},
+            // After device started: report the block device node
+            move |ctrl: &UblkCtrl| {
+                let bdev = ctrl.get_bdev_path();
+                log::info!("ublk: device {} ready at {}", ctrl.dev_info().dev_id, bdev);
+                if let Some(file) = &dev_path_file
+                    && let Err(e) = std::fs::write(file, format!("{}\n", bdev))
+                {
+                    log::error!("ublk: failed to write device path to {}: {}", file.display(), e);
+                }
+                // Format and mount while the queues serve the I/O; on failure shut down
+                match super::mount::prepare(&bdev, &setup_cfg) {
+                    Ok(done) => {
+                        mounted.store(done, Ordering::SeqCst);
+                        if setup_cfg.health.frontend_ready() {
+                            if let Some(ready) = &setup_cfg.ready {
+                                ready.ready();
+                            }
+                            crate::systemd::notify_ready();
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("ublk: filesystem setup failed, shutting down: {:#}", e);
+                        *setup_error_hook.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+                        let _ = shutdown_tx.send(());
+                    }
+                }
+            },
         )
         .context("libublk run_target failed")?;
 
+        if let Some(file) = &cfg.dev_path_file
+            && let Err(e) = std::fs::remove_file(file)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            log::warn!("ublk: failed to remove {}: {}", file.display(), e);
+        }
+
         // Wait for shutdown waiter to finish
         let _ = shutdown_thread.join();
-        Ok(())
+        match setup_error.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            Some(e) => Err(e.context("ublk filesystem setup failed")),
+            None => Ok(()),
+        }
     })
     .await
     .context("ublk blocking task failed to join")??;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAP: u64 = 1 << 20;
+
+    #[test]
+    fn request_before_end_is_served() {
+        assert_eq!(check_bounds(0, 4096, CAP).unwrap(), 4096);
+        assert_eq!(check_bounds(CAP - 1, 1, CAP).unwrap(), 1);
+    }
+
+    #[test]
+    fn request_running_past_end_is_clipped() {
+        assert_eq!(check_bounds(CAP - 1, 4096, CAP).unwrap(), 1);
+        assert_eq!(check_bounds(CAP - 4096, 8192, CAP).unwrap(), 4096);
+    }
+
+    #[test]
+    fn request_at_or_past_end_fails() {
+        assert!(check_bounds(CAP, 4096, CAP).is_err());
+        assert!(check_bounds(CAP, 0, CAP).is_err());
+        assert!(check_bounds(CAP + 1, 4096, CAP).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Vulkan instance setup, device enumeration and memory type selection
+
+use anyhow::{bail, Context, Result};
+use ash::{vk, Entry, Instance};
+
+/// A Vulkan device as shown by `--list-devices`
+#[derive(Debug, Clone)]
+pub struct VulkanDeviceInfo {
+    pub index: usize,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    /// Size of the largest device-local heap
+    pub device_local_memory: u64,
+    /// Whether staging memory is coherent; otherwise transfers flush explicitly
+    pub coherent_staging: bool,
+}
+
+/// Load the Vulkan loader and create an instance
+pub(super) fn create_instance() -> Result<(Entry, Instance)> {
+    let entry = unsafe { Entry::load() }.context("Failed to load the Vulkan loader")?;
+    let app_info = vk::ApplicationInfo::default()
+        .application_name(c"vramblk")
+        .api_version(vk::API_VERSION_1_1);
+    let create_info = vk::InstanceCreateInfo::default().application_info(&app_info);
+    let instance = unsafe { entry.create_instance(&create_info, None) }
+        .context("Failed to create Vulkan instance")?;
+    Ok((entry, instance))
+}
+
+/// Name of a physical device
+pub(super) fn device_name(properties: &vk::PhysicalDeviceProperties) -> String {
+    properties
+        .device_name_as_c_str()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "Unknown device".to_string())
+}
+
+/// Device-local memory type for a buffer allowed in `type_bits`, preferring
+/// one that is not host-visible (that is the actual VRAM on discrete GPUs,
+/// host-visible device-local memory is the small BAR window) and then the
+/// one on the largest heap
+pub(super) fn device_local_type(
+    memory: &vk::PhysicalDeviceMemoryProperties,
+    type_bits: u32,
+) -> Option<u32> {
+    let heaps = memory.memory_heaps_as_slice();
+    allowed_types(memory, type_bits)
+        .filter(|(_, ty)| {
+            ty.property_flags
+                .contains(vk::MemoryPropertyFlags::DEVICE_LOCAL)
+        })
+        .max_by_key(|(_, ty)| {
+            (
+                !ty.property_flags
+                    .contains(vk::MemoryPropertyFlags::HOST_VISIBLE),
+                heaps[ty.heap_index as usize].size,
+            )
+        })
+        .map(|(index, _)| index)
+}
+
+/// Host-visible memory type for the staging buffer allowed in `type_bits`,
+/// preferring coherent and then cached memory
+pub(super) fn staging_type(
+    memory: &vk::PhysicalDeviceMemoryProperties,
+    type_bits: u32,
+) -> Option<u32> {
+    allowed_types(memory, type_bits)
+        .filter(|(_, ty)| {
+            ty.property_flags
+                .contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+        })
+        .max_by_key(|(_, ty)| {
+            (
+                ty.property_flags
+                    .contains(vk::MemoryPropertyFlags::HOST_COHERENT),
+                ty.property_flags
+                    .contains(vk::MemoryPropertyFlags::HOST_CACHED),
+            )
+        })
+        .map(|(index, _)| index)
+}
+
+/// Whether memory type `index` is host-coherent
+pub(super) fn is_coherent(memory: &vk::PhysicalDeviceMemoryProperties, index: u32) -> bool {
+    memory.memory_types_as_slice()[index as usize]
+        .property_flags
+        .contains(vk::MemoryPropertyFlags::HOST_COHERENT)
+}
+
+fn allowed_types(
+    memory: &vk::PhysicalDeviceMemoryProperties,
+    type_bits: u32,
+) -> impl Iterator<Item = (u32, &vk::MemoryType)> {
+    memory
+        .memory_types_as_slice()
+        .iter()
+        .enumerate()
+        .filter(move |(index, _)| type_bits & (1 << index) != 0)
+        .map(|(index, ty)| (index as u32, ty))
+}
+
+/// Queue family for transfers, preferring a transfer-only family (a
+/// dedicated copy engine) over graphics and compute families, which also
+/// support transfers
+pub(super) fn transfer_queue_family(
+    instance: &Instance,
+    device: vk::PhysicalDevice,
+) -> Result<u32> {
+    let families = unsafe { instance.get_physical_device_queue_family_properties(device) };
+    let general = vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE;
+    let usable = |family: &vk::QueueFamilyProperties| {
+        family.queue_count > 0
+            && family
+                .queue_flags
+                .intersects(general | vk::QueueFlags::TRANSFER)
+    };
+    let dedicated = families
+        .iter()
+        .position(|family| usable(family) && !family.queue_flags.intersects(general));
+    match dedicated.or_else(|| families.iter().position(usable)) {
+        Some(index) => Ok(index as u32),
+        None => bail!("Vulkan device has no queue family supporting transfers"),
+    }
+}
+
+/// All Vulkan devices, in enumeration order
+pub fn vulkan_devices() -> Result<Vec<VulkanDeviceInfo>> {
+    let (_entry, instance) = create_instance()?;
+    let devices = unsafe { instance.enumerate_physical_devices() };
+    let result = devices
+        .context("Failed to enumerate Vulkan devices")
+        .map(|devices| {
+            devices
+                .into_iter()
+                .enumerate()
+                .map(|(index, device)| {
+                    let properties = unsafe { instance.get_physical_device_properties(device) };
+                    let memory = unsafe { instance.get_physical_device_memory_properties(device) };
+                    let device_local_memory = memory
+                        .memory_heaps_as_slice()
+                        .iter()
+                        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+                        .map(|heap| heap.size)
+                        .max()
+                        .unwrap_or(0);
+                    VulkanDeviceInfo {
+                        index,
+                        name: device_name(&properties),
+                        device_type: properties.device_type,
+                        device_local_memory,
+                        coherent_staging: staging_type(&memory, !0)
+                            .is_some_and(|index| is_coherent(&memory, index)),
+                    }
+                })
+                .collect()
+        });
+    unsafe { instance.destroy_instance(None) };
+    result
+}
@@ -0,0 +1,408 @@
+//! GPU memory management via Vulkan
+//!
+//! `VulkanBuffer` mirrors `VRamBuffer`: the data lives in a single
+//! `DEVICE_LOCAL` buffer, and reads and writes are copied through a
+//! persistently mapped host-visible staging buffer with transfer commands,
+//! in chunks of at most `VULKAN_STAGING_SIZE`. When the device has no
+//! host-coherent memory type, the staging memory is flushed before a write
+//! is copied to the device and invalidated before a read is copied out.
+//!
+//! Transfers share one staging buffer and command buffer, so they are
+//! serialized; each waits on a fence for its copy to complete.
+
+use super::device::{
+    create_instance, device_local_type, device_name, is_coherent, staging_type,
+    transfer_queue_family,
+};
+use anyhow::{bail, Context, Result};
+use ash::{vk, Device, Entry, Instance};
+use std::sync::Mutex;
+
+/// Size of the host-visible staging buffer; larger transfers are split
+pub const VULKAN_STAGING_SIZE: usize = 8 * 1024 * 1024;
+
+/// Command buffer and mapped staging memory, used under the transfer lock
+struct Transfer {
+    command: vk::CommandBuffer,
+    fence: vk::Fence,
+    mapped: *mut u8,
+}
+
+// The mapping belongs to the buffer and is only touched under its lock
+unsafe impl Send for Transfer {}
+
+/// A buffer allocated in GPU memory via Vulkan
+pub struct VulkanBuffer {
+    size: usize,
+    name: String,
+    /// Whether the staging memory is host-coherent; if not, transfers
+    /// flush and invalidate it explicitly
+    coherent: bool,
+    queue: vk::Queue,
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    staging: vk::Buffer,
+    staging_memory: vk::DeviceMemory,
+    pool: vk::CommandPool,
+    transfer: Mutex<Transfer>,
+    device: Device,
+    instance: Instance,
+    _entry: Entry,
+}
+
+impl VulkanBuffer {
+    /// Allocate `size` bytes of device-local memory on Vulkan device `device_index`
+    pub fn new(device_index: usize, size: usize) -> Result<Self> {
+        if size == 0 {
+            bail!("Cannot allocate an empty Vulkan buffer");
+        }
+        let (entry, instance) = create_instance()?;
+        let (physical, family, device) = match Self::create_device(&instance, device_index) {
+            Ok(created) => created,
+            Err(e) => {
+                unsafe { instance.destroy_instance(None) };
+                return Err(e);
+            }
+        };
+
+        let properties = unsafe { instance.get_physical_device_properties(physical) };
+        // From here on, Drop releases whatever has been created; destroying
+        // null handles is a no-op
+        let mut this = Self {
+            size,
+            name: device_name(&properties),
+            coherent: true,
+            queue: unsafe { device.get_device_queue(family, 0) },
+            buffer: vk::Buffer::null(),
+            memory: vk::DeviceMemory::null(),
+            staging: vk::Buffer::null(),
+            staging_memory: vk::DeviceMemory::null(),
+            pool: vk::CommandPool::null(),
+            transfer: Mutex::new(Transfer {
+                command: vk::CommandBuffer::null(),
+                fence: vk::Fence::null(),
+                mapped: std::ptr::null_mut(),
+            }),
+            device,
+            instance,
+            _entry: entry,
+        };
+        this.allocate(physical, &properties, family)?;
+        this.fill_zeroes(0, size)?;
+
+        log::info!(
+            "Created Vulkan buffer of size {} bytes on device: {}{}",
+            size,
+            this.name,
+            if this.coherent {
+                ""
+            } else {
+                " (non-coherent staging, flushing explicitly)"
+            }
+        );
+        Ok(this)
+    }
+
+    /// Open device `device_index` with one transfer queue; returns the
+    /// physical device, the queue family and the logical device
+    fn create_device(
+        instance: &Instance,
+        device_index: usize,
+    ) -> Result<(vk::PhysicalDevice, u32, Device)> {
+        let devices = unsafe { instance.enumerate_physical_devices() }
+            .context("Failed to enumerate Vulkan devices")?;
+        let Some(&physical) = devices.get(device_index) else {
+            bail!(
+                "Vulkan device index {} is out of bounds ({} device(s) found)",
+                device_index,
+                devices.len()
+            );
+        };
+        let family = transfer_queue_family(instance, physical)?;
+        let priorities = [1.0];
+        let queue_info = [vk::DeviceQueueCreateInfo::default()
+            .queue_family_index(family)
+            .queue_priorities(&priorities)];
+        let create_info = vk::DeviceCreateInfo::default().queue_create_infos(&queue_info);
+        let device = unsafe { instance.create_device(physical, &create_info, None) }
+            .context("Failed to create Vulkan device")?;
+        Ok((physical, family, device))
+    }
+
+    /// Create the device-local and staging buffers and the transfer commands
+    fn allocate(
+        &mut self,
+        physical: vk::PhysicalDevice,
+        properties: &vk::PhysicalDeviceProperties,
+        family: u32,
+    ) -> Result<()> {
+        let memory = unsafe {
+            self.instance
+                .get_physical_device_memory_properties(physical)
+        };
+        if properties.api_version >= vk::API_VERSION_1_1 {
+            let mut maintenance3 = vk::PhysicalDeviceMaintenance3Properties::default();
+            let mut properties2 =
+                vk::PhysicalDeviceProperties2::default().push_next(&mut maintenance3);
+            unsafe {
+                self.instance
+                    .get_physical_device_properties2(physical, &mut properties2)
+            };
+            if self.size as u64 > maintenance3.max_memory_allocation_size {
+                bail!(
+                    "Device size {} exceeds the largest Vulkan allocation on {} ({} bytes); \
+                     stripe across several devices instead",
+                    self.size,
+                    self.name,
+                    maintenance3.max_memory_allocation_size
+                );
+            }
+        }
+
+        let usage = vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST;
+        (self.buffer, self.memory, _) = self.create_buffer(self.size as u64, usage, |bits| {
+            device_local_type(&memory, bits)
+                .context("Vulkan device has no device-local memory type for the buffer")
+        })?;
+        let staging_memory_type;
+        (self.staging, self.staging_memory, staging_memory_type) =
+            self.create_buffer(VULKAN_STAGING_SIZE as u64, usage, |bits| {
+                staging_type(&memory, bits)
+                    .context("Vulkan device has no host-visible memory type for staging")
+            })?;
+        self.coherent = is_coherent(&memory, staging_memory_type);
+
+        let pool_info = vk::CommandPoolCreateInfo::default()
+            .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+            .queue_family_index(family);
+        self.pool = unsafe { self.device.create_command_pool(&pool_info, None) }
+            .context("Failed to create Vulkan command pool")?;
+
+        let transfer = self.transfer.get_mut().unwrap_or_else(|e| e.into_inner());
+        let command_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(self.pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        transfer.command = unsafe { self.device.allocate_command_buffers(&command_info) }
+            .context("Failed to allocate Vulkan command buffer")?[0];
+        transfer.fence = unsafe {
+            self.device
+                .create_fence(&vk::FenceCreateInfo::default(), None)
+        }
+        .context("Failed to create Vulkan fence")?;
+        transfer.mapped = unsafe {
+            self.device.map_memory(
+                self.staging_memory,
+                0,
+                vk::WHOLE_SIZE,
+                vk::MemoryMapFlags::empty(),
+            )
+        }
+        .context("Failed to map Vulkan staging memory")?
+        .cast();
+        Ok(())
+    }
+
+    /// Create a buffer and bind it to memory of the type chosen by `pick`
+    /// from the allowed type bits; returns the buffer, its memory and the
+    /// memory type
+    fn create_buffer(
+        &self,
+        size: u64,
+        usage: vk::BufferUsageFlags,
+        pick: impl FnOnce(u32) -> Result<u32>,
+    ) -> Result<(vk::Buffer, vk::DeviceMemory, u32)> {
+        let buffer_info = vk::BufferCreateInfo::default()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let buffer = unsafe { self.device.create_buffer(&buffer_info, None) }
+            .context("Failed to create Vulkan buffer")?;
+        let requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
+        let bound = pick(requirements.memory_type_bits).and_then(|memory_type| {
+            let allocate_info = vk::MemoryAllocateInfo::default()
+                .allocation_size(requirements.size)
+                .memory_type_index(memory_type);
+            let memory = unsafe { self.device.allocate_memory(&allocate_info, None) }
+                .context("Failed to allocate GPU memory")?;
+            if let Err(e) = unsafe { self.device.bind_buffer_memory(buffer, memory, 0) } {
+                unsafe { self.device.free_memory(memory, None) };
+                return Err(e).context("Failed to bind Vulkan buffer memory");
+            }
+            Ok((memory, memory_type))
+        });
+        match bound {
+            Ok((memory, memory_type)) => Ok((buffer, memory, memory_type)),
+            Err(e) => {
+                unsafe { self.device.destroy_buffer(buffer, None) };
+                Err(e)
+            }
+        }
+    }
+
+    /// Get the buffer size in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Get the device name
+    pub fn device_name(&self) -> String {
+        self.name.clone()
+    }
+
+    /// Read data from the GPU buffer
+    pub fn read(&self, offset: usize, data: &mut [u8]) -> Result<()> {
+        if offset + data.len() > self.size {
+            bail!("Attempted to read past end of buffer");
+        }
+        let transfer = self.transfer.lock().unwrap_or_else(|e| e.into_inner());
+        for (i, chunk) in data.chunks_mut(VULKAN_STAGING_SIZE).enumerate() {
+            let at = (offset + i * VULKAN_STAGING_SIZE) as u64;
+            let len = chunk.len() as u64;
+            self.submit(&transfer, |device, command| unsafe {
+                let region = vk::BufferCopy::default().src_offset(at).size(len);
+                device.cmd_copy_buffer(command, self.buffer, self.staging, &[region]);
+                // Make the copy visible to the host once the fence signals
+                let barrier = vk::BufferMemoryBarrier::default()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::HOST_READ)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .buffer(self.staging)
+                    .size(vk::WHOLE_SIZE);
+                device.cmd_pipeline_barrier(
+                    command,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::HOST,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[barrier],
+                    &[],
+                );
+            })
+            .context("Vulkan read from buffer failed")?;
+            if !self.coherent {
+                unsafe {
+                    self.device
+                        .invalidate_mapped_memory_ranges(&[self.staging_range()])
+                }
+                .context("Failed to invalidate Vulkan staging memory")?;
+            }
+            let staged = unsafe { std::slice::from_raw_parts(transfer.mapped, chunk.len()) };
+            chunk.copy_from_slice(staged);
+        }
+        Ok(())
+    }
+
+    /// Write data to the GPU buffer
+    pub fn write(&self, offset: usize, data: &[u8]) -> Result<()> {
+        if offset + data.len() > self.size {
+            bail!("Attempted to write past end of buffer");
+        }
+        let transfer = self.transfer.lock().unwrap_or_else(|e| e.into_inner());
+        for (i, chunk) in data.chunks(VULKAN_STAGING_SIZE).enumerate() {
+            let at = (offset + i * VULKAN_STAGING_SIZE) as u64;
+            let len = chunk.len() as u64;
+            let staged = unsafe { std::slice::from_raw_parts_mut(transfer.mapped, chunk.len()) };
+            staged.copy_from_slice(chunk);
+            if !self.coherent {
+                unsafe {
+                    self.device
+                        .flush_mapped_memory_ranges(&[self.staging_range()])
+                }
+                .context("Failed to flush Vulkan staging memory")?;
+            }
+            // Host writes are made visible to the device by the submission
+            self.submit(&transfer, |device, command| unsafe {
+                let region = vk::BufferCopy::default().dst_offset(at).size(len);
+                device.cmd_copy_buffer(command, self.staging, self.buffer, &[region]);
+            })
+            .context("Vulkan write to buffer failed")?;
+        }
+        Ok(())
+    }
+
+    /// Zero a range on the device without transferring data from the host
+    pub fn fill_zeroes(&self, offset: usize, len: usize) -> Result<()> {
+        if offset + len > self.size {
+            bail!("Attempted to fill past end of buffer");
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        // vkCmdFillBuffer works in 4-byte words; other ranges are written
+        if !offset.is_multiple_of(4) || !len.is_multiple_of(4) {
+            let zeroes = vec![0u8; len.min(VULKAN_STAGING_SIZE)];
+            let mut done = 0;
+            while done < len {
+                let n = (len - done).min(zeroes.len());
+                self.write(offset + done, &zeroes[..n])?;
+                done += n;
+            }
+            return Ok(());
+        }
+        let transfer = self.transfer.lock().unwrap_or_else(|e| e.into_inner());
+        self.submit(&transfer, |device, command| unsafe {
+            device.cmd_fill_buffer(command, self.buffer, offset as u64, len as u64, 0);
+        })
+        .context("Vulkan buffer fill failed")
+    }
+
+    /// Wait for all outstanding work on the device to complete. Transfers
+    /// already wait for their copies, so this only guards against a lost
+    /// device going unnoticed.
+    pub fn finish(&self) -> Result<()> {
+        let _transfer = self.transfer.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe { self.device.queue_wait_idle(self.queue) }.context("Vulkan queue wait failed")
+    }
+
+    /// Record commands with `record`, submit them and wait for completion
+    fn submit(
+        &self,
+        transfer: &Transfer,
+        record: impl FnOnce(&Device, vk::CommandBuffer),
+    ) -> Result<()> {
+        let begin = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            self.device.begin_command_buffer(transfer.command, &begin)?;
+            record(&self.device, transfer.command);
+            self.device.end_command_buffer(transfer.command)?;
+            self.device.reset_fences(&[transfer.fence])?;
+            let commands = [transfer.command];
+            let submit = vk::SubmitInfo::default().command_buffers(&commands);
+            self.device
+                .queue_submit(self.queue, &[submit], transfer.fence)?;
+            self.device
+                .wait_for_fences(&[transfer.fence], true, u64::MAX)?;
+        }
+        Ok(())
+    }
+
+    fn staging_range(&self) -> vk::MappedMemoryRange<'static> {
+        vk::MappedMemoryRange::default()
+            .memory(self.staging_memory)
+            .size(vk::WHOLE_SIZE)
+    }
+}
+
+impl Drop for VulkanBuffer {
+    fn drop(&mut self) {
+        log::debug!("Freeing Vulkan memory buffer");
+        let transfer = self.transfer.get_mut().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            let _ = self.device.device_wait_idle();
+            if !transfer.mapped.is_null() {
+                self.device.unmap_memory(self.staging_memory);
+            }
+            self.device.destroy_fence(transfer.fence, None);
+            self.device.destroy_command_pool(self.pool, None);
+            self.device.destroy_buffer(self.staging, None);
+            self.device.free_memory(self.staging_memory, None);
+            self.device.destroy_buffer(self.buffer, None);
+            self.device.free_memory(self.memory, None);
+            self.device.destroy_device(None);
+            self.instance.destroy_instance(None);
+        }
+    }
+}
@@ -0,0 +1,12 @@
+//! Vulkan module for GPU memory allocation on any vendor
+//!
+//! An alternative to the OpenCL module for drivers with poor or no OpenCL
+//! support (Intel Arc, Apple GPUs through MoltenVK). The device's data lives
+//! in a device-local buffer; transfers are staged through a host-visible
+//! buffer with copy commands.
+
+mod device;
+mod memory;
+
+pub use device::vulkan_devices;
+pub use memory::VulkanBuffer;